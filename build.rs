@@ -5,6 +5,7 @@ pub(crate) fn main() -> Result<(), Box<dyn std::error::Error>> {
         .compile(
             &[
                 "./protos/protot/core/task.proto",
+                "./protos/protot/core/workflow.proto",
                 "./protos/protot/core/configs.proto",
                 "./protos/protot/scheduler/v1/scheduler.proto",
                 "./protos/protot/scheduler/v1/scheduler_worker.proto",
@@ -15,13 +15,14 @@
 use std::{error::Error, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use prost_types::Any;
 use protot::{
     // Useful core traits and struct
     core::worker_pool::{TaskExecutor, TaskRegistry, GrpcWorkersRegistry, AsyncTaskExecutor},
     // Protobuf Impl for communication and other common objects
     internal::protot::{
-        core::{Config, NodeType, TaskState, LoadBalancer},
-        scheduler::v1::{ExecuteRequest, TaskCompletion},
+        core::{Config, NodeType, TaskError, LoadBalancer},
+        scheduler::v1::ExecuteRequest,
     },
     // Easy startup procedure
     start,
@@ -33,8 +34,10 @@ use tonic::{Status, Response};
 struct MyWorker;
 // Impl the TaskExecutor trait to hold the actual task logic
 impl TaskExecutor for MyWorker {
-    fn execute(&self, args: ExecuteRequest) {
+    fn execute(&self, args: ExecuteRequest) -> Result<Any, TaskError> {
         println!("custom worker executed task");
+        // The output is stored with the execution and returned by `GetExecution`
+        Ok(Any::default())
     }
 }
 
@@ -54,7 +57,8 @@ pub async fn main() {
         graceful_timeout: 30,
         heartbeat_interval: None,
         load_balancer: LoadBalancer::RoundRobin.into(),
-        data_store: None
+        data_store: None,
+        ..Default::default()
     };
 
     // Startup the scheduler service and workers
@@ -26,7 +26,9 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Create Tasks
     let task1 = Task {
-        id: "task-1".to_string(),
+        id: "order-1".to_string(),
+        // Runs on the executor registered as "task-1"
+        task_type: "task-1".to_string(),
         payload: Some(any),
         ..Default::default()
     };
@@ -12,19 +12,22 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{error::Error, sync::Arc, time::Duration, env};
+use std::{error::Error, sync::Arc, time::Duration};
 
 use async_trait::async_trait;
+use prost_types::Any;
 use protot::{
     // Useful core traits and struct
     core::worker_pool::{TaskExecutor, TaskRegistry, GrpcWorkersRegistry, AsyncTaskExecutor},
+    // Handle for submitting child tasks from a running execution
+    client::TaskContext,
     // Protobuf Impl for communication and other common objects
     internal::protot::{
-        core::{Config, NodeType, TaskState},
-        scheduler::v1::{ExecuteRequest, TaskCompletion},
+        core::{Config, NodeType, TaskError},
+        scheduler::v1::ExecuteRequest,
     },
     // Easy startup procedure
-    start,
+    start_worker,
 };
 use tokio::time::sleep;
 use tonic::{Status, Response};
@@ -33,8 +36,10 @@ use tonic::{Status, Response};
 struct MyWorker;
 // Impl the TaskExecutor trait to hold the actual task logic
 impl TaskExecutor for MyWorker {
-    fn execute(&self, args: ExecuteRequest) {
+    fn execute(&self, args: ExecuteRequest) -> Result<Any, TaskError> {
         println!("custom worker executed task");
+        // The output is stored with the execution and returned by `GetExecution`
+        Ok(Any::default())
     }
 }
 
@@ -45,8 +50,9 @@ struct MyGrpcWorker;
 #[async_trait]
 impl AsyncTaskExecutor for MyGrpcWorker {
     /// the execute will be invoked on worker once it recieved `AssignTaskRequest` from scheduler
-    async fn execute(&self, args: ExecuteRequest) -> Result<TaskCompletion, ()> {
-        println!("gRPC worker executing async task {:?}", args);
+    /// `ctx` can submit child tasks of this execution, e.g. `ctx.submit(task).await?`
+    async fn execute(&self, args: ExecuteRequest, ctx: TaskContext) -> Result<Any, TaskError> {
+        println!("gRPC worker executing async task {:?} ({})", args, ctx.execution_id());
 
         // ..Your code execution logic goes here..
 
@@ -59,27 +65,21 @@ impl AsyncTaskExecutor for MyGrpcWorker {
             eprintln!("Failed to join spawned task: {}", e);
         });
 
-        Ok(
-            TaskCompletion {
-                task_id: args.task.unwrap().id.clone(),
-                state: TaskState::Pending.into(),
-                execution_id: args.execution_id 
-            }
-        )
+        // Failures are reported with a structured error instead
+        match args.task.and_then(|task| task.payload) {
+            Some(payload) => Ok(payload),
+            None => Err(TaskError {
+                code: "MISSING_PAYLOAD".to_string(),
+                message: "task has no payload to echo".to_string(),
+                details: None,
+            }),
+        }
     }
 }
 
 #[tokio::main]
 async fn main() {
 
-    // Parse command-line arguments to get worker ID
-    let args: Vec<String> = env::args().collect();
-    let worker_id = if args.len() > 1 {
-        args[1].clone()
-    } else {
-        "default-worker-id".to_string() // fallback to a default worker ID
-    };
-
     // ** gRPC Worker Setup **
     let mut grpc_registry = GrpcWorkersRegistry::new();
 
@@ -89,14 +89,9 @@ async fn main() {
  
     // ..any task that is not registered on the worker will bot be executed on this worker..
 
-    // worker id must be unique on the scheduler
-    // otherwise it will return error on communicate
-    let worker = protot::client::GrpcWorkerBuilder::new()
-        .with_id(worker_id) 
-        .with_registry(grpc_registry)
-        .build();
-    // the only communication channel process for scheduler<->worker
-    let res = worker.communicate().await;
+    // Scheduler addresses, worker id (must be unique on the scheduler), concurrency
+    // and magic cookie are read from the `worker` section of `configs.yaml`
+    let res = start_worker(grpc_registry, None).await;
     
     match res {
         Ok(()) => println!("worker done"),
@@ -0,0 +1,21 @@
+-- Task executions submitted to the scheduler, keyed by their execution id.
+-- `request` holds the protobuf encoded `protot.scheduler.v1.ExecuteRequest`.
+-- `claimed_by` is the scheduler holding a pending execution in its queue, a newly
+-- elected leader claims the pending executions of the former one.
+CREATE TABLE IF NOT EXISTS task_executions (
+    execution_id TEXT PRIMARY KEY,
+    task_id TEXT NOT NULL,
+    task_type TEXT NOT NULL,
+    worker_id TEXT,
+    claimed_by TEXT,
+    state INTEGER NOT NULL,
+    request BYTEA NOT NULL,
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_task_executions_state ON task_executions (state);
+CREATE INDEX IF NOT EXISTS idx_task_executions_worker_id ON task_executions (worker_id);
+CREATE INDEX IF NOT EXISTS idx_task_executions_task_type ON task_executions (task_type);
+-- Serves the `FOR UPDATE SKIP LOCKED` claim scan over pending executions, oldest first
+CREATE INDEX IF NOT EXISTS idx_task_executions_claims ON task_executions (state, created_at);
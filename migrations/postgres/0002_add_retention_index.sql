@@ -0,0 +1,2 @@
+-- Retention sweeps delete finished executions by state and age
+CREATE INDEX IF NOT EXISTS idx_task_executions_state_updated_at ON task_executions (state, updated_at);
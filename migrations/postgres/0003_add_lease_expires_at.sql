@@ -0,0 +1,2 @@
+-- Set while a worker owns a running execution, it is requeued once the lease runs out.
+ALTER TABLE task_executions ADD COLUMN lease_expires_at BIGINT;
@@ -0,0 +1,2 @@
+-- Number of times an execution was queued again after a failed attempt.
+ALTER TABLE task_executions ADD COLUMN retries BIGINT NOT NULL DEFAULT 0;
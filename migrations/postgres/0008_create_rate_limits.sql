@@ -0,0 +1,7 @@
+-- Token buckets of the rate limits, shared by the schedulers using the database.
+CREATE TABLE IF NOT EXISTS rate_limits (
+    rate_key TEXT PRIMARY KEY,
+    tokens DOUBLE PRECISION NOT NULL,
+    -- Unix timestamp in milliseconds
+    updated_at BIGINT NOT NULL
+);
@@ -0,0 +1,11 @@
+-- Executions that failed after their last attempt, kept until they are requeued or purged.
+CREATE TABLE IF NOT EXISTS dead_letters (
+    execution_id TEXT PRIMARY KEY,
+    task_type TEXT NOT NULL,
+    -- Unix timestamp in seconds
+    dead_lettered_at BIGINT NOT NULL,
+    -- Encoded DeadLetter message
+    dead_letter BYTEA NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_dead_letters_dead_lettered_at ON dead_letters (dead_lettered_at, execution_id);
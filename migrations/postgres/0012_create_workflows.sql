@@ -0,0 +1,12 @@
+-- Submitted workflows along with the state of each of their nodes.
+CREATE TABLE IF NOT EXISTS workflows (
+    workflow_id TEXT PRIMARY KEY,
+    state INTEGER NOT NULL,
+    -- Unix timestamps in seconds
+    created_at BIGINT NOT NULL,
+    updated_at BIGINT NOT NULL,
+    -- Encoded WorkflowStatus message
+    workflow BYTEA NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_workflows_state ON workflows (state, created_at);
@@ -0,0 +1,16 @@
+-- Task executions submitted to the scheduler, keyed by their execution id.
+-- `request` holds the protobuf encoded `protot.scheduler.v1.ExecuteRequest`.
+CREATE TABLE IF NOT EXISTS task_executions (
+    execution_id TEXT PRIMARY KEY NOT NULL,
+    task_id TEXT NOT NULL,
+    task_type TEXT NOT NULL,
+    worker_id TEXT,
+    state INTEGER NOT NULL,
+    request BLOB NOT NULL,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_task_executions_state ON task_executions (state);
+CREATE INDEX IF NOT EXISTS idx_task_executions_worker_id ON task_executions (worker_id);
+CREATE INDEX IF NOT EXISTS idx_task_executions_task_type ON task_executions (task_type);
@@ -0,0 +1,5 @@
+-- The scheduler holding a pending execution in its queue, a newly elected leader
+-- claims the pending executions of the former one.
+ALTER TABLE task_executions ADD COLUMN claimed_by TEXT;
+
+CREATE INDEX IF NOT EXISTS idx_task_executions_claims ON task_executions (state, created_at);
@@ -0,0 +1,8 @@
+-- Leader lease of the scheduler replicas, a single row that changes hands when it expires.
+CREATE TABLE IF NOT EXISTS scheduler_leader (
+    id INTEGER PRIMARY KEY,
+    holder TEXT NOT NULL,
+    address TEXT NOT NULL,
+    token INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+);
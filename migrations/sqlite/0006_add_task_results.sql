@@ -0,0 +1,4 @@
+-- Protobuf encoded `google.protobuf.Any` output of a successful execution
+-- and `protot.core.TaskError` of a failed one.
+ALTER TABLE task_executions ADD COLUMN output BLOB;
+ALTER TABLE task_executions ADD COLUMN error BLOB;
@@ -0,0 +1,8 @@
+-- Running executions holding a slot of their concurrency key.
+CREATE TABLE IF NOT EXISTS concurrency_slots (
+    concurrency_key TEXT NOT NULL,
+    execution_id TEXT NOT NULL,
+    -- Unix timestamp in milliseconds
+    acquired_at INTEGER NOT NULL,
+    PRIMARY KEY (concurrency_key, execution_id)
+);
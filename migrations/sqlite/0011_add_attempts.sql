@@ -0,0 +1,2 @@
+-- Failed attempts of an execution that were retried, an encoded ExecutionAttempts message.
+ALTER TABLE task_executions ADD COLUMN attempts BLOB;
@@ -0,0 +1,12 @@
+-- Submitted workflows along with the state of each of their nodes.
+CREATE TABLE IF NOT EXISTS workflows (
+    workflow_id TEXT PRIMARY KEY NOT NULL,
+    state INTEGER NOT NULL,
+    -- Unix timestamps in seconds
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    -- Encoded WorkflowStatus message
+    workflow BLOB NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS idx_workflows_state ON workflows (state, created_at);
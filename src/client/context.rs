@@ -0,0 +1,283 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Child tasks submitted by running executions over the worker stream.
+//!
+//! ```ignore
+//! #[async_trait]
+//! impl AsyncTaskExecutor for Crawl {
+//!     async fn execute(&self, args: ExecuteRequest, ctx: TaskContext) -> Result<Any, TaskError> {
+//!         // Fire and forget
+//!         ctx.submit(Task { task_type: "index".to_string(), ..Default::default() }).await?;
+//!         // Waits for the child to finish before the parent does
+//!         let child = ctx.spawn(Task { task_type: "fetch".to_string(), ..Default::default() }).await?;
+//!         let completion = child.wait().await?;
+//!         ...
+//!     }
+//! }
+//! ```
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::{mpsc, oneshot};
+use tonic::{Code, Status};
+
+use crate::internal::protot::{
+    core::{Task, TaskError},
+    scheduler::v1::{worker_message, ExecuteRequest, SubmitTask, TaskCompletion, TaskSubmitted, WorkerMessage},
+};
+
+/// Handle given to the executors of a `GrpcWorker`, submits child tasks of the running
+/// execution to the scheduler the worker is connected to.
+#[derive(Clone)]
+pub struct TaskContext {
+    execution_id: String,
+    /// Messages forwarded to the scheduler of the current session, `None` when detached.
+    outbound: Option<mpsc::Sender<WorkerMessage>>,
+    children: Arc<ChildTasks>,
+}
+
+impl TaskContext {
+    pub(crate) fn new(execution_id: String, outbound: mpsc::Sender<WorkerMessage>, children: Arc<ChildTasks>) -> Self {
+        Self { execution_id, outbound: Some(outbound), children }
+    }
+
+    /// A context not connected to any scheduler, child tasks are rejected with `FAILED_PRECONDITION`.
+    ///
+    /// Useful to call executors outside of a worker, e.g. in tests.
+    pub fn detached(execution_id: impl Into<String>) -> Self {
+        Self { execution_id: execution_id.into(), outbound: None, children: Arc::default() }
+    }
+
+    /// Execution running the task, the parent of the child tasks it submits.
+    pub fn execution_id(&self) -> &str {
+        &self.execution_id
+    }
+
+    /// Submits a child task, returns the id of its execution.
+    ///
+    /// Takes a `Task` or a whole `ExecuteRequest`, e.g. to set an idempotency key that
+    /// makes it safe to submit again after an `UNAVAILABLE` error.
+    pub async fn submit(&self, request: impl Into<ExecuteRequest>) -> Result<String, Status> {
+        let (execution_id, _) = self.send(request.into(), false).await?;
+        Ok(execution_id)
+    }
+
+    /// Submits a child task the execution can wait for.
+    ///
+    /// A parent waiting for its children keeps its slot on the worker, so children should be
+    /// able to run elsewhere or the worker should run more than one execution at once.
+    pub async fn spawn(&self, request: impl Into<ExecuteRequest>) -> Result<ChildTask, Status> {
+        let (execution_id, completion) = self.send(request.into(), true).await?;
+        let completion = completion.expect("completion is tracked for spawned tasks");
+        Ok(ChildTask { execution_id, completion })
+    }
+
+    async fn send(
+        &self,
+        request: ExecuteRequest,
+        notify_completion: bool,
+    ) -> Result<(String, Option<oneshot::Receiver<TaskCompletion>>), Status> {
+        let outbound = self
+            .outbound
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("task context is not connected to a scheduler"))?;
+        let (request_id, reply, completion) = self.children.track(notify_completion);
+        let message = WorkerMessage {
+            worker_message_type: Some(worker_message::WorkerMessageType::SubmitTask(SubmitTask {
+                request_id: request_id.clone(),
+                request: Some(request),
+                parent_execution_id: self.execution_id.clone(),
+                notify_completion,
+            })),
+        };
+        if outbound.send(message).await.is_err() {
+            self.children.forget(&request_id);
+            return Err(Status::unavailable("worker stopped before the child task could be submitted"));
+        }
+        let reply = reply.await.map_err(|_| lost_connection())?;
+        if reply.code != Code::Ok as i32 {
+            return Err(Status::new(Code::from_i32(reply.code), reply.message));
+        }
+        Ok((reply.execution_id, completion))
+    }
+}
+
+/// Child task submitted with `TaskContext::spawn`.
+#[derive(Debug)]
+pub struct ChildTask {
+    execution_id: String,
+    completion: oneshot::Receiver<TaskCompletion>,
+}
+
+impl ChildTask {
+    pub fn execution_id(&self) -> &str {
+        &self.execution_id
+    }
+
+    /// Waits for the child task to finish, after its retries if it has any.
+    ///
+    /// Fails with `UNAVAILABLE` when the connection to the scheduler is lost first, the
+    /// child keeps running and its result can still be fetched with `GetExecution`.
+    pub async fn wait(self) -> Result<TaskCompletion, Status> {
+        self.completion.await.map_err(|_| lost_connection())
+    }
+}
+
+impl From<Task> for ExecuteRequest {
+    fn from(task: Task) -> Self {
+        ExecuteRequest { task: Some(task), ..Default::default() }
+    }
+}
+
+impl From<Status> for TaskError {
+    fn from(status: Status) -> Self {
+        TaskError::new("CHILD_TASK_FAILED", status.message())
+    }
+}
+
+fn lost_connection() -> Status {
+    Status::unavailable("connection to the scheduler was lost")
+}
+
+/// Child task submissions waiting for their reply, and spawned child tasks waiting for their
+/// completion, shared by the executions of a worker and its sessions.
+#[derive(Default)]
+pub(crate) struct ChildTasks {
+    inner: Mutex<PendingChildren>,
+}
+
+#[derive(Default)]
+struct PendingChildren {
+    next_request: u64,
+    /// Keyed by request id, with the completion sender of spawned tasks.
+    submissions: HashMap<String, (oneshot::Sender<TaskSubmitted>, Option<oneshot::Sender<TaskCompletion>>)>,
+    /// Keyed by execution id, several spawns can share an execution through an idempotency key.
+    completions: HashMap<String, Vec<oneshot::Sender<TaskCompletion>>>,
+}
+
+impl ChildTasks {
+    fn track(&self, notify_completion: bool) -> (String, oneshot::Receiver<TaskSubmitted>, Option<oneshot::Receiver<TaskCompletion>>) {
+        let (reply_tx, reply_rx) = oneshot::channel();
+        let (completion_tx, completion_rx) = match notify_completion {
+            true => {
+                let (sender, receiver) = oneshot::channel();
+                (Some(sender), Some(receiver))
+            }
+            false => (None, None),
+        };
+        let mut inner = self.inner.lock().unwrap();
+        let request_id = inner.next_request.to_string();
+        inner.next_request += 1;
+        inner.submissions.insert(request_id.clone(), (reply_tx, completion_tx));
+        (request_id, reply_rx, completion_rx)
+    }
+
+    fn forget(&self, request_id: &str) {
+        self.inner.lock().unwrap().submissions.remove(request_id);
+    }
+
+    /// Hands the reply to its submission, the completion of a spawned task is expected next.
+    pub(crate) fn submitted(&self, reply: TaskSubmitted) {
+        let mut inner = self.inner.lock().unwrap();
+        let Some((reply_tx, completion_tx)) = inner.submissions.remove(&reply.request_id) else {
+            return;
+        };
+        if let (Some(completion_tx), Code::Ok) = (completion_tx, Code::from_i32(reply.code)) {
+            inner.completions.entry(reply.execution_id.clone()).or_default().push(completion_tx);
+        }
+        let _ = reply_tx.send(reply);
+    }
+
+    /// Hands the completion of a child task to the executions waiting for it.
+    pub(crate) fn completed(&self, completion: TaskCompletion) {
+        let senders = self.inner.lock().unwrap().completions.remove(&completion.execution_id);
+        for sender in senders.into_iter().flatten() {
+            let _ = sender.send(completion.clone());
+        }
+    }
+
+    /// Fails every pending submission and wait, the scheduler that would have answered them is gone.
+    pub(crate) fn disconnected(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.submissions.clear();
+        inner.completions.clear();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::internal::protot::core::TaskState;
+
+    fn reply(request_id: &str, execution_id: &str) -> TaskSubmitted {
+        TaskSubmitted { request_id: request_id.to_string(), execution_id: execution_id.to_string(), code: Code::Ok as i32, message: String::new() }
+    }
+
+    /// Answers the child task submissions of `outbound` as a scheduler would.
+    fn answer(mut outbound: mpsc::Receiver<WorkerMessage>, children: Arc<ChildTasks>) -> tokio::task::JoinHandle<Vec<SubmitTask>> {
+        tokio::spawn(async move {
+            let mut submitted = Vec::new();
+            while let Some(message) = outbound.recv().await {
+                let Some(worker_message::WorkerMessageType::SubmitTask(submit)) = message.worker_message_type else { continue };
+                let execution_id = format!("child-{}", submitted.len());
+                children.submitted(reply(&submit.request_id, &execution_id));
+                if submit.notify_completion {
+                    children.completed(TaskCompletion::from_result(String::new(), execution_id, Ok(Default::default())));
+                }
+                submitted.push(submit);
+            }
+            submitted
+        })
+    }
+
+    #[tokio::test]
+    async fn test_spawned_children_are_waited_for() {
+        let (outbound, receiver) = mpsc::channel(4);
+        let children = Arc::new(ChildTasks::default());
+        let scheduler = answer(receiver, children.clone());
+        let ctx = TaskContext::new("parent".to_string(), outbound, children);
+
+        assert_eq!(ctx.submit(Task::default()).await.unwrap(), "child-0");
+        let child = ctx.spawn(Task::default()).await.unwrap();
+        assert_eq!(child.execution_id(), "child-1");
+        assert_eq!(child.wait().await.unwrap().state(), TaskState::Success);
+
+        drop(ctx);
+        let submitted = scheduler.await.unwrap();
+        assert!(submitted.iter().all(|submit| submit.parent_execution_id == "parent"));
+        assert!(!submitted[0].notify_completion && submitted[1].notify_completion);
+    }
+
+    #[tokio::test]
+    async fn test_waits_fail_when_disconnected() {
+        let (outbound, mut receiver) = mpsc::channel(4);
+        let children = Arc::new(ChildTasks::default());
+        let ctx = TaskContext::new("parent".to_string(), outbound, children.clone());
+
+        let spawn = tokio::spawn(async move { ctx.spawn(Task::default()).await });
+        let Some(worker_message::WorkerMessageType::SubmitTask(submit)) = receiver.recv().await.unwrap().worker_message_type else {
+            panic!("expected a child task submission");
+        };
+        children.submitted(reply(&submit.request_id, "child"));
+        let child = spawn.await.unwrap().unwrap();
+
+        children.disconnected();
+        assert_eq!(child.wait().await.unwrap_err().code(), Code::Unavailable);
+        assert_eq!(TaskContext::detached("parent").submit(Task::default()).await.unwrap_err().code(), Code::FailedPrecondition);
+    }
+}
@@ -14,15 +14,20 @@
 
 use std::{
     sync::Arc,
-    error::Error
+    error::Error,
+    time::Duration,
 };
-use log::debug;
+use log::{debug, error, info, warn};
 use tokio::{
-    sync::mpsc,
+    select,
+    sync::{mpsc, Semaphore},
+    time::{interval, sleep},
 };
+use tokio_stream::wrappers::ReceiverStream;
+use tokio_util::sync::CancellationToken;
 use tonic::Request;
 use crate::{internal::protot::{
-    core::TaskState,
+    core::TaskError,
     scheduler::v1::{
         ExecuteRequest,
         scheduler_worker_service_client::SchedulerWorkerServiceClient,
@@ -31,6 +36,14 @@ use crate::{internal::protot::{
 }, core::worker_pool::GrpcWorkersRegistry,
 };
 
+pub mod context;
+pub mod workflows;
+
+pub use context::{ChildTask, TaskContext};
+pub use workflows::{chain, chord, group, Canvas};
+
+use context::ChildTasks;
+
 // #[macro_export]
 // macro_rules! execute_task {
 //     ($client:expr, $name:expr) => {
@@ -64,9 +77,22 @@ use crate::{internal::protot::{
 //     }
 // }
 
+/// Scheduler address used when the worker is not given any.
+pub const DEFAULT_SCHEDULER_ADDRESS: &str = "http://0.0.0.0:44880";
+
+/// How long a worker waits before connecting to the next scheduler.
+pub const DEFAULT_RECONNECT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often a shutting down worker checks whether its executions are done.
+const DRAIN_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
 pub struct GrpcWorker {
     registeration_details: Arc<RegistrationRequest>,
-    registry: GrpcWorkersRegistry,
+    registry: Arc<GrpcWorkersRegistry>,
+    schedulers: Vec<String>,
+    reconnect_interval: Duration,
+    concurrency: usize,
+    shutdown: CancellationToken,
     // shared_data: Arc<SharedData>,
 }
 
@@ -83,6 +109,17 @@ pub struct GrpcWorkerBuilder {
     tasks: Vec<String>,
     cookie: Option<String>,
     registry: Option<GrpcWorkersRegistry>,
+    schedulers: Vec<String>,
+    reconnect_interval: Duration,
+    concurrency: usize,
+    queues: Vec<String>,
+    shutdown: CancellationToken,
+}
+
+impl Default for GrpcWorkerBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl GrpcWorkerBuilder {
@@ -92,6 +129,11 @@ impl GrpcWorkerBuilder {
             tasks: Vec::new(),
             cookie: None,
             registry: None,
+            schedulers: Vec::new(),
+            reconnect_interval: DEFAULT_RECONNECT_INTERVAL,
+            concurrency: 1,
+            queues: Vec::new(),
+            shutdown: CancellationToken::new(),
         }
     }
 
@@ -105,6 +147,42 @@ impl GrpcWorkerBuilder {
         self
     }
 
+    /// Secret presented to the scheduler on registration.
+    pub fn with_cookie(mut self, cookie: String) -> Self {
+        self.cookie = Some(cookie);
+        self
+    }
+
+    /// Max number of executions running at once.
+    pub fn with_concurrency(mut self, concurrency: usize) -> Self {
+        self.concurrency = concurrency.max(1);
+        self
+    }
+
+    /// Queues the worker takes executions from, all of them when none is set.
+    pub fn with_queues(mut self, queues: Vec<String>) -> Self {
+        self.queues = queues;
+        self
+    }
+
+    /// Stops the worker gracefully once `shutdown` is cancelled: running executions
+    /// are finished and reported before the worker disconnects.
+    pub fn with_shutdown(mut self, shutdown: CancellationToken) -> Self {
+        self.shutdown = shutdown;
+        self
+    }
+
+    /// Addresses of the scheduler replicas, tried in turn until one of them leads.
+    pub fn with_schedulers(mut self, schedulers: Vec<String>) -> Self {
+        self.schedulers = schedulers;
+        self
+    }
+
+    pub fn with_reconnect_interval(mut self, interval: Duration) -> Self {
+        self.reconnect_interval = interval;
+        self
+    }
+
     pub fn build(self) -> GrpcWorker {
         GrpcWorker {
             registeration_details:Arc::new(RegistrationRequest {
@@ -114,122 +192,226 @@ impl GrpcWorkerBuilder {
                 supported_tasks: self.tasks,
                 magic_cookie: self.cookie
                     .clone()
-                    .unwrap_or("SomeSecert".to_string())
+                    .unwrap_or("SomeSecert".to_string()),
+                queues: self.queues,
             }),
-            registry: self.registry.unwrap_or(GrpcWorkersRegistry::new()),
+            registry: Arc::new(self.registry.unwrap_or_default()),
+            schedulers: if self.schedulers.is_empty() {
+                vec![DEFAULT_SCHEDULER_ADDRESS.to_string()]
+            } else {
+                self.schedulers
+            },
+            reconnect_interval: self.reconnect_interval,
+            concurrency: self.concurrency,
+            shutdown: self.shutdown,
         }
     }
 }
 
+/// Why a session with a scheduler ended.
+enum SessionEnd {
+    /// The scheduler asked the worker to leave.
+    Disconnected,
+    /// The scheduler is a standby, the leader address is given when known.
+    Redirected(Option<String>),
+    /// The connection was closed, e.g. the scheduler went away.
+    Closed,
+}
+
 impl GrpcWorker {
     
+    /// Serves the scheduler until it asks the worker to disconnect or the worker shuts down.
+    ///
+    /// The worker follows redirects to the leading scheduler and, when the connection is
+    /// lost, reconnects to the next scheduler address. Completions of executions that
+    /// finish while disconnected are sent to the next scheduler.
     pub async fn communicate(self) -> Result<(), Box<dyn Error>>  {
-        let mut client = SchedulerWorkerServiceClient::connect("http://0.0.0.0:44880").await?;
-        
         let (task_tx, mut task_rx) = mpsc::channel::<AssignTaskRequest>(1);  // Task is your custom type representing a task.
-        let (completion_tx, mut completion_rx) = mpsc::channel::<WorkerMessage>(1);
-        
-        let binding_tx_complete = completion_tx.clone();
-        let binding = self.registeration_details.clone();
+        let (completion_tx, mut completion_rx) = mpsc::channel::<WorkerMessage>(self.concurrency);
+        let permits = Arc::new(Semaphore::new(self.concurrency));
+        let children = Arc::new(ChildTasks::default());
+
+        let registry = self.registry.clone();
+        let executor_permits = permits.clone();
+        let shutdown = self.shutdown.clone();
+        let executor_children = children.clone();
         tokio::spawn(async move {
+            // Executions wait for a permit in their own task, so assignments never block the scheduler stream
             while let Some(task) = task_rx.recv().await {
-                let bind = task.task.clone();
-                let executor = self.registry.get_executor(&bind.unwrap().id);
-                match executor {
-                    Ok(operation) => {
-                        // Here perform the actual task execution.
-                        let execution_id = task.execution_id.clone();
-                        let execute_req = ExecuteRequest { task: task.task.clone(), execution_id: execution_id };
-                        match operation.execute(execute_req).await {
-                            Ok(completion) => {
-                                // Send task response to the communicate loop
-                                let response = WorkerMessage {
-                                    worker_message_type: Some(
-                                        worker_message::WorkerMessageType::Completion(completion)
-                                    ),
-                                };
-                                completion_tx.send(response).await.expect("send completion");
-                            }
-                            Err(err) => {
-                                eprintln!("Failed to execute task: {:?}", err);
-                            }
-                        }
-                    },
-                    Err(err) => {
-                        println!("Error: {:?}", err);
-                        let execution_id = task.execution_id;
-                        completion_tx.send(WorkerMessage { worker_message_type: Some(
-                            worker_message::WorkerMessageType::Completion(
-                                TaskCompletion { 
-                                    task_id: task.task.unwrap().id,
-                                    state: TaskState::Fail.into(),
-                                    execution_id: execution_id
-                                }
-                            )
-                        ) }).await.expect("send completion");
+                let registry = registry.clone();
+                let permits = executor_permits.clone();
+                let completion_tx = completion_tx.clone();
+                let shutdown = shutdown.clone();
+                let children = executor_children.clone();
+                tokio::spawn(async move {
+                    let Ok(_permit) = permits.acquire_owned().await else { return };
+                    if shutdown.is_cancelled() {
+                        // The lease runs out and the scheduler queues it again
+                        warn!("shutting down, leaving task execution {} to another worker", task.execution_id);
+                        return;
                     }
-                }
+                    // Child tasks are submitted along with the completions, to the scheduler of the current session
+                    let ctx = TaskContext::new(task.execution_id.clone(), completion_tx.clone(), children);
+                    let completion = execute_task(&registry, task, ctx).await;
+                    if completion_tx.send(completion).await.is_err() {
+                        error!("worker stopped before the completion could be sent");
+                    }
+                });
             }
         });
 
-        // Create the outbound stream for gRPC.
-        let t = Arc::clone(&binding);
-        let outbound = async_stream::stream! {
-            // worker registration code
-            let register = WorkerMessage {
-                worker_message_type: Some(
-                    worker_message::WorkerMessageType::Registration(
-                        (*t).clone()
-                    )
-                )
-            };
-            yield register;
-            // Loop to forward completions from worker task to gRPC.
-            while let Some(completion) = completion_rx.recv().await {
-                yield completion;
+        let mut next_scheduler = 0;
+        let mut leader: Option<String> = None;
+        while !self.shutdown.is_cancelled() {
+            let address = leader.take().unwrap_or_else(|| {
+                let address = self.schedulers[next_scheduler % self.schedulers.len()].clone();
+                next_scheduler += 1;
+                address
+            });
+
+            let session = self.session(&address, &task_tx, &mut completion_rx, &permits, &children).await;
+            // Only the scheduler of the session answers the child tasks submitted to it
+            children.disconnected();
+            match session {
+                Ok(SessionEnd::Disconnected) => return Ok(()),
+                Ok(SessionEnd::Redirected(Some(leader_address))) if leader_address != address => {
+                    info!("scheduler {} is standing by, connecting to leader {}", address, leader_address);
+                    leader = Some(leader_address);
+                    continue;
+                }
+                Ok(SessionEnd::Redirected(_)) => info!("scheduler {} is standing by", address),
+                Ok(SessionEnd::Closed) => info!("connection to scheduler {} closed", address),
+                Err(err) => error!("unable to communicate with scheduler {}: {:?}", address, err),
             }
-        };
-    
-        let response = client.communicate(Request::new(outbound)).await?;
+            select! {
+                _ = sleep(self.reconnect_interval) => {}
+                _ = self.shutdown.cancelled() => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers on the scheduler at `address` and serves it until the session ends.
+    ///
+    /// Once the worker shuts down, the session lasts until the running executions are
+    /// done and their completions are sent.
+    async fn session(
+        &self,
+        address: &str,
+        task_tx: &mpsc::Sender<AssignTaskRequest>,
+        completion_rx: &mut mpsc::Receiver<WorkerMessage>,
+        permits: &Semaphore,
+        children: &ChildTasks,
+    ) -> Result<SessionEnd, Box<dyn Error>> {
+        let mut client = SchedulerWorkerServiceClient::connect(address.to_string()).await?;
+
+        // Create the outbound stream for gRPC, starting with the worker registration
+        let (outbound_tx, outbound_rx) = mpsc::channel::<WorkerMessage>(1);
+        outbound_tx.send(WorkerMessage {
+            worker_message_type: Some(
+                worker_message::WorkerMessageType::Registration((*self.registeration_details).clone())
+            )
+        }).await?;
+
+        let response = client.communicate(Request::new(ReceiverStream::new(outbound_rx))).await?;
         let mut inbound: tonic::Streaming<SchedulerMessage> = response.into_inner();
-        while let Some(scheduler_msg) = inbound.message().await? {
-            match scheduler_msg.scheduler_message_type {
-                Some(msg) => {
-                    match msg {
-                        scheduler_message::SchedulerMessageType::AssignTask(t) => {
-                            println!("new incoming task");
-                            
-                            task_tx.send(t).await.expect("send task");
-                            
-                        },
-                        scheduler_message::SchedulerMessageType::Disconnect(disconnect) => {
-                            println!("disconnecting worker: {:?}", disconnect);
-                        }
-                        scheduler_message::SchedulerMessageType::Ack(ack) => {
-                            println!("worker registerd on scheduler server: {:?}", ack);
+        let mut draining = false;
+        let mut drain_ticker = interval(DRAIN_POLL_INTERVAL);
+        loop {
+            select! {
+                _ = self.shutdown.cancelled(), if !draining => {
+                    info!("worker shutting down, waiting for running executions");
+                    draining = true;
+                }
+                _ = drain_ticker.tick(), if draining => {
+                    if permits.available_permits() == self.concurrency {
+                        while let Ok(completion) = completion_rx.try_recv() {
+                            outbound_tx.send(completion).await?;
                         }
-                        scheduler_message::SchedulerMessageType::Heartbeat(_) => {
-                            debug!("got heartbeat from scheduler");
-                            binding_tx_complete.send(WorkerMessage { worker_message_type: Some(
-                                worker_message::WorkerMessageType::Heartbeat(
-                                    Pong {
-                                        metrics: Some(
-                                            WorkerMetrics {
-                                                ..Default::default()
+                        return Ok(SessionEnd::Disconnected);
+                    }
+                }
+                scheduler_msg = inbound.message() => {
+                    let scheduler_msg = match scheduler_msg? {
+                        Some(scheduler_msg) => scheduler_msg,
+                        None => return Ok(SessionEnd::Closed),
+                    };
+                    match scheduler_msg.scheduler_message_type {
+                        Some(msg) => {
+                            match msg {
+                                scheduler_message::SchedulerMessageType::AssignTask(t) => {
+                                    println!("new incoming task");
+                                    
+                                    task_tx.send(t).await.expect("send task");
+                                    
+                                },
+                                scheduler_message::SchedulerMessageType::Disconnect(disconnect) => {
+                                    println!("disconnecting worker: {:?}", disconnect);
+                                    return Ok(SessionEnd::Disconnected);
+                                }
+                                scheduler_message::SchedulerMessageType::Redirect(redirect) => {
+                                    let leader = Some(redirect.leader_address).filter(|address| !address.is_empty());
+                                    return Ok(SessionEnd::Redirected(leader));
+                                }
+                                scheduler_message::SchedulerMessageType::TaskSubmitted(reply) => {
+                                    children.submitted(reply);
+                                }
+                                scheduler_message::SchedulerMessageType::ChildCompletion(completion) => {
+                                    children.completed(completion);
+                                }
+                                scheduler_message::SchedulerMessageType::Ack(ack) => {
+                                    println!("worker registerd on scheduler server: {:?}", ack);
+                                }
+                                scheduler_message::SchedulerMessageType::Heartbeat(_) => {
+                                    debug!("got heartbeat from scheduler");
+                                    outbound_tx.send(WorkerMessage { worker_message_type: Some(
+                                        worker_message::WorkerMessageType::Heartbeat(
+                                            Pong {
+                                                metrics: Some(
+                                                    WorkerMetrics {
+                                                        ..Default::default()
+                                                    }
+                                                )
                                             }
                                         )
-                                    }
-                                )
-                            ) }).await?;
+                                    ) }).await?;
+                                }
+                            }
                         }
-                        _ => println!("unable to communicate with unknown scheduler message")
+                        None => println!("invalid scheduler message"),
                     }
                 }
-                None => println!("invalid scheduler message"),
+                Some(completion) = completion_rx.recv() => {
+                    // Forward completions from worker task to gRPC
+                    outbound_tx.send(completion).await?;
+                }
             }
-
-            ()
         }
-        Ok(())
     }
-}
\ No newline at end of file
+}
+
+/// Runs the execution on its registered executor, returns the completion to report.
+async fn execute_task(registry: &GrpcWorkersRegistry, task: AssignTaskRequest, ctx: TaskContext) -> WorkerMessage {
+    let (task_id, task_type) = task.task.as_ref()
+        .map(|task| (task.id.clone(), task.executor_name().to_string()))
+        .unwrap_or_default();
+    let execution_id = task.execution_id.clone();
+    let result = match registry.get_executor(&task_type) {
+        Ok(operation) => {
+            // Here perform the actual task execution.
+            let execute_req = ExecuteRequest { task: task.task, execution_id: execution_id.clone(), ..Default::default() };
+            operation.execute(execute_req, ctx).await
+        },
+        Err(err) => Err(TaskError::new("EXECUTOR_NOT_FOUND", err.to_string())),
+    };
+    if let Err(err) = &result {
+        error!("task execution {} failed: {:?}", execution_id, err);
+    }
+
+    // Send task response to the communicate loop
+    WorkerMessage {
+        worker_message_type: Some(
+            worker_message::WorkerMessageType::Completion(TaskCompletion::from_result(task_id, execution_id, result))
+        ),
+    }
+}
@@ -0,0 +1,166 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Chains, groups and chords of tasks, compiled into workflows run by the scheduler.
+//!
+//! ```ignore
+//! let resize = |size: &str| Task { task_type: "resize".to_string(), payload: Some(size_payload(size)), ..Default::default() };
+//! // Downloads the image, resizes it to every size at once, then publishes all of the resized images
+//! let flow = chain([
+//!     Canvas::from(download),
+//!     chord([resize("small"), resize("large")], publish),
+//! ]);
+//! let status = flow.submit(&mut client, "thumbnails").await?;
+//! ```
+
+use tonic::{transport::Channel, Request, Status};
+
+use crate::internal::protot::{
+    core::{Task, Workflow, WorkflowInput, WorkflowNode, WorkflowStatus},
+    scheduler::v1::scheduler_service_client::SchedulerServiceClient,
+};
+
+/// Tasks combined into a workflow.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Canvas {
+    /// A single task.
+    Task(Box<Task>),
+    /// Runs one after the other, each step taking the output of the previous one as its payload.
+    Chain(Vec<Canvas>),
+    /// Runs side by side, each member taking the same input.
+    Group(Vec<Canvas>),
+    /// Runs the group, then the callback with the outputs of all of its members as `WorkflowOutputs`.
+    Chord(Vec<Canvas>, Box<Canvas>),
+}
+
+impl From<Task> for Canvas {
+    fn from(task: Task) -> Self {
+        Canvas::Task(Box::new(task))
+    }
+}
+
+/// Runs `steps` one after the other, passing the output of each step to the next one.
+///
+/// A step after a group or a chord takes the outputs of all of their last tasks as `WorkflowOutputs`.
+pub fn chain<C: Into<Canvas>>(steps: impl IntoIterator<Item = C>) -> Canvas {
+    Canvas::Chain(steps.into_iter().map(Into::into).collect())
+}
+
+/// Runs `members` side by side.
+pub fn group<C: Into<Canvas>>(members: impl IntoIterator<Item = C>) -> Canvas {
+    Canvas::Group(members.into_iter().map(Into::into).collect())
+}
+
+/// Runs `header` side by side, then `callback` with the outputs of all of them once they all succeeded.
+pub fn chord<C: Into<Canvas>>(header: impl IntoIterator<Item = C>, callback: impl Into<Canvas>) -> Canvas {
+    Canvas::Chord(header.into_iter().map(Into::into).collect(), Box::new(callback.into()))
+}
+
+impl Canvas {
+    /// The workflow running these tasks, with an id generated by the scheduler.
+    ///
+    /// Nodes are named after their position and task type, e.g. `2-resize`.
+    pub fn to_workflow(&self, name: &str) -> Workflow {
+        let mut nodes = Vec::new();
+        self.compile(&[], WorkflowInput::Payload, &mut nodes);
+        Workflow { workflow_id: String::new(), name: name.to_string(), nodes }
+    }
+
+    /// Submits the workflow running these tasks to the scheduler.
+    pub async fn submit(&self, client: &mut SchedulerServiceClient<Channel>, name: &str) -> Result<WorkflowStatus, Status> {
+        let response = client.submit_workflow(Request::new(self.to_workflow(name))).await?;
+        Ok(response.into_inner())
+    }
+
+    /// Adds the nodes of this canvas depending on `depends_on` to `nodes`, taking `input` from them.
+    /// Returns the nodes the following steps depend on.
+    fn compile(&self, depends_on: &[String], input: WorkflowInput, nodes: &mut Vec<WorkflowNode>) -> Vec<String> {
+        match self {
+            Canvas::Task(task) => {
+                let name = format!("{}-{}", nodes.len(), task.executor_name());
+                let input = if depends_on.is_empty() { WorkflowInput::Payload } else { input };
+                nodes.push(WorkflowNode {
+                    name: name.clone(),
+                    task: Some(task.as_ref().clone()),
+                    depends_on: depends_on.to_vec(),
+                    input: input.into(),
+                    compensation: None,
+                });
+                vec![name]
+            }
+            Canvas::Chain(steps) => {
+                let mut last = depends_on.to_vec();
+                for (index, step) in steps.iter().enumerate() {
+                    let input = if index == 0 { input } else { WorkflowInput::Output };
+                    last = step.compile(&last, input, nodes);
+                }
+                last
+            }
+            Canvas::Group(members) => members
+                .iter()
+                .flat_map(|member| member.compile(depends_on, input, nodes))
+                .collect(),
+            Canvas::Chord(header, callback) => {
+                let header = Canvas::Group(header.clone()).compile(depends_on, input, nodes);
+                callback.compile(&header, WorkflowInput::Outputs, nodes)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn task(task_type: &str) -> Task {
+        Task { task_type: task_type.to_string(), ..Default::default() }
+    }
+
+    fn node<'a>(workflow: &'a Workflow, name: &str) -> &'a WorkflowNode {
+        workflow.nodes.iter().find(|node| node.name == name).unwrap()
+    }
+
+    #[test]
+    fn test_chain_passes_outputs() {
+        let workflow = chain([task("fetch"), task("parse"), task("store")]).to_workflow("etl");
+        let names: Vec<&str> = workflow.nodes.iter().map(|node| node.name.as_str()).collect();
+        assert_eq!(names, vec!["0-fetch", "1-parse", "2-store"]);
+        assert_eq!(node(&workflow, "0-fetch").input(), WorkflowInput::Payload);
+        assert!(node(&workflow, "0-fetch").depends_on.is_empty());
+        assert_eq!(node(&workflow, "2-store").depends_on, vec!["1-parse"]);
+        assert_eq!(node(&workflow, "2-store").input(), WorkflowInput::Output);
+    }
+
+    #[test]
+    fn test_chord_collects_group_outputs() {
+        let workflow = chain([
+            Canvas::from(task("download")),
+            chord([task("resize"), task("resize")], task("publish")),
+            group([task("notify"), task("audit")]),
+        ]).to_workflow("thumbnails");
+
+        assert_eq!(node(&workflow, "1-resize").depends_on, vec!["0-download"]);
+        assert_eq!(node(&workflow, "2-resize").input(), WorkflowInput::Output);
+        let publish = node(&workflow, "3-publish");
+        assert_eq!(publish.depends_on, vec!["1-resize", "2-resize"]);
+        assert_eq!(publish.input(), WorkflowInput::Outputs);
+        // Members of a group each take the output of the step before it
+        assert_eq!(node(&workflow, "4-notify").depends_on, vec!["3-publish"]);
+        assert_eq!(node(&workflow, "5-audit").depends_on, vec!["3-publish"]);
+
+        // Groups side by side at the top level depend on nothing
+        let workflow = group([task("a"), task("b")]).to_workflow("fan-out");
+        assert!(workflow.nodes.iter().all(|node| node.depends_on.is_empty() && node.input() == WorkflowInput::Payload));
+    }
+}
@@ -12,45 +12,189 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use crate::{internal::protot::{scheduler::v1::{SchedulerMessage, scheduler_message, AssignTaskRequest, ExecuteResponse}, core::TaskState}, utils::shared::GrpcWorkerChannels};
+use crate::{data::{DataStoreError, TaskQueue}, internal::protot::{scheduler::v1::{SchedulerMessage, scheduler_message, AssignTaskRequest, ExecuteResponse}, core::TaskState}, utils::shared::GrpcWorkerChannels};
 
-use super::{worker_pool::AsyncTaskExecutor, load_balancer::LoadBalancer};
-use std::{collections::HashMap, error::Error, sync::Arc, time::Instant};
-use async_trait::async_trait;
+use super::{worker_pool::WorkerPool, load_balancer::LoadBalancer, queues::{QueueRegistry, DEFAULT_QUEUE}, rate_limit::RateLimiter, waiters::CompletionWaiters, workflows::WorkflowEngine};
+use std::{collections::HashMap, sync::Arc, time::Instant};
 use log::error;
 use tokio::sync::{
     mpsc,
-    Mutex
+    Mutex,
+    Notify,
 };
 use tonic::{Status, Response};
+use uuid::Uuid;
 
 pub struct GrpcSharedState<B: LoadBalancer> {
     pub grpc_worker_channels: Mutex<GrpcWorkerChannels>,
     balancer: Mutex<B>,
     pub worker_heartbeat: Arc<Mutex<HashMap<String, Instant>>>,
     max_task_queue: usize,
-}
-
-#[async_trait]
-trait DropClient {
-    async fn drop(&mut self);
+    /// Named queues holding the executions waiting for a worker.
+    queues: QueueRegistry,
+    /// Rate limits checked before an execution is handed to a worker.
+    rate_limiter: Arc<RateLimiter>,
+    /// Queues each worker subscribed to at registration, empty for all of them.
+    worker_queues: Mutex<HashMap<String, Vec<String>>>,
+    /// Wakes the dispatcher up when executions are queued or workers connect.
+    dispatch: Notify,
+    /// Runs the tasks registered in the scheduler itself instead of dispatching them.
+    local_pool: Option<WorkerPool>,
+    /// Worker id leasing the executions of the local pool.
+    local_worker_id: String,
+    /// `ExecuteAndWait` callers waiting for their executions to complete.
+    waiters: Arc<CompletionWaiters>,
+    /// Starts the nodes of workflows as the nodes they depend on succeed.
+    workflows: WorkflowEngine,
+    /// Local executions waiting for a slot of their concurrency key, by key and oldest first.
+    parked: std::sync::Mutex<HashMap<String, Vec<AssignTaskRequest>>>,
 }
 
 impl<B: LoadBalancer> GrpcSharedState<B> {
 
     pub fn new(balancer: B, max_task_queue: Option<usize>) -> Self {
-        let max_queue_size = match max_task_queue {
-            None => {100},
-            Some(queue_size) => {queue_size}
-        };
+        let max_queue_size = max_task_queue.unwrap_or(100);
         Self {
             grpc_worker_channels: Mutex::new(HashMap::new()),
             balancer: Mutex::new(balancer),
             worker_heartbeat: Arc::new(Mutex::new(HashMap::new())),
             max_task_queue: max_queue_size,
+            queues: QueueRegistry::default(),
+            rate_limiter: Arc::new(RateLimiter::default()),
+            worker_queues: Mutex::new(HashMap::new()),
+            dispatch: Notify::new(),
+            local_pool: None,
+            local_worker_id: format!("scheduler-{}", Uuid::new_v4()),
+            waiters: Arc::new(CompletionWaiters::new()),
+            workflows: WorkflowEngine::default(),
+            parked: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
+    /// Runs the tasks registered in `pool` on its threads, the others still go to remote workers.
+    pub fn with_local_pool(mut self, pool: WorkerPool) -> Self {
+        self.local_pool = Some(pool);
+        self
+    }
+
+    pub fn local_pool(&self) -> Option<&WorkerPool> {
+        self.local_pool.as_ref()
+    }
+
+    pub fn local_worker_id(&self) -> &str {
+        &self.local_worker_id
+    }
+
+    pub fn waiters(&self) -> &Arc<CompletionWaiters> {
+        &self.waiters
+    }
+
+    pub fn workflows(&self) -> &WorkflowEngine {
+        &self.workflows
+    }
+
+    /// Keeps a local execution aside until a slot of its concurrency key may be free again.
+    pub fn park(&self, request: AssignTaskRequest) {
+        let concurrency_key = request.task.as_ref().map(|task| task.concurrency_key.clone()).unwrap_or_default();
+        self.parked.lock().unwrap().entry(concurrency_key).or_default().push(request);
+    }
+
+    /// Takes the parked executions to try them again.
+    pub fn take_parked(&self) -> HashMap<String, Vec<AssignTaskRequest>> {
+        std::mem::take(&mut *self.parked.lock().unwrap())
+    }
+
+    /// Parks executions taken with `take_parked` again, ahead of the ones parked since.
+    pub fn repark(&self, concurrency_key: &str, requests: Vec<AssignTaskRequest>) {
+        let mut parked = self.parked.lock().unwrap();
+        parked.entry(concurrency_key.to_string()).or_default().splice(0..0, requests);
+    }
+
+    /// Whether the task runs in the scheduler process rather than on a remote worker.
+    pub fn runs_locally(&self, task_name: &str) -> bool {
+        self.local_pool
+            .as_ref()
+            .is_some_and(|pool| pool.runs_locally(task_name))
+    }
+
+    /// Queues executions of the default queue in `queue` instead of the scheduler memory.
+    pub fn with_queue(mut self, queue: Arc<dyn TaskQueue>) -> Self {
+        self.queues = QueueRegistry::new(queue);
+        self
+    }
+
+    /// Dispatches executions from the named queues of `queues`.
+    pub fn with_queues(mut self, queues: QueueRegistry) -> Self {
+        self.queues = queues;
+        self
+    }
+
+    pub fn queues(&self) -> &QueueRegistry {
+        &self.queues
+    }
+
+    /// Holds executions back until the rate limits of their task type and queue allow them to start.
+    pub fn with_rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Self {
+        self.rate_limiter = rate_limiter;
+        self
+    }
+
+    pub fn rate_limiter(&self) -> &Arc<RateLimiter> {
+        &self.rate_limiter
+    }
+
+    /// Queues the execution in the queue of its task until a worker is available.
+    pub async fn enqueue(&self, request: AssignTaskRequest) -> Result<(), DataStoreError> {
+        let queue = request.task.as_ref().map_or(DEFAULT_QUEUE, |task| task.queue_name());
+        let named = self
+            .queues
+            .get(queue)
+            .map_err(|err| DataStoreError::InvalidArgument(err.to_string()))?;
+        named.queue().push(request).await?;
+        self.dispatch.notify_one();
+        Ok(())
+    }
+
+    /// Records the queues a worker takes executions from, all of them when empty.
+    pub async fn subscribe_worker(&self, worker_id: &str, queues: Vec<String>) {
+        self.worker_queues.lock().await.insert(worker_id.to_string(), queues);
+    }
+
+    pub async fn unsubscribe_worker(&self, worker_id: &str) {
+        self.worker_queues.lock().await.remove(worker_id);
+    }
+
+    /// Wakes the dispatcher up, e.g. when a worker connects.
+    pub fn notify_dispatcher(&self) {
+        self.dispatch.notify_one();
+    }
+
+    /// Waits until the dispatcher is notified.
+    pub async fn dispatch_notified(&self) {
+        self.dispatch.notified().await
+    }
+
+    /// Picks a worker subscribed to `queue` with the load balancer, returns its id and channel.
+    pub async fn select_worker(&self, queue: &str) -> Option<(String, mpsc::Sender<Result<SchedulerMessage, Status>>)> {
+        let worker_channels = self.grpc_worker_channels.lock().await;
+        let worker_queues = self.worker_queues.lock().await;
+        let subscribed: GrpcWorkerChannels = worker_channels
+            .iter()
+            .filter(|(worker_id, _)| {
+                worker_queues
+                    .get(*worker_id)
+                    .is_none_or(|queues| queues.is_empty() || queues.iter().any(|name| name == queue))
+            })
+            .map(|(worker_id, channels)| (worker_id.clone(), channels.clone()))
+            .collect();
+        drop(worker_queues);
+        let mut balancer = self.balancer.lock().await;
+        let worker_id = balancer.select_worker(&subscribed).await?;
+        worker_channels
+            .get(&worker_id)
+            .map(|(sender, _)| (worker_id.clone(), sender.clone()))
+    }
+
     pub fn max_queue_size(&self) -> usize {
         self.max_task_queue
     }
@@ -66,6 +210,17 @@ impl<B: LoadBalancer> GrpcSharedState<B> {
     }
 
     pub async fn distribute_task(&self, task: SchedulerMessage) -> Result<Response<ExecuteResponse>, Status> {
+        if let Some(scheduler_message::SchedulerMessageType::AssignTask(AssignTaskRequest { task: Some(assigned), .. })) =
+            &task.scheduler_message_type
+        {
+            if let Some(limited) = self.rate_limiter.try_acquire(assigned).await? {
+                return Err(Status::resource_exhausted(format!(
+                    "Task {} is rate limited, retry in {:?}",
+                    assigned.id,
+                    limited.wait()
+                )));
+            }
+        }
         let worker_channels = self.grpc_worker_channels.lock().await;
 
         let mut balancer = self.balancer.lock().await;
@@ -81,22 +236,14 @@ impl<B: LoadBalancer> GrpcSharedState<B> {
                 }
                 _ => { ("UnknownTaskId".to_string(), "UnknownExecutionId".to_string()) }
             };
-            return Ok(Response::new(ExecuteResponse{
+            Ok(Response::new(ExecuteResponse{
                 execution_id,
                 task_id,
                 state: TaskState::Pending.into(),
+                ..Default::default()
             }))
         } else {
-            return Err(Status::aborted("No available workers"));
+            Err(Status::aborted("No available workers"))
         }
     }
-}
-
-#[async_trait]
-impl<B: LoadBalancer> DropClient for GrpcSharedState<B> {
-    async fn drop(&mut self) {
-        println!("dropping clients");
-        let binding = self.grpc_worker_channels.lock().await;
-        drop(binding)
-    }
 }
\ No newline at end of file
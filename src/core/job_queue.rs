@@ -0,0 +1,324 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::BinaryHeap,
+    sync::{
+        mpsc::{RecvError, RecvTimeoutError},
+        Arc, Condvar, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// Time a queued job has to wait to gain one priority level.
+pub const DEFAULT_AGING_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Place of an item in a `PriorityHeap`, kept to put a taken item back where it was.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Position {
+    rank: i128,
+    // Earlier items come first among equal ranks
+    sequence: Reverse<u64>,
+}
+
+struct Entry<T> {
+    position: Position,
+    item: T,
+}
+
+impl<T> PartialEq for Entry<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.position == other.position
+    }
+}
+
+impl<T> Eq for Entry<T> {}
+
+impl<T> PartialOrd for Entry<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<T> Ord for Entry<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.position.cmp(&other.position)
+    }
+}
+
+/// Items ordered by priority, highest first, and by arrival among equal priorities.
+///
+/// Waiting items age: every `aging_interval` spent in the heap counts as one more
+/// priority level, so a steady flow of urgent items cannot starve the others forever.
+/// All items age at the same pace, so their order only depends on
+/// `priority - pushed_at / aging_interval`, which is computed once on push.
+/// A zero `aging_interval` disables aging.
+pub struct PriorityHeap<T> {
+    heap: BinaryHeap<Entry<T>>,
+    created_at: Instant,
+    aging_interval: Duration,
+    next_sequence: u64,
+}
+
+impl<T> PriorityHeap<T> {
+    pub fn new(aging_interval: Duration) -> Self {
+        Self {
+            heap: BinaryHeap::new(),
+            created_at: Instant::now(),
+            aging_interval,
+            next_sequence: 0,
+        }
+    }
+
+    pub fn push(&mut self, item: T, priority: i32) -> Position {
+        let interval = self.aging_interval.as_nanos() as i128;
+        let rank = match interval {
+            0 => priority as i128,
+            _ => priority as i128 * interval - self.created_at.elapsed().as_nanos() as i128,
+        };
+        let position = Position { rank, sequence: Reverse(self.next_sequence) };
+        self.next_sequence += 1;
+        self.push_at(item, position);
+        position
+    }
+
+    /// Puts an item back at a position returned by `push` or `pop`.
+    pub fn push_at(&mut self, item: T, position: Position) {
+        self.heap.push(Entry { position, item });
+    }
+
+    /// Takes the item with the highest aged priority.
+    pub fn pop(&mut self) -> Option<(T, Position)> {
+        self.heap.pop().map(|entry| (entry.item, entry.position))
+    }
+
+    pub fn len(&self) -> usize {
+        self.heap.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.heap.is_empty()
+    }
+}
+
+struct QueueState<T> {
+    heap: PriorityHeap<T>,
+    /// Jobs sent with a delay, with when they are due and their priority.
+    delayed: Vec<(Instant, T, i32)>,
+    senders: usize,
+}
+
+impl<T> QueueState<T> {
+    /// Moves the delayed jobs that are due into the heap, returns when the next one is due.
+    fn queue_due(&mut self, now: Instant) -> Option<Instant> {
+        let mut next_due = None;
+        let mut i = 0;
+        while i < self.delayed.len() {
+            let due = self.delayed[i].0;
+            if due <= now {
+                let (_, job, priority) = self.delayed.swap_remove(i);
+                self.heap.push(job, priority);
+            } else {
+                next_due = Some(next_due.map_or(due, |next: Instant| next.min(due)));
+                i += 1;
+            }
+        }
+        next_due
+    }
+}
+
+struct Shared<T> {
+    state: Mutex<QueueState<T>>,
+    available: Condvar,
+}
+
+/// Creates a job queue handing the highest priority jobs out first.
+///
+/// It works like a `std::sync::mpsc` channel: the receiver is disconnected
+/// once every sender is dropped and the queued jobs are taken.
+pub fn job_queue<T>(aging_interval: Duration) -> (JobSender<T>, JobReceiver<T>) {
+    let shared = Arc::new(Shared {
+        state: Mutex::new(QueueState { heap: PriorityHeap::new(aging_interval), delayed: Vec::new(), senders: 1 }),
+        available: Condvar::new(),
+    });
+    (JobSender { shared: shared.clone() }, JobReceiver { shared })
+}
+
+pub struct JobSender<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> JobSender<T> {
+    pub fn send(&self, job: T, priority: i32) {
+        self.shared.state.lock().unwrap().heap.push(job, priority);
+        self.shared.available.notify_one();
+    }
+
+    /// Queues the job once `delay` elapsed, it is not handed out before.
+    pub fn send_after(&self, job: T, priority: i32, delay: Duration) {
+        self.shared.state.lock().unwrap().delayed.push((Instant::now() + delay, job, priority));
+        // A waiting receiver has to wake up when the job is due
+        self.shared.available.notify_one();
+    }
+}
+
+impl<T> Clone for JobSender<T> {
+    fn clone(&self) -> Self {
+        self.shared.state.lock().unwrap().senders += 1;
+        Self { shared: self.shared.clone() }
+    }
+}
+
+impl<T> Drop for JobSender<T> {
+    fn drop(&mut self) {
+        let mut state = self.shared.state.lock().unwrap();
+        state.senders -= 1;
+        if state.senders == 0 {
+            drop(state);
+            self.shared.available.notify_all();
+        }
+    }
+}
+
+pub struct JobReceiver<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T> JobReceiver<T> {
+    /// Waits for the next job, fails once the queue is empty and every sender is gone.
+    pub fn recv(&self) -> Result<T, RecvError> {
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            let next_due = state.queue_due(Instant::now());
+            if let Some((job, _)) = state.heap.pop() {
+                return Ok(job);
+            }
+            state = match next_due {
+                Some(due) => self.shared.available.wait_timeout(state, due.saturating_duration_since(Instant::now())).unwrap().0,
+                None if state.senders == 0 => return Err(RecvError),
+                None => self.shared.available.wait(state).unwrap(),
+            };
+        }
+    }
+
+    /// Waits at most `timeout` for the next job.
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<T, RecvTimeoutError> {
+        let deadline = Instant::now() + timeout;
+        let mut state = self.shared.state.lock().unwrap();
+        loop {
+            let now = Instant::now();
+            let next_due = state.queue_due(now);
+            if let Some((job, _)) = state.heap.pop() {
+                return Ok(job);
+            }
+            if state.senders == 0 && next_due.is_none() {
+                return Err(RecvTimeoutError::Disconnected);
+            }
+            if now >= deadline {
+                return Err(RecvTimeoutError::Timeout);
+            }
+            let wake_at = next_due.map_or(deadline, |due| due.min(deadline));
+            state = self.shared.available.wait_timeout(state, wake_at - now).unwrap().0;
+        }
+    }
+
+    /// Number of jobs waiting in the queue, including the delayed ones.
+    pub fn len(&self) -> usize {
+        let state = self.shared.state.lock().unwrap();
+        state.heap.len() + state.delayed.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn test_highest_priority_first() {
+        let mut heap = PriorityHeap::new(Duration::ZERO);
+        heap.push("bulk-1", 0);
+        heap.push("urgent", 10);
+        heap.push("bulk-2", 0);
+        heap.push("low", -5);
+
+        let order: Vec<_> = std::iter::from_fn(|| heap.pop().map(|(item, _)| item)).collect();
+        assert_eq!(order, vec!["urgent", "bulk-1", "bulk-2", "low"]);
+    }
+
+    #[test]
+    fn test_waiting_items_age() {
+        let mut heap = PriorityHeap::new(Duration::from_millis(10));
+        heap.push("bulk", 0);
+        thread::sleep(Duration::from_millis(50));
+        // Pushed later with a slightly higher priority, the bulk item has aged past it
+        heap.push("urgent", 2);
+        heap.push("very-urgent", 100);
+
+        let order: Vec<_> = std::iter::from_fn(|| heap.pop().map(|(item, _)| item)).collect();
+        assert_eq!(order, vec!["very-urgent", "bulk", "urgent"]);
+    }
+
+    #[test]
+    fn test_item_put_back_keeps_its_place() {
+        let mut heap = PriorityHeap::new(Duration::ZERO);
+        heap.push("first", 1);
+        heap.push("second", 1);
+
+        let (first, position) = heap.pop().unwrap();
+        heap.push("third", 1);
+        heap.push_at(first, position);
+        assert_eq!(heap.pop().unwrap().0, "first");
+        assert_eq!(heap.pop().unwrap().0, "second");
+    }
+
+    #[test]
+    fn test_receiver_disconnects_when_senders_are_gone() {
+        let (sender, receiver) = job_queue(DEFAULT_AGING_INTERVAL);
+        let other = sender.clone();
+        sender.send(1, 0);
+        other.send(2, 5);
+        drop(sender);
+
+        assert_eq!(receiver.len(), 2);
+        assert_eq!(receiver.recv(), Ok(2));
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Ok(1));
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+
+        let waiting = thread::spawn(move || receiver.recv());
+        drop(other);
+        assert_eq!(waiting.join().unwrap(), Err(RecvError));
+    }
+
+    #[test]
+    fn test_delayed_job_waits_its_turn() {
+        let (sender, receiver) = job_queue(DEFAULT_AGING_INTERVAL);
+        sender.send_after("later", 10, Duration::from_millis(50));
+        sender.send("now", 0);
+        drop(sender);
+
+        assert_eq!(receiver.len(), 2);
+        assert_eq!(receiver.recv(), Ok("now"));
+        assert_eq!(receiver.recv_timeout(Duration::from_millis(10)), Err(RecvTimeoutError::Timeout));
+        // Its sender is gone, but the queue still hands the delayed job out once due
+        assert_eq!(receiver.recv(), Ok("later"));
+        assert_eq!(receiver.recv(), Err(RecvError));
+    }
+}
@@ -25,6 +25,12 @@ pub struct RoundRobinBalancer {
     current_worker: usize,
 }
 
+impl Default for RoundRobinBalancer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RoundRobinBalancer {
     pub fn new() -> Self {
         RoundRobinBalancer { current_worker: 0 }
@@ -13,7 +13,13 @@
 // limitations under the License.
 
 pub mod job;
+pub mod task;
+pub mod job_queue;
 pub mod worker;
 pub mod worker_pool;
 pub mod grpc_executor;
-pub mod load_balancer;
\ No newline at end of file
+pub mod load_balancer;
+pub mod queues;
+pub mod rate_limit;
+pub mod waiters;
+pub mod workflows;
@@ -0,0 +1,335 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tonic::Status;
+
+use crate::{
+    data::{DataStoreError, InMemoryTaskQueue, TaskQueue},
+    internal::protot::{
+        core::{QueueConfig, RetryPolicy, Task},
+        scheduler::v1::QueueStatus,
+    },
+};
+
+/// Queue of the tasks that don't name one, it always exists.
+pub const DEFAULT_QUEUE: &str = "default";
+
+/// Delay before the first retry when the policy doesn't set one.
+pub const DEFAULT_INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Growth of the delay between retries when the policy doesn't set one.
+pub const DEFAULT_BACKOFF_MULTIPLIER: f64 = 2.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueueError {
+    /// No queue is configured with the given name.
+    Unknown(String),
+    /// The queue is draining and rejects new submissions.
+    Draining(String),
+    /// The queue holds `max_depth` executions already.
+    Full(String),
+}
+
+impl std::fmt::Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            QueueError::Unknown(name) => write!(f, "Unknown queue: {}", name),
+            QueueError::Draining(name) => write!(f, "Queue is draining: {}", name),
+            QueueError::Full(name) => write!(f, "Queue is full: {}", name),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+impl From<QueueError> for Status {
+    fn from(err: QueueError) -> Self {
+        let message = err.to_string();
+        match err {
+            QueueError::Unknown(_) => Status::invalid_argument(message),
+            QueueError::Draining(_) => Status::unavailable(message),
+            QueueError::Full(_) => Status::resource_exhausted(message),
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Whether an execution already retried `retries` times gets another attempt.
+    pub fn allows_retry(&self, retries: u32) -> bool {
+        retries.saturating_add(1) < self.max_attempts
+    }
+
+    /// Delay before the retry following `retries` earlier ones.
+    pub fn backoff(&self, retries: u32) -> Duration {
+        let initial = self.initial_backoff.as_ref().map_or(DEFAULT_INITIAL_BACKOFF, to_duration);
+        let multiplier = if self.backoff_multiplier > 0.0 { self.backoff_multiplier } else { DEFAULT_BACKOFF_MULTIPLIER };
+        let backoff = initial.as_secs_f64() * multiplier.powi(retries.min(i32::MAX as u32) as i32);
+        let max = self.max_backoff.as_ref().map(to_duration).unwrap_or(Duration::MAX);
+        // Float overflows end up at the upper bound
+        Duration::try_from_secs_f64(backoff).unwrap_or(Duration::MAX).min(max)
+    }
+}
+
+fn to_duration(duration: &prost_types::Duration) -> Duration {
+    Duration::new(duration.seconds.max(0) as u64, duration.nanos.max(0) as u32)
+}
+
+/// A named queue, with the executions waiting in it and its dispatch settings.
+pub struct NamedQueue {
+    config: QueueConfig,
+    queue: Arc<dyn TaskQueue>,
+    paused: AtomicBool,
+    draining: AtomicBool,
+}
+
+impl NamedQueue {
+    pub fn name(&self) -> &str {
+        &self.config.name
+    }
+
+    pub fn config(&self) -> &QueueConfig {
+        &self.config
+    }
+
+    /// Executions waiting in this queue.
+    pub fn queue(&self) -> &dyn TaskQueue {
+        &*self.queue
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+}
+
+/// The named queues of a scheduler, fixed by its configuration.
+///
+/// Also tracks which executions of each queue run on workers, so the dispatcher
+/// keeps every queue under its `max_concurrency`.
+pub struct QueueRegistry {
+    queues: Vec<NamedQueue>,
+    /// Queue of every execution running on a worker, by execution id.
+    running: Mutex<HashMap<String, String>>,
+}
+
+impl Default for QueueRegistry {
+    fn default() -> Self {
+        Self::new(Arc::new(InMemoryTaskQueue::new()))
+    }
+}
+
+impl QueueRegistry {
+    /// A registry with only the default queue, unbounded and without retries.
+    pub fn new(default_queue: Arc<dyn TaskQueue>) -> Self {
+        let config = QueueConfig { name: DEFAULT_QUEUE.to_string(), ..Default::default() };
+        Self { queues: Vec::new(), running: Mutex::new(HashMap::new()) }.with_queue(config, default_queue)
+    }
+
+    /// Adds the queue configured by `config`, replacing the one with the same name if any.
+    pub fn with_queue(mut self, config: QueueConfig, queue: Arc<dyn TaskQueue>) -> Self {
+        self.queues.retain(|named| named.config.name != config.name);
+        self.queues.push(NamedQueue {
+            config,
+            queue,
+            paused: AtomicBool::new(false),
+            draining: AtomicBool::new(false),
+        });
+        self
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &NamedQueue> {
+        self.queues.iter()
+    }
+
+    pub fn get(&self, name: &str) -> Result<&NamedQueue, QueueError> {
+        self.queues
+            .iter()
+            .find(|named| named.config.name == name)
+            .ok_or_else(|| QueueError::Unknown(name.to_string()))
+    }
+
+    /// The queue of `task`.
+    pub fn queue_of(&self, task: &Task) -> Result<&NamedQueue, QueueError> {
+        self.get(task.queue_name())
+    }
+
+    /// Fills the priority and retry policy `task` doesn't set with the defaults of its queue.
+    pub fn apply_defaults(&self, task: &mut Task) -> Result<(), QueueError> {
+        let config = &self.queue_of(task)?.config;
+        if task.priority == 0 {
+            task.priority = config.default_priority;
+        }
+        if task.retry_policy.is_none() {
+            task.retry_policy = config.retry_policy.clone();
+        }
+        Ok(())
+    }
+
+    /// Fails when the queue of `task` can't take one more execution.
+    pub async fn admit(&self, task: &Task) -> Result<(), Status> {
+        let named = self.queue_of(task)?;
+        if named.is_draining() {
+            return Err(QueueError::Draining(named.name().to_string()).into());
+        }
+        let max_depth = named.config.max_depth as usize;
+        if max_depth > 0 && named.queue.len().await? >= max_depth {
+            return Err(QueueError::Full(named.name().to_string()).into());
+        }
+        Ok(())
+    }
+
+    /// Retry policy of `task`, falling back to the one of its queue.
+    pub fn retry_policy(&self, task: &Task) -> Option<RetryPolicy> {
+        task.retry_policy
+            .clone()
+            .or_else(|| self.queue_of(task).ok().and_then(|named| named.config.retry_policy.clone()))
+    }
+
+    /// Whether the dispatcher can hand one more execution of `named` to a worker.
+    pub fn can_dispatch(&self, named: &NamedQueue) -> bool {
+        let max_concurrency = named.config.max_concurrency as usize;
+        !named.is_paused() && (max_concurrency == 0 || self.running(named.name()) < max_concurrency)
+    }
+
+    /// Records that the execution of `queue` runs on a worker.
+    pub fn started(&self, execution_id: &str, queue: &str) {
+        self.running.lock().unwrap().insert(execution_id.to_string(), queue.to_string());
+    }
+
+    /// Records that the execution stopped running, it completed or its lease expired.
+    pub fn finished(&self, execution_id: &str) {
+        self.running.lock().unwrap().remove(execution_id);
+    }
+
+    /// Number of executions of `queue` running on workers.
+    pub fn running(&self, queue: &str) -> usize {
+        self.running.lock().unwrap().values().filter(|name| *name == queue).count()
+    }
+
+    pub fn pause(&self, name: &str) -> Result<&NamedQueue, QueueError> {
+        let named = self.get(name)?;
+        named.paused.store(true, Ordering::SeqCst);
+        Ok(named)
+    }
+
+    /// Resumes a paused or draining queue.
+    pub fn resume(&self, name: &str) -> Result<&NamedQueue, QueueError> {
+        let named = self.get(name)?;
+        named.paused.store(false, Ordering::SeqCst);
+        named.draining.store(false, Ordering::SeqCst);
+        Ok(named)
+    }
+
+    pub fn drain(&self, name: &str) -> Result<&NamedQueue, QueueError> {
+        let named = self.get(name)?;
+        named.draining.store(true, Ordering::SeqCst);
+        Ok(named)
+    }
+
+    pub async fn status(&self, named: &NamedQueue) -> Result<QueueStatus, DataStoreError> {
+        Ok(QueueStatus {
+            queue: named.name().to_string(),
+            paused: named.is_paused(),
+            draining: named.is_draining(),
+            running: self.running(named.name()) as u32,
+            queued: named.queue.len().await? as u64,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::protot::scheduler::v1::AssignTaskRequest;
+
+    use super::*;
+
+    fn queue(name: &str, max_concurrency: u32, max_depth: u32) -> QueueConfig {
+        QueueConfig {
+            name: name.to_string(),
+            max_concurrency,
+            max_depth,
+            default_priority: 5,
+            retry_policy: Some(RetryPolicy { max_attempts: 3, ..Default::default() }),
+            rate_limit: None,
+        }
+    }
+
+    fn task(queue: &str) -> Task {
+        Task { queue: queue.to_string(), ..Default::default() }
+    }
+
+    #[tokio::test]
+    async fn test_queue_admission_and_concurrency() {
+        let registry = QueueRegistry::default()
+            .with_queue(queue("reports", 1, 1), Arc::new(InMemoryTaskQueue::new()));
+
+        let mut report = task("reports");
+        registry.apply_defaults(&mut report).unwrap();
+        assert_eq!(report.priority, 5);
+        assert_eq!(registry.retry_policy(&report).unwrap().max_attempts, 3);
+        assert!(registry.retry_policy(&task("")).is_none());
+        assert_eq!(registry.apply_defaults(&mut task("missing")), Err(QueueError::Unknown("missing".to_string())));
+
+        registry.admit(&report).await.unwrap();
+        let named = registry.get("reports").unwrap();
+        named.queue().push(AssignTaskRequest { task: Some(report.clone()), execution_id: "e1".to_string() }).await.unwrap();
+        assert_eq!(registry.admit(&report).await.unwrap_err().code(), tonic::Code::ResourceExhausted);
+
+        assert!(registry.can_dispatch(named));
+        registry.started("e1", "reports");
+        assert!(!registry.can_dispatch(named));
+        // The default queue is unbounded
+        assert!(registry.can_dispatch(registry.get(DEFAULT_QUEUE).unwrap()));
+        registry.finished("e1");
+        assert!(registry.can_dispatch(named));
+
+        registry.pause("reports").unwrap();
+        assert!(!registry.can_dispatch(named));
+        registry.drain("reports").unwrap();
+        assert_eq!(registry.admit(&task("reports")).await.unwrap_err().code(), tonic::Code::Unavailable);
+        let status = registry.status(registry.resume("reports").unwrap()).await.unwrap();
+        assert!(!status.paused && !status.draining);
+        assert_eq!(status.queued, 1);
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let policy = RetryPolicy {
+            max_attempts: 3,
+            max_backoff: Some(prost_types::Duration { seconds: 3, nanos: 0 }),
+            ..Default::default()
+        };
+        assert!(policy.allows_retry(0));
+        assert!(policy.allows_retry(1));
+        assert!(!policy.allows_retry(2));
+        assert!(!RetryPolicy::default().allows_retry(0));
+
+        assert_eq!(policy.backoff(0), Duration::from_secs(1));
+        assert_eq!(policy.backoff(1), Duration::from_secs(2));
+        assert_eq!(policy.backoff(2), Duration::from_secs(3));
+        assert_eq!(policy.backoff(u32::MAX), Duration::from_secs(3));
+    }
+}
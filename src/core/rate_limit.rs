@@ -0,0 +1,317 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use log::error;
+use tokio::{runtime::Handle, time::Instant};
+
+use crate::{
+    data::{DataStore, DataStoreError, TokenBucket},
+    internal::protot::core::{RateLimit, Task},
+};
+
+/// How long a pool job waits before asking the data store again after it failed.
+const RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+const QUEUE_BUCKET_PREFIX: &str = "queue:";
+const TASK_TYPE_BUCKET_PREFIX: &str = "task_type:";
+
+impl RateLimit {
+    /// The token bucket enforcing the limit, `None` when it doesn't limit anything.
+    pub fn bucket(&self) -> Option<TokenBucket> {
+        let period = self.period.as_ref()?;
+        let period = Duration::new(period.seconds.max(0) as u64, period.nanos.max(0) as u32);
+        if self.limit == 0 || period.is_zero() {
+            return None;
+        }
+        let capacity = if self.burst > 0 { self.burst } else { self.limit };
+        Some(TokenBucket {
+            capacity: capacity as f64,
+            rate: self.limit as f64 / period.as_secs_f64(),
+        })
+    }
+}
+
+/// The rate limit holding an execution back, with how long until it may start.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateLimited {
+    /// The limit of its queue, no execution of the queue may start before.
+    Queue(Duration),
+    /// The limit of its task type, executions of other task types may start.
+    TaskType(Duration),
+}
+
+impl RateLimited {
+    pub fn wait(&self) -> Duration {
+        match *self {
+            RateLimited::Queue(wait) | RateLimited::TaskType(wait) => wait,
+        }
+    }
+}
+
+/// Token bucket rate limits of task types and queues.
+///
+/// The buckets live in the data store when there is one, so every scheduler and worker pool
+/// using it shares the same limits. Without a data store they are kept in memory.
+#[derive(Default)]
+pub struct RateLimiter {
+    task_types: HashMap<String, TokenBucket>,
+    queues: HashMap<String, TokenBucket>,
+    store: Option<Arc<dyn DataStore>>,
+    /// Tokens left in each bucket and when they were counted (on the runtime clock), without a data store.
+    local: Mutex<HashMap<String, (f64, Instant)>>,
+}
+
+impl RateLimiter {
+    pub fn new(store: Option<Arc<dyn DataStore>>) -> Self {
+        Self { store, ..Default::default() }
+    }
+
+    /// Limits how often executions of `task_type` start.
+    pub fn with_task_type_limit(mut self, task_type: &str, bucket: TokenBucket) -> Self {
+        self.task_types.insert(task_type.to_string(), bucket);
+        self
+    }
+
+    /// Limits how often executions of `queue` start.
+    pub fn with_queue_limit(mut self, queue: &str, bucket: TokenBucket) -> Self {
+        self.queues.insert(queue.to_string(), bucket);
+        self
+    }
+
+    /// Whether no limit is configured, every execution starts right away.
+    pub fn is_empty(&self) -> bool {
+        self.task_types.is_empty() && self.queues.is_empty()
+    }
+
+    /// The buckets `task` draws from, the one of its queue first.
+    fn buckets(&self, task: &Task) -> Vec<(String, TokenBucket)> {
+        let queue = self
+            .queues
+            .get(task.queue_name())
+            .map(|bucket| (format!("{}{}", QUEUE_BUCKET_PREFIX, task.queue_name()), *bucket));
+        let task_type = self
+            .task_types
+            .get(task.executor_name())
+            .map(|bucket| (format!("{}{}", TASK_TYPE_BUCKET_PREFIX, task.executor_name()), *bucket));
+        queue.into_iter().chain(task_type).collect()
+    }
+
+    /// Takes a token for `task` from every bucket it draws from, or from none of them when
+    /// one is empty, in which case it returns the limit holding `task` back.
+    pub async fn try_acquire(&self, task: &Task) -> Result<Option<RateLimited>, DataStoreError> {
+        let buckets = self.buckets(task);
+        if buckets.is_empty() {
+            return Ok(None);
+        }
+        let empty = match &self.store {
+            Some(store) => store.take_rate_limit_tokens(&buckets).await?,
+            None => self.take_local(&buckets),
+        };
+        Ok(empty.map(|(position, wait)| match buckets[position].0.starts_with(QUEUE_BUCKET_PREFIX) {
+            true => RateLimited::Queue(wait),
+            false => RateLimited::TaskType(wait),
+        }))
+    }
+
+    /// Puts back the tokens `task` took with `try_acquire`, when its execution didn't start after all.
+    pub async fn give_back(&self, task: &Task) -> Result<(), DataStoreError> {
+        let buckets = self.buckets(task);
+        if buckets.is_empty() {
+            return Ok(());
+        }
+        match &self.store {
+            Some(store) => store.return_rate_limit_tokens(&buckets).await,
+            None => {
+                let mut local = self.local.lock().unwrap();
+                for (key, bucket) in &buckets {
+                    if let Some((tokens, _)) = local.get_mut(key) {
+                        *tokens = (*tokens + 1.0).min(bucket.capacity);
+                    }
+                }
+                Ok(())
+            }
+        }
+    }
+
+    fn take_local(&self, buckets: &[(String, TokenBucket)]) -> Option<(usize, Duration)> {
+        let mut local = self.local.lock().unwrap();
+        let now = Instant::now();
+        let mut taken = Vec::with_capacity(buckets.len());
+        for (position, (key, bucket)) in buckets.iter().enumerate() {
+            let (tokens, elapsed_ms) = match local.get(key) {
+                Some((tokens, at)) => (Some(*tokens), now.duration_since(*at).as_millis() as i64),
+                None => (None, 0),
+            };
+            match bucket.take(tokens, elapsed_ms) {
+                (_, Some(wait)) => return Some((position, wait)),
+                (tokens, None) => taken.push((key.clone(), tokens)),
+            }
+        }
+        for (key, tokens) in taken {
+            local.insert(key, (tokens, now));
+        }
+        None
+    }
+
+    /// Like `try_acquire`, returns how long until `task` may start when it is held back.
+    ///
+    /// Meant for the worker pool threads, `handle` runs the data store calls and must not
+    /// belong to the calling thread. A failing data store holds `task` back for a while.
+    pub fn try_acquire_blocking(&self, handle: Option<&Handle>, task: &Task) -> Option<Duration> {
+        let acquired = match handle {
+            Some(handle) => handle.block_on(self.try_acquire(task)),
+            None => futures::executor::block_on(self.try_acquire(task)),
+        };
+        match acquired {
+            Ok(limited) => limited.map(|limited| limited.wait()),
+            Err(err) => {
+                error!("failed to take a rate limit token for task {}: {}", task.id, err);
+                Some(RETRY_INTERVAL)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{core::worker_pool::Builder, internal::protot::scheduler::v1::ExecuteRequest};
+
+    use super::*;
+
+    fn per_minute(limit: u32, burst: u32) -> RateLimit {
+        RateLimit {
+            limit,
+            period: Some(prost_types::Duration { seconds: 60, nanos: 0 }),
+            burst,
+        }
+    }
+
+    #[test]
+    fn test_rate_limit_bucket() {
+        assert_eq!(per_minute(50, 10).bucket(), Some(TokenBucket { capacity: 10.0, rate: 50.0 / 60.0 }));
+        assert_eq!(per_minute(60, 0).bucket(), Some(TokenBucket { capacity: 60.0, rate: 1.0 }));
+        assert_eq!(per_minute(0, 10).bucket(), None);
+        assert_eq!(RateLimit { limit: 10, ..Default::default() }.bucket(), None);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limiter_without_store() {
+        let limiter = RateLimiter::new(None)
+            .with_queue_limit("reports", per_minute(60, 2).bucket().unwrap())
+            .with_task_type_limit("email", per_minute(1, 1).bucket().unwrap());
+        let report = Task { task_type: "render".to_string(), queue: "reports".to_string(), ..Default::default() };
+        let email = Task { task_type: "email".to_string(), ..Default::default() };
+
+        assert_eq!(limiter.try_acquire(&report).await.unwrap(), None);
+        assert_eq!(limiter.try_acquire(&report).await.unwrap(), None);
+        let wait = match limiter.try_acquire(&report).await.unwrap() {
+            Some(RateLimited::Queue(wait)) => wait,
+            limited => panic!("expected the queue to be limited, got {:?}", limited),
+        };
+        assert!(wait <= Duration::from_secs(1));
+
+        assert_eq!(limiter.try_acquire(&email).await.unwrap(), None);
+        assert!(matches!(limiter.try_acquire(&email).await.unwrap(), Some(RateLimited::TaskType(wait)) if wait > Duration::from_secs(50)));
+        // Other task types of the default queue are not limited
+        assert_eq!(limiter.try_acquire(&Task::default()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_given_back_token_is_taken_again() {
+        let limiter = RateLimiter::new(None).with_task_type_limit("email", per_minute(1, 1).bucket().unwrap());
+        let email = Task { task_type: "email".to_string(), ..Default::default() };
+
+        assert_eq!(limiter.try_acquire(&email).await.unwrap(), None);
+        limiter.give_back(&email).await.unwrap();
+        assert_eq!(limiter.try_acquire(&email).await.unwrap(), None);
+        assert!(matches!(limiter.try_acquire(&email).await.unwrap(), Some(RateLimited::TaskType(_))));
+        // A full bucket doesn't grow past its capacity
+        limiter.give_back(&email).await.unwrap();
+        limiter.give_back(&email).await.unwrap();
+        assert_eq!(limiter.try_acquire(&email).await.unwrap(), None);
+        assert!(matches!(limiter.try_acquire(&email).await.unwrap(), Some(RateLimited::TaskType(_))));
+    }
+
+    #[tokio::test]
+    async fn test_limited_task_type_keeps_queue_tokens() {
+        let limiter = RateLimiter::new(None)
+            .with_queue_limit("reports", per_minute(60, 2).bucket().unwrap())
+            .with_task_type_limit("email", per_minute(1, 1).bucket().unwrap());
+        let email = Task { task_type: "email".to_string(), queue: "reports".to_string(), ..Default::default() };
+        let report = Task { task_type: "render".to_string(), queue: "reports".to_string(), ..Default::default() };
+
+        assert_eq!(limiter.try_acquire(&email).await.unwrap(), None);
+        assert!(matches!(limiter.try_acquire(&email).await.unwrap(), Some(RateLimited::TaskType(_))));
+        // The limited email didn't take the last token of the queue
+        assert_eq!(limiter.try_acquire(&report).await.unwrap(), None);
+        assert!(matches!(limiter.try_acquire(&report).await.unwrap(), Some(RateLimited::Queue(_))));
+    }
+
+    #[test]
+    fn test_pool_waits_for_tokens() {
+        let limiter = RateLimiter::new(None).with_task_type_limit("ping", TokenBucket { capacity: 1.0, rate: 10.0 });
+        let pool = Builder::new().num_workers(2).rate_limiter(Arc::new(limiter)).build().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+
+        let started = Instant::now();
+        for _ in 0..3 {
+            let tx = tx.clone();
+            let request = ExecuteRequest {
+                task: Some(Task { task_type: "ping".to_string(), ..Default::default() }),
+                ..Default::default()
+            };
+            pool.execute(move |_| tx.send(()).unwrap(), request).unwrap();
+        }
+        for _ in 0..3 {
+            rx.recv().unwrap();
+        }
+        // The first job starts right away, the next two wait 100ms each
+        assert!(started.elapsed() >= Duration::from_millis(190));
+    }
+
+    /// Waits for the next job sent on `jobs` from a blocking thread, the paused clock stands still meanwhile.
+    async fn next_job(jobs: &Arc<Mutex<std::sync::mpsc::Receiver<&'static str>>>) -> &'static str {
+        let jobs = jobs.clone();
+        let next = tokio::task::spawn_blocking(move || jobs.lock().unwrap().recv_timeout(Duration::from_secs(5)));
+        tokio::time::timeout(Duration::from_secs(5), next).await.unwrap().unwrap().expect("the job didn't run")
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_limited_job_leaves_the_pool_thread() {
+        let limiter = RateLimiter::new(None).with_task_type_limit("ping", TokenBucket { capacity: 1.0, rate: 2.0 });
+        let pool = Builder::new().num_workers(1).rate_limiter(Arc::new(limiter)).build().unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let rx = Arc::new(Mutex::new(rx));
+
+        for task_type in ["ping", "ping", "pong"] {
+            let tx = tx.clone();
+            let request = ExecuteRequest {
+                task: Some(Task { task_type: task_type.to_string(), ..Default::default() }),
+                ..Default::default()
+            };
+            pool.execute(move |_| tx.send(task_type).unwrap(), request).unwrap();
+        }
+        // The only thread runs the pong while the second ping waits for its token
+        assert_eq!(next_job(&rx).await, "ping");
+        assert_eq!(next_job(&rx).await, "pong");
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(next_job(&rx).await, "ping");
+    }
+}
@@ -0,0 +1,138 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+
+use prost_types::Any;
+
+use super::queues::DEFAULT_QUEUE;
+use crate::internal::protot::{core::{Task, TaskError, TaskState}, scheduler::v1::TaskCompletion};
+
+/// Code of the error reporting a task discarded because it expired before it started.
+pub const TASK_EXPIRED: &str = "EXPIRED";
+
+/// What came out of an execution of a task.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TaskOutcome {
+    /// The executor returned the output of the task.
+    Success(Any),
+    /// The executor failed the task.
+    Failed(TaskError),
+    /// The task expired before it started, it was discarded without running.
+    Expired(TaskError),
+}
+
+impl TaskOutcome {
+    /// The outcome of a task that expired at `expires_at` before it started.
+    pub fn expired(task: &Task) -> Self {
+        TaskOutcome::Expired(TaskError::new(
+            TASK_EXPIRED,
+            format!("task {} expired at {} before it started", task.id, task.expires_at),
+        ))
+    }
+}
+
+impl From<Result<Any, TaskError>> for TaskOutcome {
+    fn from(result: Result<Any, TaskError>) -> Self {
+        match result {
+            Ok(output) => TaskOutcome::Success(output),
+            Err(error) => TaskOutcome::Failed(error),
+        }
+    }
+}
+
+impl Task {
+    /// Name of the executor running the task.
+    ///
+    /// Tasks submitted before `task_type` existed named their executor in `id`.
+    pub fn executor_name(&self) -> &str {
+        if self.task_type.is_empty() {
+            &self.id
+        } else {
+            &self.task_type
+        }
+    }
+
+    /// Max number of running executions sharing the concurrency key of the task, `None` without a key.
+    pub fn concurrency_slots(&self) -> Option<u32> {
+        if self.concurrency_key.is_empty() {
+            None
+        } else {
+            Some(self.concurrency_limit.max(1))
+        }
+    }
+
+    /// Name of the queue holding the task, the default one when it doesn't name any.
+    pub fn queue_name(&self) -> &str {
+        if self.queue.is_empty() {
+            DEFAULT_QUEUE
+        } else {
+            &self.queue
+        }
+    }
+
+    /// Sets `expires_at` out of the `ttl` counted from `now`, unless the task already expires at a set time.
+    pub fn resolve_expiry(&mut self, now: i64) {
+        if let (0, Some(ttl)) = (self.expires_at, &self.ttl) {
+            self.expires_at = now + ttl.seconds.max(0) + i64::from(ttl.nanos > 0);
+        }
+    }
+
+    /// Whether the task was not started before its `expires_at`.
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at != 0 && self.expires_at <= now
+    }
+}
+
+impl TaskError {
+    pub fn new(code: &str, message: impl Into<String>) -> Self {
+        TaskError { code: code.to_string(), message: message.into(), details: None }
+    }
+}
+
+impl TaskCompletion {
+    /// The completion reporting `result`, `Success` with its output or `Fail` with its error.
+    pub fn from_result(task_id: String, execution_id: String, result: Result<Any, TaskError>) -> Self {
+        Self::from_outcome(task_id, execution_id, result.into())
+    }
+
+    /// The completion reporting `outcome`, `Expired` with its error for a discarded task.
+    pub fn from_outcome(task_id: String, execution_id: String, outcome: TaskOutcome) -> Self {
+        let (state, output, error) = match outcome {
+            TaskOutcome::Success(output) => (TaskState::Success, Some(output), None),
+            TaskOutcome::Failed(error) => (TaskState::Fail, None, Some(error)),
+            TaskOutcome::Expired(error) => (TaskState::Expired, None, Some(error)),
+        };
+        TaskCompletion { task_id, state: state.into(), execution_id, output, error }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_user_errors_fail_the_task() {
+        // An executor failing with the code of expired tasks didn't expire
+        let error = TaskError::new(TASK_EXPIRED, "the user's token expired");
+        let completion = TaskCompletion::from_result("task-1".to_string(), "exec-1".to_string(), Err(error.clone()));
+        assert_eq!(completion.state(), TaskState::Fail);
+        assert_eq!(completion.error, Some(error));
+
+        let task = Task { id: "task-1".to_string(), expires_at: 10, ..Default::default() };
+        let completion = TaskCompletion::from_outcome(task.id.clone(), "exec-1".to_string(), TaskOutcome::expired(&task));
+        assert_eq!(completion.state(), TaskState::Expired);
+        assert_eq!(completion.error.unwrap().code, TASK_EXPIRED);
+        assert_eq!(completion.output, None);
+    }
+}
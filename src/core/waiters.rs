@@ -0,0 +1,217 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashMap, sync::Mutex, time::Duration};
+
+use tokio::sync::oneshot;
+
+use crate::internal::protot::scheduler::v1::{ExecuteResponse, TaskCompletion};
+
+/// Callers waiting for their executions to complete, keyed by execution id.
+///
+/// Completions are fed from the worker streams and from the pool threads, which live
+/// outside of the runtime, so the map is behind a blocking mutex.
+#[derive(Default)]
+pub struct CompletionWaiters {
+    waiters: Mutex<WaiterMap>,
+}
+
+#[derive(Default)]
+struct WaiterMap {
+    next_id: u64,
+    by_execution: HashMap<String, HashMap<u64, oneshot::Sender<TaskCompletion>>>,
+}
+
+impl CompletionWaiters {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Starts waiting for the completion of `execution_id`, several callers can wait for
+    /// the same execution.
+    ///
+    /// Must be called before the execution is submitted, so a fast completion isn't missed.
+    pub fn register(&self, execution_id: &str) -> CompletionWaiter<'_> {
+        let (sender, receiver) = oneshot::channel();
+        let mut waiters = self.waiters.lock().unwrap();
+        let id = waiters.next_id;
+        waiters.next_id += 1;
+        waiters.by_execution.entry(execution_id.to_string()).or_default().insert(id, sender);
+        CompletionWaiter {
+            waiters: self,
+            execution_id: execution_id.to_string(),
+            id,
+            receiver,
+        }
+    }
+
+    /// Hands the completion to its waiting callers, returns whether there was any.
+    pub fn notify(&self, completion: &TaskCompletion) -> bool {
+        let senders = self.waiters.lock().unwrap().by_execution.remove(&completion.execution_id);
+        senders
+            .into_iter()
+            .flat_map(HashMap::into_values)
+            .map(|sender| sender.send(completion.clone()).is_ok())
+            .filter(|sent| *sent)
+            .count()
+            > 0
+    }
+
+    /// Number of executions being waited for.
+    pub fn len(&self) -> usize {
+        self.waiters.lock().unwrap().by_execution.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn remove(&self, execution_id: &str, id: u64) {
+        let mut waiters = self.waiters.lock().unwrap();
+        if let Some(senders) = waiters.by_execution.get_mut(execution_id) {
+            senders.remove(&id);
+            if senders.is_empty() {
+                waiters.by_execution.remove(execution_id);
+            }
+        }
+    }
+}
+
+/// A caller waiting for one execution, it stops waiting when dropped
+/// (e.g. when the client cancels the call).
+pub struct CompletionWaiter<'a> {
+    waiters: &'a CompletionWaiters,
+    execution_id: String,
+    id: u64,
+    receiver: oneshot::Receiver<TaskCompletion>,
+}
+
+impl CompletionWaiter<'_> {
+    /// Waits for the completion for at most `deadline`, forever when it is not set.
+    ///
+    /// Returns `None` when the deadline expired first.
+    pub async fn wait(mut self, deadline: Option<Duration>) -> Option<TaskCompletion> {
+        let receiver = &mut self.receiver;
+        match deadline {
+            Some(deadline) => tokio::time::timeout(deadline, receiver).await.ok()?.ok(),
+            None => receiver.await.ok(),
+        }
+    }
+}
+
+impl Drop for CompletionWaiter<'_> {
+    fn drop(&mut self) {
+        self.waiters.remove(&self.execution_id, self.id);
+    }
+}
+
+impl From<TaskCompletion> for ExecuteResponse {
+    fn from(completion: TaskCompletion) -> Self {
+        Self {
+            task_id: completion.task_id,
+            state: completion.state,
+            execution_id: completion.execution_id,
+            output: completion.output,
+            error: completion.error,
+        }
+    }
+}
+
+/// Parses a `grpc-timeout` header value, e.g. `500m` for 500 milliseconds.
+pub fn parse_grpc_timeout(value: &str) -> Option<Duration> {
+    if value.len() < 2 || !value.is_ascii() {
+        return None;
+    }
+    let (amount, unit) = value.split_at(value.len() - 1);
+    // The spec allows at most 8 digits
+    if amount.len() > 8 {
+        return None;
+    }
+    let amount: u64 = amount.parse().ok()?;
+    match unit {
+        "H" => Some(Duration::from_secs(amount * 60 * 60)),
+        "M" => Some(Duration::from_secs(amount * 60)),
+        "S" => Some(Duration::from_secs(amount)),
+        "m" => Some(Duration::from_millis(amount)),
+        "u" => Some(Duration::from_micros(amount)),
+        "n" => Some(Duration::from_nanos(amount)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::protot::core::TaskState;
+
+    use super::*;
+
+    fn completion(execution_id: &str) -> TaskCompletion {
+        TaskCompletion {
+            task_id: "resize".to_string(),
+            state: TaskState::Success.into(),
+            execution_id: execution_id.to_string(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_completion_reaches_its_waiter() {
+        let waiters = CompletionWaiters::new();
+        let waiter = waiters.register("execution-1");
+
+        // Completions of executions nobody waits for are dropped
+        assert!(!waiters.notify(&completion("execution-2")));
+        assert!(waiters.notify(&completion("execution-1")));
+        assert!(waiters.is_empty());
+
+        let completed = waiter.wait(Some(Duration::from_secs(1))).await.unwrap();
+        assert_eq!(completed.execution_id, "execution-1");
+        assert_eq!(ExecuteResponse::from(completed).state(), TaskState::Success);
+    }
+
+    #[tokio::test]
+    async fn test_every_waiter_of_an_execution_is_notified() {
+        let waiters = CompletionWaiters::new();
+        let first = waiters.register("execution-1");
+        let second = waiters.register("execution-1");
+        let gone = waiters.register("execution-1");
+        drop(gone);
+        assert_eq!(waiters.len(), 1);
+
+        assert!(waiters.notify(&completion("execution-1")));
+        assert!(first.wait(None).await.is_some());
+        assert!(second.wait(None).await.is_some());
+        assert!(waiters.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_expired_waiter_is_removed() {
+        let waiters = CompletionWaiters::new();
+        let waiter = waiters.register("execution-1");
+        assert!(waiter.wait(Some(Duration::from_millis(10))).await.is_none());
+
+        assert!(waiters.is_empty());
+        assert!(!waiters.notify(&completion("execution-1")));
+    }
+
+    #[test]
+    fn test_parse_grpc_timeout() {
+        assert_eq!(parse_grpc_timeout("2S"), Some(Duration::from_secs(2)));
+        assert_eq!(parse_grpc_timeout("500m"), Some(Duration::from_millis(500)));
+        assert_eq!(parse_grpc_timeout("1H"), Some(Duration::from_secs(3600)));
+        assert_eq!(parse_grpc_timeout("10"), None);
+        assert_eq!(parse_grpc_timeout("S"), None);
+        assert_eq!(parse_grpc_timeout("123456789S"), None);
+    }
+}
@@ -27,10 +27,13 @@ use std::{
 
 use super::worker_pool::{Sentinel, WorkerPoolSharedData};
 
-#[derive(Default, Debug, Clone, Copy)]
+#[derive(Default, Debug, Clone, Copy, PartialEq)]
 pub enum WorkerType {
+    /// Every task runs on the pool threads.
     #[default]
     Local,
+    /// Tasks registered in the pool `TaskRegistry` run on the pool threads,
+    /// the others are dispatched to remote gRPC workers.
     Remote,
 }
 
@@ -39,10 +42,9 @@ pub fn create_worker(
     id: usize,
     shared_data: Arc<WorkerPoolSharedData>,
 ) -> Box<dyn Worker> {
+    // Remote workers register with the scheduler over gRPC, the pool only runs local threads
     match worker_type {
-        WorkerType::Local => Box::new(LocalWorker::new(id, shared_data.clone())),
-        // WorkerType::Remote => Box::new(RemoteWorker::new(id, shared_data.clone())),
-        _ => panic!("worker type not supported"),
+        WorkerType::Local | WorkerType::Remote => Box::new(LocalWorker::new(id, shared_data.clone())),
     }
 }
 
@@ -85,8 +87,9 @@ fn process_task(
         };
 
         let job = match message_result {
-            Err(e) => {
-                println!("disconnecting: {:?}", thread::current().name().unwrap());
+            Err(_) => {
+                // The ThreadPool was dropped.
+                log::debug!("worker {} disconnecting", worker_id);
                 break;
             }
             Ok(j) => j,
@@ -167,7 +170,7 @@ fn process_task(
             }
             Err(RecvTimeoutError::Disconnected) => {
                 // The ThreadPool was dropped.
-                println!("disconnecting: {:?}.", thread::current().name().unwrap());
+                log::debug!("worker {} disconnecting", worker_id);
                 break;
             }
         }
@@ -263,16 +266,16 @@ mod tests {
     use crate::internal::protot::scheduler::v1::ExecuteRequest;
 
     use super::*;
-    use std::sync::mpsc::channel;
+    use crate::core::job_queue::{job_queue, DEFAULT_AGING_INTERVAL};
 
     #[test]
     fn test_local_worker_spawn() {
-        let (_, rx) = channel::<Job<'static, ExecuteRequest>>();
+        let (_, rx) = job_queue::<Job<'static, ExecuteRequest>>(DEFAULT_AGING_INTERVAL);
 
         // Create mock shared data instance
         let shared_data: Arc<WorkerPoolSharedData> =
             WorkerPoolSharedData::new(1, None, rx, false, Some(String::from("Test")));
-        let mut shared_data_clone = Arc::clone(&shared_data);
+        let shared_data_clone = Arc::clone(&shared_data);
 
         // Create the worker
         // let worker: LocalWorker = LocalWorker::new(1, shared_data.clone());
@@ -287,6 +290,6 @@ mod tests {
         thread::sleep(std::time::Duration::from_millis(100));
 
         // Assertions
-        assert_eq!(shared_data.has_work(), false);
+        assert!(!shared_data.has_work());
     }
 }
@@ -17,39 +17,43 @@ use std::{
     fmt,
     sync::{
         atomic::{AtomicBool, AtomicUsize, Ordering},
-        mpsc::{channel, Receiver, Sender},
         Arc, Condvar, Mutex,
     },
-    thread, error::Error
+    thread, time::Duration,
 };
 
 use async_trait::async_trait;
 use futures::Future;
-use tonic::{Status, Response};
+use prost_types::Any;
+use tokio::runtime::Handle;
 
 use super::{
     job::Job,
+    job_queue::{job_queue, JobReceiver, JobSender, DEFAULT_AGING_INTERVAL},
+    rate_limit::RateLimiter,
+    task::TaskOutcome,
     worker::{LocalWorker, Worker, WorkerType},
 };
 
 // Lib modules
 #[allow(unused_imports)]
-use crate::{internal::protot::scheduler::v1::{ExecuteRequest, TaskCompletion}, logger, SchedulerError};
+use crate::{client::TaskContext, internal::protot::{core::TaskError, scheduler::v1::ExecuteRequest}, logger, utils::current_timestamp, SchedulerError};
 
 #[cfg(feature = "stats")]
 use crate::server::metrics::{
-    self, increment_task, set_worker_pool_metric, WorkerPoolMetricType, WorkerPoolTaskType,
+    self, increment_task, WorkerPoolMetricType, WorkerPoolTaskType,
 };
 
-// Trait for task execution
+// Trait for task execution, returns the task output or why it failed
 pub trait TaskExecutor: Send + Sync + 'static {
-    fn execute(&self, args: ExecuteRequest);
+    fn execute(&self, args: ExecuteRequest) -> Result<Any, TaskError>;
 }
 
-// Trait for task execution
+// Trait for task execution, returns the task output or why it failed.
+// `ctx` submits child tasks of the execution to the scheduler
 #[async_trait]
 pub trait AsyncTaskExecutor: Send + Sync + 'static {
-    async fn execute(&self, args: ExecuteRequest) -> Result<TaskCompletion, ()>;
+    async fn execute(&self, args: ExecuteRequest, ctx: TaskContext) -> Result<Any, TaskError>;
 }
 
 // Struct to hold task executions and their argument implementations
@@ -63,6 +67,12 @@ pub struct GrpcWorkersRegistry {
 }
 
 
+impl Default for GrpcWorkersRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl GrpcWorkersRegistry {
     pub fn new() -> Self {
         GrpcWorkersRegistry {
@@ -70,6 +80,7 @@ impl GrpcWorkersRegistry {
         }
     }
 
+    /// Registers the executor of the tasks whose `task_type` is `task_name`.
     pub fn register_task<E>(&mut self, task_name: &str, executor: E)
     where
         E: AsyncTaskExecutor,
@@ -81,9 +92,9 @@ impl GrpcWorkersRegistry {
     pub fn get_executor(
         &self,
         excutor_name: &str,
-    ) -> Result<&Box<dyn AsyncTaskExecutor>, SchedulerError> {
+    ) -> Result<&dyn AsyncTaskExecutor, SchedulerError> {
         if let Some(executor) = self.registry.get(excutor_name) {
-            Ok(executor)
+            Ok(executor.as_ref())
         } else {
             Err(SchedulerError::TaskExecutionError(format!(
                 "Executor not found for task: {}",
@@ -106,6 +117,7 @@ impl TaskRegistry {
         }
     }
 
+    /// Registers the executor of the tasks whose `task_type` is `task_name`.
     pub fn register_task<E>(&mut self, task_name: &str, executor: E)
     where
         E: TaskExecutor,
@@ -118,12 +130,31 @@ impl TaskRegistry {
         self.registry.keys()
     }
 
+    pub fn contains(&self, task_name: &str) -> bool {
+        self.registry.contains_key(task_name)
+    }
+
+    /// Runs `args` on the executor of `task_name`, fails with `EXECUTOR_NOT_FOUND` when none is registered.
+    ///
+    /// A task that expired before it got here is discarded with an `Expired` outcome instead.
+    pub fn execute(&self, task_name: &str, args: ExecuteRequest) -> TaskOutcome {
+        if let Some(task) = args.task.as_ref().filter(|task| task.is_expired(current_timestamp())) {
+            #[cfg(feature = "stats")]
+            increment_task(WorkerPoolTaskType::Expired);
+            return TaskOutcome::expired(task);
+        }
+        match self.get_executor(task_name) {
+            Ok(executor) => executor.execute(args).into(),
+            Err(err) => TaskOutcome::Failed(TaskError::new("EXECUTOR_NOT_FOUND", err.to_string())),
+        }
+    }
+
     pub fn get_executor(
         &self,
         excutor_name: &str,
-    ) -> Result<&Box<dyn TaskExecutor>, SchedulerError> {
+    ) -> Result<&dyn TaskExecutor, SchedulerError> {
         if let Some(executor) = self.registry.get(excutor_name) {
-            Ok(executor)
+            Ok(executor.as_ref())
         } else {
             Err(SchedulerError::TaskExecutionError(format!(
                 "Executor not found for task: {}",
@@ -187,6 +218,8 @@ pub struct Builder {
     workers_type: WorkerType,
     force_shutdown: Option<bool>,
     executors: Option<Arc<Mutex<TaskRegistry>>>,
+    aging_interval: Option<Duration>,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 fn init_registry(executors: Option<Arc<Mutex<TaskRegistry>>>) -> Arc<Mutex<TaskRegistry>> {
@@ -217,7 +250,8 @@ impl Builder {
         }
     }
 
-    /// If the scheduler system work with remote workers over gRPC
+    /// If the scheduler system work with remote workers over gRPC,
+    /// the registered tasks still run on the pool threads
     pub fn grpc_workers(mut self) -> Builder {
         self.workers_type = WorkerType::Remote;
         self
@@ -252,17 +286,26 @@ impl Builder {
         self
     }
 
+    /// How long a queued job waits to gain one priority level, zero disables aging
+    pub fn aging_interval(mut self, aging_interval: Duration) -> Builder {
+        self.aging_interval = Some(aging_interval);
+        self
+    }
+
+    /// Holds every job back in the queue until the rate limits of its task allow it to start
+    pub fn rate_limiter(mut self, rate_limiter: Arc<RateLimiter>) -> Builder {
+        self.rate_limiter = Some(rate_limiter);
+        self
+    }
+
     pub fn build(self) -> Result<WorkerPool, SchedulerError> {
         let num_workers = self.num_workers.unwrap_or_else(num_cpus::get);
         let force_shutdown = self.force_shutdown.unwrap_or(false);
-        let (tx, rx) = channel::<Job<'static, ExecuteRequest>>();
+        let aging_interval = self.aging_interval.unwrap_or(DEFAULT_AGING_INTERVAL);
+        let (tx, rx) = job_queue::<Job<'static, ExecuteRequest>>(aging_interval);
 
-        let (shared_data, workers) = match self.workers_type {
-            WorkerType::Local => initialize_thread_pool(num_workers, rx, force_shutdown, self.name),
-            WorkerType::Remote => Err(SchedulerError::PoolCreationError(
-                "Cant serve gRPC based workers currently".to_string(),
-            ))?,
-        };
+        // Both types run the local threads, remote workers connect to the scheduler by themselves
+        let (shared_data, workers) = initialize_thread_pool(num_workers, rx, force_shutdown, self.name);
 
         #[cfg(feature = "stats")]
         {
@@ -279,21 +322,62 @@ impl Builder {
             shared_data,
             executors: registry,
             workers,
+            workers_type: self.workers_type,
+            rate_limiter: self.rate_limiter,
         })
     }
 
 }
 
 
-pub struct GrpcWorkerPool {
-    execute_channel: Option<Sender<ExecuteRequest>>,
+type PoolJob = Box<dyn FnOnce(ExecuteRequest) + Send + 'static>;
+
+/// What a rate limited job needs to wait for its token in the queue of its pool.
+struct JobLimits {
+    limiter: Arc<RateLimiter>,
+    handle: Option<Handle>,
+    jobs: JobSender<Job<'static, ExecuteRequest>>,
+    shared_data: Arc<WorkerPoolSharedData>,
+}
+
+/// Wraps `job` to start only once the rate limits of its task allow it, a job held back
+/// is queued again for when its next token is due. The runtime submitting the job times
+/// the wait when there is one, so the limits follow its clock.
+fn rate_limited(job: PoolJob, limits: JobLimits, id: usize, priority: i32) -> PoolJob {
+    Box::new(move |args: ExecuteRequest| {
+        let wait = args
+            .task
+            .as_ref()
+            .filter(|task| !task.is_expired(current_timestamp()))
+            .and_then(|task| limits.limiter.try_acquire_blocking(limits.handle.as_ref(), task));
+        match wait {
+            Some(wait) => {
+                limits.shared_data.queued_count.fetch_add(1, Ordering::SeqCst);
+                let jobs = limits.jobs.clone();
+                let handle = limits.handle.clone();
+                let job = Job { id, data: args, job: Box::new(rate_limited(job, limits, id, priority)) };
+                match handle {
+                    Some(handle) => {
+                        handle.spawn(async move {
+                            tokio::time::sleep(wait).await;
+                            jobs.send(job, priority);
+                        });
+                    }
+                    None => jobs.send_after(job, priority, wait),
+                }
+            }
+            None => job(args),
+        }
+    })
 }
 
 pub struct WorkerPool {
-    jobs: Option<Sender<Job<'static, ExecuteRequest>>>,
+    jobs: Option<JobSender<Job<'static, ExecuteRequest>>>,
     shared_data: Arc<WorkerPoolSharedData>,
     pub executors: Arc<Mutex<TaskRegistry>>,
     workers: Vec<Arc<dyn Worker>>,
+    workers_type: WorkerType,
+    rate_limiter: Option<Arc<RateLimiter>>,
 }
 
 impl WorkerPool {
@@ -307,40 +391,55 @@ impl WorkerPool {
         Builder::new().num_workers(num_workers).name(name).build()
     }
 
-    pub fn execute<F>(&self, job: F, args: ExecuteRequest) -> Result<(), SchedulerError>
+    /// Queues the job, the pool threads take the jobs with the highest task priority first.
+    ///
+    /// With a rate limiter, a rate limited job goes back to the queue until its next token, so
+    /// the pool threads keep running other jobs meanwhile. A task with a `ttl` starts expiring
+    /// once queued, expired tasks don't wait for a token.
+    pub fn execute<F>(&self, job: F, mut args: ExecuteRequest) -> Result<(), SchedulerError>
     where
         F: FnOnce(ExecuteRequest) + Send + 'static,
     {
-        let job_count = self.shared_data.job_counter.fetch_add(1, Ordering::SeqCst);
-        self.shared_data.queued_count.fetch_add(1, Ordering::SeqCst);
-        let mut task = match args.clone().task {
-            Some(t) => t,
+        if let Some(task) = args.task.as_mut() {
+            task.resolve_expiry(current_timestamp());
+        }
+        let priority = match &args.task {
+            Some(task) => task.priority,
             None => Err(SchedulerError::TaskExecutionError(
                 "task execution must include valid data".to_string(),
             ))?,
         };
-        task.id = job_count.to_string();
-        let request = ExecuteRequest { task: Some(task), execution_id: args.execution_id.clone()  };
+        let jobs = match &self.jobs {
+            Some(jobs) => jobs,
+            None => Err(SchedulerError::TaskExecutionError(
+                "Couldn't excute the job as the WorkerPool is not initalized properly".to_string(),
+            ))?,
+        };
 
+        // Counted before it's sent, a pool thread may take it right away
+        let job_count = self.shared_data.job_counter.fetch_add(1, Ordering::SeqCst);
+        self.shared_data.queued_count.fetch_add(1, Ordering::SeqCst);
         #[cfg(feature = "stats")]
         increment_task(WorkerPoolTaskType::Queued);
 
-        if self.jobs.is_some() {
-            self.jobs
-                .as_ref()
-                .unwrap()
-                .send(Job {
-                    id: job_count,
-                    data: request,
-                    job: Box::new(job),
-                })
-                .expect("WorkerPool::execute unable to send job into queue.");
-        } else {
-            Err(SchedulerError::TaskExecutionError(
-                "Couldn't excute the job as the WorkerPool is not initalized properly".to_string(),
-            ))?
-        }
+        let job: PoolJob = match &self.rate_limiter {
+            Some(limiter) if !limiter.is_empty() => {
+                // Stores talk to the runtime submitting the job, the pool threads don't run one
+                let handle = Handle::try_current().ok();
+                let limits = JobLimits { limiter: limiter.clone(), handle, jobs: jobs.clone(), shared_data: self.shared_data.clone() };
+                rate_limited(Box::new(job), limits, job_count, priority)
+            }
+            _ => Box::new(job),
+        };
 
+        jobs.send(
+            Job {
+                id: job_count,
+                data: args,
+                job: Box::new(job),
+            },
+            priority,
+        );
         Ok(())
     }
 
@@ -357,6 +456,18 @@ impl WorkerPool {
         self.execute(job_wrapper, args)
     }
 
+    pub fn workers_type(&self) -> WorkerType {
+        self.workers_type
+    }
+
+    /// Whether the task runs on the pool threads rather than on a remote gRPC worker.
+    pub fn runs_locally(&self, task_name: &str) -> bool {
+        match self.workers_type {
+            WorkerType::Local => true,
+            WorkerType::Remote => self.executors.lock().unwrap().contains(task_name),
+        }
+    }
+
     pub fn queued_count(&self) -> usize {
         self.shared_data.queued_count.load(Ordering::Relaxed)
     }
@@ -425,12 +536,12 @@ impl Clone for WorkerPool {
     /// We could for example submit jobs from multiple threads concurrently.
     ///
     /// ```rust,no_run
-    /// use protot::core::worker_pool::WorkerPool;
-    /// use protot::internal::sylklabs::scheduler::v1::ExecuteRequest;
+    /// use protot::core::worker_pool::Builder;
+    /// use protot::internal::protot::scheduler::v1::ExecuteRequest;
     /// use std::thread;
     /// use std::sync::mpsc::channel;
     ///
-    /// let pool = WorkerPool::with_name("clone example".into(), 2).unwrap();
+    /// let pool = Builder::new().name("clone example".into()).num_workers(2).build().unwrap();
     ///
     /// let results = (0..2)
     ///     .map(|i| {
@@ -441,7 +552,7 @@ impl Clone for WorkerPool {
     ///                 let tx = tx.clone();
     ///                 pool.execute(move |args| {
     ///                     tx.send(i).expect("channel will be waiting");
-    ///                 }, ExecuteRequest {..Default::default()});
+    ///                 }, ExecuteRequest {..Default::default()}).unwrap();
     ///             }
     ///             drop(tx);
     ///             if i == 0 {
@@ -462,6 +573,8 @@ impl Clone for WorkerPool {
             shared_data: self.shared_data.clone(),
             executors: self.executors.clone(),
             workers: self.workers.clone(),
+            workers_type: self.workers_type,
+            rate_limiter: self.rate_limiter.clone(),
         }
     }
 }
@@ -488,7 +601,7 @@ impl fmt::Debug for WorkerPool {
 
 pub struct WorkerPoolSharedData {
     name: Option<String>,
-    pub job_receiver: Arc<Mutex<Receiver<Job<'static, ExecuteRequest>>>>,
+    pub job_receiver: Arc<Mutex<JobReceiver<Job<'static, ExecuteRequest>>>>,
     empty_trigger: Mutex<()>,
     empty_condvar: Condvar,
     join_generation: AtomicUsize,
@@ -506,7 +619,7 @@ impl WorkerPoolSharedData {
     pub fn new(
         num_threads: usize,
         thread_stack_size: Option<usize>,
-        receiver: Receiver<Job<'static, ExecuteRequest>>,
+        receiver: JobReceiver<Job<'static, ExecuteRequest>>,
         force_shutdown: bool,
         pool_name: Option<String>,
     ) -> Arc<Self> {
@@ -583,7 +696,7 @@ impl WorkerPoolSharedData {
 // Function to initialize the pool
 pub fn initialize_thread_pool(
     num_threads: usize,
-    receiver: Receiver<Job<'static, ExecuteRequest>>,
+    receiver: JobReceiver<Job<'static, ExecuteRequest>>,
     force_shutdown: bool,
     pool_name: Option<String>,
 ) -> (Arc<WorkerPoolSharedData>, Vec<Arc<dyn Worker>>) {
@@ -0,0 +1,660 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::collections::{HashMap, HashSet};
+
+use log::{info, warn};
+use prost::Message;
+use prost_types::Any;
+use tokio::sync::Mutex;
+use tonic::Status;
+use uuid::Uuid;
+
+use crate::{
+    data::{DataStore, DataStoreError, ExecutionRecord},
+    internal::protot::{
+        core::{
+            Task, TaskError, TaskState, Workflow, WorkflowInput, WorkflowNode, WorkflowNodeState, WorkflowNodeStatus,
+            WorkflowOutputs, WorkflowState, WorkflowStatus,
+        },
+        scheduler::v1::TaskCompletion,
+    },
+    utils::current_timestamp,
+};
+
+/// Type url of the `WorkflowOutputs` payload of the nodes taking the outputs of several nodes.
+pub const WORKFLOW_OUTPUTS_TYPE_URL: &str = "type.googleapis.com/protot.core.WorkflowOutputs";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum WorkflowError {
+    /// The workflow has no nodes.
+    Empty,
+    /// A node has no name or no task.
+    InvalidNode(String),
+    /// Two nodes share the given name.
+    DuplicateNode(String),
+    /// A node depends on a node the workflow doesn't have.
+    UnknownDependency { node: String, dependency: String },
+    /// The dependencies of the given node loop back to it.
+    Cycle(String),
+}
+
+impl std::fmt::Display for WorkflowError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            WorkflowError::Empty => write!(f, "Workflow has no nodes"),
+            WorkflowError::InvalidNode(name) => write!(f, "Workflow node must have a name and a task: {:?}", name),
+            WorkflowError::DuplicateNode(name) => write!(f, "Workflow node is defined twice: {}", name),
+            WorkflowError::UnknownDependency { node, dependency } => {
+                write!(f, "Workflow node {} depends on unknown node {}", node, dependency)
+            }
+            WorkflowError::Cycle(name) => write!(f, "Workflow dependencies form a cycle through node {}", name),
+        }
+    }
+}
+
+impl std::error::Error for WorkflowError {}
+
+impl From<WorkflowError> for Status {
+    fn from(err: WorkflowError) -> Self {
+        Status::invalid_argument(err.to_string())
+    }
+}
+
+/// Checks that the nodes of `workflow` have unique names, tasks and dependencies without cycles.
+pub fn validate(workflow: &Workflow) -> Result<(), WorkflowError> {
+    if workflow.nodes.is_empty() {
+        return Err(WorkflowError::Empty);
+    }
+    let mut names = HashSet::new();
+    for node in &workflow.nodes {
+        if node.name.is_empty() || node.task.is_none() {
+            return Err(WorkflowError::InvalidNode(node.name.clone()));
+        }
+        if !names.insert(node.name.as_str()) {
+            return Err(WorkflowError::DuplicateNode(node.name.clone()));
+        }
+    }
+    for node in &workflow.nodes {
+        if let Some(dependency) = node.depends_on.iter().find(|dependency| !names.contains(dependency.as_str())) {
+            return Err(WorkflowError::UnknownDependency { node: node.name.clone(), dependency: dependency.clone() });
+        }
+    }
+
+    // Peels the nodes whose dependencies are all peeled already, the ones left over sit on a cycle
+    let mut remaining: HashMap<&str, usize> = workflow.nodes
+        .iter()
+        .map(|node| (node.name.as_str(), node.depends_on.iter().collect::<HashSet<_>>().len()))
+        .collect();
+    let mut ready: Vec<&str> = remaining.iter().filter(|(_, count)| **count == 0).map(|(name, _)| *name).collect();
+    while let Some(name) = ready.pop() {
+        remaining.remove(name);
+        for node in &workflow.nodes {
+            if node.depends_on.iter().any(|dependency| dependency == name) {
+                if let Some(count) = remaining.get_mut(node.name.as_str()) {
+                    *count -= 1;
+                    if *count == 0 {
+                        ready.push(&node.name);
+                    }
+                }
+            }
+        }
+    }
+    match workflow.nodes.iter().find(|node| remaining.contains_key(node.name.as_str())) {
+        Some(node) => Err(WorkflowError::Cycle(node.name.clone())),
+        None => Ok(()),
+    }
+}
+
+/// Indexes of the nodes depending on the node `name`, directly or not.
+fn dependents(workflow: &Workflow, name: &str) -> Vec<usize> {
+    let mut found: Vec<usize> = Vec::new();
+    let mut names = vec![name];
+    while let Some(name) = names.pop() {
+        for (index, node) in workflow.nodes.iter().enumerate() {
+            if !found.contains(&index) && node.depends_on.iter().any(|dependency| dependency == name) {
+                found.push(index);
+                names.push(&node.name);
+            }
+        }
+    }
+    found
+}
+
+impl WorkflowStatus {
+    /// A new workflow with every node waiting, once `workflow` is valid.
+    pub fn new(workflow: Workflow, now: i64) -> Result<Self, WorkflowError> {
+        validate(&workflow)?;
+        let nodes = workflow.nodes
+            .iter()
+            .map(|node| WorkflowNodeStatus { name: node.name.clone(), ..Default::default() })
+            .collect();
+        Ok(WorkflowStatus {
+            workflow_id: workflow.workflow_id.clone(),
+            state: WorkflowState::Running.into(),
+            nodes,
+            created_at: now,
+            updated_at: now,
+            workflow: Some(workflow),
+        })
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.state() != WorkflowState::Running
+    }
+
+    /// Whether a node of the workflow has a compensation, failures then compensate the nodes that
+    /// succeeded instead of letting the independent branches run to the end.
+    pub fn has_compensations(&self) -> bool {
+        self.workflow.as_ref().is_some_and(|workflow| workflow.nodes.iter().any(|node| node.compensation.is_some()))
+    }
+
+    fn node_state(&self, name: &str) -> Option<WorkflowNodeState> {
+        self.nodes.iter().find(|node| node.name == name).map(|node| node.state())
+    }
+
+    fn node_output(&self, name: &str) -> Option<Any> {
+        self.nodes.iter().find(|node| node.name == name).and_then(|node| node.output.clone())
+    }
+
+    /// The task `node` runs, tied to this workflow and taking the outputs of its dependencies as its `input` says.
+    fn node_task(&self, node: &WorkflowNode, now: i64) -> Task {
+        let mut task = node.task.clone().unwrap_or_default();
+        task.workflow_id = self.workflow_id.clone();
+        task.workflow_node = node.name.clone();
+        task.resolve_expiry(now);
+        if node.depends_on.is_empty() {
+            return task;
+        }
+        match (node.input(), node.depends_on.as_slice()) {
+            (WorkflowInput::Payload, _) => {}
+            (WorkflowInput::Output, [dependency]) => task.payload = self.node_output(dependency),
+            (WorkflowInput::Output | WorkflowInput::Outputs, dependencies) => {
+                let outputs = WorkflowOutputs {
+                    outputs: dependencies.iter().map(|dependency| self.node_output(dependency).unwrap_or_default()).collect(),
+                };
+                task.payload = Some(Any { type_url: WORKFLOW_OUTPUTS_TYPE_URL.to_string(), value: outputs.encode_to_vec() });
+            }
+        }
+        task
+    }
+
+    /// The compensation task of `node`, tied to this workflow and taking the node output as its
+    /// payload when it has none.
+    fn compensation_task(&self, node: &WorkflowNode, now: i64) -> Task {
+        let mut task = node.compensation.clone().unwrap_or_default();
+        task.workflow_id = self.workflow_id.clone();
+        task.workflow_node = node.name.clone();
+        task.resolve_expiry(now);
+        if task.payload.is_none() {
+            task.payload = self.node_output(&node.name);
+        }
+        task
+    }
+
+    /// Starts the waiting nodes whose dependencies all succeeded, returns their executions to submit.
+    ///
+    /// Once a workflow with compensations failed, starts instead the compensations of the succeeded
+    /// nodes whose dependents, direct or not, are all compensated. The tasks of the executions name
+    /// the workflow and their node, so their completions find their way back.
+    pub fn start_ready_nodes(&mut self, now: i64) -> Vec<ExecutionRecord> {
+        let workflow = self.workflow.clone().unwrap_or_default();
+        let mut started = Vec::new();
+        for (index, node) in workflow.nodes.iter().enumerate() {
+            let ready = self.nodes[index].state() == WorkflowNodeState::Waiting
+                && node.depends_on.iter().all(|dependency| self.node_state(dependency) == Some(WorkflowNodeState::Success));
+            if !ready {
+                continue;
+            }
+            let task = self.node_task(node, now);
+            let status = &mut self.nodes[index];
+            status.set_state(WorkflowNodeState::Running);
+            status.execution_id = Uuid::new_v4().to_string();
+            started.push(ExecutionRecord::new(status.execution_id.clone(), task));
+        }
+        if self.is_compensating() {
+            for (index, node) in workflow.nodes.iter().enumerate() {
+                let ready = self.awaits_compensation(index)
+                    && dependents(&workflow, &node.name).into_iter().all(|dependent| {
+                        !self.awaits_compensation(dependent) && self.nodes[dependent].state() != WorkflowNodeState::Compensating
+                    });
+                if !ready {
+                    continue;
+                }
+                let task = self.compensation_task(node, now);
+                let status = &mut self.nodes[index];
+                status.set_state(WorkflowNodeState::Compensating);
+                status.compensation_execution_id = Uuid::new_v4().to_string();
+                started.push(ExecutionRecord::new(status.compensation_execution_id.clone(), task));
+            }
+        }
+        if !started.is_empty() {
+            self.updated_at = now;
+        }
+        started
+    }
+
+    /// Whether the workflow undoes its succeeded nodes: a node failed, none runs anymore and
+    /// no compensation failed so far.
+    fn is_compensating(&self) -> bool {
+        self.state() == WorkflowState::Running
+            && self.has_compensations()
+            && self.nodes.iter().any(|node| node.state() == WorkflowNodeState::Fail)
+            && !self.nodes.iter().any(|node| {
+                matches!(node.state(), WorkflowNodeState::Waiting | WorkflowNodeState::Running | WorkflowNodeState::CompensationFailed)
+            })
+    }
+
+    /// Whether the node at `index` succeeded and has a compensation that didn't start yet.
+    fn awaits_compensation(&self, index: usize) -> bool {
+        let has_compensation = self.workflow
+            .as_ref()
+            .and_then(|workflow| workflow.nodes.get(index))
+            .is_some_and(|node| node.compensation.is_some());
+        has_compensation && self.nodes[index].state() == WorkflowNodeState::Success
+    }
+
+    /// Records the final state of the execution of a running node along with its output or error,
+    /// or of the compensation of a compensating node, returns whether the node was running it.
+    ///
+    /// A node that did not succeed skips every node depending on it, directly or not, and every
+    /// waiting node when the workflow has compensations. The workflow finishes once no node waits
+    /// or runs anymore and, after a failure, the succeeded nodes are compensated.
+    pub fn complete_node(
+        &mut self,
+        name: &str,
+        execution_id: &str,
+        state: TaskState,
+        output: Option<Any>,
+        error: Option<TaskError>,
+        now: i64,
+    ) -> bool {
+        let has_compensations = self.has_compensations();
+        let Some(status) = self.nodes.iter_mut().find(|node| node.name == name) else {
+            return false;
+        };
+        match (status.state(), state) {
+            (WorkflowNodeState::Running, TaskState::Success) if status.execution_id == execution_id => {
+                status.set_state(WorkflowNodeState::Success);
+                status.output = output;
+            }
+            (WorkflowNodeState::Running, _) if status.execution_id == execution_id => {
+                status.set_state(WorkflowNodeState::Fail);
+                status.error = error;
+                if has_compensations {
+                    self.skip_waiting();
+                } else {
+                    self.skip_dependents();
+                }
+            }
+            (WorkflowNodeState::Compensating, TaskState::Success) if status.compensation_execution_id == execution_id => {
+                status.set_state(WorkflowNodeState::Compensated);
+            }
+            (WorkflowNodeState::Compensating, _) if status.compensation_execution_id == execution_id => {
+                status.set_state(WorkflowNodeState::CompensationFailed);
+                status.error = error;
+            }
+            _ => return false,
+        }
+        self.settle();
+        self.updated_at = now;
+        true
+    }
+
+    /// Finishes the workflow once no node waits or runs anymore and nothing is left to compensate.
+    fn settle(&mut self) {
+        let busy = self.nodes.iter().any(|node| {
+            matches!(node.state(), WorkflowNodeState::Waiting | WorkflowNodeState::Running | WorkflowNodeState::Compensating)
+        });
+        if busy {
+            return;
+        }
+        let failed = self.nodes.iter().any(|node| node.state() == WorkflowNodeState::Fail);
+        let state = match (failed, self.has_compensations()) {
+            (false, _) => WorkflowState::Success,
+            (true, false) => WorkflowState::Fail,
+            // Compensations stop at the first one failing, the nodes left keep their success
+            (true, true) if self.nodes.iter().any(|node| node.state() == WorkflowNodeState::CompensationFailed) => {
+                WorkflowState::CompensationFailed
+            }
+            (true, true) if (0..self.nodes.len()).any(|index| self.awaits_compensation(index)) => return,
+            (true, true) => WorkflowState::Compensated,
+        };
+        self.set_state(state);
+    }
+
+    /// Skips every waiting node, a workflow with compensations stops at its first failure.
+    fn skip_waiting(&mut self) {
+        for node in self.nodes.iter_mut().filter(|node| node.state() == WorkflowNodeState::Waiting) {
+            node.set_state(WorkflowNodeState::Skipped);
+        }
+    }
+
+    fn skip_dependents(&mut self) {
+        let workflow = self.workflow.clone().unwrap_or_default();
+        let mut skipped = true;
+        while skipped {
+            skipped = false;
+            for (index, node) in workflow.nodes.iter().enumerate() {
+                let blocked = self.nodes[index].state() == WorkflowNodeState::Waiting
+                    && node.depends_on.iter().any(|dependency| {
+                        matches!(self.node_state(dependency), Some(WorkflowNodeState::Fail | WorkflowNodeState::Skipped))
+                    });
+                if blocked {
+                    self.nodes[index].set_state(WorkflowNodeState::Skipped);
+                    skipped = true;
+                }
+            }
+        }
+    }
+}
+
+/// Advances workflows as the executions of their nodes complete, keeping their state in the data store.
+///
+/// Changes to workflows are made one at a time, so nodes completing together can't overwrite
+/// each other's state.
+#[derive(Default)]
+pub struct WorkflowEngine {
+    lock: Mutex<()>,
+}
+
+impl WorkflowEngine {
+    /// Stores a new workflow and starts its nodes without dependencies.
+    ///
+    /// Returns the workflow as stored and the executions of the started nodes, which the
+    /// caller stores and submits.
+    pub async fn submit(&self, db: &dyn DataStore, mut workflow: WorkflowStatus) -> Result<(WorkflowStatus, Vec<ExecutionRecord>), DataStoreError> {
+        let _guard = self.lock.lock().await;
+        let started = workflow.start_ready_nodes(current_timestamp());
+        db.add_workflow(&workflow).await?;
+        info!("workflow {} submitted, starting {} nodes", workflow.workflow_id, started.len());
+        Ok((workflow, started))
+    }
+
+    /// Records the completion of an execution of a workflow node, returns the executions of the
+    /// nodes it unblocked. Executions of tasks outside of any workflow are ignored.
+    pub async fn complete(&self, db: &dyn DataStore, record: &ExecutionRecord, completion: &TaskCompletion) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+        if record.task.workflow_id.is_empty() {
+            return Ok(Vec::new());
+        }
+        let _guard = self.lock.lock().await;
+        let mut workflow = db.get_workflow(&record.task.workflow_id).await?;
+        let now = current_timestamp();
+        let (state, output, error) = (completion.state(), completion.output.clone(), completion.error.clone());
+        if !workflow.complete_node(&record.task.workflow_node, &record.execution_id, state, output, error, now) {
+            warn!("ignoring completion of task execution {} which workflow {} doesn't run", record.execution_id, workflow.workflow_id);
+            return Ok(Vec::new());
+        }
+        let started = workflow.start_ready_nodes(now);
+        db.update_workflow(&workflow).await?;
+        if workflow.is_finished() {
+            info!("workflow {} finished in state {:?}", workflow.workflow_id, workflow.state());
+        }
+        Ok(started)
+    }
+
+    /// Catches running workflows up with the executions of their nodes after a (re)start.
+    ///
+    /// Completions recorded while the workflow was not updated advance it, and running or
+    /// compensating nodes whose execution was never stored get it back. Returns the executions
+    /// to store and submit.
+    pub async fn recover(&self, db: &dyn DataStore) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+        let _guard = self.lock.lock().await;
+        let now = current_timestamp();
+        let mut submit = Vec::new();
+        for mut workflow in db.list_workflows(WorkflowState::Running).await? {
+            let running: Vec<(String, String, bool)> = workflow.nodes
+                .iter()
+                .filter_map(|node| match node.state() {
+                    WorkflowNodeState::Running => Some((node.name.clone(), node.execution_id.clone(), false)),
+                    WorkflowNodeState::Compensating => Some((node.name.clone(), node.compensation_execution_id.clone(), true)),
+                    _ => None,
+                })
+                .collect();
+            let mut changed = false;
+            for (name, execution_id, compensating) in running {
+                match db.get_task_execution(&execution_id).await {
+                    Ok(record) if record.is_finished() => {
+                        changed |= workflow.complete_node(&name, &execution_id, record.state, record.output, record.error, now);
+                    }
+                    Ok(_) => {}
+                    Err(DataStoreError::NotFound(_)) => {
+                        let definition = workflow.workflow.clone().unwrap_or_default();
+                        if let Some(node) = definition.nodes.iter().find(|node| node.name == name) {
+                            let task = if compensating { workflow.compensation_task(node, now) } else { workflow.node_task(node, now) };
+                            submit.push(ExecutionRecord::new(execution_id, task));
+                        }
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            let started = workflow.start_ready_nodes(now);
+            if changed || !started.is_empty() {
+                db.update_workflow(&workflow).await?;
+            }
+            submit.extend(started);
+        }
+        Ok(submit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::protot::core::{Task, WorkflowNode};
+
+    use super::*;
+
+    fn node(name: &str, depends_on: &[&str]) -> WorkflowNode {
+        WorkflowNode {
+            name: name.to_string(),
+            task: Some(Task { id: name.to_string(), task_type: "step".to_string(), ..Default::default() }),
+            depends_on: depends_on.iter().map(|dependency| dependency.to_string()).collect(),
+            ..Default::default()
+        }
+    }
+
+    fn workflow(nodes: Vec<WorkflowNode>) -> Workflow {
+        Workflow { workflow_id: "wf-1".to_string(), name: "pipeline".to_string(), nodes }
+    }
+
+    fn started_nodes(started: &[ExecutionRecord]) -> Vec<&str> {
+        started.iter().map(|record| record.task.workflow_node.as_str()).collect()
+    }
+
+    fn states(workflow: &WorkflowStatus) -> Vec<WorkflowNodeState> {
+        workflow.nodes.iter().map(|node| node.state()).collect()
+    }
+
+    #[test]
+    fn test_validate() {
+        assert_eq!(validate(&workflow(vec![])), Err(WorkflowError::Empty));
+        assert_eq!(
+            validate(&workflow(vec![node("a", &[]), node("a", &[])])),
+            Err(WorkflowError::DuplicateNode("a".to_string()))
+        );
+        assert_eq!(
+            validate(&workflow(vec![node("a", &["missing"])])),
+            Err(WorkflowError::UnknownDependency { node: "a".to_string(), dependency: "missing".to_string() })
+        );
+        assert_eq!(
+            validate(&workflow(vec![node("a", &[]), node("b", &["a", "c"]), node("c", &["b"])])),
+            Err(WorkflowError::Cycle("b".to_string()))
+        );
+        let mut without_task = node("a", &[]);
+        without_task.task = None;
+        assert_eq!(validate(&workflow(vec![without_task])), Err(WorkflowError::InvalidNode("a".to_string())));
+
+        assert_eq!(validate(&workflow(vec![node("a", &[]), node("b", &["a", "a"]), node("c", &["a", "b"])])), Ok(()));
+    }
+
+    #[test]
+    fn test_nodes_start_once_dependencies_succeed() {
+        let mut status = WorkflowStatus::new(workflow(vec![
+            node("extract", &[]),
+            node("clean", &["extract"]),
+            node("enrich", &["extract"]),
+            node("load", &["clean", "enrich"]),
+        ]), 100).unwrap();
+
+        let started = status.start_ready_nodes(100);
+        assert_eq!(started_nodes(&started), vec!["extract"]);
+        assert_eq!(started[0].task.workflow_id, "wf-1");
+        assert!(status.complete_node("extract", &started[0].execution_id, TaskState::Success, None, None, 101));
+
+        let started = status.start_ready_nodes(101);
+        assert_eq!(started_nodes(&started), vec!["clean", "enrich"]);
+        assert!(status.complete_node("clean", &started[0].execution_id, TaskState::Success, None, None, 102));
+        assert!(status.start_ready_nodes(102).is_empty());
+        // A completion of another execution of the node is not the node's
+        assert!(!status.complete_node("enrich", "other", TaskState::Success, None, None, 102));
+        assert!(status.complete_node("enrich", &started[1].execution_id, TaskState::Success, None, None, 103));
+
+        let started = status.start_ready_nodes(103);
+        assert_eq!(started_nodes(&started), vec!["load"]);
+        assert!(!status.is_finished());
+        assert!(status.complete_node("load", &started[0].execution_id, TaskState::Success, None, None, 104));
+        assert_eq!(status.state(), WorkflowState::Success);
+        assert_eq!(status.updated_at, 104);
+    }
+
+    #[test]
+    fn test_failure_skips_dependents() {
+        let mut status = WorkflowStatus::new(workflow(vec![
+            node("a", &[]),
+            node("b", &["a"]),
+            node("c", &["b"]),
+            node("d", &[]),
+        ]), 100).unwrap();
+
+        let started = status.start_ready_nodes(100);
+        assert_eq!(started_nodes(&started), vec!["a", "d"]);
+        let error = TaskError::new("BROKEN", "always fails");
+        assert!(status.complete_node("a", &started[0].execution_id, TaskState::Fail, None, Some(error.clone()), 101));
+        assert_eq!(
+            states(&status),
+            vec![WorkflowNodeState::Fail, WorkflowNodeState::Skipped, WorkflowNodeState::Skipped, WorkflowNodeState::Running]
+        );
+        assert_eq!(status.nodes[0].error, Some(error));
+        // Independent nodes still run to the end
+        assert!(!status.is_finished());
+        assert!(status.complete_node("d", &started[1].execution_id, TaskState::Success, None, None, 102));
+        assert_eq!(status.state(), WorkflowState::Fail);
+    }
+
+    #[test]
+    fn test_outputs_become_inputs() {
+        let output = |value: &str| Any { type_url: "type.googleapis.com/google.protobuf.StringValue".to_string(), value: value.as_bytes().to_vec() };
+        let mut single = node("single", &["a"]);
+        single.input = WorkflowInput::Output.into();
+        let mut both = node("both", &["a", "b"]);
+        both.input = WorkflowInput::Output.into();
+        let mut all = node("all", &["a"]);
+        all.input = WorkflowInput::Outputs.into();
+        let mut status = WorkflowStatus::new(workflow(vec![node("a", &[]), node("b", &[]), single, both, all, node("own", &["a"])]), 100).unwrap();
+
+        let started = status.start_ready_nodes(100);
+        assert!(status.complete_node("a", &started[0].execution_id, TaskState::Success, Some(output("a")), None, 101));
+        assert!(status.complete_node("b", &started[1].execution_id, TaskState::Success, Some(output("b")), None, 101));
+        assert_eq!(status.nodes[0].output, Some(output("a")));
+
+        let started = status.start_ready_nodes(101);
+        let payload = |name: &str| started.iter().find(|record| record.task.workflow_node == name).unwrap().task.payload.clone();
+        assert_eq!(payload("single"), Some(output("a")));
+        let outputs = |payload: Option<Any>| {
+            let payload = payload.unwrap();
+            assert_eq!(payload.type_url, WORKFLOW_OUTPUTS_TYPE_URL);
+            WorkflowOutputs::decode(payload.value.as_slice()).unwrap().outputs
+        };
+        assert_eq!(outputs(payload("both")), vec![output("a"), output("b")]);
+        assert_eq!(outputs(payload("all")), vec![output("a")]);
+        // Nodes taking their own payload keep it
+        assert_eq!(payload("own"), None);
+    }
+
+    fn compensated(name: &str, depends_on: &[&str]) -> WorkflowNode {
+        let mut node = node(name, depends_on);
+        node.compensation = Some(Task { id: format!("undo-{}", name), task_type: "undo".to_string(), ..Default::default() });
+        node
+    }
+
+    #[test]
+    fn test_compensations_run_in_reverse_order() {
+        let output = Any { type_url: "type.googleapis.com/google.protobuf.StringValue".to_string(), value: b"vm-1".to_vec() };
+        let mut status = WorkflowStatus::new(workflow(vec![
+            compensated("create-vm", &[]),
+            compensated("attach-disk", &["create-vm"]),
+            node("notify", &["attach-disk"]),
+            node("configure", &["notify"]),
+        ]), 100).unwrap();
+
+        for (name, result) in [("create-vm", Some(output.clone())), ("attach-disk", None), ("notify", None)] {
+            let started = status.start_ready_nodes(100);
+            assert_eq!(started_nodes(&started), vec![name]);
+            assert!(status.complete_node(name, &started[0].execution_id, TaskState::Success, result, None, 101));
+        }
+        let started = status.start_ready_nodes(101);
+        assert!(status.complete_node("configure", &started[0].execution_id, TaskState::Fail, None, None, 102));
+        assert!(!status.is_finished());
+
+        // The last node with a compensation is undone first
+        let started = status.start_ready_nodes(102);
+        assert_eq!(started_nodes(&started), vec!["attach-disk"]);
+        assert_eq!(started[0].task.id, "undo-attach-disk");
+        assert_eq!(started[0].execution_id, status.nodes[1].compensation_execution_id);
+        assert_eq!(status.nodes[1].state(), WorkflowNodeState::Compensating);
+        // A completion of the node's own execution is not its compensation's
+        assert!(!status.complete_node("attach-disk", &status.nodes[1].execution_id.clone(), TaskState::Success, None, None, 103));
+        assert!(status.complete_node("attach-disk", &started[0].execution_id, TaskState::Success, None, None, 103));
+
+        let started = status.start_ready_nodes(103);
+        assert_eq!(started_nodes(&started), vec!["create-vm"]);
+        // Compensations take the output of their node
+        assert_eq!(started[0].task.payload, Some(output));
+        assert!(status.complete_node("create-vm", &started[0].execution_id, TaskState::Success, None, None, 104));
+        assert_eq!(
+            states(&status),
+            vec![WorkflowNodeState::Compensated, WorkflowNodeState::Compensated, WorkflowNodeState::Success, WorkflowNodeState::Fail]
+        );
+        assert_eq!(status.state(), WorkflowState::Compensated);
+        assert!(status.start_ready_nodes(104).is_empty());
+    }
+
+    #[test]
+    fn test_failed_compensation() {
+        let mut status = WorkflowStatus::new(workflow(vec![
+            compensated("a", &[]),
+            compensated("b", &[]),
+            node("c", &["a"]),
+            node("d", &["b"]),
+        ]), 100).unwrap();
+
+        let started = status.start_ready_nodes(100);
+        assert!(status.complete_node("a", &started[0].execution_id, TaskState::Success, None, None, 101));
+        let c = status.start_ready_nodes(101);
+        assert!(status.complete_node("c", &c[0].execution_id, TaskState::Fail, None, None, 102));
+        // Nothing else starts, compensations wait for the running nodes
+        assert_eq!(status.nodes[3].state(), WorkflowNodeState::Skipped);
+        assert!(status.start_ready_nodes(102).is_empty());
+        assert!(status.complete_node("b", &started[1].execution_id, TaskState::Success, None, None, 103));
+
+        let started = status.start_ready_nodes(103);
+        assert_eq!(started_nodes(&started), vec!["a", "b"]);
+        let error = TaskError::new("STUCK", "could not undo");
+        assert!(status.complete_node("a", &started[0].execution_id, TaskState::Fail, None, Some(error.clone()), 104));
+        assert_eq!(status.nodes[0].error, Some(error));
+        assert!(!status.is_finished());
+        assert!(status.complete_node("b", &started[1].execution_id, TaskState::Success, None, None, 105));
+        assert_eq!(status.state(), WorkflowState::CompensationFailed);
+    }
+}
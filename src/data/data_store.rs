@@ -1,19 +1,634 @@
+use std::{collections::HashMap, time::Duration};
+
 use async_trait::async_trait;
+use tonic::Status;
+
+use prost_types::Any;
+
+use crate::{internal::protot::{scheduler::v1::{self, DeadLetter, ExecuteRequest, ExecuteResponse, TaskCompletion}, core::{ExecutionAttempt, Task, TaskError, TaskState, WorkflowState, WorkflowStatus}}, utils::current_timestamp};
 
-use crate::{internal::protot::{scheduler::v1::ExecuteRequest, core::TaskState}, SchedulerError};
+/// Page size used when a `PageRequest` does not set a limit.
+pub const DEFAULT_PAGE_LIMIT: usize = 100;
 
+/// How long an idempotency key suppresses duplicate submissions when not configured.
+pub const DEFAULT_IDEMPOTENCY_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum DataStoreError {
-    NotFound,
-    Duplicate,
+    /// No execution exists for the given execution id.
+    NotFound(String),
+    /// An execution with the given execution id already exists.
+    Duplicate(String),
+    /// The request to the data store is malformed (e.g. missing task or invalid cursor).
+    InvalidArgument(String),
+    /// The underlying storage failed.
     InternalError(String),
+    /// The execution is not running on the worker reporting it anymore, e.g. its lease expired.
+    LeaseLost(String),
+    /// The fencing token of the write is not the one of the current leader lease anymore.
+    Fenced(u64),
+}
+
+impl std::fmt::Display for DataStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            DataStoreError::NotFound(id) => write!(f, "Task execution not found: {}", id),
+            DataStoreError::Duplicate(id) => write!(f, "Task execution already exists: {}", id),
+            DataStoreError::InvalidArgument(msg) => write!(f, "Invalid argument: {}", msg),
+            DataStoreError::InternalError(msg) => write!(f, "Internal data store error: {}", msg),
+            DataStoreError::LeaseLost(id) => write!(f, "Task execution is not leased to the worker anymore: {}", id),
+            DataStoreError::Fenced(token) => write!(f, "Fencing token {} belongs to a former leader", token),
+        }
+    }
+}
+
+impl std::error::Error for DataStoreError {}
+
+impl From<DataStoreError> for Status {
+    fn from(err: DataStoreError) -> Self {
+        let message = err.to_string();
+        match err {
+            DataStoreError::NotFound(_) => Status::not_found(message),
+            DataStoreError::Duplicate(_) => Status::already_exists(message),
+            DataStoreError::InvalidArgument(_) => Status::invalid_argument(message),
+            DataStoreError::InternalError(_) => Status::internal(message),
+            DataStoreError::LeaseLost(_) | DataStoreError::Fenced(_) => Status::failed_precondition(message),
+        }
+    }
+}
+
+/// A single task execution as it is kept by a `DataStore`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ExecutionRecord {
+    pub execution_id: String,
+    pub task: Task,
+    pub state: TaskState,
+    /// The worker the execution was assigned to, if any.
+    pub worker_id: Option<String>,
+    /// The scheduler that holds the pending execution in its queue, if any.
+    pub claimed_by: Option<String>,
+    /// Unix timestamp (seconds) of the submission.
+    pub created_at: i64,
+    /// Unix timestamp (seconds) of the last state change.
+    pub updated_at: i64,
+    /// Unix timestamp (seconds) until which the assigned worker owns a `Running` execution.
+    pub lease_expires_at: Option<i64>,
+    /// What the task returned, once it succeeded.
+    pub output: Option<Any>,
+    /// Why the task failed, once it failed, or why its last attempt failed while it is retried.
+    pub error: Option<TaskError>,
+    /// How many times the execution was queued again after a failed attempt.
+    pub retries: u32,
+    /// The attempts that failed and were retried, oldest first.
+    pub attempts: Vec<ExecutionAttempt>,
+}
+
+impl ExecutionRecord {
+    /// A new pending execution of `task`.
+    pub fn new(execution_id: String, task: Task) -> Self {
+        let now = current_timestamp();
+        Self {
+            execution_id,
+            task,
+            state: TaskState::Pending,
+            worker_id: None,
+            claimed_by: None,
+            created_at: now,
+            updated_at: now,
+            lease_expires_at: None,
+            output: None,
+            error: None,
+            retries: 0,
+            attempts: Vec::new(),
+        }
+    }
+
+    /// Whether the execution reached a final state.
+    pub fn is_finished(&self) -> bool {
+        !matches!(self.state, TaskState::Pending | TaskState::Running)
+    }
+
+    /// Whether the execution is running under a lease that ran out before `now`.
+    pub fn lease_expired(&self, now: i64) -> bool {
+        self.state == TaskState::Running && self.lease_expires_at.is_none_or(|expires_at| expires_at < now)
+    }
+
+    /// Builds a pending execution out of an incoming `ExecuteRequest`.
+    pub fn from_request(request: ExecuteRequest) -> Result<Self, DataStoreError> {
+        match request.task {
+            Some(task) => Ok(Self::new(request.execution_id, task)),
+            None => Err(DataStoreError::InvalidArgument("Task is missing in ExecuteRequest".to_string())),
+        }
+    }
+
+    /// The name of the executor that runs this execution.
+    pub fn task_type(&self) -> &str {
+        self.task.executor_name()
+    }
+
+    pub fn to_execute_request(&self) -> ExecuteRequest {
+        ExecuteRequest {
+            task: Some(self.task.clone()),
+            execution_id: self.execution_id.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// The current attempt, failed on `worker_id` with `error`.
+    pub fn failed_attempt(&self, worker_id: Option<&str>, error: Option<TaskError>) -> ExecutionAttempt {
+        ExecutionAttempt {
+            attempt: self.retries + 1,
+            worker_id: worker_id.or(self.worker_id.as_deref()).unwrap_or_default().to_string(),
+            failed_at: current_timestamp(),
+            error,
+        }
+    }
+
+    /// The dead letter of the execution once its `last` attempt failed for good.
+    pub fn to_dead_letter(&self, last: ExecutionAttempt) -> DeadLetter {
+        DeadLetter {
+            execution_id: self.execution_id.clone(),
+            task: Some(self.task.clone()),
+            error: last.error.clone(),
+            attempts: self.attempts.iter().cloned().chain(Some(last)).collect(),
+            dead_lettered_at: current_timestamp(),
+        }
+    }
+
+    /// The completion of the execution as it stands, meant for finished executions.
+    pub fn to_completion(&self) -> TaskCompletion {
+        TaskCompletion {
+            task_id: self.task.id.clone(),
+            state: self.state.into(),
+            execution_id: self.execution_id.clone(),
+            output: self.output.clone(),
+            error: self.error.clone(),
+        }
+    }
+
+    pub fn to_execute_response(&self) -> ExecuteResponse {
+        ExecuteResponse {
+            task_id: self.task.id.clone(),
+            state: self.state.into(),
+            execution_id: self.execution_id.clone(),
+            output: self.output.clone(),
+            error: self.error.clone(),
+        }
+    }
+}
+
+/// Selects executions by their attributes, unset fields match everything.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ExecutionFilter {
+    /// Match any of the given states.
+    pub states: Vec<TaskState>,
+    pub task_type: Option<String>,
+    pub worker_id: Option<String>,
+    /// Inclusive lower bound on `created_at`.
+    pub created_after: Option<i64>,
+    /// Exclusive upper bound on `created_at`.
+    pub created_before: Option<i64>,
+    /// Exclusive upper bound on `updated_at`.
+    pub updated_before: Option<i64>,
+}
+
+impl ExecutionFilter {
+    pub fn with_state(mut self, state: TaskState) -> Self {
+        self.states.push(state);
+        self
+    }
+
+    pub fn with_task_type(mut self, task_type: &str) -> Self {
+        self.task_type = Some(task_type.to_string());
+        self
+    }
+
+    pub fn with_worker(mut self, worker_id: &str) -> Self {
+        self.worker_id = Some(worker_id.to_string());
+        self
+    }
+
+    pub fn created_between(mut self, from: Option<i64>, to: Option<i64>) -> Self {
+        self.created_after = from;
+        self.created_before = to;
+        self
+    }
+
+    pub fn updated_before(mut self, to: i64) -> Self {
+        self.updated_before = Some(to);
+        self
+    }
+
+    /// Whether the filter matches every execution.
+    pub fn is_empty(&self) -> bool {
+        self == &Self::default()
+    }
+
+    pub fn matches(&self, record: &ExecutionRecord) -> bool {
+        (self.states.is_empty() || self.states.contains(&record.state))
+            && self.task_type.as_deref().is_none_or(|t| t == record.task_type())
+            && self.worker_id.as_deref().is_none_or(|w| Some(w) == record.worker_id.as_deref())
+            && self.created_after.is_none_or(|from| record.created_at >= from)
+            && self.created_before.is_none_or(|to| record.created_at < to)
+            && self.updated_before.is_none_or(|to| record.updated_at < to)
+    }
+}
+
+impl TryFrom<&v1::ExecutionFilter> for ExecutionFilter {
+    type Error = DataStoreError;
+
+    fn try_from(filter: &v1::ExecutionFilter) -> Result<Self, Self::Error> {
+        let non_empty = |value: &str| Some(value.to_string()).filter(|value| !value.is_empty());
+        let non_zero = |value: i64| Some(value).filter(|value| *value != 0);
+
+        Ok(Self {
+            states: filter.states
+                .iter()
+                .map(|state| TaskState::from_i32(*state)
+                    .ok_or_else(|| DataStoreError::InvalidArgument(format!("Unknown task state: {}", state))))
+                .collect::<Result<_, _>>()?,
+            task_type: non_empty(&filter.task_type),
+            worker_id: non_empty(&filter.worker_id),
+            created_after: non_zero(filter.created_after),
+            created_before: non_zero(filter.created_before),
+            updated_before: non_zero(filter.updated_before),
+        })
+    }
+}
+
+/// Requests a page of results, ordered by submission time.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PageRequest {
+    /// Opaque cursor returned as `Page::next_cursor` by the previous page.
+    pub cursor: Option<String>,
+    pub limit: usize,
+}
+
+impl Default for PageRequest {
+    fn default() -> Self {
+        Self {
+            cursor: None,
+            limit: DEFAULT_PAGE_LIMIT,
+        }
+    }
+}
+
+impl PageRequest {
+    pub fn first(limit: usize) -> Self {
+        Self { cursor: None, limit }
+    }
+
+    pub fn after(cursor: String, limit: usize) -> Self {
+        Self { cursor: Some(cursor), limit }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Set when more results are available.
+    pub next_cursor: Option<String>,
+}
+
+/// Position of an execution in the `(created_at, execution_id)` ordering shared by all stores.
+///
+/// Dead letters are ordered the same way by `dead_lettered_at`.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct Cursor {
+    pub created_at: i64,
+    pub execution_id: String,
+}
+
+impl Cursor {
+    pub fn of(record: &ExecutionRecord) -> Self {
+        Self {
+            created_at: record.created_at,
+            execution_id: record.execution_id.clone(),
+        }
+    }
+
+    pub fn encode(&self) -> String {
+        format!("{}:{}", self.created_at, self.execution_id)
+    }
+
+    pub fn decode(cursor: &str) -> Result<Self, DataStoreError> {
+        cursor
+            .split_once(':')
+            .and_then(|(created_at, execution_id)| {
+                created_at.parse().ok().map(|created_at| Self {
+                    created_at,
+                    execution_id: execution_id.to_string(),
+                })
+            })
+            .ok_or_else(|| DataStoreError::InvalidArgument(format!("Invalid page cursor: {}", cursor)))
+    }
+}
+
+/// Items listed page by page, in the order of their `Cursor`.
+pub(crate) trait Paged {
+    fn cursor(&self) -> Cursor;
+}
+
+impl Paged for ExecutionRecord {
+    fn cursor(&self) -> Cursor {
+        Cursor::of(self)
+    }
+}
+
+impl Paged for DeadLetter {
+    fn cursor(&self) -> Cursor {
+        Cursor {
+            created_at: self.dead_lettered_at,
+            execution_id: self.execution_id.clone(),
+        }
+    }
+}
+
+/// Pages through `records`, which must already be filtered and ordered by their `Cursor`.
+pub(crate) fn paginate<T: Paged>(records: Vec<T>, page: &PageRequest) -> Result<Page<T>, DataStoreError> {
+    let after = page.cursor.as_deref().map(Cursor::decode).transpose()?;
+    let limit = page.limit.max(1);
+
+    let mut items: Vec<T> = records
+        .into_iter()
+        .filter(|record| after.as_ref().is_none_or(|after| &record.cursor() > after))
+        .take(limit + 1)
+        .collect();
+
+    let next_cursor = if items.len() > limit {
+        items.truncate(limit);
+        items.last().map(|record| record.cursor().encode())
+    } else {
+        None
+    };
+
+    Ok(Page { items, next_cursor })
+}
+
+/// The scheduler that currently leads, as recorded by `DataStore::acquire_leader_lease`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LeaderLease {
+    /// Unique id of the leading scheduler.
+    pub holder: String,
+    /// Address workers use to reach the leader, may be empty.
+    pub address: String,
+    /// Fencing token, incremented every time the lease changes hands.
+    pub token: u64,
+}
+
+/// Token bucket of a rate limit, refilled at `rate` tokens per second up to `capacity` tokens.
+///
+/// A bucket without recorded state is full.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TokenBucket {
+    pub capacity: f64,
+    pub rate: f64,
+}
+
+impl TokenBucket {
+    /// Takes one token from a bucket that held `tokens` (full when `None`) `elapsed_ms` ago.
+    ///
+    /// Returns the tokens left afterwards, and how long until a token is available when the
+    /// bucket is empty, in which case nothing is taken.
+    pub fn take(&self, tokens: Option<f64>, elapsed_ms: i64) -> (f64, Option<Duration>) {
+        let refilled = match tokens {
+            Some(tokens) => (tokens + self.rate * elapsed_ms.max(0) as f64 / 1000.0).min(self.capacity),
+            None => self.capacity,
+        };
+        if refilled >= 1.0 {
+            (refilled - 1.0, None)
+        } else {
+            (refilled, Some(Duration::from_secs_f64((1.0 - refilled) / self.rate)))
+        }
+    }
+
+    /// How long an untouched bucket takes to fill up again, after which its state can be dropped.
+    pub fn refill_time(&self) -> Duration {
+        Duration::from_secs_f64(self.capacity / self.rate)
+    }
 }
 
 #[async_trait]
 pub trait DataStore: Send + Sync + 'static {
-    async fn add_task_execution(&self, execute: ExecuteRequest) -> Result<(), SchedulerError>;
-    async fn get_task_executions_by_worker(&self, state: TaskState) -> Result<Vec<ExecuteRequest>, SchedulerError>;
-    async fn get_tasks_executions_by_state(&self, state: TaskState) -> Result<Vec<ExecuteRequest>, SchedulerError>;
-    async fn update_task_execution_state(&self, task_id: &str, execution_id: &str, new_state: &TaskState) -> Result<(), SchedulerError>;
+    /// Stores a new execution, fails with `DataStoreError::Duplicate` if the execution id is taken.
+    async fn add_task_execution(&self, record: ExecutionRecord) -> Result<(), DataStoreError>;
+    async fn get_task_execution(&self, execution_id: &str) -> Result<ExecutionRecord, DataStoreError>;
+    /// Lists the executions matching `filter`, oldest first.
+    async fn list_task_executions(&self, filter: &ExecutionFilter, page: &PageRequest) -> Result<Page<ExecutionRecord>, DataStoreError>;
+    /// Number of executions matching `filter` per state, states without executions are omitted.
+    async fn count_task_executions_by_state(&self, filter: &ExecutionFilter) -> Result<HashMap<TaskState, u64>, DataStoreError>;
+    /// Moves the execution to `new_state`, assigning it to `worker_id` when given. Releases the lease if any.
+    async fn update_task_execution_state(&self, execution_id: &str, new_state: TaskState, worker_id: Option<&str>) -> Result<(), DataStoreError>;
+    /// Moves the execution to the final state of `completion` and stores its output or error,
+    /// assigning it to `worker_id` when given. Releases the lease if any.
+    ///
+    /// A completion reported by `worker_id` is only recorded while the execution runs on it,
+    /// it fails with `LeaseLost` once the lease went to another worker or back to the queue.
+    async fn complete_task_execution(&self, completion: &TaskCompletion, worker_id: Option<&str>) -> Result<(), DataStoreError>;
+    /// Moves a failed execution back to `Pending` so it is attempted again, appending `attempt` to
+    /// its attempts and keeping its error as the error of the execution. Releases the lease if any
+    /// and returns the new retries count.
+    async fn retry_task_execution(&self, execution_id: &str, attempt: &ExecutionAttempt) -> Result<u32, DataStoreError>;
+    /// Moves the execution to `Running` on `worker_id`, which owns it until `lease_expires_at` unless renewed.
+    ///
+    /// With a `fencing_token`, the lease is only recorded while it is the token of the current
+    /// leader lease, it fails with `Fenced` once another scheduler took the leadership over.
+    async fn lease_task_execution(&self, execution_id: &str, worker_id: &str, lease_expires_at: i64, fencing_token: Option<u64>) -> Result<(), DataStoreError>;
+    /// Moves a running execution back to `Pending` and releases its lease, for the leader holding
+    /// `fencing_token`. Fails with `Fenced` once another scheduler took the leadership over.
+    async fn requeue_task_execution(&self, execution_id: &str, fencing_token: u64) -> Result<(), DataStoreError>;
+    /// Extends the lease of every running execution of `worker_id`, returns how many were renewed.
+    async fn renew_task_execution_leases(&self, worker_id: &str, lease_expires_at: i64) -> Result<u64, DataStoreError>;
+    /// Atomically takes ownership of up to `limit` pending executions (oldest first) on behalf
+    /// of `owner`, among the unclaimed ones and the ones a former owner claimed. Concurrent
+    /// claims never return the same execution, so a leader taking over can't race a stale one.
+    async fn claim_task_executions(&self, owner: &str, limit: usize) -> Result<Vec<ExecutionRecord>, DataStoreError>;
+    /// Deletes the executions matching `filter`, returns how many were deleted.
+    async fn purge_task_executions(&self, filter: &ExecutionFilter) -> Result<u64, DataStoreError>;
+    /// Atomically records `key` as submitted by `execution_id` for `window`, unless a submission
+    /// within the window already holds it. Returns the execution id holding the key afterwards,
+    /// which is another execution's for a duplicate submission.
+    async fn claim_idempotency_key(&self, key: &str, execution_id: &str, window: Duration) -> Result<String, DataStoreError>;
+    /// Forgets `key` if `execution_id` holds it, e.g. when its submission failed.
+    async fn release_idempotency_key(&self, key: &str, execution_id: &str) -> Result<(), DataStoreError>;
+    /// Atomically takes a token from every rate limit bucket of `buckets` by key, shared by every
+    /// scheduler using the store. Nothing is taken when one of them is empty, the position of the
+    /// first empty bucket is returned along with how long until it has a token.
+    async fn take_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<Option<(usize, Duration)>, DataStoreError>;
+    /// Puts back a token taken by `take_rate_limit_tokens` into every bucket of `buckets`, e.g.
+    /// when the execution that took them didn't start after all. Buckets never overflow their capacity.
+    async fn return_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<(), DataStoreError>;
+    /// Atomically takes one of the `limit` slots of the concurrency key `key` for `execution_id`,
+    /// unless `limit` executions hold one already. Returns whether `execution_id` holds a slot
+    /// afterwards, taking a slot it already holds succeeds.
+    async fn acquire_concurrency_slot(&self, key: &str, execution_id: &str, limit: u32) -> Result<bool, DataStoreError>;
+    /// Gives the slot of `execution_id` back, if it holds one.
+    async fn release_concurrency_slot(&self, key: &str, execution_id: &str) -> Result<(), DataStoreError>;
+    /// Stores the dead letter of an execution that failed for good, replacing any previous one.
+    async fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), DataStoreError>;
+    async fn get_dead_letter(&self, execution_id: &str) -> Result<DeadLetter, DataStoreError>;
+    /// Lists the dead letters of `task_type` (all of them when `None`), oldest first.
+    async fn list_dead_letters(&self, task_type: Option<&str>, page: &PageRequest) -> Result<Page<DeadLetter>, DataStoreError>;
+    /// Atomically deletes and returns a dead letter, so it is requeued once at most.
+    async fn take_dead_letter(&self, execution_id: &str) -> Result<DeadLetter, DataStoreError>;
+    /// Deletes the dead letters of `task_type` (all of them when `None`), returns how many were deleted.
+    async fn purge_dead_letters(&self, task_type: Option<&str>) -> Result<u64, DataStoreError>;
+    /// Stores a new workflow, fails with `DataStoreError::Duplicate` if the workflow id is taken.
+    async fn add_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError>;
+    async fn get_workflow(&self, workflow_id: &str) -> Result<WorkflowStatus, DataStoreError>;
+    /// Replaces the stored state of an existing workflow.
+    async fn update_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError>;
+    /// Lists the workflows in `state`, oldest first.
+    async fn list_workflows(&self, state: WorkflowState) -> Result<Vec<WorkflowStatus>, DataStoreError>;
+
+    /// Takes the leader lease for `holder` for `ttl` if it is free or expired, or extends it if
+    /// `holder` already leads. Returns the lease as it stands afterwards, which belongs to
+    /// another scheduler when `holder` lost the election.
+    ///
+    /// Stores that can only be opened by one process grant the lease to every caller.
+    async fn acquire_leader_lease(&self, holder: &str, address: &str, _ttl: Duration) -> Result<LeaderLease, DataStoreError> {
+        Ok(LeaderLease { holder: holder.to_string(), address: address.to_string(), token: 1 })
+    }
+
+    /// Gives up the leader lease if `holder` owns it, so a standby can take over right away.
+    async fn release_leader_lease(&self, _holder: &str) -> Result<(), DataStoreError> {
+        Ok(())
+    }
+
+    /// Whether the store expires finished executions by itself according to its `RetentionPolicy`,
+    /// stores that don't are swept periodically instead.
+    fn expires_executions(&self) -> bool {
+        false
+    }
+
+    /// Walks every page of `list_task_executions`.
+    async fn list_all_task_executions(&self, filter: &ExecutionFilter) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+        let mut records = Vec::new();
+        let mut page = PageRequest::default();
+        loop {
+            let result = self.list_task_executions(filter, &page).await?;
+            records.extend(result.items);
+            match result.next_cursor {
+                Some(cursor) => page = PageRequest::after(cursor, page.limit),
+                None => return Ok(records),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record(execution_id: &str, created_at: i64) -> ExecutionRecord {
+        ExecutionRecord {
+            created_at,
+            ..ExecutionRecord::new(execution_id.to_string(), Task::default())
+        }
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        let cursor = Cursor { created_at: 42, execution_id: "a:b".to_string() };
+        assert_eq!(Cursor::decode(&cursor.encode()).unwrap(), cursor);
+        assert!(Cursor::decode("not-a-cursor").is_err());
+    }
+
+    #[test]
+    fn test_paginate() {
+        let records = vec![record("a", 1), record("b", 1), record("c", 2), record("d", 3)];
+
+        let first = paginate(records.clone(), &PageRequest::first(3)).unwrap();
+        assert_eq!(first.items.len(), 3);
+        let cursor = first.next_cursor.expect("more pages");
+
+        let second = paginate(records, &PageRequest::after(cursor, 3)).unwrap();
+        assert_eq!(second.items.iter().map(|r| r.execution_id.as_str()).collect::<Vec<_>>(), vec!["d"]);
+        assert_eq!(second.next_cursor, None);
+    }
+
+    #[test]
+    fn test_dead_letter_keeps_attempts() {
+        let mut r = record("a", 10);
+        r.worker_id = Some("worker-1".to_string());
+        let first = r.failed_attempt(None, Some(TaskError { code: "TIMEOUT".to_string(), ..Default::default() }));
+        assert_eq!((first.attempt, first.worker_id.as_str()), (1, "worker-1"));
+        r.attempts.push(first.clone());
+        r.retries = 1;
+
+        let last = r.failed_attempt(Some("worker-2"), Some(TaskError { code: "INVALID_PAYLOAD".to_string(), ..Default::default() }));
+        let dead_letter = r.to_dead_letter(last.clone());
+        assert_eq!(dead_letter.attempts, vec![first, last.clone()]);
+        assert_eq!(dead_letter.error, last.error);
+        assert_eq!(dead_letter.attempts[1].attempt, 2);
+    }
+
+    #[test]
+    fn test_filter_matches() {
+        let mut r = record("a", 10);
+        r.worker_id = Some("worker-1".to_string());
+
+        assert!(ExecutionFilter::default().matches(&r));
+        assert!(ExecutionFilter::default().with_state(TaskState::Pending).with_worker("worker-1").matches(&r));
+        assert!(!ExecutionFilter::default().with_state(TaskState::Success).matches(&r));
+        assert!(!ExecutionFilter::default().with_worker("worker-2").matches(&r));
+        assert!(!ExecutionFilter::default().created_between(Some(11), None).matches(&r));
+        assert!(ExecutionFilter::default().created_between(Some(10), Some(11)).matches(&r));
+        assert!(!ExecutionFilter::default().updated_before(r.updated_at).matches(&r));
+    }
+
+    #[test]
+    fn test_lease_expired() {
+        let mut r = record("a", 10);
+        assert!(!r.lease_expired(100));
+
+        r.state = TaskState::Running;
+        r.lease_expires_at = Some(50);
+        assert!(!r.lease_expired(50));
+        assert!(r.lease_expired(51));
+    }
+
+    #[test]
+    fn test_task_type_keeps_task_id() {
+        let task = Task { id: "order-42".to_string(), task_type: "resize".to_string(), ..Default::default() };
+        let r = ExecutionRecord::new("a".to_string(), task);
+        assert_eq!(r.task_type(), "resize");
+        assert_eq!(r.to_execute_response().task_id, "order-42");
+        assert!(ExecutionFilter::default().with_task_type("resize").matches(&r));
+
+        // Tasks stored before `task_type` existed named their executor in `id`
+        let legacy = ExecutionRecord::new("b".to_string(), Task { id: "resize".to_string(), ..Default::default() });
+        assert_eq!(legacy.task_type(), "resize");
+    }
+
+    #[test]
+    fn test_filter_from_proto() {
+        let filter = ExecutionFilter::try_from(&v1::ExecutionFilter {
+            states: vec![TaskState::Success.into()],
+            task_type: "task-1".to_string(),
+            updated_before: 100,
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(filter, ExecutionFilter::default().with_state(TaskState::Success).with_task_type("task-1").updated_before(100));
+
+        assert!(ExecutionFilter::try_from(&v1::ExecutionFilter::default()).unwrap().is_empty());
+        assert!(ExecutionFilter::try_from(&v1::ExecutionFilter { states: vec![42], ..Default::default() }).is_err());
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        // 1 token per second, bursts of 2
+        let bucket = TokenBucket { capacity: 2.0, rate: 1.0 };
+        assert_eq!(bucket.take(None, 0), (1.0, None));
+        assert_eq!(bucket.take(Some(1.0), 0), (0.0, None));
+
+        let (tokens, wait) = bucket.take(Some(0.0), 250);
+        assert_eq!(tokens, 0.25);
+        assert_eq!(wait, Some(Duration::from_millis(750)));
+
+        // Idle buckets don't fill past their capacity
+        assert_eq!(bucket.take(Some(0.0), 60_000), (1.0, None));
+        assert_eq!(bucket.refill_time(), Duration::from_secs(2));
+    }
 }
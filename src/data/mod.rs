@@ -1,4 +1,20 @@
 mod data_store;
+mod queue;
+mod redis_queue;
 mod redis_store;
+mod retention;
+mod wal_store;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
+#[cfg(feature = "postgres")]
+mod postgres_store;
+pub use queue::{InMemoryTaskQueue, QueuedTask, TaskQueue};
+pub use redis_queue::{RedisStreamsTaskQueue, DEFAULT_CLAIM_IDLE};
 pub use redis_store::RedisDataStore;
-pub use data_store::DataStore;
\ No newline at end of file
+pub use wal_store::WalDataStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::SqliteDataStore;
+#[cfg(feature = "postgres")]
+pub use postgres_store::PostgresDataStore;
+pub use data_store::{DataStore, DataStoreError, ExecutionFilter, ExecutionRecord, LeaderLease, Page, PageRequest, TokenBucket, DEFAULT_IDEMPOTENCY_WINDOW, DEFAULT_PAGE_LIMIT};
+pub use retention::{spawn_retention_sweeper, RetentionPolicy, DEFAULT_SWEEP_INTERVAL};
\ No newline at end of file
@@ -0,0 +1,201 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use tokio::sync::Mutex;
+
+use crate::{core::job_queue::{Position, PriorityHeap, DEFAULT_AGING_INTERVAL}, internal::protot::scheduler::v1::AssignTaskRequest};
+
+use super::data_store::DataStoreError;
+
+/// An execution taken from a `TaskQueue`.
+///
+/// It stays owned by the consumer that reserved it until it is acked or released.
+#[derive(Debug, Clone, PartialEq)]
+pub struct QueuedTask {
+    /// Id of the queue entry, e.g. the stream entry id for Redis Streams.
+    pub entry_id: String,
+    pub request: AssignTaskRequest,
+}
+
+/// Executions waiting for a worker, highest task priority first and oldest first among equals.
+#[async_trait]
+pub trait TaskQueue: Send + Sync + 'static {
+    /// Queues the execution behind the ones already queued with the same priority.
+    async fn push(&self, request: AssignTaskRequest) -> Result<(), DataStoreError>;
+
+    /// Takes the next execution, `None` when the queue is empty.
+    async fn reserve(&self) -> Result<Option<QueuedTask>, DataStoreError>;
+
+    /// Removes a reserved execution for good once it is assigned to a worker.
+    async fn ack(&self, task: &QueuedTask) -> Result<(), DataStoreError>;
+
+    /// Gives back a reserved execution that could not be assigned.
+    async fn release(&self, task: QueuedTask) -> Result<(), DataStoreError>;
+
+    /// Number of queued executions, including reserved ones that are not acked yet.
+    async fn len(&self) -> Result<usize, DataStoreError>;
+
+    /// Whether no execution is queued.
+    async fn is_empty(&self) -> Result<bool, DataStoreError> {
+        Ok(self.len().await? == 0)
+    }
+
+    /// Whether queued executions survive a scheduler restart.
+    ///
+    /// Queues that do not are rebuilt from the data store on startup.
+    fn is_durable(&self) -> bool {
+        false
+    }
+}
+
+/// `TaskQueue` kept in the scheduler memory.
+///
+/// Queued executions age like the local pool jobs, so low priority ones are not
+/// starved. Released executions are put back where they were in line.
+pub struct InMemoryTaskQueue {
+    entries: Mutex<Entries>,
+}
+
+struct Entries {
+    queued: PriorityHeap<AssignTaskRequest>,
+    /// Positions of the reserved executions, by entry id.
+    reserved: HashMap<String, Position>,
+}
+
+impl Default for InMemoryTaskQueue {
+    fn default() -> Self {
+        Self::with_aging_interval(DEFAULT_AGING_INTERVAL)
+    }
+}
+
+impl InMemoryTaskQueue {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// How long a queued execution waits to gain one priority level, zero disables aging.
+    pub fn with_aging_interval(aging_interval: Duration) -> Self {
+        Self {
+            entries: Mutex::new(Entries {
+                queued: PriorityHeap::new(aging_interval),
+                reserved: HashMap::new(),
+            }),
+        }
+    }
+}
+
+#[async_trait]
+impl TaskQueue for InMemoryTaskQueue {
+    async fn push(&self, request: AssignTaskRequest) -> Result<(), DataStoreError> {
+        let priority = request.task.as_ref().map_or(0, |task| task.priority);
+        self.entries.lock().await.queued.push(request, priority);
+        Ok(())
+    }
+
+    async fn reserve(&self) -> Result<Option<QueuedTask>, DataStoreError> {
+        let mut entries = self.entries.lock().await;
+        Ok(entries.queued.pop().map(|(request, position)| {
+            entries.reserved.insert(request.execution_id.clone(), position);
+            QueuedTask {
+                entry_id: request.execution_id.clone(),
+                request,
+            }
+        }))
+    }
+
+    async fn ack(&self, task: &QueuedTask) -> Result<(), DataStoreError> {
+        self.entries.lock().await.reserved.remove(&task.entry_id);
+        Ok(())
+    }
+
+    async fn release(&self, task: QueuedTask) -> Result<(), DataStoreError> {
+        let mut entries = self.entries.lock().await;
+        match entries.reserved.remove(&task.entry_id) {
+            Some(position) => entries.queued.push_at(task.request, position),
+            None => {
+                let priority = task.request.task.as_ref().map_or(0, |task| task.priority);
+                entries.queued.push(task.request, priority);
+            }
+        }
+        Ok(())
+    }
+
+    async fn len(&self) -> Result<usize, DataStoreError> {
+        let entries = self.entries.lock().await;
+        Ok(entries.queued.len() + entries.reserved.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::protot::core::Task;
+
+    use super::*;
+
+    fn request(execution_id: &str) -> AssignTaskRequest {
+        AssignTaskRequest { task: None, execution_id: execution_id.to_string() }
+    }
+
+    fn prioritized(execution_id: &str, priority: i32) -> AssignTaskRequest {
+        AssignTaskRequest {
+            task: Some(Task { priority, ..Default::default() }),
+            execution_id: execution_id.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_queue_order() {
+        let queue = InMemoryTaskQueue::new();
+        for execution_id in ["first", "second", "third"] {
+            queue.push(request(execution_id)).await.unwrap();
+        }
+
+        let first = queue.reserve().await.unwrap().unwrap();
+        assert_eq!(first.request.execution_id, "first");
+        let second = queue.reserve().await.unwrap().unwrap();
+        queue.ack(&second).await.unwrap();
+
+        // A released execution keeps its place in line
+        queue.release(first).await.unwrap();
+        assert_eq!(queue.len().await.unwrap(), 2);
+        assert_eq!(queue.reserve().await.unwrap().unwrap().request.execution_id, "first");
+        assert_eq!(queue.reserve().await.unwrap().unwrap().request.execution_id, "third");
+        assert!(queue.reserve().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_in_memory_queue_priority() {
+        let queue = InMemoryTaskQueue::with_aging_interval(Duration::ZERO);
+        queue.push(prioritized("bulk-1", 0)).await.unwrap();
+        queue.push(prioritized("bulk-2", 0)).await.unwrap();
+        queue.push(prioritized("urgent", 5)).await.unwrap();
+        queue.push(prioritized("low", -1)).await.unwrap();
+
+        let urgent = queue.reserve().await.unwrap().unwrap();
+        assert_eq!(urgent.request.execution_id, "urgent");
+        let bulk = queue.reserve().await.unwrap().unwrap();
+        assert_eq!(bulk.request.execution_id, "bulk-1");
+
+        // Released, the urgent execution still goes before the bulk ones
+        queue.release(urgent).await.unwrap();
+        queue.ack(&bulk).await.unwrap();
+        assert_eq!(queue.len().await.unwrap(), 3);
+        for expected in ["urgent", "bulk-2", "low"] {
+            assert_eq!(queue.reserve().await.unwrap().unwrap().request.execution_id, expected);
+        }
+    }
+}
@@ -0,0 +1,368 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, error};
+use prost::Message;
+use redis::{aio::ConnectionManager, Client, RedisError, Value};
+use uuid::Uuid;
+
+use crate::{core::queues::DEFAULT_QUEUE, internal::protot::scheduler::v1::AssignTaskRequest, SchedulerError};
+
+use super::{data_store::DataStoreError, queue::{QueuedTask, TaskQueue}};
+
+/// Stream holding the queued executions with the default priority.
+const QUEUE_STREAM_KEY: &str = "executions:stream";
+/// Stream holding the queued executions with a positive priority.
+const HIGH_PRIORITY_STREAM_KEY: &str = "executions:stream:high";
+/// Stream holding the queued executions with a negative priority.
+const LOW_PRIORITY_STREAM_KEY: &str = "executions:stream:low";
+/// Consumer group shared by all schedulers reading the stream.
+const QUEUE_GROUP: &str = "schedulers";
+/// Entry field holding the encoded `AssignTaskRequest`.
+const EXECUTION_FIELD: &str = "execution";
+
+/// How long an entry can stay reserved before another consumer takes it over.
+pub const DEFAULT_CLAIM_IDLE: Duration = Duration::from_secs(30);
+
+/// `TaskQueue` backed by a Redis Stream and a consumer group.
+///
+/// Executions are appended with `XADD` and read with `XREADGROUP`, which keeps every
+/// reserved entry in the group's pending entries list until it is acked. Entries left
+/// pending by a consumer that went away (e.g. a crashed scheduler) are taken over with
+/// `XAUTOCLAIM` once they have been idle for `claim_idle`, so nothing queued is lost.
+///
+/// Acked entries are deleted from the stream, released ones are appended again.
+///
+/// Streams are FIFO, so executions are spread over three streams by the sign of their
+/// priority, and an execution is only taken from a stream when the higher priority ones
+/// are empty. Priorities are not finer grained than that and queued executions don't age.
+///
+/// Every named queue has streams of its own, the default queue keeps the `executions:stream` ones.
+pub struct RedisStreamsTaskQueue {
+    con: ConnectionManager,
+    consumer: String,
+    claim_idle: Duration,
+    /// Streams of the queue in the order they are read, highest priority first.
+    streams: [String; 3],
+}
+
+impl RedisStreamsTaskQueue {
+    /// The default queue.
+    pub async fn new(redis_url: &str) -> Result<Self, SchedulerError> {
+        Self::for_queue(redis_url, DEFAULT_QUEUE).await
+    }
+
+    /// The named queue `queue`.
+    pub async fn for_queue(redis_url: &str, queue: &str) -> Result<Self, SchedulerError> {
+        let cleaned_redis_host = redis_url.replace("\"", "");
+        let redis_host = cleaned_redis_host.clone();
+        let client = Client::open(cleaned_redis_host)
+            .map_err(|err| SchedulerError::DataLayerError(format!("Error when calling redis host: {} {:?}", redis_host, err)))?;
+
+        let mut con = ConnectionManager::new(client).await
+            .map_err(|_| SchedulerError::DataLayerError(format!("Unable to connect to redis host: {}", redis_host)))?;
+
+        let streams = stream_keys(queue);
+        for stream in &streams {
+            // Reading from id 0 hands the entries queued before the group existed to the group
+            let created: Result<(), RedisError> = redis::cmd("XGROUP")
+                .arg("CREATE")
+                .arg(stream)
+                .arg(QUEUE_GROUP)
+                .arg("0")
+                .arg("MKSTREAM")
+                .query_async(&mut con)
+                .await;
+            match created {
+                Err(err) if err.code() != Some("BUSYGROUP") => {
+                    return Err(SchedulerError::DataLayerError(format!("Unable to create redis consumer group: {:?}", err)))
+                }
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            con,
+            consumer: format!("scheduler-{}", Uuid::new_v4()),
+            claim_idle: DEFAULT_CLAIM_IDLE,
+            streams,
+        })
+    }
+
+    /// Name of this scheduler in the consumer group, unique per process by default.
+    pub fn with_consumer(mut self, consumer: impl Into<String>) -> Self {
+        self.consumer = consumer.into();
+        self
+    }
+
+    /// How long an entry can stay reserved by another consumer before it is taken over.
+    pub fn with_claim_idle(mut self, claim_idle: Duration) -> Self {
+        self.claim_idle = claim_idle;
+        self
+    }
+
+    /// Takes over the oldest entry left pending by a consumer for longer than `claim_idle`.
+    async fn autoclaim(&self, con: &mut ConnectionManager, stream: &str) -> Result<Vec<(String, Option<Vec<u8>>)>, DataStoreError> {
+        let reply: Value = redis::cmd("XAUTOCLAIM")
+            .arg(stream)
+            .arg(QUEUE_GROUP)
+            .arg(&self.consumer)
+            .arg(self.claim_idle.as_millis() as u64)
+            .arg("0-0")
+            .arg("COUNT")
+            .arg(1)
+            .query_async(con)
+            .await
+            .map_err(|err| internal_error("Failed to claim pending queue entries", err))?;
+
+        // [next start id, entries, deleted ids (Redis 7)]
+        match reply {
+            Value::Bulk(mut parts) if parts.len() >= 2 => Ok(parse_entries(parts.swap_remove(1))),
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Reads the next entry never delivered to any consumer of the group.
+    async fn read_new(&self, con: &mut ConnectionManager, stream: &str) -> Result<Vec<(String, Option<Vec<u8>>)>, DataStoreError> {
+        let reply: Value = redis::cmd("XREADGROUP")
+            .arg("GROUP")
+            .arg(QUEUE_GROUP)
+            .arg(&self.consumer)
+            .arg("COUNT")
+            .arg(1)
+            .arg("STREAMS")
+            .arg(stream)
+            .arg(">")
+            .query_async(con)
+            .await
+            .map_err(|err| internal_error("Failed to read queue entries", err))?;
+
+        // [[stream, entries]], nil when there is nothing new
+        let streams = match reply {
+            Value::Bulk(streams) => streams,
+            _ => return Ok(Vec::new()),
+        };
+        Ok(streams
+            .into_iter()
+            .filter_map(|stream| match stream {
+                Value::Bulk(mut parts) if parts.len() == 2 => Some(parse_entries(parts.swap_remove(1))),
+                _ => None,
+            })
+            .flatten()
+            .collect())
+    }
+
+    async fn remove(&self, con: &mut ConnectionManager, stream: &str, entry_id: &str) -> Result<(), DataStoreError> {
+        redis::pipe()
+            .atomic()
+            .cmd("XACK").arg(stream).arg(QUEUE_GROUP).arg(entry_id).ignore()
+            .cmd("XDEL").arg(stream).arg(entry_id).ignore()
+            .query_async::<_, ()>(con)
+            .await
+            .map_err(|err| internal_error("Failed to ack queue entry", err))
+    }
+}
+
+#[async_trait]
+impl TaskQueue for RedisStreamsTaskQueue {
+    async fn push(&self, request: AssignTaskRequest) -> Result<(), DataStoreError> {
+        let mut con = self.con.clone();
+        redis::cmd("XADD")
+            .arg(stream_key(&self.streams, &request))
+            .arg("*")
+            .arg(EXECUTION_FIELD)
+            .arg(request.encode_to_vec())
+            .query_async::<_, String>(&mut con)
+            .await
+            .map_err(|err| internal_error("Failed to queue task execution", err))?;
+        Ok(())
+    }
+
+    async fn reserve(&self) -> Result<Option<QueuedTask>, DataStoreError> {
+        let mut con = self.con.clone();
+        let mut streams = self.streams.iter();
+        let mut stream = streams.next();
+        while let Some(key) = stream {
+            let mut entries = self.autoclaim(&mut con, key).await?;
+            if entries.is_empty() {
+                entries = self.read_new(&mut con, key).await?;
+            }
+            let (entry_id, payload) = match entries.into_iter().next() {
+                Some(entry) => entry,
+                None => {
+                    stream = streams.next();
+                    continue;
+                }
+            };
+
+            match payload.map(|payload| AssignTaskRequest::decode(payload.as_slice())) {
+                Some(Ok(request)) => {
+                    debug!("reserved queue entry {} for task execution {}", entry_id, request.execution_id);
+                    return Ok(Some(QueuedTask { entry_id, request }));
+                }
+                // A malformed entry would be claimed again forever, so it is dropped
+                _ => {
+                    error!("dropping malformed queue entry {}", entry_id);
+                    self.remove(&mut con, key, &entry_id).await?;
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    async fn ack(&self, task: &QueuedTask) -> Result<(), DataStoreError> {
+        let mut con = self.con.clone();
+        self.remove(&mut con, stream_key(&self.streams, &task.request), &task.entry_id).await
+    }
+
+    async fn release(&self, task: QueuedTask) -> Result<(), DataStoreError> {
+        // Streams cannot put an entry back in front, so it is appended again in one transaction
+        let mut con = self.con.clone();
+        let stream = stream_key(&self.streams, &task.request);
+        redis::pipe()
+            .atomic()
+            .cmd("XADD").arg(stream).arg("*").arg(EXECUTION_FIELD).arg(task.request.encode_to_vec()).ignore()
+            .cmd("XACK").arg(stream).arg(QUEUE_GROUP).arg(&task.entry_id).ignore()
+            .cmd("XDEL").arg(stream).arg(&task.entry_id).ignore()
+            .query_async::<_, ()>(&mut con)
+            .await
+            .map_err(|err| internal_error("Failed to release queue entry", err))
+    }
+
+    async fn len(&self) -> Result<usize, DataStoreError> {
+        let mut con = self.con.clone();
+        let mut pipe = redis::pipe();
+        for stream in &self.streams {
+            pipe.cmd("XLEN").arg(stream);
+        }
+        let lengths: Vec<usize> = pipe
+            .query_async(&mut con)
+            .await
+            .map_err(|err| internal_error("Failed to count queue entries", err))?;
+        Ok(lengths.into_iter().sum())
+    }
+
+    fn is_durable(&self) -> bool {
+        true
+    }
+}
+
+/// Streams of a queue, highest priority first.
+fn stream_keys(queue: &str) -> [String; 3] {
+    if queue == DEFAULT_QUEUE {
+        return [HIGH_PRIORITY_STREAM_KEY, QUEUE_STREAM_KEY, LOW_PRIORITY_STREAM_KEY].map(String::from);
+    }
+    let stream = format!("queues:{}:stream", queue);
+    [format!("{}:high", stream), stream.clone(), format!("{}:low", stream)]
+}
+
+/// Stream of `streams` an execution is queued in, by the sign of its task priority.
+fn stream_key<'a>(streams: &'a [String; 3], request: &AssignTaskRequest) -> &'a str {
+    match request.task.as_ref().map_or(0, |task| task.priority) {
+        priority if priority > 0 => &streams[0],
+        priority if priority < 0 => &streams[2],
+        _ => &streams[1],
+    }
+}
+
+fn internal_error(context: &str, err: RedisError) -> DataStoreError {
+    DataStoreError::InternalError(format!("{}: {:?}", context, err))
+}
+
+/// Parses `[[id, [field, value, ...]], ...]` stream entries into their id and execution payload.
+///
+/// Entries deleted while pending come back as nil and have no payload.
+fn parse_entries(entries: Value) -> Vec<(String, Option<Vec<u8>>)> {
+    let entries = match entries {
+        Value::Bulk(entries) => entries,
+        _ => return Vec::new(),
+    };
+
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            let mut parts = match entry {
+                Value::Bulk(parts) if parts.len() == 2 => parts,
+                _ => return None,
+            };
+            let fields = parts.pop()?;
+            let entry_id: String = redis::from_redis_value(&parts.pop()?).ok()?;
+
+            let payload = match fields {
+                Value::Bulk(fields) => fields
+                    .chunks(2)
+                    .find(|pair| matches!(&pair[0], Value::Data(name) if name == EXECUTION_FIELD.as_bytes()))
+                    .and_then(|pair| match pair.get(1) {
+                        Some(Value::Data(payload)) => Some(payload.clone()),
+                        _ => None,
+                    }),
+                _ => None,
+            };
+            Some((entry_id, payload))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::protot::core::Task;
+
+    use super::*;
+
+    fn data(value: &[u8]) -> Value {
+        Value::Data(value.to_vec())
+    }
+
+    #[test]
+    fn test_parse_entries() {
+        let request = AssignTaskRequest { task: None, execution_id: "execution-1".to_string() };
+        let entries = Value::Bulk(vec![
+            Value::Bulk(vec![
+                data(b"1-0"),
+                Value::Bulk(vec![data(EXECUTION_FIELD.as_bytes()), data(&request.encode_to_vec())]),
+            ]),
+            // Deleted while pending
+            Value::Bulk(vec![data(b"2-0"), Value::Nil]),
+        ]);
+
+        let parsed = parse_entries(entries);
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].0, "1-0");
+        let decoded = AssignTaskRequest::decode(parsed[0].1.as_deref().unwrap()).unwrap();
+        assert_eq!(decoded, request);
+        assert_eq!(parsed[1], ("2-0".to_string(), None));
+        assert!(parse_entries(Value::Nil).is_empty());
+    }
+
+    #[test]
+    fn test_stream_key_by_priority() {
+        let request = |priority| AssignTaskRequest {
+            task: Some(Task { priority, ..Default::default() }),
+            execution_id: "execution-1".to_string(),
+        };
+        let streams = stream_keys(DEFAULT_QUEUE);
+        assert_eq!(stream_key(&streams, &request(3)), HIGH_PRIORITY_STREAM_KEY);
+        assert_eq!(stream_key(&streams, &request(0)), QUEUE_STREAM_KEY);
+        assert_eq!(stream_key(&streams, &request(-3)), LOW_PRIORITY_STREAM_KEY);
+        assert_eq!(stream_key(&streams, &AssignTaskRequest::default()), QUEUE_STREAM_KEY);
+
+        let streams = stream_keys("reports");
+        assert_eq!(stream_key(&streams, &request(3)), "queues:reports:stream:high");
+        assert_eq!(stream_key(&streams, &request(0)), "queues:reports:stream");
+        assert_eq!(stream_key(&streams, &request(-3)), "queues:reports:stream:low");
+    }
+}
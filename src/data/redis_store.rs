@@ -1,20 +1,350 @@
-use std::sync::Arc;
+use std::{collections::HashMap, time::Duration};
 use async_trait::async_trait;
 use log::debug;
-// use prost_types::Any;
-use protobuf::well_known_types::any::Any;
-use redis::{Client, RedisError, aio::Connection, RedisResult, AsyncCommands, ConnectionLike};
-use tokio::sync::Mutex;
-use crate::{internal::protot::{scheduler::v1::ExecuteRequest, core::{Task, TaskState}}, SchedulerError, utils::current_timestamp};
+use prost::Message;
+use prost_types::Any;
+use redis::{Client, RedisError, aio::ConnectionManager, AsyncCommands, Script};
+use crate::{internal::protot::{core::{ExecutionAttempt, ExecutionAttempts, Task, TaskError, TaskState, WorkflowState, WorkflowStatus}, scheduler::v1::{DeadLetter, TaskCompletion}}, SchedulerError, utils::current_timestamp};
 
-use super::data_store::{DataStore, DataStoreError};
+use super::{data_store::{Cursor, DataStore, DataStoreError, ExecutionFilter, ExecutionRecord, LeaderLease, Page, PageRequest, Paged, TokenBucket}, retention::RetentionPolicy};
 
-pub struct RedisClient {
-    connection: Connection,
-}
+/// Hash holding one execution, keyed by execution id.
+const EXECUTION_KEY_PREFIX: &str = "task:";
+/// Sorted set of all execution ids, scored by `created_at`.
+const EXECUTIONS_KEY: &str = "executions";
+/// Sorted sets of execution ids per state, scored by `created_at`.
+const EXECUTIONS_BY_STATE_KEY_PREFIX: &str = "executions:state:";
+
+/// Leader lease of the scheduler replicas, holds `{holder} {address}` and expires unless renewed.
+const LEADER_KEY: &str = "scheduler:leader";
+/// Fencing token of the leader lease, incremented on every new lease.
+const LEADER_TOKEN_KEY: &str = "scheduler:leader:token";
+
+/// Execution id holding an idempotency key, expires with the idempotency window.
+const IDEMPOTENCY_KEY_PREFIX: &str = "idempotency:";
+
+/// Token bucket of a rate limit, holds its `tokens` and when they were `updated_at`.
+const RATE_LIMIT_KEY_PREFIX: &str = "rate_limit:";
+
+/// Set of the executions holding a slot of a concurrency key.
+const CONCURRENCY_KEY_PREFIX: &str = "concurrency:";
+
+/// Hash holding the `dead_letter` of an execution and its `task_type`, keyed by execution id.
+const DEAD_LETTER_KEY_PREFIX: &str = "dead_letter:";
+/// Sorted set of all dead-lettered execution ids, scored by `dead_lettered_at`.
+const DEAD_LETTERS_KEY: &str = "dead_letters";
+/// Sorted sets of dead-lettered execution ids per task type, scored by `dead_lettered_at`.
+const DEAD_LETTERS_BY_TASK_TYPE_KEY_PREFIX: &str = "dead_letters:task_type:";
+
+/// Hash holding the encoded `workflow` status and its `state`, keyed by workflow id.
+const WORKFLOW_KEY_PREFIX: &str = "workflow:";
+/// Sorted sets of workflow ids per state, scored by `created_at`.
+const WORKFLOWS_BY_STATE_KEY_PREFIX: &str = "workflows:state:";
+
+/// Number of ids fetched per round trip while scanning an index.
+const SCAN_BATCH_SIZE: usize = 100;
+
+// KEYS: execution hash, all executions, executions of the state
+// ARGV: execution id, task, state, task type, worker id, claimed by, created at, updated at, ttl,
+//       lease expires at, output, error, retries, attempts
+const ADD_EXECUTION_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'task', ARGV[2], 'state', ARGV[3], 'task_type', ARGV[4],
+    'worker_id', ARGV[5], 'claimed_by', ARGV[6], 'created_at', ARGV[7], 'updated_at', ARGV[8])
+redis.call('ZADD', KEYS[2], ARGV[7], ARGV[1])
+redis.call('ZADD', KEYS[3], ARGV[7], ARGV[1])
+if tonumber(ARGV[9]) > 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[9])
+end
+if tonumber(ARGV[10]) > 0 then
+    redis.call('HSET', KEYS[1], 'lease_expires_at', ARGV[10])
+end
+if ARGV[11] ~= '' then
+    redis.call('HSET', KEYS[1], 'output', ARGV[11])
+end
+if ARGV[12] ~= '' then
+    redis.call('HSET', KEYS[1], 'error', ARGV[12])
+end
+if tonumber(ARGV[13]) > 0 then
+    redis.call('HSET', KEYS[1], 'retries', ARGV[13])
+end
+if ARGV[14] ~= '' then
+    redis.call('HSET', KEYS[1], 'attempts', ARGV[14])
+end
+return 1
+"#;
+
+// KEYS: execution hash
+// ARGV: encoded attempts holding the attempt to append
+// Concatenated protobuf messages merge their repeated fields, so the attempt is appended in place.
+const APPEND_ATTEMPT_SCRIPT: &str = r#"
+local attempts = redis.call('HGET', KEYS[1], 'attempts') or ''
+redis.call('HSET', KEYS[1], 'attempts', attempts .. ARGV[1])
+return redis.call('HINCRBY', KEYS[1], 'retries', 1)
+"#;
+
+// KEYS: execution hash, leader token
+// ARGV: execution id, new state, updated at, worker id, state key prefix, ttl, lease expires at, output, error,
+//       worker the execution must run on, running state, fencing token of the leader writing
+const UPDATE_STATE_SCRIPT: &str = r#"
+local old_state = redis.call('HGET', KEYS[1], 'state')
+if not old_state then
+    return 0
+end
+if ARGV[12] ~= '' and redis.call('GET', KEYS[2]) ~= ARGV[12] then
+    return -2
+end
+if ARGV[10] ~= '' and (old_state ~= ARGV[11] or redis.call('HGET', KEYS[1], 'worker_id') ~= ARGV[10]) then
+    return -1
+end
+local created_at = redis.call('HGET', KEYS[1], 'created_at')
+redis.call('HSET', KEYS[1], 'state', ARGV[2], 'updated_at', ARGV[3])
+if ARGV[4] ~= '' then
+    redis.call('HSET', KEYS[1], 'worker_id', ARGV[4])
+end
+if tonumber(ARGV[7]) > 0 then
+    redis.call('HSET', KEYS[1], 'lease_expires_at', ARGV[7])
+else
+    redis.call('HDEL', KEYS[1], 'lease_expires_at')
+end
+for index, name in ipairs({'output', 'error'}) do
+    if ARGV[7 + index] ~= '' then
+        redis.call('HSET', KEYS[1], name, ARGV[7 + index])
+    else
+        redis.call('HDEL', KEYS[1], name)
+    end
+end
+redis.call('ZREM', ARGV[5] .. old_state, ARGV[1])
+redis.call('ZADD', ARGV[5] .. ARGV[2], created_at, ARGV[1])
+if tonumber(ARGV[6]) > 0 then
+    redis.call('EXPIRE', KEYS[1], ARGV[6])
+else
+    redis.call('PERSIST', KEYS[1])
+end
+return 1
+"#;
+
+// KEYS: execution hashes
+// ARGV: lease expires at, worker id, running state
+const RENEW_LEASES_SCRIPT: &str = r#"
+local renewed = 0
+for _, key in ipairs(KEYS) do
+    local execution = redis.call('HMGET', key, 'state', 'worker_id')
+    if execution[1] == ARGV[3] and execution[2] == ARGV[2] then
+        redis.call('HSET', key, 'lease_expires_at', ARGV[1])
+        renewed = renewed + 1
+    end
+end
+return renewed
+"#;
+
+// KEYS: pending executions
+// ARGV: limit, owner, updated at, execution key prefix, scan batch size
+// Walks the pending executions oldest first, taking the ones `owner` doesn't hold yet.
+const CLAIM_SCRIPT: &str = r#"
+local limit, batch = tonumber(ARGV[1]), tonumber(ARGV[5])
+local claimed, offset = {}, 0
+while #claimed < limit do
+    local ids = redis.call('ZRANGE', KEYS[1], offset, offset + batch - 1)
+    if #ids == 0 then
+        break
+    end
+    for _, id in ipairs(ids) do
+        local key = ARGV[4] .. id
+        if #claimed < limit and redis.call('EXISTS', key) == 1 and redis.call('HGET', key, 'claimed_by') ~= ARGV[2] then
+            redis.call('HSET', key, 'claimed_by', ARGV[2], 'updated_at', ARGV[3])
+            table.insert(claimed, id)
+        end
+    end
+    offset = offset + #ids
+end
+return claimed
+"#;
+
+// KEYS: leader, leader token
+// ARGV: holder, address, ttl in milliseconds
+const ACQUIRE_LEADER_SCRIPT: &str = r#"
+local lease = ARGV[1] .. ' ' .. ARGV[2]
+local current = redis.call('GET', KEYS[1])
+if current == lease then
+    redis.call('PEXPIRE', KEYS[1], ARGV[3])
+    return {current, redis.call('GET', KEYS[2])}
+end
+if redis.call('SET', KEYS[1], lease, 'NX', 'PX', ARGV[3]) then
+    return {lease, tostring(redis.call('INCR', KEYS[2]))}
+end
+return {current, redis.call('GET', KEYS[2])}
+"#;
+
+// KEYS: leader
+// ARGV: holder
+const RELEASE_LEADER_SCRIPT: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if current and string.sub(current, 1, #ARGV[1] + 1) == ARGV[1] .. ' ' then
+    redis.call('DEL', KEYS[1])
+end
+return 1
+"#;
+
+// KEYS: idempotency key
+// ARGV: execution id, window in milliseconds
+const CLAIM_IDEMPOTENCY_KEY_SCRIPT: &str = r#"
+if redis.call('SET', KEYS[1], ARGV[1], 'NX', 'PX', ARGV[2]) then
+    return ARGV[1]
+end
+return redis.call('GET', KEYS[1])
+"#;
+
+// KEYS: idempotency key
+// ARGV: execution id
+const RELEASE_IDEMPOTENCY_KEY_SCRIPT: &str = r#"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    redis.call('DEL', KEYS[1])
+end
+return 1
+"#;
+
+// KEYS: rate limit buckets
+// ARGV: capacity, tokens per millisecond, for each bucket
+// Returns nothing when a token was taken from every bucket. Otherwise nothing is taken and it returns
+// the position of the first empty bucket, from 1, and the milliseconds until it has a token.
+// The server clock is shared by every scheduler, and full buckets expire as they hold nothing.
+const TAKE_RATE_LIMIT_TOKENS_SCRIPT: &str = r#"
+local time = redis.call('TIME')
+local now = tonumber(time[1]) * 1000 + math.floor(tonumber(time[2]) / 1000)
+local left = {}
+for i, key in ipairs(KEYS) do
+    local capacity, rate = tonumber(ARGV[2 * i - 1]), tonumber(ARGV[2 * i])
+    local bucket = redis.call('HMGET', key, 'tokens', 'updated_at')
+    local tokens = capacity
+    if bucket[1] then
+        tokens = math.min(capacity, tonumber(bucket[1]) + rate * math.max(0, now - tonumber(bucket[2])))
+    end
+    if tokens < 1 then
+        return {i, math.ceil((1 - tokens) / rate)}
+    end
+    left[i] = tokens - 1
+end
+for i, key in ipairs(KEYS) do
+    local capacity, rate = tonumber(ARGV[2 * i - 1]), tonumber(ARGV[2 * i])
+    redis.call('HSET', key, 'tokens', tostring(left[i]), 'updated_at', now)
+    redis.call('PEXPIRE', key, math.ceil(capacity / rate))
+end
+return {}
+"#;
+
+// KEYS: rate limit buckets
+// ARGV: capacity, for each bucket
+// Expired buckets are full already and stay gone.
+const RETURN_RATE_LIMIT_TOKENS_SCRIPT: &str = r#"
+for i, key in ipairs(KEYS) do
+    local tokens = redis.call('HGET', key, 'tokens')
+    if tokens then
+        redis.call('HSET', key, 'tokens', tostring(math.min(tonumber(ARGV[i]), tonumber(tokens) + 1)))
+    end
+end
+return {}
+"#;
+
+// KEYS: concurrency key set
+// ARGV: execution id, limit
+// Returns 1 when the execution holds a slot, taking one unless `limit` executions hold them already.
+const ACQUIRE_CONCURRENCY_SLOT_SCRIPT: &str = r#"
+if redis.call('SISMEMBER', KEYS[1], ARGV[1]) == 1 then
+    return 1
+end
+if redis.call('SCARD', KEYS[1]) >= tonumber(ARGV[2]) then
+    return 0
+end
+redis.call('SADD', KEYS[1], ARGV[1])
+return 1
+"#;
+
+// KEYS: dead letter hash, all dead letters
+// ARGV: execution id, task type, dead letter, dead lettered at, task type index prefix
+const ADD_DEAD_LETTER_SCRIPT: &str = r#"
+local old_task_type = redis.call('HGET', KEYS[1], 'task_type')
+if old_task_type then
+    redis.call('ZREM', ARGV[5] .. old_task_type, ARGV[1])
+end
+redis.call('HSET', KEYS[1], 'task_type', ARGV[2], 'dead_letter', ARGV[3])
+redis.call('ZADD', KEYS[2], ARGV[4], ARGV[1])
+redis.call('ZADD', ARGV[5] .. ARGV[2], ARGV[4], ARGV[1])
+return 1
+"#;
+
+// KEYS: dead letter hash, all dead letters
+// ARGV: execution id, task type index prefix
+// Returns the dead letter it deleted, nil when there was none.
+const TAKE_DEAD_LETTER_SCRIPT: &str = r#"
+local dead_letter = redis.call('HMGET', KEYS[1], 'task_type', 'dead_letter')
+if not dead_letter[2] then
+    return false
+end
+redis.call('DEL', KEYS[1])
+redis.call('ZREM', KEYS[2], ARGV[1])
+redis.call('ZREM', ARGV[2] .. dead_letter[1], ARGV[1])
+return dead_letter[2]
+"#;
 
+// KEYS: index of the dead letters to purge
+// ARGV: dead letter key prefix, all dead letters index, task type index prefix
+const PURGE_DEAD_LETTERS_SCRIPT: &str = r#"
+local ids = redis.call('ZRANGE', KEYS[1], 0, -1)
+for _, id in ipairs(ids) do
+    local key = ARGV[1] .. id
+    local task_type = redis.call('HGET', key, 'task_type')
+    if task_type then
+        redis.call('ZREM', ARGV[3] .. task_type, id)
+    end
+    redis.call('DEL', key)
+    redis.call('ZREM', ARGV[2], id)
+end
+return #ids
+"#;
+
+// KEYS: workflow hash
+// ARGV: workflow id, state, workflow, created at, state index prefix
+// Returns 0 when the workflow id is taken.
+const ADD_WORKFLOW_SCRIPT: &str = r#"
+if redis.call('EXISTS', KEYS[1]) == 1 then
+    return 0
+end
+redis.call('HSET', KEYS[1], 'state', ARGV[2], 'workflow', ARGV[3])
+redis.call('ZADD', ARGV[5] .. ARGV[2], ARGV[4], ARGV[1])
+return 1
+"#;
+
+// KEYS: workflow hash
+// ARGV: workflow id, state, workflow, created at, state index prefix
+// Returns 0 when the workflow doesn't exist.
+const UPDATE_WORKFLOW_SCRIPT: &str = r#"
+local old_state = redis.call('HGET', KEYS[1], 'state')
+if not old_state then
+    return 0
+end
+redis.call('ZREM', ARGV[5] .. old_state, ARGV[1])
+redis.call('HSET', KEYS[1], 'state', ARGV[2], 'workflow', ARGV[3])
+redis.call('ZADD', ARGV[5] .. ARGV[2], ARGV[4], ARGV[1])
+return 1
+"#;
+
+/// `DataStore` backed by Redis.
+///
+/// Every execution is a hash under `task:{execution_id}`, indexed by sorted sets (scored
+/// by submission time) over all executions, and executions per state.
+/// Writes touching several keys run as Lua scripts so the indexes never drift apart.
+///
+/// Finished executions expire through native key TTLs according to the `RetentionPolicy`,
+/// index entries of expired executions are dropped lazily the next time they are read.
+///
+/// Calls share one multiplexed connection, which reconnects in the background when Redis
+/// goes away; commands sent while it is down fail instead of blocking.
 pub struct RedisDataStore {
-    con: Arc<Mutex<Connection>>,
+    con: ConnectionManager,
+    retention: RetentionPolicy,
 }
 
 impl RedisDataStore {
@@ -23,52 +353,683 @@ impl RedisDataStore {
         let redis_host = cleaned_redis_host.clone();
         let client = Client::open(cleaned_redis_host)
             .map_err(|err| SchedulerError::DataLayerError(format!("Error when calling redis host: {} {:?}", redis_host, err)))?;
-        
-        let con = client.get_async_connection().await
+
+        let con = ConnectionManager::new(client).await
             .map_err(|_| SchedulerError::DataLayerError(format!("Unable to connect to redis host: {}", redis_host)))?;
 
-        Ok(Self { con: Arc::new(Mutex::new(con)) })
+        Ok(Self { con, retention: RetentionPolicy::default() })
+    }
+
+    /// Expires finished executions according to `retention`.
+    pub fn with_retention(mut self, retention: RetentionPolicy) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Seconds until an execution in `state` expires, 0 when it is kept forever.
+    fn ttl_seconds(&self, state: TaskState) -> u64 {
+        self.retention.ttl(state).map_or(0, |ttl| ttl.as_secs().max(1))
+    }
+
+    /// Moves the execution to `new_state` and keeps its indexes, expiry, lease and result in sync.
+    ///
+    /// With a worker in `guard`, only an execution running on that worker is moved. With a
+    /// fencing token, only while it is the token of the current leader lease.
+    async fn move_execution(
+        &self,
+        execution_id: &str,
+        new_state: TaskState,
+        worker_id: Option<&str>,
+        lease_expires_at: Option<i64>,
+        result: (Option<&Any>, Option<&TaskError>),
+        guard: (Option<&str>, Option<u64>),
+    ) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+
+        let updated: i32 = Script::new(UPDATE_STATE_SCRIPT)
+            .key(execution_key(execution_id))
+            .key(LEADER_TOKEN_KEY)
+            .arg(execution_id)
+            .arg(i32::from(new_state))
+            .arg(current_timestamp())
+            .arg(worker_id.unwrap_or_default())
+            .arg(EXECUTIONS_BY_STATE_KEY_PREFIX)
+            .arg(self.ttl_seconds(new_state))
+            .arg(lease_expires_at.unwrap_or_default())
+            .arg(result.0.map(Message::encode_to_vec).unwrap_or_default())
+            .arg(result.1.map(Message::encode_to_vec).unwrap_or_default())
+            .arg(guard.0.unwrap_or_default())
+            .arg(i32::from(TaskState::Running))
+            .arg(guard.1.map(|token| token.to_string()).unwrap_or_default())
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to update task execution state", err))?;
+
+        match updated {
+            0 => return Err(DataStoreError::NotFound(execution_id.to_string())),
+            -1 => return Err(DataStoreError::LeaseLost(execution_id.to_string())),
+            -2 => return Err(DataStoreError::Fenced(guard.1.unwrap_or_default())),
+            _ => {}
+        }
+
+        debug!("task execution {} moved to {:?}", execution_id, new_state);
+        Ok(())
+    }
+
+    /// Loads the executions stored under `ids`, skipping (and unindexing) the ones that expired.
+    async fn fetch_records(&self, con: &mut ConnectionManager, ids: &[String]) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+        let mut pipe = redis::pipe();
+        for id in ids {
+            pipe.hgetall(execution_key(id));
+        }
+        let hashes: Vec<HashMap<String, Vec<u8>>> = pipe
+            .query_async(con)
+            .await
+            .map_err(|err| internal_error("Failed to fetch task executions", err))?;
+
+        let mut records = Vec::with_capacity(ids.len());
+        let mut expired = Vec::new();
+        for (id, hash) in ids.iter().zip(hashes) {
+            if hash.is_empty() {
+                expired.push(id.clone());
+            } else {
+                records.push(record_from_hash(id, &hash)?);
+            }
+        }
+
+        if !expired.is_empty() {
+            // Only states with a retention expire, so only their indexes can hold stale ids
+            let mut pipe = redis::pipe();
+            pipe.zrem(EXECUTIONS_KEY, &expired).ignore();
+            for state in self.retention.states() {
+                pipe.zrem(state_key(state), &expired).ignore();
+            }
+            pipe.query_async::<_, ()>(con)
+                .await
+                .map_err(|err| internal_error("Failed to unindex expired task executions", err))?;
+        }
+
+        Ok(records)
     }
+
+    /// Loads the dead letters stored under `ids`, skipping the ones deleted in the meantime.
+    async fn fetch_dead_letters(&self, con: &mut ConnectionManager, ids: &[String]) -> Result<Vec<DeadLetter>, DataStoreError> {
+        let mut pipe = redis::pipe();
+        for id in ids {
+            pipe.hget(dead_letter_key(id), "dead_letter");
+        }
+        let encoded: Vec<Option<Vec<u8>>> = pipe
+            .query_async(con)
+            .await
+            .map_err(|err| internal_error("Failed to fetch dead letters", err))?;
+
+        encoded
+            .into_iter()
+            .flatten()
+            .map(|dead_letter| decode_dead_letter(&dead_letter))
+            .collect()
+    }
+}
+
+fn execution_key(execution_id: &str) -> String {
+    format!("{}{}", EXECUTION_KEY_PREFIX, execution_id)
+}
+
+fn state_key(state: TaskState) -> String {
+    format!("{}{}", EXECUTIONS_BY_STATE_KEY_PREFIX, i32::from(state))
+}
+
+fn dead_letter_key(execution_id: &str) -> String {
+    format!("{}{}", DEAD_LETTER_KEY_PREFIX, execution_id)
+}
+
+/// Index of the dead letters of `task_type`, or of all of them.
+fn dead_letters_key(task_type: Option<&str>) -> String {
+    task_type.map_or(DEAD_LETTERS_KEY.to_string(), |task_type| format!("{}{}", DEAD_LETTERS_BY_TASK_TYPE_KEY_PREFIX, task_type))
+}
+
+fn workflow_key(workflow_id: &str) -> String {
+    format!("{}{}", WORKFLOW_KEY_PREFIX, workflow_id)
+}
+
+fn decode_workflow(workflow: &[u8]) -> Result<WorkflowStatus, DataStoreError> {
+    WorkflowStatus::decode(workflow).map_err(|err| DataStoreError::InternalError(format!("Failed to decode workflow: {:?}", err)))
+}
+
+fn decode_dead_letter(dead_letter: &[u8]) -> Result<DeadLetter, DataStoreError> {
+    DeadLetter::decode(dead_letter).map_err(|err| DataStoreError::InternalError(format!("Failed to decode dead letter: {:?}", err)))
+}
+
+fn internal_error(context: &str, err: RedisError) -> DataStoreError {
+    DataStoreError::InternalError(format!("{}: {:?}", context, err))
+}
+
+fn record_from_hash(execution_id: &str, hash: &HashMap<String, Vec<u8>>) -> Result<ExecutionRecord, DataStoreError> {
+    let malformed = |field: &str| DataStoreError::InternalError(format!("Malformed task execution {}: {}", execution_id, field));
+    let field = |name: &str| -> Result<String, DataStoreError> {
+        hash.get(name)
+            .and_then(|value| String::from_utf8(value.clone()).ok())
+            .ok_or_else(|| malformed(name))
+    };
+    let optional = |name: &str| field(name).ok().filter(|value| !value.is_empty());
+
+    let task = hash.get("task")
+        .and_then(|value| Task::decode(value.as_slice()).ok())
+        .ok_or_else(|| malformed("task"))?;
+    let state = field("state")?
+        .parse::<i32>()
+        .ok()
+        .and_then(TaskState::from_i32)
+        .ok_or_else(|| malformed("state"))?;
+
+    Ok(ExecutionRecord {
+        execution_id: execution_id.to_string(),
+        task,
+        state,
+        worker_id: optional("worker_id"),
+        claimed_by: optional("claimed_by"),
+        created_at: field("created_at")?.parse().map_err(|_| malformed("created_at"))?,
+        updated_at: field("updated_at")?.parse().map_err(|_| malformed("updated_at"))?,
+        lease_expires_at: optional("lease_expires_at")
+            .map(|expires_at| expires_at.parse().map_err(|_| malformed("lease_expires_at")))
+            .transpose()?,
+        output: hash.get("output")
+            .map(|value| Any::decode(value.as_slice()).map_err(|_| malformed("output")))
+            .transpose()?,
+        error: hash.get("error")
+            .map(|value| TaskError::decode(value.as_slice()).map_err(|_| malformed("error")))
+            .transpose()?,
+        retries: optional("retries")
+            .map(|retries| retries.parse().map_err(|_| malformed("retries")))
+            .transpose()?
+            .unwrap_or_default(),
+        attempts: hash.get("attempts")
+            .map(|value| ExecutionAttempts::decode(value.as_slice()).map_err(|_| malformed("attempts")))
+            .transpose()?
+            .map_or_else(Vec::new, |log| log.attempts),
+    })
 }
 
 #[async_trait]
 impl DataStore for RedisDataStore {
-    async fn add_task_execution(&self, execute: ExecuteRequest) -> Result<(), SchedulerError> {
-        let mut db = self.con.lock().await;
-    
-        let task = execute
-            .task
-            .as_ref()
-            .ok_or_else(|| SchedulerError::DataLayerError("Task is missing in ExecuteRequest".to_string()))?;
-        
-        let payload = task
-            .payload
-            .as_ref()
-            .ok_or_else(|| SchedulerError::DataLayerError("Payload is missing in Task".to_string()))?;
-            
-        let key = format!("task:{}", task.id);
-        
-        let mut val = Any::new();
-        val.type_url = payload.type_url.clone();
-        val.value = payload.value.clone();
-
-        db.hset(&key, "execution", &val.to_string())
-            .await
-            .map_err(|_| SchedulerError::DataLayerError("Failed to add task execution".to_string()))?;
-    
+    async fn add_task_execution(&self, record: ExecutionRecord) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+
+        let added: i32 = Script::new(ADD_EXECUTION_SCRIPT)
+            .key(execution_key(&record.execution_id))
+            .key(EXECUTIONS_KEY)
+            .key(state_key(record.state))
+            .arg(&record.execution_id)
+            .arg(record.task.encode_to_vec())
+            .arg(i32::from(record.state))
+            .arg(record.task_type())
+            .arg(record.worker_id.as_deref().unwrap_or_default())
+            .arg(record.claimed_by.as_deref().unwrap_or_default())
+            .arg(record.created_at)
+            .arg(record.updated_at)
+            .arg(self.ttl_seconds(record.state))
+            .arg(record.lease_expires_at.unwrap_or_default())
+            .arg(record.output.as_ref().map(Message::encode_to_vec).unwrap_or_default())
+            .arg(record.error.as_ref().map(Message::encode_to_vec).unwrap_or_default())
+            .arg(record.retries)
+            .arg(if record.attempts.is_empty() {
+                Vec::new()
+            } else {
+                ExecutionAttempts { attempts: record.attempts.clone() }.encode_to_vec()
+            })
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to add task execution", err))?;
+
+        if added == 0 {
+            return Err(DataStoreError::Duplicate(record.execution_id));
+        }
+
         Ok(())
     }
 
-    async fn get_task_executions_by_worker(&self, state: TaskState) -> Result<Vec<ExecuteRequest>, SchedulerError> {
-        todo!()
+    async fn get_task_execution(&self, execution_id: &str) -> Result<ExecutionRecord, DataStoreError> {
+        let mut db = self.con.clone();
+
+        let hash: HashMap<String, Vec<u8>> = db.hgetall(execution_key(execution_id))
+            .await
+            .map_err(|err| internal_error("Failed to get task execution", err))?;
+
+        if hash.is_empty() {
+            return Err(DataStoreError::NotFound(execution_id.to_string()));
+        }
+        record_from_hash(execution_id, &hash)
     }
 
-    async fn get_tasks_executions_by_state(&self, state: TaskState) -> Result<Vec<ExecuteRequest>, SchedulerError> {
-        todo!()
+    async fn list_task_executions(&self, filter: &ExecutionFilter, page: &PageRequest) -> Result<Page<ExecutionRecord>, DataStoreError> {
+        let limit = page.limit.max(1);
+        let after = page.cursor.as_deref().map(Cursor::decode).transpose()?;
+
+        // A single state has its own index, anything else is filtered while scanning all executions
+        let index = match filter.states.as_slice() {
+            [state] => state_key(*state),
+            _ => EXECUTIONS_KEY.to_string(),
+        };
+        let min = filter.created_after
+            .into_iter()
+            .chain(after.as_ref().map(|after| after.created_at))
+            .max()
+            .map_or("-inf".to_string(), |min| min.to_string());
+        let max = filter.created_before.map_or("+inf".to_string(), |max| format!("({}", max));
+
+        let mut db = self.con.clone();
+        let mut items = Vec::new();
+        let mut offset = 0;
+        loop {
+            let ids: Vec<String> = db.zrangebyscore_limit(&index, &min, &max, offset, SCAN_BATCH_SIZE as isize)
+                .await
+                .map_err(|err| internal_error("Failed to list task executions", err))?;
+            offset += ids.len() as isize;
+
+            for record in self.fetch_records(&mut db, &ids).await? {
+                if after.as_ref().is_none_or(|after| &Cursor::of(&record) > after) && filter.matches(&record) {
+                    items.push(record);
+                }
+            }
+
+            // One extra item tells whether there is a next page
+            if items.len() > limit || ids.len() < SCAN_BATCH_SIZE {
+                break;
+            }
+        }
+
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|record| Cursor::of(record).encode())
+        } else {
+            None
+        };
+
+        Ok(Page { items, next_cursor })
+    }
+
+    async fn count_task_executions_by_state(&self, filter: &ExecutionFilter) -> Result<HashMap<TaskState, u64>, DataStoreError> {
+        let mut counts = HashMap::new();
+
+        if filter.states.is_empty() || filter.task_type.is_some() || filter.worker_id.is_some() || filter.updated_before.is_some() {
+            for record in self.list_all_task_executions(filter).await? {
+                *counts.entry(record.state).or_insert(0) += 1;
+            }
+            return Ok(counts);
+        }
+
+        let min = filter.created_after.map_or("-inf".to_string(), |min| min.to_string());
+        let max = filter.created_before.map_or("+inf".to_string(), |max| format!("({}", max));
+        let mut db = self.con.clone();
+        for state in &filter.states {
+            let count: u64 = db.zcount(state_key(*state), &min, &max)
+                .await
+                .map_err(|err| internal_error("Failed to count task executions", err))?;
+            if count > 0 {
+                counts.insert(*state, count);
+            }
+        }
+
+        Ok(counts)
     }
 
-    async fn update_task_execution_state(&self, task_id: &str, execution_id: &str, new_state: &TaskState) -> Result<(), SchedulerError> {
-        todo!()
+    async fn update_task_execution_state(&self, execution_id: &str, new_state: TaskState, worker_id: Option<&str>) -> Result<(), DataStoreError> {
+        self.move_execution(execution_id, new_state, worker_id, None, (None, None), (None, None)).await
     }
 
-}
\ No newline at end of file
+    async fn complete_task_execution(&self, completion: &TaskCompletion, worker_id: Option<&str>) -> Result<(), DataStoreError> {
+        let result = (completion.output.as_ref(), completion.error.as_ref());
+        self.move_execution(&completion.execution_id, completion.state(), worker_id, None, result, (worker_id, None)).await
+    }
+
+    async fn retry_task_execution(&self, execution_id: &str, attempt: &ExecutionAttempt) -> Result<u32, DataStoreError> {
+        self.move_execution(execution_id, TaskState::Pending, None, None, (None, attempt.error.as_ref()), (None, None)).await?;
+
+        let mut db = self.con.clone();
+        Script::new(APPEND_ATTEMPT_SCRIPT)
+            .key(execution_key(execution_id))
+            .arg(ExecutionAttempts { attempts: vec![attempt.clone()] }.encode_to_vec())
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to record task execution attempt", err))
+    }
+
+    async fn lease_task_execution(&self, execution_id: &str, worker_id: &str, lease_expires_at: i64, fencing_token: Option<u64>) -> Result<(), DataStoreError> {
+        self.move_execution(execution_id, TaskState::Running, Some(worker_id), Some(lease_expires_at), (None, None), (None, fencing_token)).await
+    }
+
+    async fn requeue_task_execution(&self, execution_id: &str, fencing_token: u64) -> Result<(), DataStoreError> {
+        self.move_execution(execution_id, TaskState::Pending, None, None, (None, None), (None, Some(fencing_token))).await
+    }
+
+    async fn renew_task_execution_leases(&self, worker_id: &str, lease_expires_at: i64) -> Result<u64, DataStoreError> {
+        let leased = self
+            .list_all_task_executions(&ExecutionFilter::default().with_state(TaskState::Running).with_worker(worker_id))
+            .await?;
+        if leased.is_empty() {
+            return Ok(0);
+        }
+
+        let script = Script::new(RENEW_LEASES_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for record in &leased {
+            invocation.key(execution_key(&record.execution_id));
+        }
+        let mut db = self.con.clone();
+        invocation
+            .arg(lease_expires_at)
+            .arg(worker_id)
+            .arg(i32::from(TaskState::Running))
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to renew task execution leases", err))
+    }
+
+    async fn purge_task_executions(&self, filter: &ExecutionFilter) -> Result<u64, DataStoreError> {
+        let records = self.list_all_task_executions(filter).await?;
+        if records.is_empty() {
+            return Ok(0);
+        }
+
+        let mut db = self.con.clone();
+        let mut pipe = redis::pipe();
+        pipe.atomic();
+        for record in &records {
+            pipe.del(execution_key(&record.execution_id)).ignore()
+                .zrem(EXECUTIONS_KEY, &record.execution_id).ignore()
+                .zrem(state_key(record.state), &record.execution_id).ignore();
+        }
+        pipe.query_async::<_, ()>(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to purge task executions", err))?;
+
+        Ok(records.len() as u64)
+    }
+
+    fn expires_executions(&self) -> bool {
+        true
+    }
+
+    async fn claim_task_executions(&self, owner: &str, limit: usize) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+        if limit == 0 {
+            return Ok(Vec::new());
+        }
+
+        let mut db = self.con.clone();
+        let ids: Vec<String> = Script::new(CLAIM_SCRIPT)
+            .key(state_key(TaskState::Pending))
+            .arg(limit)
+            .arg(owner)
+            .arg(current_timestamp())
+            .arg(EXECUTION_KEY_PREFIX)
+            .arg(SCAN_BATCH_SIZE)
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to claim task executions", err))?;
+
+        self.fetch_records(&mut db, &ids).await
+    }
+
+    async fn acquire_leader_lease(&self, holder: &str, address: &str, ttl: Duration) -> Result<LeaderLease, DataStoreError> {
+        let mut db = self.con.clone();
+        let (lease, token): (Option<String>, Option<String>) = Script::new(ACQUIRE_LEADER_SCRIPT)
+            .key(LEADER_KEY)
+            .key(LEADER_TOKEN_KEY)
+            .arg(holder)
+            .arg(address)
+            .arg(ttl.as_millis().max(1) as u64)
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to acquire leader lease", err))?;
+
+        // The lease can only be missing if it expired between the two reads of the script
+        let lease = lease.ok_or_else(|| DataStoreError::InternalError("Leader lease vanished".to_string()))?;
+        let (holder, address) = lease.split_once(' ').unwrap_or((lease.as_str(), ""));
+        Ok(LeaderLease {
+            holder: holder.to_string(),
+            address: address.to_string(),
+            token: token.and_then(|token| token.parse().ok()).unwrap_or_default(),
+        })
+    }
+
+    async fn release_leader_lease(&self, holder: &str) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+        Script::new(RELEASE_LEADER_SCRIPT)
+            .key(LEADER_KEY)
+            .arg(holder)
+            .invoke_async::<_, ()>(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to release leader lease", err))
+    }
+
+    async fn claim_idempotency_key(&self, key: &str, execution_id: &str, window: Duration) -> Result<String, DataStoreError> {
+        let mut db = self.con.clone();
+        Script::new(CLAIM_IDEMPOTENCY_KEY_SCRIPT)
+            .key(format!("{}{}", IDEMPOTENCY_KEY_PREFIX, key))
+            .arg(execution_id)
+            .arg(window.as_millis().max(1) as u64)
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to claim idempotency key", err))
+    }
+
+    async fn release_idempotency_key(&self, key: &str, execution_id: &str) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+        Script::new(RELEASE_IDEMPOTENCY_KEY_SCRIPT)
+            .key(format!("{}{}", IDEMPOTENCY_KEY_PREFIX, key))
+            .arg(execution_id)
+            .invoke_async::<_, ()>(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to release idempotency key", err))
+    }
+
+    async fn take_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<Option<(usize, Duration)>, DataStoreError> {
+        let mut db = self.con.clone();
+        let script = Script::new(TAKE_RATE_LIMIT_TOKENS_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for (key, bucket) in buckets {
+            invocation.key(format!("{}{}", RATE_LIMIT_KEY_PREFIX, key)).arg(bucket.capacity).arg(bucket.rate / 1000.0);
+        }
+        let empty: Vec<u64> = invocation
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to take rate limit tokens", err))?;
+        Ok(match empty[..] {
+            [position, wait] => Some((position as usize - 1, Duration::from_millis(wait))),
+            _ => None,
+        })
+    }
+
+    async fn return_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+        let script = Script::new(RETURN_RATE_LIMIT_TOKENS_SCRIPT);
+        let mut invocation = script.prepare_invoke();
+        for (key, bucket) in buckets {
+            invocation.key(format!("{}{}", RATE_LIMIT_KEY_PREFIX, key)).arg(bucket.capacity);
+        }
+        invocation
+            .invoke_async::<_, ()>(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to return rate limit tokens", err))
+    }
+
+    async fn acquire_concurrency_slot(&self, key: &str, execution_id: &str, limit: u32) -> Result<bool, DataStoreError> {
+        let mut db = self.con.clone();
+        Script::new(ACQUIRE_CONCURRENCY_SLOT_SCRIPT)
+            .key(format!("{}{}", CONCURRENCY_KEY_PREFIX, key))
+            .arg(execution_id)
+            .arg(limit)
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to acquire concurrency slot", err))
+    }
+
+    async fn release_concurrency_slot(&self, key: &str, execution_id: &str) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+        db.srem::<_, _, ()>(format!("{}{}", CONCURRENCY_KEY_PREFIX, key), execution_id)
+            .await
+            .map_err(|err| internal_error("Failed to release concurrency slot", err))
+    }
+
+    async fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+        Script::new(ADD_DEAD_LETTER_SCRIPT)
+            .key(dead_letter_key(&dead_letter.execution_id))
+            .key(DEAD_LETTERS_KEY)
+            .arg(&dead_letter.execution_id)
+            .arg(dead_letter.task.as_ref().map(|task| task.executor_name()).unwrap_or_default())
+            .arg(dead_letter.encode_to_vec())
+            .arg(dead_letter.dead_lettered_at)
+            .arg(DEAD_LETTERS_BY_TASK_TYPE_KEY_PREFIX)
+            .invoke_async::<_, ()>(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to add dead letter", err))
+    }
+
+    async fn get_dead_letter(&self, execution_id: &str) -> Result<DeadLetter, DataStoreError> {
+        let mut db = self.con.clone();
+        let dead_letter: Option<Vec<u8>> = db.hget(dead_letter_key(execution_id), "dead_letter")
+            .await
+            .map_err(|err| internal_error("Failed to get dead letter", err))?;
+        match dead_letter {
+            Some(dead_letter) => decode_dead_letter(&dead_letter),
+            None => Err(DataStoreError::NotFound(execution_id.to_string())),
+        }
+    }
+
+    async fn list_dead_letters(&self, task_type: Option<&str>, page: &PageRequest) -> Result<Page<DeadLetter>, DataStoreError> {
+        let limit = page.limit.max(1);
+        let after = page.cursor.as_deref().map(Cursor::decode).transpose()?;
+        let index = dead_letters_key(task_type);
+        let min = after.as_ref().map_or("-inf".to_string(), |after| after.created_at.to_string());
+
+        let mut db = self.con.clone();
+        let mut items = Vec::new();
+        let mut offset = 0;
+        loop {
+            let ids: Vec<String> = db.zrangebyscore_limit(&index, &min, "+inf", offset, SCAN_BATCH_SIZE as isize)
+                .await
+                .map_err(|err| internal_error("Failed to list dead letters", err))?;
+            offset += ids.len() as isize;
+
+            for dead_letter in self.fetch_dead_letters(&mut db, &ids).await? {
+                if after.as_ref().is_none_or(|after| &dead_letter.cursor() > after) {
+                    items.push(dead_letter);
+                }
+            }
+
+            // One extra item tells whether there is a next page
+            if items.len() > limit || ids.len() < SCAN_BATCH_SIZE {
+                break;
+            }
+        }
+
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|dead_letter| dead_letter.cursor().encode())
+        } else {
+            None
+        };
+
+        Ok(Page { items, next_cursor })
+    }
+
+    async fn take_dead_letter(&self, execution_id: &str) -> Result<DeadLetter, DataStoreError> {
+        let mut db = self.con.clone();
+        let dead_letter: Option<Vec<u8>> = Script::new(TAKE_DEAD_LETTER_SCRIPT)
+            .key(dead_letter_key(execution_id))
+            .key(DEAD_LETTERS_KEY)
+            .arg(execution_id)
+            .arg(DEAD_LETTERS_BY_TASK_TYPE_KEY_PREFIX)
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to take dead letter", err))?;
+        match dead_letter {
+            Some(dead_letter) => decode_dead_letter(&dead_letter),
+            None => Err(DataStoreError::NotFound(execution_id.to_string())),
+        }
+    }
+
+    async fn purge_dead_letters(&self, task_type: Option<&str>) -> Result<u64, DataStoreError> {
+        let mut db = self.con.clone();
+        Script::new(PURGE_DEAD_LETTERS_SCRIPT)
+            .key(dead_letters_key(task_type))
+            .arg(DEAD_LETTER_KEY_PREFIX)
+            .arg(DEAD_LETTERS_KEY)
+            .arg(DEAD_LETTERS_BY_TASK_TYPE_KEY_PREFIX)
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to purge dead letters", err))
+    }
+
+    async fn add_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+        let added: i64 = Script::new(ADD_WORKFLOW_SCRIPT)
+            .key(workflow_key(&workflow.workflow_id))
+            .arg(&workflow.workflow_id)
+            .arg(workflow.state)
+            .arg(workflow.encode_to_vec())
+            .arg(workflow.created_at)
+            .arg(WORKFLOWS_BY_STATE_KEY_PREFIX)
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to add workflow", err))?;
+        if added == 0 {
+            return Err(DataStoreError::Duplicate(workflow.workflow_id.clone()));
+        }
+        Ok(())
+    }
+
+    async fn get_workflow(&self, workflow_id: &str) -> Result<WorkflowStatus, DataStoreError> {
+        let mut db = self.con.clone();
+        let workflow: Option<Vec<u8>> = db.hget(workflow_key(workflow_id), "workflow")
+            .await
+            .map_err(|err| internal_error("Failed to get workflow", err))?;
+        match workflow {
+            Some(workflow) => decode_workflow(&workflow),
+            None => Err(DataStoreError::NotFound(workflow_id.to_string())),
+        }
+    }
+
+    async fn update_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError> {
+        let mut db = self.con.clone();
+        let updated: i64 = Script::new(UPDATE_WORKFLOW_SCRIPT)
+            .key(workflow_key(&workflow.workflow_id))
+            .arg(&workflow.workflow_id)
+            .arg(workflow.state)
+            .arg(workflow.encode_to_vec())
+            .arg(workflow.created_at)
+            .arg(WORKFLOWS_BY_STATE_KEY_PREFIX)
+            .invoke_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to update workflow", err))?;
+        if updated == 0 {
+            return Err(DataStoreError::NotFound(workflow.workflow_id.clone()));
+        }
+        Ok(())
+    }
+
+    async fn list_workflows(&self, state: WorkflowState) -> Result<Vec<WorkflowStatus>, DataStoreError> {
+        let mut db = self.con.clone();
+        let ids: Vec<String> = db.zrange(format!("{}{}", WORKFLOWS_BY_STATE_KEY_PREFIX, i32::from(state)), 0, -1)
+            .await
+            .map_err(|err| internal_error("Failed to list workflows", err))?;
+        if ids.is_empty() {
+            return Ok(Vec::new());
+        }
+
+        let mut pipe = redis::pipe();
+        for id in &ids {
+            pipe.hget(workflow_key(id), "workflow");
+        }
+        let encoded: Vec<Option<Vec<u8>>> = pipe
+            .query_async(&mut db)
+            .await
+            .map_err(|err| internal_error("Failed to fetch workflows", err))?;
+        encoded
+            .into_iter()
+            .flatten()
+            .map(|workflow| decode_workflow(&workflow))
+            .collect()
+    }
+}
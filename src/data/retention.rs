@@ -0,0 +1,157 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashMap, sync::Arc, time::Duration};
+
+use log::{debug, error};
+use tokio::task::JoinHandle;
+
+use crate::{
+    internal::protot::core::{Retention, TaskState},
+    utils::current_timestamp,
+};
+
+use super::data_store::{DataStore, DataStoreError, ExecutionFilter};
+
+/// Sweep interval used when the configuration does not set one.
+pub const DEFAULT_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long finished executions are kept, per state.
+///
+/// States without a retention are kept forever.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    ttls: HashMap<TaskState, Duration>,
+    sweep_interval: Duration,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self {
+            ttls: HashMap::new(),
+            sweep_interval: DEFAULT_SWEEP_INTERVAL,
+        }
+    }
+}
+
+impl RetentionPolicy {
+    /// Keeps executions in `state` for `ttl` after their last state change.
+    pub fn keep(mut self, state: TaskState, ttl: Duration) -> Self {
+        self.ttls.insert(state, ttl);
+        self
+    }
+
+    pub fn sweep_interval(mut self, interval: Duration) -> Self {
+        self.sweep_interval = interval;
+        self
+    }
+
+    pub fn ttl(&self, state: TaskState) -> Option<Duration> {
+        self.ttls.get(&state).copied()
+    }
+
+    /// States with a retention.
+    pub fn states(&self) -> impl Iterator<Item = TaskState> + '_ {
+        self.ttls.keys().copied()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ttls.is_empty()
+    }
+
+    /// Deletes the executions whose retention is over, returns how many were deleted.
+    pub async fn sweep(&self, store: &dyn DataStore) -> Result<u64, DataStoreError> {
+        let now = current_timestamp();
+        let mut purged = 0;
+        for (state, ttl) in &self.ttls {
+            let filter = ExecutionFilter::default()
+                .with_state(*state)
+                .updated_before(now - ttl.as_secs() as i64);
+            purged += store.purge_task_executions(&filter).await?;
+        }
+        Ok(purged)
+    }
+}
+
+impl From<&Retention> for RetentionPolicy {
+    fn from(retention: &Retention) -> Self {
+        let to_std = |duration: &prost_types::Duration| {
+            Duration::new(duration.seconds.max(0) as u64, duration.nanos.max(0) as u32)
+        };
+
+        let mut policy = RetentionPolicy::default();
+        if let Some(success) = &retention.success {
+            policy = policy.keep(TaskState::Success, to_std(success));
+        }
+        if let Some(fail) = &retention.fail {
+            policy = policy.keep(TaskState::Fail, to_std(fail));
+        }
+        if let Some(expired) = &retention.expired {
+            policy = policy.keep(TaskState::Expired, to_std(expired));
+        }
+        if let Some(interval) = &retention.sweep_interval {
+            policy = policy.sweep_interval(to_std(interval));
+        }
+        policy
+    }
+}
+
+/// Periodically sweeps the executions whose retention is over from `store`.
+pub fn spawn_retention_sweeper(store: Arc<dyn DataStore>, policy: RetentionPolicy) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(policy.sweep_interval.max(Duration::from_secs(1)));
+        loop {
+            interval.tick().await;
+            match policy.sweep(&*store).await {
+                Ok(0) => {}
+                Ok(purged) => debug!("retention sweep purged {} task executions", purged),
+                Err(err) => error!("retention sweep failed: {}", err),
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{data::{ExecutionRecord, WalDataStore}, internal::protot::core::Task};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_sweep_purges_expired_executions() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = WalDataStore::open(dir.path(), 100).unwrap();
+        let now = current_timestamp();
+
+        let finished = |execution_id: &str, state: TaskState, age: i64| ExecutionRecord {
+            state,
+            updated_at: now - age,
+            ..ExecutionRecord::new(execution_id.to_string(), Task::default())
+        };
+        store.add_task_execution(finished("old-success", TaskState::Success, 120)).await.unwrap();
+        store.add_task_execution(finished("new-success", TaskState::Success, 10)).await.unwrap();
+        store.add_task_execution(finished("old-fail", TaskState::Fail, 120)).await.unwrap();
+        store.add_task_execution(finished("old-pending", TaskState::Pending, 120)).await.unwrap();
+
+        let policy = RetentionPolicy::default()
+            .keep(TaskState::Success, Duration::from_secs(60))
+            .keep(TaskState::Fail, Duration::from_secs(3600));
+        assert_eq!(policy.sweep(&store).await.unwrap(), 1);
+
+        let remaining = store.list_all_task_executions(&ExecutionFilter::default()).await.unwrap();
+        let mut ids: Vec<_> = remaining.iter().map(|r| r.execution_id.as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, vec!["new-success", "old-fail", "old-pending"]);
+    }
+}
@@ -0,0 +1,944 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashMap, time::Duration};
+
+use async_trait::async_trait;
+use log::debug;
+use prost::Message;
+use sqlx::{
+    sqlite::{SqliteConnectOptions, SqliteJournalMode, SqlitePool, SqlitePoolOptions, SqliteRow},
+    QueryBuilder, Row, Sqlite,
+};
+
+use crate::{
+    internal::protot::{core::{ExecutionAttempt, ExecutionAttempts, TaskState, WorkflowState, WorkflowStatus}, scheduler::v1::{DeadLetter, ExecuteRequest, TaskCompletion}},
+    utils::current_timestamp,
+    SchedulerError,
+};
+
+use super::data_store::{
+    Cursor, DataStore, DataStoreError, ExecutionFilter, ExecutionRecord, LeaderLease, Page, PageRequest, Paged, TokenBucket,
+};
+
+/// Max number of pooled connections, SQLite allows a single writer at a time
+/// so a small pool is enough for the scheduler reads to not block on writes.
+const MAX_CONNECTIONS: u32 = 4;
+
+const SELECT_EXECUTIONS: &str =
+    "SELECT execution_id, worker_id, claimed_by, state, request, created_at, updated_at, lease_expires_at, output, error, retries, attempts FROM task_executions WHERE 1 = 1";
+
+const SELECT_DEAD_LETTERS: &str = "SELECT dead_letter FROM dead_letters WHERE 1 = 1";
+
+/// Embedded `DataStore` backed by a single SQLite database file.
+///
+/// Executions are persisted on every call (the database runs in WAL journal mode),
+/// so pending executions are still available after a process restart.
+pub struct SqliteDataStore {
+    pool: SqlitePool,
+}
+
+impl SqliteDataStore {
+    /// Opens (or creates) the database file at `path` and runs the pending schema migrations.
+    pub async fn new(path: &str) -> Result<Self, SchedulerError> {
+        let cleaned_path = path.replace('"', "");
+        let db_path = cleaned_path
+            .strip_prefix("sqlite://")
+            .unwrap_or(&cleaned_path)
+            .to_string();
+
+        let options = SqliteConnectOptions::new()
+            .filename(&db_path)
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+
+        let store = Self::connect(options, MAX_CONNECTIONS, &db_path).await?;
+        debug!("sqlite data store ready at: {}", db_path);
+        Ok(store)
+    }
+
+    /// A private in-memory database, dropped with the store.
+    #[cfg(test)]
+    async fn in_memory() -> Result<Self, SchedulerError> {
+        use std::str::FromStr;
+
+        let options = SqliteConnectOptions::from_str("sqlite::memory:")
+            .map_err(|err| SchedulerError::DataLayerError(format!("Invalid sqlite options: {:?}", err)))?;
+        // A single connection, the in-memory database goes away with the last one closed
+        Self::connect(options, 1, ":memory:").await
+    }
+
+    async fn connect(options: SqliteConnectOptions, max_connections: u32, db_path: &str) -> Result<Self, SchedulerError> {
+        let pool = SqlitePoolOptions::new()
+            .max_connections(max_connections)
+            .connect_with(options)
+            .await
+            .map_err(|err| SchedulerError::DataLayerError(format!("Unable to open sqlite database: {} {:?}", db_path, err)))?;
+
+        sqlx::migrate!("./migrations/sqlite")
+            .run(&pool)
+            .await
+            .map_err(|err| SchedulerError::DataLayerError(format!("Failed to run sqlite migrations: {:?}", err)))?;
+
+        Ok(Self { pool })
+    }
+}
+
+fn push_filter(query: &mut QueryBuilder<'_, Sqlite>, filter: &ExecutionFilter) {
+    if !filter.states.is_empty() {
+        query.push(" AND state IN (");
+        let mut states = query.separated(", ");
+        for state in &filter.states {
+            states.push_bind(i32::from(*state));
+        }
+        states.push_unseparated(")");
+    }
+    if let Some(task_type) = &filter.task_type {
+        query.push(" AND task_type = ").push_bind(task_type.clone());
+    }
+    if let Some(worker_id) = &filter.worker_id {
+        query.push(" AND worker_id = ").push_bind(worker_id.clone());
+    }
+    if let Some(created_after) = filter.created_after {
+        query.push(" AND created_at >= ").push_bind(created_after);
+    }
+    if let Some(created_before) = filter.created_before {
+        query.push(" AND created_at < ").push_bind(created_before);
+    }
+    if let Some(updated_before) = filter.updated_before {
+        query.push(" AND updated_at < ").push_bind(updated_before);
+    }
+}
+
+fn internal_error(context: &str, err: sqlx::Error) -> DataStoreError {
+    DataStoreError::InternalError(format!("{}: {:?}", context, err))
+}
+
+fn record_from_row(row: &SqliteRow) -> Result<ExecutionRecord, DataStoreError> {
+    let malformed = |err| internal_error("Malformed task execution row", err);
+
+    let request: Vec<u8> = row.try_get("request").map_err(malformed)?;
+    let request = ExecuteRequest::decode(request.as_slice())
+        .map_err(|err| DataStoreError::InternalError(format!("Failed to decode task execution: {:?}", err)))?;
+    let state: i32 = row.try_get("state").map_err(malformed)?;
+
+    Ok(ExecutionRecord {
+        execution_id: row.try_get("execution_id").map_err(malformed)?,
+        task: request.task.unwrap_or_default(),
+        state: TaskState::from_i32(state)
+            .ok_or_else(|| DataStoreError::InternalError(format!("Unknown task state: {}", state)))?,
+        worker_id: row.try_get("worker_id").map_err(malformed)?,
+        claimed_by: row.try_get("claimed_by").map_err(malformed)?,
+        created_at: row.try_get("created_at").map_err(malformed)?,
+        updated_at: row.try_get("updated_at").map_err(malformed)?,
+        lease_expires_at: row.try_get("lease_expires_at").map_err(malformed)?,
+        output: decode_column(row, "output")?,
+        error: decode_column(row, "error")?,
+        retries: row.try_get::<i64, _>("retries").map_err(malformed)? as u32,
+        attempts: decode_column::<ExecutionAttempts>(row, "attempts")?.map_or_else(Vec::new, |log| log.attempts),
+    })
+}
+
+fn dead_letter_from_row(row: &SqliteRow) -> Result<DeadLetter, DataStoreError> {
+    let dead_letter: Vec<u8> = row.try_get("dead_letter").map_err(|err| internal_error("Malformed dead letter row", err))?;
+    DeadLetter::decode(dead_letter.as_slice())
+        .map_err(|err| DataStoreError::InternalError(format!("Failed to decode dead letter: {:?}", err)))
+}
+
+fn workflow_from_row(row: &SqliteRow) -> Result<WorkflowStatus, DataStoreError> {
+    let workflow: Vec<u8> = row.try_get("workflow").map_err(|err| internal_error("Malformed workflow row", err))?;
+    WorkflowStatus::decode(workflow.as_slice())
+        .map_err(|err| DataStoreError::InternalError(format!("Failed to decode workflow: {:?}", err)))
+}
+
+/// Encodes the attempts of an execution, `None` while there are none.
+fn encode_attempts(attempts: &[ExecutionAttempt]) -> Option<Vec<u8>> {
+    (!attempts.is_empty()).then(|| ExecutionAttempts { attempts: attempts.to_vec() }.encode_to_vec())
+}
+
+fn push_task_type(query: &mut QueryBuilder<'_, Sqlite>, task_type: Option<&str>) {
+    if let Some(task_type) = task_type {
+        query.push(" AND task_type = ").push_bind(task_type.to_string());
+    }
+}
+
+/// Decodes the protobuf message held by the nullable `column`.
+fn decode_column<M: Message + Default>(row: &SqliteRow, column: &str) -> Result<Option<M>, DataStoreError> {
+    let bytes: Option<Vec<u8>> = row.try_get(column)
+        .map_err(|err| internal_error("Malformed task execution row", err))?;
+    bytes
+        .map(|bytes| M::decode(bytes.as_slice())
+            .map_err(|err| DataStoreError::InternalError(format!("Failed to decode task execution {}: {:?}", column, err))))
+        .transpose()
+}
+
+#[async_trait]
+impl DataStore for SqliteDataStore {
+    async fn add_task_execution(&self, record: ExecutionRecord) -> Result<(), DataStoreError> {
+        sqlx::query(
+            "INSERT INTO task_executions (execution_id, task_id, task_type, worker_id, claimed_by, state, request, created_at, updated_at, lease_expires_at, output, error, retries, attempts)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&record.execution_id)
+        .bind(&record.task.id)
+        .bind(record.task_type())
+        .bind(&record.worker_id)
+        .bind(&record.claimed_by)
+        .bind(i32::from(record.state))
+        .bind(record.to_execute_request().encode_to_vec())
+        .bind(record.created_at)
+        .bind(record.updated_at)
+        .bind(record.lease_expires_at)
+        .bind(record.output.as_ref().map(Message::encode_to_vec))
+        .bind(record.error.as_ref().map(Message::encode_to_vec))
+        .bind(record.retries as i64)
+        .bind(encode_attempts(&record.attempts))
+        .execute(&self.pool)
+        .await
+        .map_err(|err| match err {
+            sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                DataStoreError::Duplicate(record.execution_id.clone())
+            }
+            err => internal_error("Failed to add task execution", err),
+        })?;
+
+        Ok(())
+    }
+
+    async fn get_task_execution(&self, execution_id: &str) -> Result<ExecutionRecord, DataStoreError> {
+        let mut query = QueryBuilder::<Sqlite>::new(SELECT_EXECUTIONS);
+        query.push(" AND execution_id = ").push_bind(execution_id.to_string());
+
+        let row = query
+            .build()
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to get task execution", err))?;
+
+        match row {
+            Some(row) => record_from_row(&row),
+            None => Err(DataStoreError::NotFound(execution_id.to_string())),
+        }
+    }
+
+    async fn list_task_executions(&self, filter: &ExecutionFilter, page: &PageRequest) -> Result<Page<ExecutionRecord>, DataStoreError> {
+        let limit = page.limit.max(1);
+        let mut query = QueryBuilder::<Sqlite>::new(SELECT_EXECUTIONS);
+        push_filter(&mut query, filter);
+        if let Some(cursor) = page.cursor.as_deref().map(Cursor::decode).transpose()? {
+            query
+                .push(" AND (created_at > ").push_bind(cursor.created_at)
+                .push(" OR (created_at = ").push_bind(cursor.created_at)
+                .push(" AND execution_id > ").push_bind(cursor.execution_id)
+                .push("))");
+        }
+        // One extra row tells whether there is a next page
+        query.push(" ORDER BY created_at, execution_id LIMIT ").push_bind((limit + 1) as i64);
+
+        let rows = query
+            .build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to list task executions", err))?;
+
+        let mut items = rows.iter().map(record_from_row).collect::<Result<Vec<_>, _>>()?;
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|record| Cursor::of(record).encode())
+        } else {
+            None
+        };
+
+        Ok(Page { items, next_cursor })
+    }
+
+    async fn count_task_executions_by_state(&self, filter: &ExecutionFilter) -> Result<HashMap<TaskState, u64>, DataStoreError> {
+        let mut query = QueryBuilder::<Sqlite>::new("SELECT state, COUNT(*) AS count FROM task_executions WHERE 1 = 1");
+        push_filter(&mut query, filter);
+        query.push(" GROUP BY state");
+
+        let rows = query
+            .build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to count task executions", err))?;
+
+        let mut counts = HashMap::new();
+        for row in rows {
+            let state: i32 = row.try_get("state").map_err(|err| internal_error("Malformed count row", err))?;
+            let count: i64 = row.try_get("count").map_err(|err| internal_error("Malformed count row", err))?;
+            if let Some(state) = TaskState::from_i32(state) {
+                counts.insert(state, count as u64);
+            }
+        }
+
+        Ok(counts)
+    }
+
+    async fn update_task_execution_state(&self, execution_id: &str, new_state: TaskState, worker_id: Option<&str>) -> Result<(), DataStoreError> {
+        let result = sqlx::query(
+            "UPDATE task_executions SET state = ?, worker_id = COALESCE(?, worker_id), updated_at = ?, lease_expires_at = NULL, output = NULL, error = NULL
+             WHERE execution_id = ?",
+        )
+        .bind(i32::from(new_state))
+        .bind(worker_id)
+        .bind(current_timestamp())
+        .bind(execution_id)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to update task execution state", err))?;
+
+        if result.rows_affected() == 0 {
+            return Err(DataStoreError::NotFound(execution_id.to_string()));
+        }
+
+        Ok(())
+    }
+
+    async fn complete_task_execution(&self, completion: &TaskCompletion, worker_id: Option<&str>) -> Result<(), DataStoreError> {
+        let result = sqlx::query(
+            "UPDATE task_executions SET state = ?, worker_id = COALESCE(?, worker_id), updated_at = ?, lease_expires_at = NULL, output = ?, error = ?
+             WHERE execution_id = ? AND (? IS NULL OR (state = ? AND worker_id = ?))",
+        )
+        .bind(completion.state)
+        .bind(worker_id)
+        .bind(current_timestamp())
+        .bind(completion.output.as_ref().map(Message::encode_to_vec))
+        .bind(completion.error.as_ref().map(Message::encode_to_vec))
+        .bind(&completion.execution_id)
+        .bind(worker_id)
+        .bind(i32::from(TaskState::Running))
+        .bind(worker_id)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to complete task execution", err))?;
+
+        if result.rows_affected() == 0 {
+            // Tells a missing execution from one leased to another worker
+            self.get_task_execution(&completion.execution_id).await?;
+            return Err(DataStoreError::LeaseLost(completion.execution_id.clone()));
+        }
+
+        Ok(())
+    }
+
+    async fn retry_task_execution(&self, execution_id: &str, attempt: &ExecutionAttempt) -> Result<u32, DataStoreError> {
+        let mut tx = self.pool.begin().await.map_err(|err| internal_error("Failed to begin transaction", err))?;
+        // Updating first takes the write lock, so concurrent retries can't both append to the same attempts
+        let row = sqlx::query(
+            "UPDATE task_executions SET state = ?, updated_at = ?, lease_expires_at = NULL, output = NULL, error = ?, retries = retries + 1
+             WHERE execution_id = ?
+             RETURNING retries, attempts",
+        )
+        .bind(i32::from(TaskState::Pending))
+        .bind(current_timestamp())
+        .bind(attempt.error.as_ref().map(Message::encode_to_vec))
+        .bind(execution_id)
+        .fetch_optional(&mut *tx)
+        .await
+        .map_err(|err| internal_error("Failed to retry task execution", err))?
+        .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))?;
+
+        let retries: i64 = row.try_get("retries").map_err(|err| internal_error("Malformed task execution row", err))?;
+        let mut attempts = decode_column::<ExecutionAttempts>(&row, "attempts")?.map_or_else(Vec::new, |log| log.attempts);
+        attempts.push(attempt.clone());
+        sqlx::query("UPDATE task_executions SET attempts = ? WHERE execution_id = ?")
+            .bind(encode_attempts(&attempts))
+            .bind(execution_id)
+            .execute(&mut *tx)
+            .await
+            .map_err(|err| internal_error("Failed to record task execution attempt", err))?;
+        tx.commit().await.map_err(|err| internal_error("Failed to commit task execution retry", err))?;
+        Ok(retries as u32)
+    }
+
+    async fn lease_task_execution(&self, execution_id: &str, worker_id: &str, lease_expires_at: i64, fencing_token: Option<u64>) -> Result<(), DataStoreError> {
+        let result = sqlx::query(
+            "UPDATE task_executions SET state = ?, worker_id = ?, lease_expires_at = ?, updated_at = ?
+             WHERE execution_id = ? AND (? IS NULL OR ? = (SELECT token FROM scheduler_leader WHERE id = 1))",
+        )
+        .bind(i32::from(TaskState::Running))
+        .bind(worker_id)
+        .bind(lease_expires_at)
+        .bind(current_timestamp())
+        .bind(execution_id)
+        .bind(fencing_token.map(|token| token as i64))
+        .bind(fencing_token.map(|token| token as i64))
+        .execute(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to lease task execution", err))?;
+
+        if result.rows_affected() == 0 {
+            let token = fencing_token.ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))?;
+            // Tells a missing execution from a lease fenced off by a newer leader
+            self.get_task_execution(execution_id).await?;
+            return Err(DataStoreError::Fenced(token));
+        }
+
+        Ok(())
+    }
+
+    async fn requeue_task_execution(&self, execution_id: &str, fencing_token: u64) -> Result<(), DataStoreError> {
+        let result = sqlx::query(
+            "UPDATE task_executions SET state = ?, updated_at = ?, lease_expires_at = NULL, output = NULL, error = NULL
+             WHERE execution_id = ? AND ? = (SELECT token FROM scheduler_leader WHERE id = 1)",
+        )
+        .bind(i32::from(TaskState::Pending))
+        .bind(current_timestamp())
+        .bind(execution_id)
+        .bind(fencing_token as i64)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to requeue task execution", err))?;
+
+        if result.rows_affected() == 0 {
+            // Tells a missing execution from a requeue fenced off by a newer leader
+            self.get_task_execution(execution_id).await?;
+            return Err(DataStoreError::Fenced(fencing_token));
+        }
+
+        Ok(())
+    }
+
+    async fn renew_task_execution_leases(&self, worker_id: &str, lease_expires_at: i64) -> Result<u64, DataStoreError> {
+        let result = sqlx::query("UPDATE task_executions SET lease_expires_at = ? WHERE worker_id = ? AND state = ?")
+            .bind(lease_expires_at)
+            .bind(worker_id)
+            .bind(i32::from(TaskState::Running))
+            .execute(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to renew task execution leases", err))?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn purge_task_executions(&self, filter: &ExecutionFilter) -> Result<u64, DataStoreError> {
+        let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM task_executions WHERE 1 = 1");
+        push_filter(&mut query, filter);
+
+        let result = query
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to purge task executions", err))?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn claim_task_executions(&self, owner: &str, limit: usize) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+        // SQLite serializes writers, so a single `UPDATE .. RETURNING` is already atomic
+        let rows = sqlx::query(
+            "UPDATE task_executions SET claimed_by = ?, updated_at = ?
+             WHERE execution_id IN (
+                 SELECT execution_id FROM task_executions
+                 WHERE state = ? AND claimed_by IS NOT ?
+                 ORDER BY created_at, execution_id
+                 LIMIT ?
+             )
+             RETURNING execution_id, worker_id, claimed_by, state, request, created_at, updated_at, lease_expires_at, output, error, retries, attempts",
+        )
+        .bind(owner)
+        .bind(current_timestamp())
+        .bind(i32::from(TaskState::Pending))
+        .bind(owner)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to claim task executions", err))?;
+
+        // `RETURNING` does not keep the subquery order
+        let mut records = rows.iter().map(record_from_row).collect::<Result<Vec<_>, _>>()?;
+        records.sort_by_key(Cursor::of);
+        Ok(records)
+    }
+
+    async fn acquire_leader_lease(&self, holder: &str, address: &str, ttl: Duration) -> Result<LeaderLease, DataStoreError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        // The row is only taken over by another holder once its lease expired
+        sqlx::query(
+            "INSERT INTO scheduler_leader (id, holder, address, token, expires_at) VALUES (1, ?, ?, 1, ?)
+             ON CONFLICT (id) DO UPDATE SET
+                 token = CASE WHEN scheduler_leader.holder = excluded.holder THEN scheduler_leader.token ELSE scheduler_leader.token + 1 END,
+                 holder = excluded.holder,
+                 address = excluded.address,
+                 expires_at = excluded.expires_at
+             WHERE scheduler_leader.holder = excluded.holder OR scheduler_leader.expires_at < ?",
+        )
+        .bind(holder)
+        .bind(address)
+        .bind(now + ttl.as_millis() as i64)
+        .bind(now)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to acquire leader lease", err))?;
+
+        let row = sqlx::query("SELECT holder, address, token FROM scheduler_leader WHERE id = 1")
+            .fetch_one(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to get leader lease", err))?;
+        Ok(LeaderLease {
+            holder: row.try_get("holder").map_err(|err| internal_error("Malformed leader lease", err))?,
+            address: row.try_get("address").map_err(|err| internal_error("Malformed leader lease", err))?,
+            token: row.try_get::<i64, _>("token").map_err(|err| internal_error("Malformed leader lease", err))? as u64,
+        })
+    }
+
+    async fn release_leader_lease(&self, holder: &str) -> Result<(), DataStoreError> {
+        // The row is kept so the next holder still gets a new fencing token
+        sqlx::query("UPDATE scheduler_leader SET expires_at = 0 WHERE id = 1 AND holder = ?")
+            .bind(holder)
+            .execute(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to release leader lease", err))?;
+        Ok(())
+    }
+
+    async fn claim_idempotency_key(&self, key: &str, execution_id: &str, window: Duration) -> Result<String, DataStoreError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        // Expired keys are free again, whoever submits next takes them over
+        sqlx::query("DELETE FROM idempotency_keys WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to expire idempotency keys", err))?;
+        sqlx::query(
+            "INSERT INTO idempotency_keys (idempotency_key, execution_id, expires_at) VALUES (?, ?, ?)
+             ON CONFLICT (idempotency_key) DO NOTHING",
+        )
+        .bind(key)
+        .bind(execution_id)
+        .bind(now + window.as_millis() as i64)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to claim idempotency key", err))?;
+
+        let row = sqlx::query("SELECT execution_id FROM idempotency_keys WHERE idempotency_key = ?")
+            .bind(key)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to get idempotency key", err))?
+            // Only a concurrent release removes the row in between
+            .ok_or_else(|| DataStoreError::InternalError(format!("Idempotency key vanished: {}", key)))?;
+        row.try_get("execution_id").map_err(|err| internal_error("Malformed idempotency key", err))
+    }
+
+    async fn release_idempotency_key(&self, key: &str, execution_id: &str) -> Result<(), DataStoreError> {
+        sqlx::query("DELETE FROM idempotency_keys WHERE idempotency_key = ? AND execution_id = ?")
+            .bind(key)
+            .bind(execution_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to release idempotency key", err))?;
+        Ok(())
+    }
+
+    async fn take_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<Option<(usize, Duration)>, DataStoreError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut tx = self.pool.begin().await.map_err(|err| internal_error("Failed to begin transaction", err))?;
+        let mut taken = Vec::with_capacity(buckets.len());
+        for (position, (key, bucket)) in buckets.iter().enumerate() {
+            // Inserting first takes the write lock, so concurrent takes can't both read the same tokens
+            sqlx::query("INSERT INTO rate_limits (rate_key, tokens, updated_at) VALUES (?, ?, ?) ON CONFLICT (rate_key) DO NOTHING")
+                .bind(key)
+                .bind(bucket.capacity)
+                .bind(now)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| internal_error("Failed to create rate limit", err))?;
+            let row = sqlx::query("SELECT tokens, updated_at FROM rate_limits WHERE rate_key = ?")
+                .bind(key)
+                .fetch_one(&mut *tx)
+                .await
+                .map_err(|err| internal_error("Failed to get rate limit", err))?;
+            let tokens: f64 = row.try_get("tokens").map_err(|err| internal_error("Malformed rate limit", err))?;
+            let updated_at: i64 = row.try_get("updated_at").map_err(|err| internal_error("Malformed rate limit", err))?;
+
+            match bucket.take(Some(tokens), now - updated_at) {
+                // Dropping the transaction rolls it back, so no bucket gives a token
+                (_, Some(wait)) => return Ok(Some((position, wait))),
+                (tokens, None) => taken.push((key, tokens, now.max(updated_at))),
+            }
+        }
+        for (key, tokens, updated_at) in taken {
+            sqlx::query("UPDATE rate_limits SET tokens = ?, updated_at = ? WHERE rate_key = ?")
+                .bind(tokens)
+                .bind(updated_at)
+                .bind(key)
+                .execute(&mut *tx)
+                .await
+                .map_err(|err| internal_error("Failed to update rate limit", err))?;
+        }
+        tx.commit().await.map_err(|err| internal_error("Failed to commit rate limit", err))?;
+        Ok(None)
+    }
+
+    async fn return_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<(), DataStoreError> {
+        for (key, bucket) in buckets {
+            sqlx::query("UPDATE rate_limits SET tokens = MIN(tokens + 1, ?) WHERE rate_key = ?")
+                .bind(bucket.capacity)
+                .bind(key)
+                .execute(&self.pool)
+                .await
+                .map_err(|err| internal_error("Failed to return rate limit token", err))?;
+        }
+        Ok(())
+    }
+
+    async fn acquire_concurrency_slot(&self, key: &str, execution_id: &str, limit: u32) -> Result<bool, DataStoreError> {
+        // One statement counts and inserts, so concurrent acquisitions can't both see a free slot
+        sqlx::query(
+            "INSERT INTO concurrency_slots (concurrency_key, execution_id, acquired_at)
+             SELECT ?, ?, ? WHERE (SELECT COUNT(*) FROM concurrency_slots WHERE concurrency_key = ?) < ?
+             ON CONFLICT (concurrency_key, execution_id) DO NOTHING",
+        )
+        .bind(key)
+        .bind(execution_id)
+        .bind(chrono::Utc::now().timestamp_millis())
+        .bind(key)
+        .bind(limit as i64)
+        .execute(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to acquire concurrency slot", err))?;
+
+        let held = sqlx::query("SELECT 1 FROM concurrency_slots WHERE concurrency_key = ? AND execution_id = ?")
+            .bind(key)
+            .bind(execution_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to get concurrency slot", err))?;
+        Ok(held.is_some())
+    }
+
+    async fn release_concurrency_slot(&self, key: &str, execution_id: &str) -> Result<(), DataStoreError> {
+        sqlx::query("DELETE FROM concurrency_slots WHERE concurrency_key = ? AND execution_id = ?")
+            .bind(key)
+            .bind(execution_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to release concurrency slot", err))?;
+        Ok(())
+    }
+
+    async fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), DataStoreError> {
+        let task_type = dead_letter.task.as_ref().map(|task| task.executor_name()).unwrap_or_default();
+        sqlx::query(
+            "INSERT INTO dead_letters (execution_id, task_type, dead_lettered_at, dead_letter) VALUES (?, ?, ?, ?)
+             ON CONFLICT (execution_id) DO UPDATE SET
+                 task_type = excluded.task_type,
+                 dead_lettered_at = excluded.dead_lettered_at,
+                 dead_letter = excluded.dead_letter",
+        )
+        .bind(&dead_letter.execution_id)
+        .bind(task_type)
+        .bind(dead_letter.dead_lettered_at)
+        .bind(dead_letter.encode_to_vec())
+        .execute(&self.pool)
+        .await
+        .map_err(|err| internal_error("Failed to add dead letter", err))?;
+        Ok(())
+    }
+
+    async fn get_dead_letter(&self, execution_id: &str) -> Result<DeadLetter, DataStoreError> {
+        let row = sqlx::query("SELECT dead_letter FROM dead_letters WHERE execution_id = ?")
+            .bind(execution_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to get dead letter", err))?
+            .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))?;
+        dead_letter_from_row(&row)
+    }
+
+    async fn list_dead_letters(&self, task_type: Option<&str>, page: &PageRequest) -> Result<Page<DeadLetter>, DataStoreError> {
+        let limit = page.limit.max(1);
+        let mut query = QueryBuilder::<Sqlite>::new(SELECT_DEAD_LETTERS);
+        push_task_type(&mut query, task_type);
+        if let Some(cursor) = page.cursor.as_deref().map(Cursor::decode).transpose()? {
+            query
+                .push(" AND (dead_lettered_at > ").push_bind(cursor.created_at)
+                .push(" OR (dead_lettered_at = ").push_bind(cursor.created_at)
+                .push(" AND execution_id > ").push_bind(cursor.execution_id)
+                .push("))");
+        }
+        // One extra row tells whether there is a next page
+        query.push(" ORDER BY dead_lettered_at, execution_id LIMIT ").push_bind((limit + 1) as i64);
+
+        let rows = query
+            .build()
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to list dead letters", err))?;
+
+        let mut items = rows.iter().map(dead_letter_from_row).collect::<Result<Vec<_>, _>>()?;
+        let next_cursor = if items.len() > limit {
+            items.truncate(limit);
+            items.last().map(|dead_letter| dead_letter.cursor().encode())
+        } else {
+            None
+        };
+
+        Ok(Page { items, next_cursor })
+    }
+
+    async fn take_dead_letter(&self, execution_id: &str) -> Result<DeadLetter, DataStoreError> {
+        let row = sqlx::query("DELETE FROM dead_letters WHERE execution_id = ? RETURNING dead_letter")
+            .bind(execution_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to take dead letter", err))?
+            .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))?;
+        dead_letter_from_row(&row)
+    }
+
+    async fn purge_dead_letters(&self, task_type: Option<&str>) -> Result<u64, DataStoreError> {
+        let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM dead_letters WHERE 1 = 1");
+        push_task_type(&mut query, task_type);
+
+        let result = query
+            .build()
+            .execute(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to purge dead letters", err))?;
+
+        Ok(result.rows_affected())
+    }
+
+    async fn add_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError> {
+        sqlx::query("INSERT INTO workflows (workflow_id, state, created_at, updated_at, workflow) VALUES (?, ?, ?, ?, ?)")
+            .bind(&workflow.workflow_id)
+            .bind(workflow.state)
+            .bind(workflow.created_at)
+            .bind(workflow.updated_at)
+            .bind(workflow.encode_to_vec())
+            .execute(&self.pool)
+            .await
+            .map_err(|err| match err {
+                sqlx::Error::Database(db_err) if db_err.is_unique_violation() => {
+                    DataStoreError::Duplicate(workflow.workflow_id.clone())
+                }
+                err => internal_error("Failed to add workflow", err),
+            })?;
+        Ok(())
+    }
+
+    async fn get_workflow(&self, workflow_id: &str) -> Result<WorkflowStatus, DataStoreError> {
+        let row = sqlx::query("SELECT workflow FROM workflows WHERE workflow_id = ?")
+            .bind(workflow_id)
+            .fetch_optional(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to get workflow", err))?
+            .ok_or_else(|| DataStoreError::NotFound(workflow_id.to_string()))?;
+        workflow_from_row(&row)
+    }
+
+    async fn update_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError> {
+        let result = sqlx::query("UPDATE workflows SET state = ?, updated_at = ?, workflow = ? WHERE workflow_id = ?")
+            .bind(workflow.state)
+            .bind(workflow.updated_at)
+            .bind(workflow.encode_to_vec())
+            .bind(&workflow.workflow_id)
+            .execute(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to update workflow", err))?;
+
+        if result.rows_affected() == 0 {
+            return Err(DataStoreError::NotFound(workflow.workflow_id.clone()));
+        }
+        Ok(())
+    }
+
+    async fn list_workflows(&self, state: WorkflowState) -> Result<Vec<WorkflowStatus>, DataStoreError> {
+        let rows = sqlx::query("SELECT workflow FROM workflows WHERE state = ? ORDER BY created_at, workflow_id")
+            .bind(i32::from(state))
+            .fetch_all(&self.pool)
+            .await
+            .map_err(|err| internal_error("Failed to list workflows", err))?;
+        rows.iter().map(workflow_from_row).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::protot::core::{Task, TaskError};
+
+    use super::*;
+
+    fn execution(task_type: &str, execution_id: &str, created_at: i64) -> ExecutionRecord {
+        let task = Task {
+            task_type: task_type.to_string(),
+            ..Default::default()
+        };
+        ExecutionRecord { created_at, ..ExecutionRecord::new(execution_id.to_string(), task) }
+    }
+
+    #[tokio::test]
+    async fn test_add_and_list_executions() {
+        let store = SqliteDataStore::in_memory().await.unwrap();
+        for (i, task_type) in ["task-1", "task-2", "task-1", "task-1"].into_iter().enumerate() {
+            store.add_task_execution(execution(task_type, &format!("exec-{}", i), i as i64)).await.unwrap();
+        }
+        assert_eq!(
+            store.add_task_execution(execution("task-1", "exec-0", 0)).await,
+            Err(DataStoreError::Duplicate("exec-0".to_string()))
+        );
+        assert_eq!(store.get_task_execution("exec-1").await.unwrap().task_type(), "task-2");
+        assert_eq!(store.get_task_execution("missing").await, Err(DataStoreError::NotFound("missing".to_string())));
+
+        let filter = ExecutionFilter::default().with_task_type("task-1");
+        let first = store.list_task_executions(&filter, &PageRequest::first(2)).await.unwrap();
+        assert_eq!(first.items.iter().map(|r| r.execution_id.as_str()).collect::<Vec<_>>(), vec!["exec-0", "exec-2"]);
+        let second = store
+            .list_task_executions(&filter, &PageRequest::after(first.next_cursor.expect("more pages"), 2))
+            .await
+            .unwrap();
+        assert_eq!(second.items.iter().map(|r| r.execution_id.as_str()).collect::<Vec<_>>(), vec!["exec-3"]);
+        assert_eq!(second.next_cursor, None);
+
+        let counts = store.count_task_executions_by_state(&ExecutionFilter::default()).await.unwrap();
+        assert_eq!(counts.get(&TaskState::Pending), Some(&4));
+    }
+
+    #[tokio::test]
+    async fn test_complete_task_execution_stores_error() {
+        let store = SqliteDataStore::in_memory().await.unwrap();
+        store.add_task_execution(execution("task-1", "exec-1", 0)).await.unwrap();
+        store.lease_task_execution("exec-1", "worker-1", current_timestamp() + 60, None).await.unwrap();
+
+        let error = TaskError::new("INVALID_PAYLOAD", "payload is missing");
+        let completion = TaskCompletion::from_result("task-1".to_string(), "exec-1".to_string(), Err(error.clone()));
+        store.complete_task_execution(&completion, Some("worker-1")).await.unwrap();
+
+        let failed = store.get_task_execution("exec-1").await.unwrap();
+        assert_eq!(failed.state, TaskState::Fail);
+        assert_eq!(failed.worker_id.as_deref(), Some("worker-1"));
+        assert_eq!(failed.output, None);
+        assert_eq!(failed.error, Some(error));
+    }
+
+    #[tokio::test]
+    async fn test_completion_after_lost_lease() {
+        let store = SqliteDataStore::in_memory().await.unwrap();
+        store.add_task_execution(execution("task-1", "exec-1", 0)).await.unwrap();
+        store.lease_task_execution("exec-1", "worker-2", current_timestamp() + 60, None).await.unwrap();
+
+        let completion = TaskCompletion::from_result("task-1".to_string(), "exec-1".to_string(), Ok(prost_types::Any::default()));
+        assert_eq!(
+            store.complete_task_execution(&completion, Some("worker-1")).await,
+            Err(DataStoreError::LeaseLost("exec-1".to_string()))
+        );
+        assert_eq!(
+            store.complete_task_execution(&TaskCompletion { execution_id: "exec-2".to_string(), ..completion.clone() }, Some("worker-1")).await,
+            Err(DataStoreError::NotFound("exec-2".to_string()))
+        );
+        assert_eq!(store.get_task_execution("exec-1").await.unwrap().state, TaskState::Running);
+
+        store.complete_task_execution(&completion, Some("worker-2")).await.unwrap();
+        assert_eq!(store.get_task_execution("exec-1").await.unwrap().state, TaskState::Success);
+    }
+
+    #[tokio::test]
+    async fn test_retries_append_attempts() {
+        let store = SqliteDataStore::in_memory().await.unwrap();
+        let mut record = execution("task-1", "exec-1", 0);
+        store.add_task_execution(record.clone()).await.unwrap();
+
+        let mut attempts = Vec::new();
+        for code in ["TIMEOUT", "UNAVAILABLE"] {
+            let attempt = record.failed_attempt(Some("worker-1"), Some(TaskError::new(code, "try again")));
+            store.retry_task_execution("exec-1", &attempt).await.unwrap();
+            attempts.push(attempt);
+            record = store.get_task_execution("exec-1").await.unwrap();
+        }
+        assert_eq!(record.state, TaskState::Pending);
+        assert_eq!(record.retries, 2);
+        assert_eq!(record.attempts, attempts);
+    }
+
+    #[tokio::test]
+    async fn test_leases() {
+        let store = SqliteDataStore::in_memory().await.unwrap();
+        store.add_task_execution(execution("task-1", "exec-1", 0)).await.unwrap();
+        store.add_task_execution(execution("task-1", "exec-2", 1)).await.unwrap();
+
+        store.lease_task_execution("exec-1", "worker-1", 100, None).await.unwrap();
+        let leased = store.get_task_execution("exec-1").await.unwrap();
+        assert_eq!((leased.state, leased.worker_id.as_deref()), (TaskState::Running, Some("worker-1")));
+        assert!(leased.lease_expired(101));
+
+        // Only the running executions of the worker are renewed
+        assert_eq!(store.renew_task_execution_leases("worker-1", 200).await.unwrap(), 1);
+        assert_eq!(store.get_task_execution("exec-1").await.unwrap().lease_expires_at, Some(200));
+        assert_eq!(store.renew_task_execution_leases("worker-2", 200).await.unwrap(), 0);
+        assert_eq!(
+            store.lease_task_execution("missing", "worker-1", 100, None).await,
+            Err(DataStoreError::NotFound("missing".to_string()))
+        );
+
+        let claimed = store.claim_task_executions("scheduler-1", 10).await.unwrap();
+        assert_eq!(claimed.iter().map(|r| r.execution_id.as_str()).collect::<Vec<_>>(), vec!["exec-2"]);
+        assert!(store.claim_task_executions("scheduler-1", 10).await.unwrap().is_empty());
+
+        // A new owner takes over the pending executions of the former one
+        let claimed = store.claim_task_executions("scheduler-2", 10).await.unwrap();
+        assert_eq!(claimed.iter().map(|r| r.execution_id.as_str()).collect::<Vec<_>>(), vec!["exec-2"]);
+        assert_eq!(store.get_task_execution("exec-2").await.unwrap().claimed_by.as_deref(), Some("scheduler-2"));
+    }
+
+    #[tokio::test]
+    async fn test_leader_lease_changes_hands() {
+        let store = SqliteDataStore::in_memory().await.unwrap();
+        let ttl = Duration::from_secs(60);
+
+        let lease = store.acquire_leader_lease("first", "http://first:44880", ttl).await.unwrap();
+        assert_eq!(lease.holder, "first");
+        assert_eq!(store.acquire_leader_lease("first", "http://first:44880", ttl).await.unwrap(), lease);
+
+        // The standby sees the current leader until the lease is released or expires
+        let standby = store.acquire_leader_lease("second", "http://second:44880", ttl).await.unwrap();
+        assert_eq!(standby, lease);
+
+        store.release_leader_lease("first").await.unwrap();
+        let takeover = store.acquire_leader_lease("second", "http://second:44880", ttl).await.unwrap();
+        assert_eq!(takeover.holder, "second");
+        assert_eq!(takeover.address, "http://second:44880");
+        assert!(takeover.token > lease.token);
+    }
+
+    #[tokio::test]
+    async fn test_former_leader_is_fenced_off() {
+        let store = SqliteDataStore::in_memory().await.unwrap();
+        let ttl = Duration::from_secs(60);
+        store.add_task_execution(execution("task-1", "exec-1", 0)).await.unwrap();
+
+        let former = store.acquire_leader_lease("first", "", ttl).await.unwrap();
+        store.release_leader_lease("first").await.unwrap();
+        let current = store.acquire_leader_lease("second", "", ttl).await.unwrap();
+
+        assert_eq!(
+            store.lease_task_execution("exec-1", "worker-1", 100, Some(former.token)).await,
+            Err(DataStoreError::Fenced(former.token))
+        );
+        assert_eq!(store.get_task_execution("exec-1").await.unwrap().state, TaskState::Pending);
+
+        store.lease_task_execution("exec-1", "worker-1", 100, Some(current.token)).await.unwrap();
+        assert_eq!(
+            store.requeue_task_execution("exec-1", former.token).await,
+            Err(DataStoreError::Fenced(former.token))
+        );
+        assert_eq!(store.get_task_execution("exec-1").await.unwrap().state, TaskState::Running);
+
+        store.requeue_task_execution("exec-1", current.token).await.unwrap();
+        let requeued = store.get_task_execution("exec-1").await.unwrap();
+        assert_eq!((requeued.state, requeued.lease_expires_at), (TaskState::Pending, None));
+    }
+}
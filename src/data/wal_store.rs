@@ -0,0 +1,1251 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use prost::Message;
+use prost_types::Any;
+use tokio::sync::oneshot;
+
+use crate::{
+    internal::protot::{core::{ExecutionAttempt, TaskError, TaskState, WorkflowState, WorkflowStatus}, scheduler::v1::{DeadLetter, ExecuteRequest, TaskCompletion}},
+    utils::current_timestamp,
+    SchedulerError,
+};
+
+use super::data_store::{
+    paginate, Cursor, DataStore, DataStoreError, ExecutionFilter, ExecutionRecord, Page, PageRequest, Paged, TokenBucket,
+};
+
+const LOG_FILE: &str = "executions.wal";
+const SNAPSHOT_FILE: &str = "executions.snapshot";
+const SNAPSHOT_TMP_FILE: &str = "executions.snapshot.tmp";
+
+/// Number of appended records after which the log is folded into a new snapshot.
+pub const DEFAULT_SNAPSHOT_INTERVAL: usize = 1000;
+
+/// Size of the `[length: u32][crc32: u32]` header preceding every record.
+const RECORD_HEADER_SIZE: usize = 8;
+
+/// A single log record, holding the full latest state of one execution
+/// (or of one idempotency key, when `idempotency_key` is set, of one concurrency
+/// slot, when `concurrency_key` is set, of one dead letter, when `dead_letter` is set, or of
+/// one workflow, when `workflow` is set).
+///
+/// Records are upserts (or deletions when `deleted` is set), so replaying the same record
+/// twice (e.g. after a crash between writing a snapshot and truncating the log) is harmless.
+#[derive(Clone, PartialEq, prost::Message)]
+struct WalEntry {
+    #[prost(string, tag = "1")]
+    execution_id: String,
+    #[prost(message, optional, tag = "2")]
+    request: Option<ExecuteRequest>,
+    #[prost(int32, tag = "3")]
+    state: i32,
+    #[prost(string, tag = "4")]
+    claimed_by: String,
+    #[prost(int64, tag = "5")]
+    created_at: i64,
+    #[prost(int64, tag = "6")]
+    updated_at: i64,
+    #[prost(string, tag = "7")]
+    worker_id: String,
+    #[prost(bool, tag = "8")]
+    deleted: bool,
+    #[prost(int64, tag = "9")]
+    lease_expires_at: i64,
+    #[prost(message, optional, tag = "10")]
+    output: Option<Any>,
+    #[prost(message, optional, tag = "11")]
+    error: Option<TaskError>,
+    /// Idempotency key held by `execution_id`.
+    #[prost(string, tag = "12")]
+    idempotency_key: String,
+    /// Unix timestamp (milliseconds) at which the idempotency key is free again.
+    #[prost(int64, tag = "13")]
+    key_expires_at: i64,
+    #[prost(uint32, tag = "14")]
+    retries: u32,
+    /// Concurrency key of which `execution_id` holds a slot.
+    #[prost(string, tag = "15")]
+    concurrency_key: String,
+    #[prost(message, repeated, tag = "16")]
+    attempts: Vec<ExecutionAttempt>,
+    /// Dead letter of `execution_id`.
+    #[prost(message, optional, tag = "17")]
+    dead_letter: Option<DeadLetter>,
+    #[prost(message, optional, tag = "18")]
+    workflow: Option<WorkflowStatus>,
+}
+
+impl WalEntry {
+    fn from_record(record: &ExecutionRecord) -> Self {
+        Self {
+            execution_id: record.execution_id.clone(),
+            request: Some(record.to_execute_request()),
+            state: record.state.into(),
+            claimed_by: record.claimed_by.clone().unwrap_or_default(),
+            created_at: record.created_at,
+            updated_at: record.updated_at,
+            worker_id: record.worker_id.clone().unwrap_or_default(),
+            deleted: false,
+            lease_expires_at: record.lease_expires_at.unwrap_or_default(),
+            output: record.output.clone(),
+            error: record.error.clone(),
+            retries: record.retries,
+            attempts: record.attempts.clone(),
+            ..Default::default()
+        }
+    }
+
+    /// A record deleting the execution `execution_id`.
+    fn tombstone(execution_id: &str) -> Self {
+        Self {
+            execution_id: execution_id.to_string(),
+            deleted: true,
+            ..Default::default()
+        }
+    }
+
+    /// A record giving `key` to `execution_id` until `expires_at`.
+    fn idempotency_key(key: &str, execution_id: &str, expires_at: i64) -> Self {
+        Self {
+            execution_id: execution_id.to_string(),
+            idempotency_key: key.to_string(),
+            key_expires_at: expires_at,
+            ..Default::default()
+        }
+    }
+
+    /// A record freeing the idempotency key `key`.
+    fn key_tombstone(key: &str) -> Self {
+        Self {
+            idempotency_key: key.to_string(),
+            deleted: true,
+            ..Default::default()
+        }
+    }
+
+    /// A record giving a slot of the concurrency key `key` to `execution_id`.
+    fn concurrency_slot(key: &str, execution_id: &str) -> Self {
+        Self {
+            execution_id: execution_id.to_string(),
+            concurrency_key: key.to_string(),
+            ..Default::default()
+        }
+    }
+
+    /// A record freeing the slot of the concurrency key `key` held by `execution_id`.
+    fn slot_tombstone(key: &str, execution_id: &str) -> Self {
+        Self {
+            deleted: true,
+            ..Self::concurrency_slot(key, execution_id)
+        }
+    }
+
+    /// A record storing `dead_letter`.
+    fn dead_letter(dead_letter: &DeadLetter) -> Self {
+        Self {
+            execution_id: dead_letter.execution_id.clone(),
+            dead_letter: Some(dead_letter.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// A record deleting the dead letter of `execution_id`.
+    fn dead_letter_tombstone(execution_id: &str) -> Self {
+        Self {
+            execution_id: execution_id.to_string(),
+            deleted: true,
+            dead_letter: Some(DeadLetter::default()),
+            ..Default::default()
+        }
+    }
+
+    /// A record storing the latest state of `workflow`.
+    fn workflow(workflow: &WorkflowStatus) -> Self {
+        Self {
+            workflow: Some(workflow.clone()),
+            ..Default::default()
+        }
+    }
+
+    /// Applies the record on top of `executions` or, for key records, `idempotency_keys`
+    /// and `concurrency_slots`, for dead letter records, `dead_letters` and for workflow
+    /// records, `workflows`.
+    fn apply(
+        self,
+        executions: &mut HashMap<String, WalEntry>,
+        idempotency_keys: &mut HashMap<String, WalEntry>,
+        concurrency_slots: &mut HashMap<(String, String), WalEntry>,
+        dead_letters: &mut HashMap<String, WalEntry>,
+        workflows: &mut HashMap<String, WalEntry>,
+    ) {
+        if let Some(workflow) = &self.workflow {
+            workflows.insert(workflow.workflow_id.clone(), self);
+            return;
+        }
+        if self.dead_letter.is_some() {
+            if self.deleted {
+                dead_letters.remove(&self.execution_id);
+            } else {
+                dead_letters.insert(self.execution_id.clone(), self);
+            }
+            return;
+        }
+        if !self.concurrency_key.is_empty() {
+            let slot = (self.concurrency_key.clone(), self.execution_id.clone());
+            if self.deleted {
+                concurrency_slots.remove(&slot);
+            } else {
+                concurrency_slots.insert(slot, self);
+            }
+            return;
+        }
+        let (table, id) = if self.idempotency_key.is_empty() {
+            (executions, self.execution_id.clone())
+        } else {
+            (idempotency_keys, self.idempotency_key.clone())
+        };
+        if self.deleted {
+            table.remove(&id);
+        } else {
+            table.insert(id, self);
+        }
+    }
+
+    fn to_record(&self) -> ExecutionRecord {
+        ExecutionRecord {
+            execution_id: self.execution_id.clone(),
+            task: self.request.as_ref().and_then(|request| request.task.clone()).unwrap_or_default(),
+            // Entries are only ever written from valid states
+            state: TaskState::from_i32(self.state).unwrap_or(TaskState::Pending),
+            worker_id: Some(self.worker_id.clone()).filter(|worker_id| !worker_id.is_empty()),
+            claimed_by: Some(self.claimed_by.clone()).filter(|claimed_by| !claimed_by.is_empty()),
+            created_at: self.created_at,
+            updated_at: self.updated_at,
+            lease_expires_at: Some(self.lease_expires_at).filter(|expires_at| *expires_at != 0),
+            output: self.output.clone(),
+            error: self.error.clone(),
+            retries: self.retries,
+            attempts: self.attempts.clone(),
+        }
+    }
+}
+
+struct WalState {
+    executions: HashMap<String, WalEntry>,
+    idempotency_keys: HashMap<String, WalEntry>,
+    /// Slots held by running executions, by concurrency key and execution id.
+    concurrency_slots: HashMap<(String, String), WalEntry>,
+    dead_letters: HashMap<String, WalEntry>,
+    workflows: HashMap<String, WalEntry>,
+    /// Rate limit buckets with their tokens and last update (milliseconds). Only one process
+    /// opens the store, so they are not logged and start full again after a restart.
+    rate_limits: HashMap<String, (f64, i64)>,
+    writer: mpsc::Sender<LogWrite>,
+    records_since_snapshot: usize,
+}
+
+/// Write handed to the log writer thread, acknowledged once it is on disk.
+enum LogWrite {
+    /// An encoded record to append to the log.
+    Append(Vec<u8>, oneshot::Sender<Result<(), String>>),
+    /// The encoded state to install as the new snapshot, truncating the log.
+    Snapshot(Vec<u8>, oneshot::Sender<Result<(), String>>),
+}
+
+/// Acknowledgement of the last write of a call, the writes before it are acknowledged first.
+#[must_use]
+#[derive(Default)]
+struct Synced(Option<oneshot::Receiver<Result<(), String>>>);
+
+impl Synced {
+    /// Waits for the writes of the call to be on disk.
+    async fn wait(self) -> Result<(), DataStoreError> {
+        match self.0 {
+            Some(ack) => ack.await.map_err(|_| writer_stopped())?.map_err(DataStoreError::InternalError),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Zero dependency durable `DataStore` that keeps executions in memory and records every
+/// change to an append-only, checksummed log inside the configured directory.
+///
+/// On startup the latest snapshot is loaded and the log is replayed on top of it, a torn
+/// record at the tail of the log (e.g. after a crash mid write) is discarded.
+/// Every `snapshot_interval` records the whole state is written to a fresh snapshot and
+/// the log is truncated, which keeps both replay time and disk usage bounded.
+///
+/// Changes are applied in memory under a lock and written by a dedicated thread, which
+/// syncs the records queued meanwhile together, calls wait for their writes without
+/// holding the lock or blocking the runtime.
+pub struct WalDataStore {
+    dir: PathBuf,
+    snapshot_interval: usize,
+    state: Mutex<WalState>,
+}
+
+impl WalDataStore {
+    /// Opens the store in `path` (a directory, created if missing) with the default snapshot interval.
+    pub async fn new(path: &str) -> Result<Self, SchedulerError> {
+        let cleaned_path = path.replace('"', "");
+        Self::open(cleaned_path, DEFAULT_SNAPSHOT_INTERVAL)
+    }
+
+    /// Opens the store in `dir`, replaying the snapshot and log found there.
+    pub fn open<P: AsRef<Path>>(dir: P, snapshot_interval: usize) -> Result<Self, SchedulerError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)
+            .map_err(|err| SchedulerError::DataLayerError(format!("Unable to create data directory: {} {:?}", dir.display(), err)))?;
+
+        let mut executions = HashMap::new();
+        let mut idempotency_keys = HashMap::new();
+        let mut concurrency_slots = HashMap::new();
+        let mut dead_letters = HashMap::new();
+        let mut workflows = HashMap::new();
+
+        let snapshot_path = dir.join(SNAPSHOT_FILE);
+        if snapshot_path.exists() {
+            let bytes = fs::read(&snapshot_path)
+                .map_err(|err| SchedulerError::DataLayerError(format!("Unable to read snapshot: {:?}", err)))?;
+            let (entries, valid_len) = decode_records(&bytes);
+            if valid_len != bytes.len() {
+                // Snapshots are renamed into place only once fully synced, so this is real corruption
+                return Err(SchedulerError::DataLayerError(format!(
+                    "Corrupted snapshot at byte {}: {}",
+                    valid_len,
+                    snapshot_path.display()
+                )));
+            }
+            for entry in entries {
+                entry.apply(&mut executions, &mut idempotency_keys, &mut concurrency_slots, &mut dead_letters, &mut workflows);
+            }
+        }
+
+        let log_path = dir.join(LOG_FILE);
+        let bytes = match fs::read(&log_path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => Err(SchedulerError::DataLayerError(format!("Unable to read log: {:?}", err)))?,
+        };
+        let (entries, valid_len) = decode_records(&bytes);
+        let records_since_snapshot = entries.len();
+        for entry in entries {
+            entry.apply(&mut executions, &mut idempotency_keys, &mut concurrency_slots, &mut dead_letters, &mut workflows);
+        }
+
+        let log = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|err| SchedulerError::DataLayerError(format!("Unable to open log: {} {:?}", log_path.display(), err)))?;
+
+        if valid_len != bytes.len() {
+            warn!(
+                "discarding {} bytes of torn or corrupted records at the tail of {}",
+                bytes.len() - valid_len,
+                log_path.display()
+            );
+            log.set_len(valid_len as u64)
+                .map_err(|err| SchedulerError::DataLayerError(format!("Unable to truncate log: {:?}", err)))?;
+        }
+
+        let (writer, writes) = mpsc::channel();
+        let writer_dir = dir.clone();
+        thread::Builder::new()
+            .name("wal-writer".to_string())
+            .spawn(move || run_log_writer(writer_dir, log, writes))
+            .map_err(|err| SchedulerError::DataLayerError(format!("Unable to start log writer: {:?}", err)))?;
+
+        debug!("replayed {} task executions from {}", executions.len(), dir.display());
+
+        Ok(Self {
+            dir,
+            snapshot_interval: snapshot_interval.max(1),
+            state: Mutex::new(WalState {
+                executions,
+                idempotency_keys,
+                concurrency_slots,
+                dead_letters,
+                workflows,
+                rate_limits: HashMap::new(),
+                writer,
+                records_since_snapshot,
+            }),
+        })
+    }
+
+    fn lock_state(&self) -> Result<std::sync::MutexGuard<'_, WalState>, DataStoreError> {
+        self.state.lock()
+            .map_err(|_| DataStoreError::InternalError("WAL state lock poisoned".to_string()))
+    }
+
+    /// Applies `entry` to the in memory state and queues it for the log writer.
+    ///
+    /// The change is visible to the next calls right away, so their checks (e.g. for a
+    /// duplicate execution) see it, and durable once the returned `Synced` resolves.
+    fn append(&self, state: &mut WalState, entry: WalEntry) -> Result<Synced, DataStoreError> {
+        let (ack, synced) = oneshot::channel();
+        state.writer.send(LogWrite::Append(encode_record(&entry), ack))
+            .map_err(|_| writer_stopped())?;
+
+        entry.apply(
+            &mut state.executions,
+            &mut state.idempotency_keys,
+            &mut state.concurrency_slots,
+            &mut state.dead_letters,
+            &mut state.workflows,
+        );
+        state.records_since_snapshot += 1;
+
+        if state.records_since_snapshot >= self.snapshot_interval {
+            return self.compact(state);
+        }
+
+        Ok(Synced(Some(synced)))
+    }
+
+    /// Queues a snapshot of the current state, which truncates the log once written.
+    fn compact(&self, state: &mut WalState) -> Result<Synced, DataStoreError> {
+        // Expired idempotency keys are free anyway, so they are left out
+        let now = chrono::Utc::now().timestamp_millis();
+        state.idempotency_keys.retain(|_, entry| entry.key_expires_at > now);
+
+        let mut snapshot = Vec::new();
+        let entries = state.executions.values()
+            .chain(state.idempotency_keys.values())
+            .chain(state.concurrency_slots.values())
+            .chain(state.dead_letters.values())
+            .chain(state.workflows.values());
+        for entry in entries {
+            snapshot.extend(encode_record(entry));
+        }
+
+        let (ack, synced) = oneshot::channel();
+        state.writer.send(LogWrite::Snapshot(snapshot, ack))
+            .map_err(|_| writer_stopped())?;
+        state.records_since_snapshot = 0;
+
+        debug!("compacting {} task executions into a snapshot of {}", state.executions.len(), self.dir.display());
+        Ok(Synced(Some(synced)))
+    }
+
+    /// Executions matching `filter`, oldest first.
+    fn executions_matching(&self, filter: &ExecutionFilter) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+        let state = self.lock_state()?;
+
+        let mut records: Vec<ExecutionRecord> = state.executions
+            .values()
+            .map(WalEntry::to_record)
+            .filter(|record| filter.matches(record))
+            .collect();
+        records.sort_by_key(Cursor::of);
+
+        Ok(records)
+    }
+
+    /// Dead letters of `task_type` (all of them when `None`), oldest first.
+    fn dead_letters_of(&self, task_type: Option<&str>) -> Result<Vec<DeadLetter>, DataStoreError> {
+        let state = self.lock_state()?;
+
+        let mut dead_letters: Vec<DeadLetter> = state.dead_letters
+            .values()
+            .filter_map(|entry| entry.dead_letter.clone())
+            .filter(|dead_letter| {
+                task_type.is_none_or(|task_type| dead_letter.task.as_ref().map(|task| task.executor_name()) == Some(task_type))
+            })
+            .collect();
+        dead_letters.sort_by_key(Paged::cursor);
+
+        Ok(dead_letters)
+    }
+}
+
+#[async_trait]
+impl DataStore for WalDataStore {
+    async fn add_task_execution(&self, record: ExecutionRecord) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            if state.executions.contains_key(&record.execution_id) {
+                return Err(DataStoreError::Duplicate(record.execution_id));
+            }
+            self.append(&mut state, WalEntry::from_record(&record))?
+        };
+        synced.wait().await
+    }
+
+    async fn get_task_execution(&self, execution_id: &str) -> Result<ExecutionRecord, DataStoreError> {
+        self.lock_state()?
+            .executions
+            .get(execution_id)
+            .map(WalEntry::to_record)
+            .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))
+    }
+
+    async fn list_task_executions(&self, filter: &ExecutionFilter, page: &PageRequest) -> Result<Page<ExecutionRecord>, DataStoreError> {
+        paginate(self.executions_matching(filter)?, page)
+    }
+
+    async fn count_task_executions_by_state(&self, filter: &ExecutionFilter) -> Result<HashMap<TaskState, u64>, DataStoreError> {
+        let mut counts = HashMap::new();
+        for record in self.executions_matching(filter)? {
+            *counts.entry(record.state).or_insert(0) += 1;
+        }
+        Ok(counts)
+    }
+
+    async fn update_task_execution_state(&self, execution_id: &str, new_state: TaskState, worker_id: Option<&str>) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            let mut entry = state.executions
+                .get(execution_id)
+                .cloned()
+                .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))?;
+
+            entry.state = new_state.into();
+            if let Some(worker_id) = worker_id {
+                entry.worker_id = worker_id.to_string();
+            }
+            entry.lease_expires_at = 0;
+            entry.output = None;
+            entry.error = None;
+            entry.updated_at = current_timestamp();
+            self.append(&mut state, entry)?
+        };
+        synced.wait().await
+    }
+
+    async fn complete_task_execution(&self, completion: &TaskCompletion, worker_id: Option<&str>) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            let mut entry = state.executions
+                .get(&completion.execution_id)
+                .cloned()
+                .ok_or_else(|| DataStoreError::NotFound(completion.execution_id.clone()))?;
+            let leased = entry.state == i32::from(TaskState::Running) && Some(entry.worker_id.as_str()) == worker_id;
+            if worker_id.is_some() && !leased {
+                return Err(DataStoreError::LeaseLost(completion.execution_id.clone()));
+            }
+
+            entry.state = completion.state;
+            if let Some(worker_id) = worker_id {
+                entry.worker_id = worker_id.to_string();
+            }
+            entry.lease_expires_at = 0;
+            entry.output = completion.output.clone();
+            entry.error = completion.error.clone();
+            entry.updated_at = current_timestamp();
+            self.append(&mut state, entry)?
+        };
+        synced.wait().await
+    }
+
+    async fn retry_task_execution(&self, execution_id: &str, attempt: &ExecutionAttempt) -> Result<u32, DataStoreError> {
+        let (retries, synced) = {
+            let mut state = self.lock_state()?;
+            let mut entry = state.executions
+                .get(execution_id)
+                .cloned()
+                .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))?;
+
+            entry.state = TaskState::Pending.into();
+            entry.lease_expires_at = 0;
+            entry.output = None;
+            entry.error = attempt.error.clone();
+            entry.attempts.push(attempt.clone());
+            entry.retries += 1;
+            entry.updated_at = current_timestamp();
+            (entry.retries, self.append(&mut state, entry)?)
+        };
+        synced.wait().await?;
+        Ok(retries)
+    }
+
+    // Only one process opens the log, there is no former leader to fence off
+    async fn lease_task_execution(&self, execution_id: &str, worker_id: &str, lease_expires_at: i64, _fencing_token: Option<u64>) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            let mut entry = state.executions
+                .get(execution_id)
+                .cloned()
+                .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))?;
+
+            entry.state = TaskState::Running.into();
+            entry.worker_id = worker_id.to_string();
+            entry.lease_expires_at = lease_expires_at;
+            entry.updated_at = current_timestamp();
+            self.append(&mut state, entry)?
+        };
+        synced.wait().await
+    }
+
+    async fn requeue_task_execution(&self, execution_id: &str, _fencing_token: u64) -> Result<(), DataStoreError> {
+        self.update_task_execution_state(execution_id, TaskState::Pending, None).await
+    }
+
+    async fn renew_task_execution_leases(&self, worker_id: &str, lease_expires_at: i64) -> Result<u64, DataStoreError> {
+        let running = i32::from(TaskState::Running);
+        let (renewed, synced) = {
+            let mut state = self.lock_state()?;
+
+            let leased: Vec<WalEntry> = state.executions
+                .values()
+                .filter(|entry| entry.state == running && entry.worker_id == worker_id)
+                .cloned()
+                .collect();
+            let mut synced = Synced::default();
+            for mut entry in leased.iter().cloned() {
+                entry.lease_expires_at = lease_expires_at;
+                synced = self.append(&mut state, entry)?;
+            }
+            (leased.len() as u64, synced)
+        };
+        synced.wait().await?;
+        Ok(renewed)
+    }
+
+    async fn purge_task_executions(&self, filter: &ExecutionFilter) -> Result<u64, DataStoreError> {
+        let (purged, synced) = {
+            let mut state = self.lock_state()?;
+
+            let purged: Vec<String> = state.executions
+                .values()
+                .filter(|entry| filter.matches(&entry.to_record()))
+                .map(|entry| entry.execution_id.clone())
+                .collect();
+            let mut synced = Synced::default();
+            for execution_id in &purged {
+                synced = self.append(&mut state, WalEntry::tombstone(execution_id))?;
+            }
+            (purged.len() as u64, synced)
+        };
+        synced.wait().await?;
+        Ok(purged)
+    }
+
+    async fn claim_task_executions(&self, owner: &str, limit: usize) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+        let pending = i32::from(TaskState::Pending);
+        let (claimed, synced) = {
+            let mut state = self.lock_state()?;
+
+            let mut candidates: Vec<WalEntry> = state.executions
+                .values()
+                .filter(|entry| entry.state == pending && entry.claimed_by != owner)
+                .cloned()
+                .collect();
+            candidates.sort_by(|a, b| (a.created_at, &a.execution_id).cmp(&(b.created_at, &b.execution_id)));
+
+            let mut claimed = Vec::new();
+            let mut synced = Synced::default();
+            for mut entry in candidates.into_iter().take(limit) {
+                entry.claimed_by = owner.to_string();
+                entry.updated_at = current_timestamp();
+                claimed.push(entry.to_record());
+                synced = self.append(&mut state, entry)?;
+            }
+            (claimed, synced)
+        };
+        synced.wait().await?;
+        Ok(claimed)
+    }
+
+    async fn claim_idempotency_key(&self, key: &str, execution_id: &str, window: Duration) -> Result<String, DataStoreError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let synced = {
+            let mut state = self.lock_state()?;
+            if let Some(entry) = state.idempotency_keys.get(key).filter(|entry| entry.key_expires_at > now) {
+                return Ok(entry.execution_id.clone());
+            }
+
+            let expires_at = now + window.as_millis() as i64;
+            self.append(&mut state, WalEntry::idempotency_key(key, execution_id, expires_at))?
+        };
+        synced.wait().await?;
+        Ok(execution_id.to_string())
+    }
+
+    async fn release_idempotency_key(&self, key: &str, execution_id: &str) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            let held = state.idempotency_keys.get(key).is_some_and(|entry| entry.execution_id == execution_id);
+            match held {
+                true => self.append(&mut state, WalEntry::key_tombstone(key))?,
+                false => Synced::default(),
+            }
+        };
+        synced.wait().await
+    }
+
+    async fn take_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<Option<(usize, Duration)>, DataStoreError> {
+        let now = chrono::Utc::now().timestamp_millis();
+        let mut state = self.lock_state()?;
+        let mut taken = Vec::with_capacity(buckets.len());
+        for (position, (key, bucket)) in buckets.iter().enumerate() {
+            let last = state.rate_limits.get(key).copied();
+            match bucket.take(last.map(|(tokens, _)| tokens), last.map_or(0, |(_, updated_at)| now - updated_at)) {
+                (_, Some(wait)) => return Ok(Some((position, wait))),
+                (tokens, None) => taken.push((key.clone(), tokens)),
+            }
+        }
+        for (key, tokens) in taken {
+            state.rate_limits.insert(key, (tokens, now));
+        }
+        Ok(None)
+    }
+
+    async fn return_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<(), DataStoreError> {
+        let mut state = self.lock_state()?;
+        for (key, bucket) in buckets {
+            // A bucket without state is full already
+            if let Some((tokens, _)) = state.rate_limits.get_mut(key) {
+                *tokens = (*tokens + 1.0).min(bucket.capacity);
+            }
+        }
+        Ok(())
+    }
+
+    async fn acquire_concurrency_slot(&self, key: &str, execution_id: &str, limit: u32) -> Result<bool, DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            if state.concurrency_slots.contains_key(&(key.to_string(), execution_id.to_string())) {
+                return Ok(true);
+            }
+            let held = state.concurrency_slots.keys().filter(|(slot_key, _)| slot_key == key).count();
+            if held >= limit as usize {
+                return Ok(false);
+            }
+            self.append(&mut state, WalEntry::concurrency_slot(key, execution_id))?
+        };
+        synced.wait().await?;
+        Ok(true)
+    }
+
+    async fn release_concurrency_slot(&self, key: &str, execution_id: &str) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            match state.concurrency_slots.contains_key(&(key.to_string(), execution_id.to_string())) {
+                true => self.append(&mut state, WalEntry::slot_tombstone(key, execution_id))?,
+                false => Synced::default(),
+            }
+        };
+        synced.wait().await
+    }
+
+    async fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            self.append(&mut state, WalEntry::dead_letter(dead_letter))?
+        };
+        synced.wait().await
+    }
+
+    async fn get_dead_letter(&self, execution_id: &str) -> Result<DeadLetter, DataStoreError> {
+        self.lock_state()?
+            .dead_letters
+            .get(execution_id)
+            .and_then(|entry| entry.dead_letter.clone())
+            .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))
+    }
+
+    async fn list_dead_letters(&self, task_type: Option<&str>, page: &PageRequest) -> Result<Page<DeadLetter>, DataStoreError> {
+        paginate(self.dead_letters_of(task_type)?, page)
+    }
+
+    async fn take_dead_letter(&self, execution_id: &str) -> Result<DeadLetter, DataStoreError> {
+        let (dead_letter, synced) = {
+            let mut state = self.lock_state()?;
+            let dead_letter = state.dead_letters
+                .get(execution_id)
+                .and_then(|entry| entry.dead_letter.clone())
+                .ok_or_else(|| DataStoreError::NotFound(execution_id.to_string()))?;
+            (dead_letter, self.append(&mut state, WalEntry::dead_letter_tombstone(execution_id))?)
+        };
+        synced.wait().await?;
+        Ok(dead_letter)
+    }
+
+    async fn purge_dead_letters(&self, task_type: Option<&str>) -> Result<u64, DataStoreError> {
+        let purged = self.dead_letters_of(task_type)?;
+        let synced = {
+            let mut state = self.lock_state()?;
+            let mut synced = Synced::default();
+            for dead_letter in &purged {
+                synced = self.append(&mut state, WalEntry::dead_letter_tombstone(&dead_letter.execution_id))?;
+            }
+            synced
+        };
+        synced.wait().await?;
+        Ok(purged.len() as u64)
+    }
+
+    async fn add_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            if state.workflows.contains_key(&workflow.workflow_id) {
+                return Err(DataStoreError::Duplicate(workflow.workflow_id.clone()));
+            }
+            self.append(&mut state, WalEntry::workflow(workflow))?
+        };
+        synced.wait().await
+    }
+
+    async fn get_workflow(&self, workflow_id: &str) -> Result<WorkflowStatus, DataStoreError> {
+        self.lock_state()?
+            .workflows
+            .get(workflow_id)
+            .and_then(|entry| entry.workflow.clone())
+            .ok_or_else(|| DataStoreError::NotFound(workflow_id.to_string()))
+    }
+
+    async fn update_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError> {
+        let synced = {
+            let mut state = self.lock_state()?;
+            if !state.workflows.contains_key(&workflow.workflow_id) {
+                return Err(DataStoreError::NotFound(workflow.workflow_id.clone()));
+            }
+            self.append(&mut state, WalEntry::workflow(workflow))?
+        };
+        synced.wait().await
+    }
+
+    async fn list_workflows(&self, state: WorkflowState) -> Result<Vec<WorkflowStatus>, DataStoreError> {
+        let mut workflows: Vec<WorkflowStatus> = self.lock_state()?
+            .workflows
+            .values()
+            .filter_map(|entry| entry.workflow.clone())
+            .filter(|workflow| workflow.state() == state)
+            .collect();
+        workflows.sort_by(|a, b| (a.created_at, &a.workflow_id).cmp(&(b.created_at, &b.workflow_id)));
+        Ok(workflows)
+    }
+}
+
+fn writer_stopped() -> DataStoreError {
+    DataStoreError::InternalError("WAL writer stopped".to_string())
+}
+
+/// Writes the records queued by the store to `log`, with one sync for all the records
+/// queued while the previous ones were written, until the store is dropped.
+///
+/// Once a write failed the log may be missing records, so every later write fails too.
+fn run_log_writer(dir: PathBuf, mut log: File, writes: mpsc::Receiver<LogWrite>) {
+    let mut failed: Option<String> = None;
+    let mut appended = Vec::new();
+    while let Ok(write) = writes.recv() {
+        for write in std::iter::once(write).chain(writes.try_iter()) {
+            match write {
+                LogWrite::Append(record, ack) => {
+                    if failed.is_none() {
+                        if let Err(err) = log.write_all(&record) {
+                            failed = Some(format!("Failed to append to log: {:?}", err));
+                        }
+                    }
+                    appended.push(ack);
+                }
+                LogWrite::Snapshot(snapshot, ack) => {
+                    sync_log(&log, &mut failed, &mut appended);
+                    if failed.is_none() {
+                        failed = install_snapshot(&dir, &log, &snapshot).err();
+                    }
+                    let _ = ack.send(failed.clone().map_or(Ok(()), Err));
+                }
+            }
+        }
+        sync_log(&log, &mut failed, &mut appended);
+    }
+}
+
+/// Syncs the records appended to `log` and acknowledges them.
+fn sync_log(log: &File, failed: &mut Option<String>, appended: &mut Vec<oneshot::Sender<Result<(), String>>>) {
+    if appended.is_empty() {
+        return;
+    }
+    if failed.is_none() {
+        if let Err(err) = log.sync_data() {
+            *failed = Some(format!("Failed to sync log: {:?}", err));
+        }
+    }
+    for ack in appended.drain(..) {
+        let _ = ack.send(failed.clone().map_or(Ok(()), Err));
+    }
+}
+
+/// Writes `snapshot` next to the current one, swaps them and truncates `log`.
+fn install_snapshot(dir: &Path, log: &File, snapshot: &[u8]) -> Result<(), String> {
+    let tmp_path = dir.join(SNAPSHOT_TMP_FILE);
+    let snapshot_path = dir.join(SNAPSHOT_FILE);
+
+    let mut tmp = File::create(&tmp_path).map_err(|err| format!("Unable to create snapshot: {:?}", err))?;
+    tmp.write_all(snapshot)
+        .and_then(|_| tmp.sync_all())
+        .map_err(|err| format!("Failed to write snapshot: {:?}", err))?;
+    fs::rename(&tmp_path, &snapshot_path).map_err(|err| format!("Failed to install snapshot: {:?}", err))?;
+
+    log.set_len(0)
+        .and_then(|_| log.sync_all())
+        .map_err(|err| format!("Failed to truncate log: {:?}", err))?;
+
+    debug!("installed snapshot {}", snapshot_path.display());
+    Ok(())
+}
+
+fn encode_record(entry: &WalEntry) -> Vec<u8> {
+    let payload = entry.encode_to_vec();
+    let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + payload.len());
+    record.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    record.extend_from_slice(&crc32(&payload).to_le_bytes());
+    record.extend_from_slice(&payload);
+    record
+}
+
+/// Decodes consecutive records, stopping at the first truncated or corrupted one.
+///
+/// Returns the decoded entries and the length of the valid prefix of `bytes`.
+fn decode_records(bytes: &[u8]) -> (Vec<WalEntry>, usize) {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+
+    while bytes.len() - offset >= RECORD_HEADER_SIZE {
+        let len = u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap()) as usize;
+        let checksum = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap());
+        let start = offset + RECORD_HEADER_SIZE;
+        if bytes.len() - start < len {
+            break;
+        }
+
+        let payload = &bytes[start..start + len];
+        if crc32(payload) != checksum {
+            break;
+        }
+        match WalEntry::decode(payload) {
+            Ok(entry) => entries.push(entry),
+            Err(_) => break,
+        }
+        offset = start + len;
+    }
+
+    (entries, offset)
+}
+
+/// CRC-32 (IEEE 802.3) checksum.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFF_FFFFu32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::internal::protot::core::Task;
+
+    use super::*;
+
+    fn execution(task_type: &str, execution_id: &str) -> ExecutionRecord {
+        let task = Task {
+            id: format!("{}-{}", task_type, execution_id),
+            task_type: task_type.to_string(),
+            ..Default::default()
+        };
+        ExecutionRecord::new(execution_id.to_string(), task)
+    }
+
+    fn output() -> Any {
+        Any { type_url: "type.googleapis.com/google.protobuf.StringValue".to_string(), value: b"done".to_vec() }
+    }
+
+    async fn executions_in_state(store: &WalDataStore, state: TaskState) -> Vec<ExecutionRecord> {
+        store
+            .list_all_task_executions(&ExecutionFilter::default().with_state(state))
+            .await
+            .unwrap()
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+            store.add_task_execution(execution("task-1", "exec-1")).await.unwrap();
+            store.add_task_execution(execution("task-2", "exec-2")).await.unwrap();
+            store.lease_task_execution("exec-1", "worker-1", current_timestamp() + 60, None).await.unwrap();
+            let completion = TaskCompletion::from_result("task-1".to_string(), "exec-1".to_string(), Ok(output()));
+            store.complete_task_execution(&completion, Some("worker-1")).await.unwrap();
+        }
+
+        let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+        let pending = executions_in_state(&store, TaskState::Pending).await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].execution_id, "exec-2");
+
+        let succeeded = store.get_task_execution("exec-1").await.unwrap();
+        assert_eq!(succeeded.state, TaskState::Success);
+        assert_eq!(succeeded.worker_id.as_deref(), Some("worker-1"));
+        assert_eq!(succeeded.output, Some(output()));
+        assert_eq!(succeeded.error, None);
+        assert_eq!(
+            store.add_task_execution(execution("task-1", "exec-1")).await,
+            Err(DataStoreError::Duplicate("exec-1".to_string()))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_completion_after_lost_lease() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+        store.add_task_execution(execution("task-1", "exec-1")).await.unwrap();
+        let completion = TaskCompletion::from_result("task-1".to_string(), "exec-1".to_string(), Ok(output()));
+
+        // Not leased yet, then leased to another worker
+        assert_eq!(
+            store.complete_task_execution(&completion, Some("worker-1")).await,
+            Err(DataStoreError::LeaseLost("exec-1".to_string()))
+        );
+        store.lease_task_execution("exec-1", "worker-2", current_timestamp() + 60, None).await.unwrap();
+        assert_eq!(
+            store.complete_task_execution(&completion, Some("worker-1")).await,
+            Err(DataStoreError::LeaseLost("exec-1".to_string()))
+        );
+        assert_eq!(store.get_task_execution("exec-1").await.unwrap().state, TaskState::Running);
+
+        store.complete_task_execution(&completion, Some("worker-2")).await.unwrap();
+        assert_eq!(store.get_task_execution("exec-1").await.unwrap().state, TaskState::Success);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_appends_survive_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = std::sync::Arc::new(WalDataStore::open(&dir, 50).unwrap());
+            let writers = (0..8).map(|writer| {
+                let store = store.clone();
+                tokio::spawn(async move {
+                    for i in 0..20 {
+                        store.add_task_execution(execution("task", &format!("exec-{}-{}", writer, i))).await.unwrap();
+                    }
+                })
+            });
+            for writer in writers.collect::<Vec<_>>() {
+                writer.await.unwrap();
+            }
+        }
+
+        let store = WalDataStore::open(&dir, 50).unwrap();
+        assert_eq!(executions_in_state(&store, TaskState::Pending).await.len(), 160);
+    }
+
+    #[tokio::test]
+    async fn test_compaction_keeps_state() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = WalDataStore::open(&dir, 3).unwrap();
+            for i in 0..5 {
+                store.add_task_execution(execution("task", &format!("exec-{}", i))).await.unwrap();
+            }
+            store.update_task_execution_state("exec-0", TaskState::Fail, None).await.unwrap();
+        }
+        assert!(dir.path().join(SNAPSHOT_FILE).exists());
+
+        let store = WalDataStore::open(&dir, 3).unwrap();
+        let counts = store.count_task_executions_by_state(&ExecutionFilter::default()).await.unwrap();
+        assert_eq!(counts.get(&TaskState::Pending), Some(&4));
+        assert_eq!(counts.get(&TaskState::Fail), Some(&1));
+    }
+
+    #[tokio::test]
+    async fn test_purge_survives_restart() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+            store.add_task_execution(execution("task-1", "exec-1")).await.unwrap();
+            store.add_task_execution(execution("task-2", "exec-2")).await.unwrap();
+            let purged = store
+                .purge_task_executions(&ExecutionFilter::default().with_task_type("task-1"))
+                .await
+                .unwrap();
+            assert_eq!(purged, 1);
+        }
+
+        let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+        assert_eq!(store.get_task_execution("exec-1").await, Err(DataStoreError::NotFound("exec-1".to_string())));
+        assert!(store.get_task_execution("exec-2").await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_idempotency_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let window = Duration::from_secs(60);
+        {
+            let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+            assert_eq!(store.claim_idempotency_key("key-1", "exec-1", window).await.unwrap(), "exec-1");
+            assert_eq!(store.claim_idempotency_key("key-1", "exec-2", window).await.unwrap(), "exec-1");
+
+            // Only the holder releases the key
+            store.release_idempotency_key("key-2", "exec-3").await.unwrap();
+            store.claim_idempotency_key("key-2", "exec-3", window).await.unwrap();
+            store.release_idempotency_key("key-2", "exec-4").await.unwrap();
+            assert_eq!(store.claim_idempotency_key("key-2", "exec-4", window).await.unwrap(), "exec-3");
+            store.release_idempotency_key("key-2", "exec-3").await.unwrap();
+
+            // An expired key is taken over by the next submission
+            store.claim_idempotency_key("key-3", "exec-5", Duration::from_millis(1)).await.unwrap();
+            tokio::time::sleep(Duration::from_millis(5)).await;
+            assert_eq!(store.claim_idempotency_key("key-3", "exec-6", window).await.unwrap(), "exec-6");
+        }
+
+        // Keys survive a restart
+        let store = WalDataStore::open(&dir, 1).unwrap();
+        assert_eq!(store.claim_idempotency_key("key-1", "exec-7", window).await.unwrap(), "exec-1");
+        assert_eq!(store.claim_idempotency_key("key-2", "exec-8", window).await.unwrap(), "exec-8");
+        assert_eq!(store.claim_idempotency_key("key-3", "exec-9", window).await.unwrap(), "exec-6");
+        // No key shows up as an execution
+        assert!(store.list_all_task_executions(&ExecutionFilter::default()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_concurrency_slots() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+            assert!(store.acquire_concurrency_slot("customer-1", "exec-1", 1).await.unwrap());
+            // Taking a held slot again succeeds, others wait until it is released
+            assert!(store.acquire_concurrency_slot("customer-1", "exec-1", 1).await.unwrap());
+            assert!(!store.acquire_concurrency_slot("customer-1", "exec-2", 1).await.unwrap());
+            assert!(store.acquire_concurrency_slot("customer-2", "exec-3", 1).await.unwrap());
+            store.release_concurrency_slot("customer-2", "exec-3").await.unwrap();
+        }
+
+        // Slots survive a restart
+        let store = WalDataStore::open(&dir, 1).unwrap();
+        assert!(!store.acquire_concurrency_slot("customer-1", "exec-2", 1).await.unwrap());
+        assert!(store.acquire_concurrency_slot("customer-2", "exec-4", 1).await.unwrap());
+        store.release_concurrency_slot("customer-1", "exec-1").await.unwrap();
+        assert!(store.acquire_concurrency_slot("customer-1", "exec-2", 1).await.unwrap());
+        assert!(store.list_all_task_executions(&ExecutionFilter::default()).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_dead_letters() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+            let mut record = execution("email", "exec-1");
+            store.add_task_execution(record.clone()).await.unwrap();
+            let first = record.failed_attempt(Some("worker-1"), Some(TaskError { code: "TIMEOUT".to_string(), ..Default::default() }));
+            assert_eq!(store.retry_task_execution("exec-1", &first).await.unwrap(), 1);
+            record = store.get_task_execution("exec-1").await.unwrap();
+            assert_eq!(record.attempts, vec![first]);
+
+            let last = record.failed_attempt(Some("worker-2"), Some(TaskError { code: "TIMEOUT".to_string(), ..Default::default() }));
+            store.add_dead_letter(&record.to_dead_letter(last)).await.unwrap();
+            store.add_dead_letter(&execution("sms", "exec-2").to_dead_letter(Default::default())).await.unwrap();
+            store.add_dead_letter(&execution("sms", "exec-3").to_dead_letter(Default::default())).await.unwrap();
+            assert_eq!(store.purge_dead_letters(Some("sms")).await.unwrap(), 2);
+        }
+
+        // Dead letters survive a restart
+        let store = WalDataStore::open(&dir, 1).unwrap();
+        let page = store.list_dead_letters(None, &PageRequest::default()).await.unwrap();
+        assert_eq!(page.items.len(), 1);
+        assert_eq!(page.items[0].attempts.len(), 2);
+        assert_eq!(page.items[0].attempts[1].worker_id, "worker-2");
+        assert!(store.list_dead_letters(Some("sms"), &PageRequest::default()).await.unwrap().items.is_empty());
+
+        // A dead letter is taken once
+        assert_eq!(store.take_dead_letter("exec-1").await.unwrap(), page.items[0]);
+        assert_eq!(store.take_dead_letter("exec-1").await, Err(DataStoreError::NotFound("exec-1".to_string())));
+        assert_eq!(store.get_dead_letter("exec-1").await, Err(DataStoreError::NotFound("exec-1".to_string())));
+    }
+
+    #[tokio::test]
+    async fn test_torn_tail_is_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        {
+            let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+            store.add_task_execution(execution("task", "exec-1")).await.unwrap();
+            store.add_task_execution(execution("task", "exec-2")).await.unwrap();
+        }
+
+        // Simulate a crash in the middle of writing the last record
+        let log_path = dir.path().join(LOG_FILE);
+        let len = fs::metadata(&log_path).unwrap().len();
+        let log = OpenOptions::new().write(true).open(&log_path).unwrap();
+        log.set_len(len - 3).unwrap();
+
+        let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+        let pending = executions_in_state(&store, TaskState::Pending).await;
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].execution_id, "exec-1");
+
+        // New records land right after the last valid one
+        store.add_task_execution(execution("task", "exec-3")).await.unwrap();
+        drop(store);
+        let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+        assert_eq!(executions_in_state(&store, TaskState::Pending).await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_workflows() {
+        let dir = tempfile::tempdir().unwrap();
+        let workflow = |id: &str, state: WorkflowState, created_at: i64| WorkflowStatus {
+            workflow_id: id.to_string(),
+            state: state.into(),
+            created_at,
+            ..Default::default()
+        };
+        {
+            let store = WalDataStore::open(&dir, DEFAULT_SNAPSHOT_INTERVAL).unwrap();
+            store.add_workflow(&workflow("wf-2", WorkflowState::Running, 20)).await.unwrap();
+            store.add_workflow(&workflow("wf-1", WorkflowState::Running, 10)).await.unwrap();
+            assert_eq!(
+                store.add_workflow(&workflow("wf-1", WorkflowState::Running, 30)).await,
+                Err(DataStoreError::Duplicate("wf-1".to_string()))
+            );
+            store.update_workflow(&workflow("wf-2", WorkflowState::Success, 20)).await.unwrap();
+            assert_eq!(
+                store.update_workflow(&workflow("missing", WorkflowState::Success, 0)).await,
+                Err(DataStoreError::NotFound("missing".to_string()))
+            );
+        }
+
+        // Workflows survive a restart, along with their latest state
+        let store = WalDataStore::open(&dir, 1).unwrap();
+        assert_eq!(store.get_workflow("wf-2").await.unwrap().state(), WorkflowState::Success);
+        let running = store.list_workflows(WorkflowState::Running).await.unwrap();
+        assert_eq!(running, vec![workflow("wf-1", WorkflowState::Running, 10)]);
+    }
+}
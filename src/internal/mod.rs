@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+// Generated by tonic-build, the message layouts follow the protos
+#[allow(clippy::large_enum_variant)]
 pub mod protot {
     pub mod core {
         tonic::include_proto!("protot.core");
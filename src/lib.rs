@@ -50,11 +50,10 @@
 //! Here's a quick example that demonstrates basic usage:
 //!
 //! ```rust
+//! use protot::config_load;
 //!
-//! fn main() {
-//!     let config = config_load(String::from("my_config.yaml"));
-//!     // Further code to initialize and run the scheduler
-//! }
+//! let config = config_load(String::from("my_config.yaml"));
+//! // Further code to initialize and run the scheduler
 //! ```
 //!
 //! For more examples and configuration options, please refer to the `examples/` directory in the repository.
@@ -76,6 +75,9 @@
 //! This crate and its documentation are brought to you by [sylk.build](https://www.sylk.build), © 2023 Sylk Technologies.
 //!
 
+// The gRPC handlers and their helpers return `tonic::Status` as it is
+#![allow(clippy::result_large_err)]
+
 pub mod client;
 pub mod core;
 pub mod internal;
@@ -83,11 +85,17 @@ pub mod data;
 pub mod utils;
 
 mod server;
-use crate::{core::worker_pool::{TaskExecutor, TaskRegistry}, server::start_single_process_grpc_server, data::{DataStore, RedisDataStore}, internal::protot::core::NodeType};
+use crate::{client::GrpcWorkerBuilder, core::{queues::{QueueRegistry, DEFAULT_QUEUE}, rate_limit::RateLimiter, worker_pool::{GrpcWorkersRegistry, TaskExecutor, TaskRegistry}}, server::start_single_process_grpc_server, data::{DataStore, InMemoryTaskQueue, RedisDataStore, RedisStreamsTaskQueue, RetentionPolicy, TaskQueue, WalDataStore, DEFAULT_IDEMPOTENCY_WINDOW}, internal::protot::core::NodeType};
+#[cfg(feature = "sqlite")]
+use crate::data::SqliteDataStore;
+#[cfg(feature = "postgres")]
+use crate::data::PostgresDataStore;
 pub use lazy_static::lazy_static;
-use log::{info, debug, error};
+use log::{debug, error};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+use uuid::Uuid;
 use server::start_scheduler_grpc_server;
-use tokio::sync::Mutex as AsyncMutex;
 
 use ::core::fmt;
 use std::{
@@ -98,7 +106,7 @@ use std::{
 
 use internal::protot::{
     self,
-    core::{Config, Task, DataStoreType, LoadBalancer},
+    core::{Config, Task, TaskError, DataStoreType, LoadBalancer, QueueType},
     scheduler::v1::ExecuteRequest,
 };
 use protobuf::well_known_types::{any::Any, struct_};
@@ -126,8 +134,31 @@ impl fmt::Display for Config {
             let cleaned_host = data_store.host.replace("\"", "");
             writeln!(f, "{:<20}{}", "Data Store", DataStoreType::from_i32(data_store.r#type).unwrap().as_str_name())?;
             writeln!(f, "{:<20}{}", "Data Store Host", cleaned_host)?;
+            writeln!(f, "{:<20}{}", "Task Queue", data_store.queue().as_str_name())?;
         } else {
-            writeln!(f, "{:<20}{}", "Data Store", "None")?;
+            writeln!(f, "{:<20}None", "Data Store")?;
+        }
+
+        if !self.advertise_address.is_empty() {
+            writeln!(f, "{:<20}{}", "Advertise Address", self.advertise_address)?;
+        }
+
+        if !self.queues.is_empty() {
+            let names: Vec<&str> = self.queues.iter().map(|queue| queue.name.as_str()).collect();
+            writeln!(f, "{:<20}{}", "Queues", names.join(", "))?;
+        }
+
+        if !self.rate_limits.is_empty() {
+            let task_types: Vec<&str> = self.rate_limits.iter().map(|limit| limit.task_type.as_str()).collect();
+            writeln!(f, "{:<20}{}", "Rate Limited Tasks", task_types.join(", "))?;
+        }
+
+        if let Some(worker) = &self.worker {
+            writeln!(f, "{:<20}{}", "Schedulers", worker.schedulers.join(", "))?;
+            writeln!(f, "{:<20}{}", "Worker Concurrency", worker.concurrency.max(1))?;
+            if !worker.queues.is_empty() {
+                writeln!(f, "{:<20}{}", "Worker Queues", worker.queues.join(", "))?;
+            }
         }
 
         writeln!(f, "{}", separator)?;
@@ -144,20 +175,7 @@ pub async fn start(
 
     let _ = logger::init();
 
-    // Loading configurations to `sylklabs.core.Config` message from yaml/json/toml
-    let cfgs = match configurations {
-        Some(cfgs) => cfgs,
-        None => match config_load("configs.yaml".to_string()) {
-            Ok(cfg) => {
-                debug!("Loaded configurations: {:?}", cfg,);
-                cfg
-            }
-            Err(e) => {
-                error!("Failed to load configuration: {:?}", e);
-                std::process::exit(1);
-            },
-        },
-    };
+    let cfgs = load_configurations(configurations);
 
     let opts = ProcessOptions { 
         task_executors: registry,
@@ -165,36 +183,226 @@ pub async fn start(
     };
     
     match cfgs.node_type() {
-        protot::core::NodeType::SingleProcess => init_single_process_grpc_scheduler(cfgs, opts).await,
+        protot::core::NodeType::SingleProcess => {
+            // The data store is optional for a single process, when set queued tasks survive restarts
+            let data_store = match cfgs.data_store.clone() {
+                Some(db) => Some(init_data_store(&db).await?),
+                None => None,
+            };
+            let rate_limiter = init_rate_limiter(&cfgs, data_store.clone())?;
+            init_single_process_grpc_scheduler(cfgs, opts, data_store, rate_limiter).await
+        },
         protot::core::NodeType::Scheduler => {
             let cfg_data_store = cfgs.data_store.clone();
             match cfg_data_store {
                 Some(db) => {
-                    let data_store = match db.r#type {
-                        0 => {
-                            let data_store: Arc<AsyncMutex<RedisDataStore>> = Arc::new(AsyncMutex::new(RedisDataStore::new(&db.host).await?));
-                            data_store
-                        },
-                        _ => panic!("Unsupported data store type"),
-                    }; 
-                    init_distributed_grpc_scheduler(cfgs, opts, data_store).await
+                    let data_store = init_data_store(&db).await?;
+                    let queues = init_task_queues(&cfgs, &db).await?;
+                    let rate_limiter = init_rate_limiter(&cfgs, Some(data_store.clone()))?;
+                    init_distributed_grpc_scheduler(cfgs, opts, data_store, queues, rate_limiter).await
                 }
                 None => {
                     return Err(SchedulerError::DataLayerError("Must set up a data store configurations".to_string()))
                 } 
             }
         },
-        _ => Err(SchedulerError::SchedulerUnimplemented(format!(
-            "Unimplemented node type {}",
-            protot::core::NodeType::from_i32(cfgs.node_type)
-                .unwrap()
-                .as_str_name()
-        ))),
+        protot::core::NodeType::Worker => Err(SchedulerError::SchedulerUnimplemented(
+            "WORKER nodes execute async tasks, start them with `protot::start_worker`".to_string()
+        )),
     }?;
 
     Ok(())
 }
 
+/// Runs a `WORKER` node executing the tasks of `registry` for the configured schedulers.
+///
+/// The worker connects to the first scheduler of `worker.schedulers` that leads and
+/// runs until interrupted: on the first interrupt it finishes its running executions
+/// (for at most `graceful_timeout` seconds) before disconnecting, a second interrupt
+/// stops it right away.
+pub async fn start_worker(
+    registry: GrpcWorkersRegistry,
+    configurations: Option<Config>
+) -> Result<(), SchedulerError> {
+
+    let _ = logger::init();
+
+    let cfgs = load_configurations(configurations);
+    if cfgs.node_type() != protot::core::NodeType::Worker {
+        return Err(SchedulerError::ConfigLoadError(format!(
+            "start_worker requires a WORKER node type, got {}",
+            cfgs.node_type().as_str_name()
+        )));
+    }
+    println!("{}", cfgs);
+
+    let worker_cfg = cfgs.worker.clone().unwrap_or_default();
+    let worker_id = if worker_cfg.worker_id.is_empty() {
+        format!("worker-{}", Uuid::new_v4())
+    } else {
+        worker_cfg.worker_id
+    };
+
+    let shutdown = CancellationToken::new();
+    spawn_shutdown_listener(shutdown.clone(), cfgs.graceful_timeout);
+
+    let mut builder = GrpcWorkerBuilder::new()
+        .with_id(worker_id)
+        .with_registry(registry)
+        .with_schedulers(worker_cfg.schedulers)
+        .with_concurrency(worker_cfg.concurrency as usize)
+        .with_queues(worker_cfg.queues)
+        .with_shutdown(shutdown);
+    if !worker_cfg.magic_cookie.is_empty() {
+        builder = builder.with_cookie(worker_cfg.magic_cookie);
+    }
+
+    match builder.build().communicate().await {
+        Err(err) => Err(SchedulerError::SchedulerServiceError(format!(
+            "Worker errored: {:?}",
+            err
+        ))),
+        Ok(_) => {
+            println!("Goodbye :)");
+            Ok(())
+        }
+    }
+}
+
+/// Loads `configs.yaml` unless the configurations are given, exits when it can't be loaded.
+fn load_configurations(configurations: Option<Config>) -> Config {
+    // Loading configurations to `sylklabs.core.Config` message from yaml/json/toml
+    match configurations {
+        Some(cfgs) => cfgs,
+        None => match config_load("configs.yaml".to_string()) {
+            Ok(cfg) => {
+                debug!("Loaded configurations: {:?}", cfg,);
+                cfg
+            }
+            Err(e) => {
+                error!("Failed to load configuration: {:?}", e);
+                std::process::exit(1);
+            },
+        },
+    }
+}
+
+/// Cancels `shutdown` on the first interrupt and forces the process out after
+/// `graceful_timeout` seconds or on a second interrupt.
+fn spawn_shutdown_listener(shutdown: CancellationToken, graceful_timeout: u64) {
+    tokio::spawn(async move {
+        let mut stream = signal(SignalKind::interrupt()).unwrap();
+        stream.recv().await;
+        debug!("worker interrupted");
+        shutdown.cancel();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_secs(graceful_timeout)).await;
+            debug!("Force shutdown after timeout: {}", graceful_timeout);
+            std::process::exit(1);
+        });
+
+        stream.recv().await;
+        debug!("second interrupt received, force quitting...");
+        std::process::exit(1);
+    });
+}
+
+
+async fn init_data_store(
+    db: &protot::core::DataStore,
+) -> Result<Arc<dyn DataStore>, SchedulerError> {
+    let retention = db.retention.as_ref().map(RetentionPolicy::from).unwrap_or_default();
+    let data_store: Arc<dyn DataStore> = match db.r#type() {
+        DataStoreType::Redis => {
+            Arc::new(RedisDataStore::new(&db.host).await?.with_retention(retention.clone()))
+        },
+        DataStoreType::File => {
+            Arc::new(WalDataStore::new(&db.host).await?)
+        },
+        #[cfg(feature = "sqlite")]
+        DataStoreType::Sqlite => {
+            Arc::new(SqliteDataStore::new(&db.host).await?)
+        },
+        #[cfg(not(feature = "sqlite"))]
+        DataStoreType::Sqlite => {
+            return Err(SchedulerError::SchedulerUnimplemented(
+                "SQLITE data store requires the \"sqlite\" feature flag".to_string()
+            ))
+        },
+        #[cfg(feature = "postgres")]
+        DataStoreType::Postgres => {
+            Arc::new(PostgresDataStore::new(&db.host).await?)
+        },
+        #[cfg(not(feature = "postgres"))]
+        DataStoreType::Postgres => {
+            return Err(SchedulerError::SchedulerUnimplemented(
+                "POSTGRES data store requires the \"postgres\" feature flag".to_string()
+            ))
+        },
+    };
+
+    // Stores without native expiry are swept in the background
+    if !retention.is_empty() && !data_store.expires_executions() {
+        data::spawn_retention_sweeper(data_store.clone(), retention);
+    }
+    Ok(data_store)
+}
+
+/// The default queue and the named ones of the configuration, all of the configured queue type.
+///
+/// A configured `default` queue sets the limits of the default one.
+async fn init_task_queues(
+    cfg: &protot::core::Config,
+    db: &protot::core::DataStore,
+) -> Result<QueueRegistry, SchedulerError> {
+    let mut queues = QueueRegistry::new(init_task_queue(db, DEFAULT_QUEUE).await?);
+    for queue in &cfg.queues {
+        if queue.name.is_empty() {
+            return Err(SchedulerError::ConfigLoadError("queues must be named".to_string()));
+        }
+        queues = queues.with_queue(queue.clone(), init_task_queue(db, &queue.name).await?);
+    }
+    Ok(queues)
+}
+
+/// The rate limits of the configured task types and queues, kept in `db` when given.
+fn init_rate_limiter(
+    cfg: &protot::core::Config,
+    db: Option<Arc<dyn DataStore>>,
+) -> Result<Arc<RateLimiter>, SchedulerError> {
+    let mut rate_limiter = RateLimiter::new(db);
+    for limit in &cfg.rate_limits {
+        if limit.task_type.is_empty() {
+            return Err(SchedulerError::ConfigLoadError("rate limits must name a task type".to_string()));
+        }
+        if let Some(bucket) = limit.rate_limit.as_ref().and_then(|rate_limit| rate_limit.bucket()) {
+            rate_limiter = rate_limiter.with_task_type_limit(&limit.task_type, bucket);
+        }
+    }
+    for queue in &cfg.queues {
+        if let Some(bucket) = queue.rate_limit.as_ref().and_then(|rate_limit| rate_limit.bucket()) {
+            rate_limiter = rate_limiter.with_queue_limit(&queue.name, bucket);
+        }
+    }
+    Ok(Arc::new(rate_limiter))
+}
+
+async fn init_task_queue(
+    db: &protot::core::DataStore,
+    queue: &str,
+) -> Result<Arc<dyn TaskQueue>, SchedulerError> {
+    match db.queue() {
+        QueueType::Memory => Ok(Arc::new(InMemoryTaskQueue::new())),
+        // The stream lives next to the executions, on the data store's Redis
+        QueueType::RedisStreams => match db.r#type() {
+            DataStoreType::Redis => Ok(Arc::new(RedisStreamsTaskQueue::for_queue(&db.host, queue).await?)),
+            other => Err(SchedulerError::DataLayerError(format!(
+                "REDIS_STREAMS queue requires a REDIS data store, got {}",
+                other.as_str_name()
+            ))),
+        },
+    }
+}
 
 fn prost_duration_to_std_duration(prost_duration: Option<prost_types::Duration>) -> Duration {
     match prost_duration {
@@ -210,10 +418,21 @@ fn prost_duration_to_std_duration(prost_duration: Option<prost_types::Duration>)
     }
 }
 
+/// How long idempotency keys suppress duplicate submissions.
+fn idempotency_window(cfg: &protot::core::Config) -> Duration {
+    cfg.data_store
+        .as_ref()
+        .and_then(|db| db.idempotency_window.clone())
+        .map(|window| prost_duration_to_std_duration(Some(window)))
+        .unwrap_or(DEFAULT_IDEMPOTENCY_WINDOW)
+}
+
 async fn init_distributed_grpc_scheduler( 
     cfg: protot::core::Config,
     opts: ProcessOptions,
-    db: Arc<AsyncMutex<dyn data::DataStore>>,
+    db: Arc<dyn data::DataStore>,
+    queues: QueueRegistry,
+    rate_limiter: Arc<RateLimiter>,
 ) -> Result<(), SchedulerError> {
     println!("{}", cfg);
 
@@ -221,24 +440,32 @@ async fn init_distributed_grpc_scheduler(
 
     let executors = Arc::new(Mutex::new(registry));
 
+    // Registered tasks run on the scheduler threads, the others on the remote workers
     let pool = core::worker_pool::Builder::new()
         .num_workers(cfg.num_workers as usize)
         .name(opts.process_name)
         .thread_stack_size(32 * 1024 * 1024)
         .executors(executors)
+        .rate_limiter(rate_limiter.clone())
+        .grpc_workers()
         .build()?;
 
     collect_stats();
 
 
     // Todo start scheduler server
+    let window = idempotency_window(&cfg);
     match start_scheduler_grpc_server(
         cfg.grpc_port,
         pool,
         cfg.graceful_timeout,
         prost_duration_to_std_duration(cfg.heartbeat_interval),
         None,
-        db
+        db,
+        queues,
+        rate_limiter,
+        cfg.advertise_address.clone(),
+        window,
     ).await {
         Err(err) => Err(SchedulerError::SchedulerServiceError(format!(
             "Scheduler errored: {:?}",
@@ -266,7 +493,7 @@ impl Default for ProcessOptions {
 pub struct TaskExecutorImpl1 {}
 
 impl TaskExecutor for TaskExecutorImpl1 {
-    fn execute(&self, args: ExecuteRequest) {
+    fn execute(&self, args: ExecuteRequest) -> Result<prost_types::Any, TaskError> {
         let task: Task = args.clone().task.unwrap().clone();
         let payload = task.payload.unwrap();
         let task_id = args.task.unwrap().id;
@@ -274,16 +501,17 @@ impl TaskExecutor for TaskExecutorImpl1 {
         data.type_url = payload.type_url;
         data.value = payload.value;
 
-        let data = Any::unpack::<struct_::Struct>(&data);
+        let _data = Any::unpack::<struct_::Struct>(&data);
         println!("task excution 1 with dynamic args! {task_id}");
-        thread::sleep(Duration::from_secs(5))
+        thread::sleep(Duration::from_secs(5));
+        Ok(prost_types::Any::default())
     }
 }
 
 pub struct TaskExecutorImpl2 {}
 
 impl TaskExecutor for TaskExecutorImpl2 {
-    fn execute(&self, args: ExecuteRequest) {
+    fn execute(&self, args: ExecuteRequest) -> Result<prost_types::Any, TaskError> {
         let task: Task = args.clone().task.unwrap().clone();
         let payload = task.payload.unwrap();
         let task_id = args.task.unwrap().id;
@@ -291,9 +519,10 @@ impl TaskExecutor for TaskExecutorImpl2 {
         data.type_url = payload.type_url;
         data.value = payload.value;
 
-        let data = Any::unpack::<struct_::Struct>(&data);
+        let _data = Any::unpack::<struct_::Struct>(&data);
         thread::sleep(Duration::from_secs(1));
         println!("task excution 2 with dynamic args! {}", task_id);
+        Ok(prost_types::Any::default())
     }
 }
 
@@ -310,6 +539,8 @@ fn collect_stats() {
 async fn init_single_process_grpc_scheduler(
     cfg: protot::core::Config,
     opts: ProcessOptions,
+    db: Option<Arc<dyn data::DataStore>>,
+    rate_limiter: Arc<RateLimiter>,
 ) -> Result<(), SchedulerError> {
 
     println!("{}", cfg);
@@ -323,6 +554,7 @@ async fn init_single_process_grpc_scheduler(
         .name(opts.process_name)
         .thread_stack_size(32 * 1024 * 1024)
         .executors(executors)
+        .rate_limiter(rate_limiter)
         .build()?;
 
     collect_stats();
@@ -351,7 +583,8 @@ async fn init_single_process_grpc_scheduler(
     // }
 
     // Todo start scheduler server
-    match start_single_process_grpc_server(cfg.grpc_port, pool, cfg.graceful_timeout).await {
+    let window = idempotency_window(&cfg);
+    match start_single_process_grpc_server(cfg.grpc_port, pool, cfg.graceful_timeout, db, window).await {
         Err(err) => Err(SchedulerError::SchedulerServiceError(format!(
             "Scheduler errored: {:?}",
             &*err
@@ -15,12 +15,18 @@
 extern crate lazy_static;
 
 use protot::config_load;
-use protot::internal::protot::core::{Config, DataStore, DataStoreType, NodeType};
+use protot::internal::protot::core::{DataStore, NodeType};
+use protot::internal::protot::scheduler::v1::{
+    scheduler_service_client::SchedulerServiceClient, DeadLetter, DeadLetterRequest, ListDeadLettersRequest,
+    PurgeDeadLettersRequest, RequeueDeadLetterRequest,
+};
 use protot::utils::{get_ascii_logo, get_protot_metadata};
 use protot::{
-    core::worker_pool::TaskRegistry, start, SchedulerError, TaskExecutorImpl1, TaskExecutorImpl2,
+    core::worker_pool::TaskRegistry, start, SchedulerError,
 };
 use clap::{Parser, Subcommand};
+use prost::Message;
+use prost_types::{value::Kind, Any, ListValue, Struct, Value};
 use std::process::exit;
 use std::{path::PathBuf, ops::RangeInclusive};
 use std::fs;
@@ -57,6 +63,58 @@ enum Commands {
         #[arg(short, long, value_parser = port_in_range, default_value = "44880")]
         grpc_port: u16,
     },
+    /// manage the executions that failed after their last attempt
+    DeadLetters {
+        /// the gRPC address of the scheduler
+        #[arg(short, long, default_value = "http://127.0.0.1:44880")]
+        scheduler: String,
+
+        #[command(subcommand)]
+        action: DeadLetterCommands,
+    },
+}
+
+#[derive(Subcommand)]
+enum DeadLetterCommands {
+    /// list dead letters, oldest first
+    List {
+        /// only list dead letters of this task type
+        #[arg(short, long)]
+        task_type: Option<String>,
+
+        /// max number of dead letters to list
+        #[arg(short, long, default_value = "20")]
+        limit: u32,
+
+        /// continue after the cursor printed by the previous list
+        #[arg(long)]
+        cursor: Option<String>,
+    },
+    /// show a dead letter with its payload, error and attempts
+    Inspect {
+        execution_id: String,
+    },
+    /// submit a dead-lettered task again as a new execution
+    Requeue {
+        execution_id: String,
+
+        /// JSON object replacing the task payload (sent as a google.protobuf.Struct)
+        #[arg(short, long, value_parser = json_payload)]
+        payload: Option<Any>,
+    },
+    /// delete dead letters
+    Purge {
+        /// execution ids of the dead letters to delete
+        execution_ids: Vec<String>,
+
+        /// delete every dead letter of this task type
+        #[arg(short, long, conflicts_with = "execution_ids")]
+        task_type: Option<String>,
+
+        /// delete every dead letter
+        #[arg(long, conflicts_with_all = ["execution_ids", "task_type"])]
+        all: bool,
+    },
 }
 
 const PORT_RANGE: RangeInclusive<usize> = 1..=65535;
@@ -91,6 +149,152 @@ fn num_workers_in_range(s: &str) -> Result<u16, String> {
     }
 }
 
+fn json_payload(s: &str) -> Result<Any, String> {
+    match serde_json::from_str(s).map_err(|err| format!("invalid JSON payload: {}", err))? {
+        serde_json::Value::Object(fields) => Ok(Any {
+            type_url: "type.googleapis.com/google.protobuf.Struct".to_string(),
+            value: json_to_struct(fields).encode_to_vec(),
+        }),
+        _ => Err("payload must be a JSON object".to_string()),
+    }
+}
+
+fn json_to_struct(fields: serde_json::Map<String, serde_json::Value>) -> Struct {
+    Struct { fields: fields.into_iter().map(|(key, value)| (key, json_to_value(value))).collect() }
+}
+
+fn json_to_value(value: serde_json::Value) -> Value {
+    let kind = match value {
+        serde_json::Value::Null => Kind::NullValue(0),
+        serde_json::Value::Bool(value) => Kind::BoolValue(value),
+        serde_json::Value::Number(value) => Kind::NumberValue(value.as_f64().unwrap_or_default()),
+        serde_json::Value::String(value) => Kind::StringValue(value),
+        serde_json::Value::Array(values) => Kind::ListValue(ListValue { values: values.into_iter().map(json_to_value).collect() }),
+        serde_json::Value::Object(fields) => Kind::StructValue(json_to_struct(fields)),
+    };
+    Value { kind: Some(kind) }
+}
+
+fn value_to_json(value: &Value) -> serde_json::Value {
+    match &value.kind {
+        None | Some(Kind::NullValue(_)) => serde_json::Value::Null,
+        Some(Kind::BoolValue(value)) => serde_json::Value::Bool(*value),
+        Some(Kind::NumberValue(value)) => serde_json::Number::from_f64(*value).map_or(serde_json::Value::Null, serde_json::Value::Number),
+        Some(Kind::StringValue(value)) => serde_json::Value::String(value.clone()),
+        Some(Kind::ListValue(list)) => serde_json::Value::Array(list.values.iter().map(value_to_json).collect()),
+        Some(Kind::StructValue(fields)) => struct_to_json(fields),
+    }
+}
+
+fn struct_to_json(fields: &Struct) -> serde_json::Value {
+    serde_json::Value::Object(fields.fields.iter().map(|(key, value)| (key.clone(), value_to_json(value))).collect())
+}
+
+/// The payload as JSON when it is a `google.protobuf.Struct`, its type and size otherwise.
+fn describe_payload(payload: Option<&Any>) -> String {
+    match payload {
+        None => "none".to_string(),
+        Some(any) if any.type_url.ends_with("/google.protobuf.Struct") => match Struct::decode(any.value.as_slice()) {
+            Ok(fields) => struct_to_json(&fields).to_string(),
+            Err(_) => format!("{} ({} bytes, malformed)", any.type_url, any.value.len()),
+        },
+        Some(any) => format!("{} ({} bytes)", any.type_url, any.value.len()),
+    }
+}
+
+fn print_dead_letter(dead_letter: &DeadLetter) {
+    let task = dead_letter.task.clone().unwrap_or_default();
+    let error = dead_letter.error.clone().unwrap_or_default();
+    println!(
+        "{}\t{}\t{}\t{} attempts\t{}: {}",
+        dead_letter.execution_id,
+        task.executor_name(),
+        format_timestamp(dead_letter.dead_lettered_at),
+        dead_letter.attempts.len(),
+        error.code,
+        error.message
+    );
+}
+
+fn format_timestamp(seconds: i64) -> String {
+    chrono::DateTime::from_timestamp(seconds, 0).map_or(seconds.to_string(), |time| time.to_rfc3339())
+}
+
+/// Runs a dead letter admin command against the scheduler at `scheduler`.
+async fn dead_letters(scheduler: String, action: DeadLetterCommands) -> Result<(), SchedulerError> {
+    let service_error = |err: tonic::Status| SchedulerError::SchedulerServiceError(format!("{}: {}", err.code(), err.message()));
+    let mut client = SchedulerServiceClient::connect(scheduler.clone())
+        .await
+        .map_err(|err| SchedulerError::SchedulerServiceError(format!("unable to connect to scheduler {}: {}", scheduler, err)))?;
+
+    match action {
+        DeadLetterCommands::List { task_type, limit, cursor } => {
+            let page = client
+                .list_dead_letters(ListDeadLettersRequest {
+                    task_type: task_type.unwrap_or_default(),
+                    limit,
+                    cursor: cursor.unwrap_or_default(),
+                })
+                .await
+                .map_err(service_error)?
+                .into_inner();
+            for dead_letter in &page.dead_letters {
+                print_dead_letter(dead_letter);
+            }
+            if !page.next_cursor.is_empty() {
+                println!("more dead letters follow, list them with --cursor {}", page.next_cursor);
+            }
+        }
+        DeadLetterCommands::Inspect { execution_id } => {
+            let dead_letter = client
+                .get_dead_letter(DeadLetterRequest { execution_id })
+                .await
+                .map_err(service_error)?
+                .into_inner();
+            let task = dead_letter.task.clone().unwrap_or_default();
+            println!("execution:       {}", dead_letter.execution_id);
+            println!("task:            {} ({})", task.id, task.executor_name());
+            println!("queue:           {}", if task.queue.is_empty() { "default" } else { &task.queue });
+            println!("payload:         {}", describe_payload(task.payload.as_ref()));
+            println!("dead-lettered:   {}", format_timestamp(dead_letter.dead_lettered_at));
+            println!("attempts:");
+            for attempt in &dead_letter.attempts {
+                let error = attempt.error.clone().unwrap_or_default();
+                println!(
+                    "  #{} {} on {}: {}: {}",
+                    attempt.attempt,
+                    format_timestamp(attempt.failed_at),
+                    if attempt.worker_id.is_empty() { "-" } else { &attempt.worker_id },
+                    error.code,
+                    error.message
+                );
+            }
+        }
+        DeadLetterCommands::Requeue { execution_id, payload } => {
+            let response = client
+                .requeue_dead_letter(RequeueDeadLetterRequest { execution_id: execution_id.clone(), payload })
+                .await
+                .map_err(service_error)?
+                .into_inner();
+            println!("requeued {} as execution {}", execution_id, response.execution_id);
+        }
+        DeadLetterCommands::Purge { execution_ids, task_type, all } => {
+            if execution_ids.is_empty() && task_type.is_none() && !all {
+                return Err(SchedulerError::SchedulerServiceError(
+                    "name the dead letters to purge, or pass --task-type or --all".to_string(),
+                ));
+            }
+            let response = client
+                .purge_dead_letters(PurgeDeadLettersRequest { execution_ids, task_type: task_type.unwrap_or_default(), all })
+                .await
+                .map_err(service_error)?
+                .into_inner();
+            println!("purged {} dead letters", response.purged);
+        }
+    }
+    Ok(())
+}
+
 fn check_config_file_available(config_path: PathBuf) -> Result<(), String> {
     if config_path.exists() && config_path.is_file() {
         // Try to open the file to ensure it's readable
@@ -106,8 +310,12 @@ fn check_config_file_available(config_path: PathBuf) -> Result<(), String> {
 #[tokio::main]
 async fn main() -> Result<(), SchedulerError> {
     let cli: Cli = Cli::parse();
+    let command = match cli.command {
+        Some(Commands::DeadLetters { scheduler, action }) => return dead_letters(scheduler, action).await,
+        command => command,
+    };
     println!("{}\n{}", get_ascii_logo(), get_protot_metadata());
-    let mut executors = TaskRegistry::new();
+    let executors = TaskRegistry::new();
     
     let loaded_cfgs = match cli.config {
         Some(cfg_file) => {
@@ -118,12 +326,9 @@ async fn main() -> Result<(), SchedulerError> {
         None => config_load("configs.yaml".to_string())
     };
 
-    let mut cfgs = Config::default();
-    if loaded_cfgs.is_ok() {
-        cfgs = loaded_cfgs.unwrap();
-    }
+    let mut cfgs = loaded_cfgs.unwrap_or_default();
 
-    match cli.command {
+    match command {
         Some(Commands::Init { 
             data_host,
             num_workers,
@@ -141,6 +346,7 @@ async fn main() -> Result<(), SchedulerError> {
             cfgs.grpc_port = grpc_port as i32;
             cfgs.node_type = NodeType::Scheduler.into();
         },
+        Some(Commands::DeadLetters { .. }) => unreachable!("handled before loading the config"),
         None => {
             println!("GoodBye :)");
             exit(1);
@@ -0,0 +1,1169 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{collections::HashSet, sync::Arc, time::Duration};
+
+use log::{debug, error, info, warn};
+use tokio::{sync::mpsc::Sender, task::JoinHandle, time::{sleep, timeout}};
+use tonic::Status;
+
+use crate::{
+    core::{grpc_executor::GrpcSharedState, load_balancer::LoadBalancer, rate_limit::RateLimited, task::TaskOutcome},
+    data::{DataStore, DataStoreError, ExecutionFilter, ExecutionRecord},
+    internal::protot::{
+        core::{Task, TaskError, TaskState},
+        scheduler::v1::{scheduler_message, AssignTaskRequest, ExecuteRequest, SchedulerMessage, TaskCompletion},
+    },
+    utils::current_timestamp,
+};
+
+use super::leader::LeaderElection;
+#[cfg(feature = "stats")]
+use super::metrics::{increment_task, WorkerPoolTaskType};
+
+/// Number of heartbeat intervals a worker can miss before its leases run out.
+const LEASE_HEARTBEATS: u32 = 3;
+
+/// How often the dispatcher re-checks the queue when it is not notified,
+/// e.g. while executions wait for a worker to connect.
+const DISPATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Pending executions claimed from the data store at once when taking the lead.
+const CLAIM_BATCH_SIZE: usize = 100;
+
+/// How long a worker owns an execution without renewing the lease.
+pub(crate) fn lease_duration(heartbeat_interval: Duration) -> Duration {
+    heartbeat_interval * LEASE_HEARTBEATS
+}
+
+fn assign_request(record: &ExecutionRecord) -> AssignTaskRequest {
+    AssignTaskRequest {
+        task: Some(record.task.clone()),
+        execution_id: record.execution_id.clone(),
+    }
+}
+
+/// Runs the execution on the scheduler's own pool when its task is registered there,
+/// queues it for the remote workers otherwise.
+pub(crate) async fn submit<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    request: AssignTaskRequest,
+    lease_duration: Duration,
+) -> Result<(), DataStoreError> {
+    let task_name = request.task.as_ref().map(|task| task.executor_name().to_string()).unwrap_or_default();
+    if state.runs_locally(&task_name) {
+        if !execute_locally(state, db, &request, lease_duration).await? {
+            state.park(request);
+        }
+        Ok(())
+    } else {
+        state.enqueue(request).await
+    }
+}
+
+/// Runs the execution on the local pool, leased to the scheduler's local worker id,
+/// returns whether it started.
+///
+/// An execution whose concurrency key is saturated is not handed to the pool and stays
+/// pending, the caller parks it until a slot may be free. The lease is renewed by
+/// `renew_local_leases` for as long as the scheduler lives, so a scheduler that goes
+/// away leaves its executions to the lease reaper.
+async fn execute_locally<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    request: &AssignTaskRequest,
+    lease_duration: Duration,
+) -> Result<bool, DataStoreError> {
+    let pool = state.local_pool().expect("local executions need a local pool");
+    let task = request.task.clone().unwrap_or_default();
+    let execution_id = request.execution_id.clone();
+    // An expired task is discarded by the pool, it needs no slot
+    if !task.is_expired(current_timestamp()) && !acquire_slot(&**db, &task, &execution_id).await? {
+        debug!("task execution {} waits for a slot of {}", execution_id, task.concurrency_key);
+        return Ok(false);
+    }
+    let lease_expires_at = current_timestamp() + lease_duration.as_secs() as i64;
+    if let Err(err) = db.lease_task_execution(&execution_id, state.local_worker_id(), lease_expires_at, None).await {
+        release_slot(&**db, &task, &execution_id).await;
+        return Err(err);
+    }
+    let (task_name, task_id) = (task.executor_name().to_string(), task.id.clone());
+
+    let executors = pool.executors.clone();
+    let shared_state = state.clone();
+    let data_layer = db.clone();
+    let handle = tokio::runtime::Handle::current();
+    let (executed_id, completed_id) = (task_id.clone(), execution_id.clone());
+    let executed = pool.execute(
+        move |args| {
+            // Pool threads live outside of the runtime, so we block on the data store updates
+            debug!("executing task {} locally", task_name);
+            let outcome = executors.lock().unwrap().execute(&task_name, args);
+            let completion = TaskCompletion::from_outcome(executed_id, completed_id, outcome);
+
+            handle.block_on(async {
+                let worker_id = shared_state.local_worker_id();
+                if let Err(err) = complete(&shared_state, &data_layer, &completion, Some(worker_id), lease_duration).await {
+                    error!("failed to update task execution {}: {}", completion.execution_id, err);
+                }
+            });
+        },
+        ExecuteRequest { task: request.task.clone(), execution_id: execution_id.clone(), ..Default::default() },
+    );
+
+    if let Err(err) = executed {
+        let error = TaskError::new("LOCAL_POOL_UNAVAILABLE", err.to_string());
+        let completion = TaskCompletion::from_result(task_id, execution_id.clone(), Err(error));
+        db.complete_task_execution(&completion, Some(state.local_worker_id())).await?;
+        release_slot(&**db, &task, &execution_id).await;
+        state.waiters().notify(&completion);
+        return Err(DataStoreError::InternalError(format!("failed to execute task locally: {}", err)));
+    }
+    Ok(true)
+}
+
+/// Runs the parked local executions whose concurrency key has a free slot again, the
+/// others stay parked in the same order.
+async fn start_parked_executions<B: LoadBalancer>(state: &Arc<GrpcSharedState<B>>, db: &Arc<dyn DataStore>, lease_duration: Duration) {
+    for (concurrency_key, requests) in state.take_parked() {
+        let mut waiting = requests.into_iter();
+        while let Some(request) = waiting.next() {
+            match execute_locally(state, db, &request, lease_duration).await {
+                Ok(true) => continue,
+                Ok(false) => {}
+                Err(DataStoreError::NotFound(_)) => {
+                    warn!("dropping parked task execution {} which no longer exists", request.execution_id);
+                    continue;
+                }
+                Err(err) => error!("failed to run parked task execution {} locally: {}", request.execution_id, err),
+            }
+            // The key is still saturated, the executions behind it keep waiting too
+            state.repark(&concurrency_key, std::iter::once(request).chain(waiting).collect());
+            break;
+        }
+    }
+}
+
+/// Records the final state of an execution reported by a worker or the local pool,
+/// then wakes up the callers waiting for it.
+///
+/// A failed execution with attempts left under its retry policy goes back to `Pending`
+/// instead and is submitted again once the backoff elapsed, its callers keep waiting.
+/// One without attempts left is kept in the dead letters. Either way the execution gives
+/// its concurrency slot and its place in the queue concurrency back.
+///
+/// A completion reported by a worker that lost the lease is rejected with `LeaseLost`, the
+/// execution runs again elsewhere and keeps its slot, its place in the queue and its
+/// callers for that run.
+pub(crate) async fn complete<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    completion: &TaskCompletion,
+    worker_id: Option<&str>,
+    lease_duration: Duration,
+) -> Result<(), DataStoreError> {
+    let record = match db.get_task_execution(&completion.execution_id).await {
+        Ok(record) => record,
+        Err(err) => {
+            state.waiters().notify(completion);
+            return Err(err);
+        }
+    };
+    if completion.state() == TaskState::Fail {
+        match retry(state, db, &record, completion, worker_id, lease_duration).await {
+            Ok(true) => {
+                stopped_running(state, db, &record).await;
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(err) => error!("failed to retry task execution {}: {}", completion.execution_id, err),
+        }
+    }
+    let result = db.complete_task_execution(completion, worker_id).await;
+    match result {
+        Ok(()) => {
+            stopped_running(state, db, &record).await;
+            if completion.state() == TaskState::Fail {
+                dead_letter(&**db, &record, completion, worker_id).await;
+            }
+            advance_workflow(state, db, &record, completion, lease_duration).await;
+        }
+        Err(DataStoreError::LeaseLost(_)) => return result,
+        Err(_) => {}
+    }
+    state.waiters().notify(completion);
+    result
+}
+
+/// Gives back what a running execution held once its run is over: the concurrency slot
+/// and its place in the queue, which can dispatch one more.
+async fn stopped_running<B: LoadBalancer>(state: &Arc<GrpcSharedState<B>>, db: &Arc<dyn DataStore>, record: &ExecutionRecord) {
+    release_slot(&**db, &record.task, &record.execution_id).await;
+    state.queues().finished(&record.execution_id);
+    state.notify_dispatcher();
+}
+
+/// Moves the workflow of the execution, if it runs a workflow node, past the node and
+/// submits the nodes it unblocked.
+async fn advance_workflow<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    record: &ExecutionRecord,
+    completion: &TaskCompletion,
+    lease_duration: Duration,
+) {
+    match state.workflows().complete(&**db, record, completion).await {
+        Ok(started) => submit_workflow_nodes(state, db, started, lease_duration).await,
+        Err(err) => error!(
+            "failed to advance workflow {} past task execution {}: {}",
+            record.task.workflow_id, record.execution_id, err
+        ),
+    }
+}
+
+/// Stores and submits the executions of started workflow nodes.
+///
+/// A node whose execution could not be stored keeps running in its workflow, the
+/// workflow recovery stores it again after a restart.
+pub(crate) async fn submit_workflow_nodes<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    records: Vec<ExecutionRecord>,
+    lease_duration: Duration,
+) {
+    for record in records {
+        let submitted = match db.add_task_execution(record.clone()).await {
+            Ok(()) => submit(state, db, assign_request(&record), lease_duration).await,
+            Err(err) => Err(err),
+        };
+        if let Err(err) = submitted {
+            error!(
+                "failed to submit node {} of workflow {}: {}",
+                record.task.workflow_node, record.task.workflow_id, err
+            );
+        }
+    }
+}
+
+/// Queues a failed execution again when its retry policy allows it, returns whether it did.
+async fn retry<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    record: &ExecutionRecord,
+    completion: &TaskCompletion,
+    worker_id: Option<&str>,
+    lease_duration: Duration,
+) -> Result<bool, DataStoreError> {
+    // Completions of a worker that lost the lease are rejected by `complete_task_execution`
+    if record.state != TaskState::Running || worker_id.is_some_and(|id| record.worker_id.as_deref() != Some(id)) {
+        return Ok(false);
+    }
+    let policy = match state.queues().retry_policy(&record.task) {
+        Some(policy) if policy.allows_retry(record.retries) => policy,
+        _ => return Ok(false),
+    };
+
+    let attempt = record.failed_attempt(worker_id, completion.error.clone());
+    let retries = db.retry_task_execution(&record.execution_id, &attempt).await?;
+    let backoff = policy.backoff(record.retries);
+    info!("task execution {} failed, retrying it in {:?} (retry {})", record.execution_id, backoff, retries);
+
+    // A restart during the backoff leaves the execution pending, so it is recovered as queued
+    let (state, db, request) = (state.clone(), db.clone(), assign_request(record));
+    tokio::spawn(async move {
+        sleep(backoff).await;
+        let execution_id = request.execution_id.clone();
+        if let Err(err) = submit(&state, &db, request, lease_duration).await {
+            error!("failed to queue task execution {} again: {}", execution_id, err);
+        }
+    });
+    Ok(true)
+}
+
+/// Keeps an execution that failed for good in the dead letters, along with all of its attempts.
+async fn dead_letter(db: &dyn DataStore, record: &ExecutionRecord, completion: &TaskCompletion, worker_id: Option<&str>) {
+    let dead_letter = record.to_dead_letter(record.failed_attempt(worker_id, completion.error.clone()));
+    match db.add_dead_letter(&dead_letter).await {
+        Ok(()) => info!("task execution {} failed after {} attempts, moved to the dead letters", record.execution_id, dead_letter.attempts.len()),
+        Err(err) => error!("failed to dead-letter task execution {}: {}", record.execution_id, err),
+    }
+}
+
+/// Takes a slot of the concurrency key of the task for the execution, returns whether it may run.
+///
+/// The slot stays with the execution until it completes, even when its lease runs out and it
+/// is queued again, so the execution can't lose it to another one while still running.
+async fn acquire_slot(db: &dyn DataStore, task: &Task, execution_id: &str) -> Result<bool, DataStoreError> {
+    match task.concurrency_slots() {
+        Some(limit) => db.acquire_concurrency_slot(&task.concurrency_key, execution_id, limit).await,
+        None => Ok(true),
+    }
+}
+
+/// Gives the slot of the execution back, so the next execution with its concurrency key can run.
+async fn release_slot(db: &dyn DataStore, task: &Task, execution_id: &str) {
+    if task.concurrency_key.is_empty() {
+        return;
+    }
+    if let Err(err) = db.release_concurrency_slot(&task.concurrency_key, execution_id).await {
+        error!("failed to release concurrency slot {} of task execution {}: {}", task.concurrency_key, execution_id, err);
+    }
+}
+
+/// Puts back the rate limit tokens an execution took for a run that doesn't start.
+async fn give_back_tokens<B: LoadBalancer>(state: &GrpcSharedState<B>, task: &Task) {
+    if let Err(err) = state.rate_limiter().give_back(task).await {
+        error!("failed to give back the rate limit tokens of task {}: {}", task.id, err);
+    }
+}
+
+/// Extends the leases of the executions running on the local pool, as worker heartbeats do.
+pub(crate) async fn renew_local_leases<B: LoadBalancer>(
+    state: &GrpcSharedState<B>,
+    db: &dyn DataStore,
+    lease_duration: Duration,
+) -> Result<(), DataStoreError> {
+    if state.local_pool().is_none() {
+        return Ok(());
+    }
+    let lease_expires_at = current_timestamp() + lease_duration.as_secs() as i64;
+    db.renew_task_execution_leases(state.local_worker_id(), lease_expires_at).await?;
+    Ok(())
+}
+
+/// Rebuilds the queue from the data store after a (re)start.
+///
+/// Pending executions are claimed for `owner`, taking them over from a former leader, and
+/// submitted again in submission order unless the queue is durable, in which case only the
+/// ones running locally (which never enter the queue) are.
+/// Running executions are left to their worker until the lease runs out, after which
+/// the lease reaper submits them again. Running workflows start the nodes their
+/// recorded completions unblocked.
+pub(crate) async fn recover_executions<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    owner: &str,
+    lease_duration: Duration,
+) -> Result<(), DataStoreError> {
+    let durable = state.queues().iter().all(|named| named.queue().is_durable());
+    if durable {
+        let mut queued = 0;
+        for named in state.queues().iter() {
+            queued += named.queue().len().await?;
+        }
+        info!("task queues are durable, {} task executions still queued", queued);
+    }
+    if !durable || state.local_pool().is_some() {
+        let mut recovered = 0;
+        loop {
+            let claimed = db.claim_task_executions(owner, CLAIM_BATCH_SIZE).await?;
+            for record in claimed.iter().filter(|record| !durable || state.runs_locally(record.task_type())) {
+                submit(state, db, assign_request(record), lease_duration).await?;
+                recovered += 1;
+            }
+            if claimed.len() < CLAIM_BATCH_SIZE {
+                break;
+            }
+        }
+        info!("recovered {} queued task executions", recovered);
+    }
+
+    // Workflows catch up with the completions they missed while the scheduler was down
+    let resumed = state.workflows().recover(&**db).await?;
+    if !resumed.is_empty() {
+        info!("resuming {} workflow nodes", resumed.len());
+    }
+    submit_workflow_nodes(state, db, resumed, lease_duration).await;
+
+    // Running executions keep counting against the concurrency of their queue
+    let running = db
+        .list_all_task_executions(&ExecutionFilter::default().with_state(TaskState::Running))
+        .await?;
+    for record in running.iter().filter(|record| !state.runs_locally(record.task_type())) {
+        state.queues().started(&record.execution_id, record.task.queue_name());
+    }
+    info!("{} running task executions wait for their lease", running.len());
+    Ok(())
+}
+
+/// Assigns queued executions to workers as they become available, while this scheduler leads.
+///
+/// The lease is recorded before the execution is sent, so a worker completion can
+/// never be overwritten by its own lease. Leases are fenced with the token of the term,
+/// so a former leader that didn't notice the takeover yet stops assigning executions.
+/// Queue entries whose execution is no longer pending (e.g. queued twice) are dropped.
+pub(crate) fn spawn_dispatcher<B: LoadBalancer>(
+    state: Arc<GrpcSharedState<B>>,
+    db: Arc<dyn DataStore>,
+    election: Arc<LeaderElection>,
+    lease_duration: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut poll_interval = DISPATCH_POLL_INTERVAL;
+        loop {
+            let _ = timeout(poll_interval, state.dispatch_notified()).await;
+            poll_interval = DISPATCH_POLL_INTERVAL;
+            // Every scheduler runs its own local executions, leading or not
+            start_parked_executions(&state, &db, lease_duration).await;
+            let fencing_token = match election.fencing_token() {
+                Some(token) => token,
+                None => continue,
+            };
+            poll_interval = dispatch_round(&state, &db, fencing_token, lease_duration).await;
+        }
+    })
+}
+
+/// Assigns the queued executions that can start to workers, returns how soon the next
+/// round should run at the latest.
+async fn dispatch_round<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    fencing_token: u64,
+    lease_duration: Duration,
+) -> Duration {
+    let mut poll_interval = DISPATCH_POLL_INTERVAL;
+    // Executions are only taken from a queue once a worker subscribed to it can take them,
+    // queues take turns so that a busy one doesn't hold the others back
+    let mut throttled = Vec::new();
+    // Executions that can't start yet stay reserved for the round, so their queue hands out
+    // the ones behind them, and are given back once it is over
+    let mut held = Vec::new();
+    let mut limited_task_types = HashSet::new();
+    let mut saturated_keys = HashSet::new();
+    let mut progress = true;
+    while progress {
+        progress = false;
+        for named in state.queues().iter() {
+            if throttled.contains(&named.name()) || !state.queues().can_dispatch(named) {
+                continue;
+            }
+            let (worker_id, sender) = match state.select_worker(named.name()).await {
+                Some(worker) => worker,
+                None => continue,
+            };
+            let queued = match named.queue().reserve().await {
+                Ok(Some(queued)) => queued,
+                Ok(None) => continue,
+                Err(err) => {
+                    error!("failed to read the task queue {}: {}", named.name(), err);
+                    continue;
+                }
+            };
+            let execution_id = queued.request.execution_id.clone();
+            let task = queued.request.task.clone().unwrap_or_default();
+
+            // Stale executions are dropped before they take a slot, a token or a worker
+            if task.is_expired(current_timestamp()) {
+                if let Err(err) = expire(state, db, &queued.request, lease_duration).await {
+                    error!("failed to expire task execution {}: {}", execution_id, err);
+                }
+                if let Err(err) = named.queue().ack(&queued).await {
+                    error!("failed to ack queued task execution {}: {}", execution_id, err);
+                }
+                progress = true;
+                continue;
+            }
+
+            // Its task type ran out of tokens or its concurrency key was saturated earlier in the round
+            if limited_task_types.contains(task.executor_name()) || saturated_keys.contains(&task.concurrency_key) {
+                held.push((named, queued));
+                progress = true;
+                continue;
+            }
+
+            // An execution whose concurrency key is saturated waits for a completion, while the
+            // executions behind it with other keys go on
+            let acquired = acquire_slot(&**db, &task, &execution_id).await.unwrap_or_else(|err| {
+                error!("failed to acquire concurrency slot of task execution {}: {}", execution_id, err);
+                false
+            });
+            if !acquired {
+                debug!("concurrency key {} has no free slot", task.concurrency_key);
+                saturated_keys.insert(task.concurrency_key.clone());
+                held.push((named, queued));
+                progress = true;
+                continue;
+            }
+
+            // A rate limited execution waits until the next token, along with the whole queue
+            // when its queue ran out of tokens, or with the executions of its task type
+            let limited = match state.rate_limiter().try_acquire(&task).await {
+                Ok(limited) => limited,
+                Err(err) => {
+                    error!("failed to take a rate limit token for task execution {}: {}", execution_id, err);
+                    Some(RateLimited::Queue(DISPATCH_POLL_INTERVAL))
+                }
+            };
+            if let Some(limited) = limited {
+                release_slot(&**db, &task, &execution_id).await;
+                poll_interval = poll_interval.min(limited.wait());
+                match limited {
+                    RateLimited::Queue(wait) => {
+                        debug!("queue {} is rate limited for {:?}", named.name(), wait);
+                        if let Err(err) = named.queue().release(queued).await {
+                            error!("failed to queue task execution {} again: {}", execution_id, err);
+                        }
+                        throttled.push(named.name());
+                    }
+                    RateLimited::TaskType(wait) => {
+                        debug!("task type {} is rate limited for {:?}", task.executor_name(), wait);
+                        limited_task_types.insert(task.executor_name().to_string());
+                        held.push((named, queued));
+                        progress = true;
+                    }
+                }
+                continue;
+            }
+
+            match dispatch(&**db, &queued.request, &worker_id, &sender, fencing_token, lease_duration).await {
+                Ok(true) => {
+                    state.queues().started(&execution_id, named.name());
+                    debug!("task execution {} assigned to worker {}", execution_id, worker_id);
+                }
+                // It doesn't wait for a worker anymore, the run it took a token for never starts
+                Ok(false) => give_back_tokens(state, &task).await,
+                Err(err) => {
+                    error!("failed to assign task execution {}, queueing it again: {}", execution_id, err);
+                    release_slot(&**db, &task, &execution_id).await;
+                    give_back_tokens(state, &task).await;
+                    if let Err(err) = named.queue().release(queued).await {
+                        error!("failed to queue task execution {} again: {}", execution_id, err);
+                    }
+                    // Another scheduler leads now, the round stops right away
+                    if let DataStoreError::Fenced(_) = err {
+                        progress = false;
+                        break;
+                    }
+                    continue;
+                }
+            }
+            if let Err(err) = named.queue().ack(&queued).await {
+                error!("failed to ack queued task execution {}: {}", execution_id, err);
+            }
+            progress = true;
+        }
+    }
+    for (named, queued) in held {
+        let execution_id = queued.request.execution_id.clone();
+        if let Err(err) = named.queue().release(queued).await {
+            error!("failed to queue task execution {} again: {}", execution_id, err);
+        }
+    }
+    poll_interval
+}
+
+/// Records a pending execution whose task expired before it started as `Expired`,
+/// without running it. Executions that are not pending anymore are left alone.
+async fn expire<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    request: &AssignTaskRequest,
+    lease_duration: Duration,
+) -> Result<(), DataStoreError> {
+    let task = request.task.clone().unwrap_or_default();
+    match db.get_task_execution(&request.execution_id).await {
+        Ok(record) if record.state == TaskState::Pending => {}
+        Ok(_) | Err(DataStoreError::NotFound(_)) => return Ok(()),
+        Err(err) => return Err(err),
+    }
+
+    #[cfg(feature = "stats")]
+    increment_task(WorkerPoolTaskType::Expired);
+    info!("task execution {} expired at {} before it started, discarding it", request.execution_id, task.expires_at);
+    let outcome = TaskOutcome::expired(&task);
+    let completion = TaskCompletion::from_outcome(task.id, request.execution_id.clone(), outcome);
+    complete(state, db, &completion, None, lease_duration).await
+}
+
+/// Leases the execution to the worker and sends it, returns whether it was sent.
+///
+/// Executions that no longer exist or are not pending anymore are skipped, giving back the
+/// concurrency slot unless they still run elsewhere. Writes fail with `Fenced` once
+/// `fencing_token` belongs to a former term.
+async fn dispatch(
+    db: &dyn DataStore,
+    request: &AssignTaskRequest,
+    worker_id: &str,
+    sender: &Sender<Result<SchedulerMessage, Status>>,
+    fencing_token: u64,
+    lease_duration: Duration,
+) -> Result<bool, DataStoreError> {
+    let execution_id = &request.execution_id;
+    let task = request.task.clone().unwrap_or_default();
+    match db.get_task_execution(execution_id).await {
+        Ok(record) if record.state == TaskState::Pending => {}
+        Ok(record) => {
+            debug!("skipping queued task execution {} in state {:?}", execution_id, record.state);
+            // A running execution (e.g. queued twice) holds its slot for the run it is in
+            if record.state != TaskState::Running {
+                release_slot(db, &task, execution_id).await;
+            }
+            return Ok(false);
+        }
+        Err(DataStoreError::NotFound(_)) => {
+            warn!("dropping queued task execution {} which no longer exists", execution_id);
+            release_slot(db, &task, execution_id).await;
+            return Ok(false);
+        }
+        Err(err) => return Err(err),
+    }
+
+    let lease_expires_at = current_timestamp() + lease_duration.as_secs() as i64;
+    db.lease_task_execution(execution_id, worker_id, lease_expires_at, Some(fencing_token)).await?;
+
+    let message = SchedulerMessage {
+        scheduler_message_type: Some(
+            scheduler_message::SchedulerMessageType::AssignTask(request.clone())
+        ),
+    };
+    if sender.send(Ok(message)).await.is_err() {
+        db.requeue_task_execution(execution_id, fencing_token).await?;
+        return Err(DataStoreError::InternalError(format!("worker {} disconnected", worker_id)));
+    }
+    Ok(true)
+}
+
+/// Submits again the running executions whose worker stopped renewing the lease,
+/// checking every `lease_duration`.
+///
+/// Only the leader reaps, standbys would race it for the same executions. The requeues are
+/// fenced with the token of the term like the leases.
+pub(crate) fn spawn_lease_reaper<B: LoadBalancer>(
+    state: Arc<GrpcSharedState<B>>,
+    db: Arc<dyn DataStore>,
+    election: Arc<LeaderElection>,
+    lease_duration: Duration,
+) -> JoinHandle<()> {
+    tokio::spawn(async move {
+        let mut ticker = tokio::time::interval(lease_duration);
+        loop {
+            ticker.tick().await;
+            let fencing_token = match election.fencing_token() {
+                Some(token) => token,
+                None => continue,
+            };
+            if let Err(err) = requeue_expired_leases(&state, &db, fencing_token, lease_duration).await {
+                error!("failed to requeue expired task executions: {}", err);
+            }
+        }
+    })
+}
+
+async fn requeue_expired_leases<B: LoadBalancer>(
+    state: &Arc<GrpcSharedState<B>>,
+    db: &Arc<dyn DataStore>,
+    fencing_token: u64,
+    lease_duration: Duration,
+) -> Result<(), DataStoreError> {
+    let now = current_timestamp();
+    let running = db
+        .list_all_task_executions(&ExecutionFilter::default().with_state(TaskState::Running))
+        .await?;
+
+    for record in running.iter().filter(|record| record.lease_expired(now)) {
+        warn!(
+            "lease of task execution {} on worker {} expired, queueing it again",
+            record.execution_id,
+            record.worker_id.as_deref().unwrap_or("unknown")
+        );
+        state.queues().finished(&record.execution_id);
+        db.requeue_task_execution(&record.execution_id, fencing_token).await?;
+        submit(state, db, assign_request(record), lease_duration).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use tokio::sync::mpsc::{self, Receiver};
+
+    use crate::{
+        core::{
+            load_balancer::RoundRobinBalancer,
+            queues::DEFAULT_QUEUE,
+            rate_limit::RateLimiter,
+            task::TASK_EXPIRED,
+            worker_pool::{Builder, TaskExecutor, TaskRegistry},
+        },
+        data::{TaskQueue, TokenBucket, WalDataStore},
+        internal::protot::core::{RetryPolicy, Task},
+    };
+
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    fn default_queue<B: LoadBalancer>(state: &GrpcSharedState<B>) -> &dyn TaskQueue {
+        state.queues().get(DEFAULT_QUEUE).unwrap().queue()
+    }
+
+    #[tokio::test]
+    async fn test_recovery_and_expired_leases() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+        let now = current_timestamp();
+
+        for execution_id in ["queued", "leased", "expired"] {
+            store.add_task_execution(ExecutionRecord::new(execution_id.to_string(), Task::default())).await.unwrap();
+        }
+        store.lease_task_execution("leased", "worker-1", now + 60, None).await.unwrap();
+        store.lease_task_execution("expired", "worker-2", now - 1, None).await.unwrap();
+
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None));
+        recover_executions(&state, &store, "scheduler-1", LEASE).await.unwrap();
+        assert_eq!(default_queue(&state).len().await.unwrap(), 1);
+        let queued = default_queue(&state).reserve().await.unwrap().unwrap();
+        assert_eq!(queued.request.execution_id, "queued");
+        assert_eq!(store.get_task_execution("queued").await.unwrap().claimed_by.as_deref(), Some("scheduler-1"));
+        default_queue(&state).ack(&queued).await.unwrap();
+
+        requeue_expired_leases(&state, &store, 1, LEASE).await.unwrap();
+        let expired = default_queue(&state).reserve().await.unwrap().unwrap();
+        assert_eq!(expired.request.execution_id, "expired");
+        default_queue(&state).ack(&expired).await.unwrap();
+        assert_eq!(default_queue(&state).len().await.unwrap(), 0);
+
+        assert_eq!(store.get_task_execution("expired").await.unwrap().state, TaskState::Pending);
+        assert_eq!(store.get_task_execution("leased").await.unwrap().state, TaskState::Running);
+    }
+
+    #[tokio::test]
+    async fn test_late_completion_of_expired_lease_is_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None));
+        let now = current_timestamp();
+
+        let keyed = Task { concurrency_key: "key".to_string(), concurrency_limit: 1, ..Default::default() };
+        store.add_task_execution(ExecutionRecord::new("late".to_string(), keyed)).await.unwrap();
+        assert!(store.acquire_concurrency_slot("key", "late", 1).await.unwrap());
+        store.lease_task_execution("late", "worker-1", now - 1, None).await.unwrap();
+        requeue_expired_leases(&state, &store, 1, LEASE).await.unwrap();
+        store.lease_task_execution("late", "worker-2", now + 60, None).await.unwrap();
+        state.queues().started("late", DEFAULT_QUEUE);
+
+        // The first worker finishes after its lease went to the second one
+        let waiter = state.waiters().register("late");
+        let completion = TaskCompletion::from_result(String::new(), "late".to_string(), Ok(Default::default()));
+        assert_eq!(
+            complete(&state, &store, &completion, Some("worker-1"), LEASE).await,
+            Err(DataStoreError::LeaseLost("late".to_string()))
+        );
+        let record = store.get_task_execution("late").await.unwrap();
+        assert_eq!((record.state, record.worker_id.as_deref()), (TaskState::Running, Some("worker-2")));
+        assert!(!store.acquire_concurrency_slot("key", "other", 1).await.unwrap());
+        assert_eq!(state.queues().running(DEFAULT_QUEUE), 1);
+
+        complete(&state, &store, &completion, Some("worker-2"), LEASE).await.unwrap();
+        assert_eq!(waiter.wait(Some(Duration::from_secs(1))).await.unwrap().state(), TaskState::Success);
+        assert!(store.acquire_concurrency_slot("key", "other", 1).await.unwrap());
+        assert_eq!(state.queues().running(DEFAULT_QUEUE), 0);
+    }
+
+    /// Registers a remote worker with the scheduler, returns what is sent to it.
+    async fn connect_worker<B: LoadBalancer>(state: &GrpcSharedState<B>, worker_id: &str) -> Receiver<Result<SchedulerMessage, Status>> {
+        let (sender, receiver) = mpsc::channel(16);
+        let (close, _) = mpsc::channel(1);
+        state.grpc_worker_channels.lock().await.insert(worker_id.to_string(), (sender, close));
+        receiver
+    }
+
+    /// The executions assigned to a worker so far.
+    fn assigned(worker: &mut Receiver<Result<SchedulerMessage, Status>>) -> Vec<String> {
+        std::iter::from_fn(|| worker.try_recv().ok())
+            .filter_map(|message| match message.ok()?.scheduler_message_type? {
+                scheduler_message::SchedulerMessageType::AssignTask(request) => Some(request.execution_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_task_type_lets_others_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+        let limiter = RateLimiter::new(None).with_task_type_limit("email", TokenBucket { capacity: 1.0, rate: 0.01 });
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None).with_rate_limiter(Arc::new(limiter)));
+        let mut worker = connect_worker(&state, "worker-1").await;
+
+        for (execution_id, task_type) in [("email-1", "email"), ("email-2", "email"), ("render-1", "render")] {
+            let record = ExecutionRecord::new(execution_id.to_string(), task(task_type));
+            store.add_task_execution(record.clone()).await.unwrap();
+            state.enqueue(assign_request(&record)).await.unwrap();
+        }
+        dispatch_round(&state, &store, 1, LEASE).await;
+
+        // The second email waits for a token without holding back the render queued behind it
+        assert_eq!(assigned(&mut worker), vec!["email-1", "render-1"]);
+        assert_eq!(default_queue(&state).reserve().await.unwrap().unwrap().request.execution_id, "email-2");
+        assert_eq!(store.get_task_execution("email-2").await.unwrap().state, TaskState::Pending);
+    }
+
+    #[tokio::test]
+    async fn test_saturated_concurrency_key_lets_others_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None));
+        let mut worker = connect_worker(&state, "worker-1").await;
+
+        // Another execution of the first customer is running
+        assert!(store.acquire_concurrency_slot("customer-1", "other", 1).await.unwrap());
+        for (execution_id, customer) in [("sync-1", "customer-1"), ("sync-2", "customer-2"), ("sync-3", "customer-1")] {
+            let mut sync = task("sync");
+            sync.concurrency_key = customer.to_string();
+            let record = ExecutionRecord::new(execution_id.to_string(), sync);
+            store.add_task_execution(record.clone()).await.unwrap();
+            state.enqueue(assign_request(&record)).await.unwrap();
+        }
+        dispatch_round(&state, &store, 1, LEASE).await;
+
+        assert_eq!(assigned(&mut worker), vec!["sync-2"]);
+        assert_eq!(default_queue(&state).reserve().await.unwrap().unwrap().request.execution_id, "sync-1");
+        assert_eq!(default_queue(&state).reserve().await.unwrap().unwrap().request.execution_id, "sync-3");
+    }
+
+    #[tokio::test]
+    async fn test_skipped_execution_gives_back_its_slot_and_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+        let limiter = RateLimiter::new(None).with_task_type_limit("email", TokenBucket { capacity: 1.0, rate: 0.01 });
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None).with_rate_limiter(Arc::new(limiter)));
+        let mut worker = connect_worker(&state, "worker-1").await;
+
+        let mut email = task("email");
+        email.concurrency_key = "customer-1".to_string();
+        // Queued twice, the first copy is done by the time the second one is taken
+        let record = ExecutionRecord::new("done".to_string(), email.clone());
+        store.add_task_execution(record.clone()).await.unwrap();
+        store.update_task_execution_state("done", TaskState::Success, Some("worker-1")).await.unwrap();
+        state.enqueue(assign_request(&record)).await.unwrap();
+        dispatch_round(&state, &store, 1, LEASE).await;
+        assert!(assigned(&mut worker).is_empty());
+
+        // The next email of the customer gets the slot and the token
+        let record = ExecutionRecord::new("next".to_string(), email);
+        store.add_task_execution(record.clone()).await.unwrap();
+        state.enqueue(assign_request(&record)).await.unwrap();
+        dispatch_round(&state, &store, 1, LEASE).await;
+        assert_eq!(assigned(&mut worker), vec!["next"]);
+    }
+
+    struct NoopExecutor;
+
+    impl TaskExecutor for NoopExecutor {
+        fn execute(&self, _args: ExecuteRequest) -> Result<prost_types::Any, TaskError> {
+            Ok(prost_types::Any::default())
+        }
+    }
+
+    struct FailingExecutor;
+
+    impl TaskExecutor for FailingExecutor {
+        fn execute(&self, _args: ExecuteRequest) -> Result<prost_types::Any, TaskError> {
+            Err(TaskError::new("BROKEN", "always fails"))
+        }
+    }
+
+    fn task(task_type: &str) -> Task {
+        Task { id: format!("{}-1", task_type), task_type: task_type.to_string(), ..Default::default() }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_registered_tasks_run_locally() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+
+        let mut registry = TaskRegistry::new();
+        registry.register_task("local-task", NoopExecutor);
+        let pool = Builder::new()
+            .num_workers(1)
+            .grpc_workers()
+            .executors(Arc::new(Mutex::new(registry)))
+            .build()
+            .unwrap();
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None).with_local_pool(pool));
+
+        for (execution_id, task_name) in [("local", "local-task"), ("remote", "remote-task")] {
+            store.add_task_execution(ExecutionRecord::new(execution_id.to_string(), task(task_name))).await.unwrap();
+        }
+        recover_executions(&state, &store, "scheduler-1", LEASE).await.unwrap();
+
+        // Only the task the scheduler can't run itself waits for a remote worker
+        assert_eq!(default_queue(&state).len().await.unwrap(), 1);
+        assert_eq!(default_queue(&state).reserve().await.unwrap().unwrap().request.execution_id, "remote");
+
+        let mut local = store.get_task_execution("local").await.unwrap();
+        for _ in 0..50 {
+            if local.state == TaskState::Success {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            local = store.get_task_execution("local").await.unwrap();
+        }
+        assert_eq!(local.state, TaskState::Success);
+        assert_eq!(local.output, Some(prost_types::Any::default()));
+        assert_eq!(local.worker_id.as_deref(), Some(state.local_worker_id()));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_local_completion_reaches_waiter() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+
+        let mut registry = TaskRegistry::new();
+        registry.register_task("local-task", NoopExecutor);
+        let pool = Builder::new()
+            .num_workers(1)
+            .grpc_workers()
+            .executors(Arc::new(Mutex::new(registry)))
+            .build()
+            .unwrap();
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None).with_local_pool(pool));
+
+        let record = ExecutionRecord::new("local".to_string(), task("local-task"));
+        store.add_task_execution(record.clone()).await.unwrap();
+        let waiter = state.waiters().register("local");
+        submit(&state, &store, assign_request(&record), LEASE).await.unwrap();
+
+        let completion = waiter.wait(Some(Duration::from_secs(5))).await.unwrap();
+        assert_eq!(completion.state(), TaskState::Success);
+        assert_eq!(completion.task_id, "local-task-1");
+        assert_eq!(completion.output, Some(prost_types::Any::default()));
+        // The completion is stored before the caller is woken up
+        assert_eq!(store.get_task_execution("local").await.unwrap().state, TaskState::Success);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_execution_is_retried() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+
+        let mut registry = TaskRegistry::new();
+        registry.register_task("flaky-task", FailingExecutor);
+        let pool = Builder::new()
+            .num_workers(1)
+            .grpc_workers()
+            .executors(Arc::new(Mutex::new(registry)))
+            .build()
+            .unwrap();
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None).with_local_pool(pool));
+
+        let mut flaky = task("flaky-task");
+        flaky.retry_policy = Some(RetryPolicy {
+            max_attempts: 3,
+            initial_backoff: Some(prost_types::Duration::default()),
+            ..Default::default()
+        });
+        let record = ExecutionRecord::new("flaky".to_string(), flaky);
+        store.add_task_execution(record.clone()).await.unwrap();
+        let waiter = state.waiters().register("flaky");
+        submit(&state, &store, assign_request(&record), LEASE).await.unwrap();
+
+        // Callers only hear about the last attempt
+        let completion = waiter.wait(Some(Duration::from_secs(5))).await.unwrap();
+        assert_eq!(completion.state(), TaskState::Fail);
+        let record = store.get_task_execution("flaky").await.unwrap();
+        assert_eq!(record.state, TaskState::Fail);
+        assert_eq!(record.retries, 2);
+        assert_eq!(record.error.unwrap().code, "BROKEN");
+
+        // With no attempts left the execution is dead-lettered, along with all three attempts
+        let dead_letter = store.get_dead_letter("flaky").await.unwrap();
+        assert_eq!(dead_letter.task.unwrap().task_type, "flaky-task");
+        assert_eq!(dead_letter.attempts.iter().map(|attempt| attempt.attempt).collect::<Vec<_>>(), vec![1, 2, 3]);
+        assert!(dead_letter.attempts.iter().all(|attempt| attempt.worker_id == state.local_worker_id()));
+        assert_eq!(dead_letter.error.unwrap().code, "BROKEN");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_concurrency_key_holds_back_executions() {
+        // The pool threads block on the runtime, a lost wakeup must fail the test rather than hang it
+        timeout(Duration::from_secs(30), async {
+            let dir = tempfile::tempdir().unwrap();
+            let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+
+            let mut registry = TaskRegistry::new();
+            registry.register_task("sync-task", NoopExecutor);
+            registry.register_task("local-task", NoopExecutor);
+            let pool = Builder::new()
+                .num_workers(1)
+                .grpc_workers()
+                .executors(Arc::new(Mutex::new(registry)))
+                .build()
+                .unwrap();
+            let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None).with_local_pool(pool));
+
+            // Another execution of the customer is running
+            assert!(store.acquire_concurrency_slot("customer-1", "other", 1).await.unwrap());
+
+            let mut sync = task("sync-task");
+            sync.concurrency_key = "customer-1".to_string();
+            let record = ExecutionRecord::new("sync".to_string(), sync);
+            store.add_task_execution(record.clone()).await.unwrap();
+            let waiter = state.waiters().register("sync");
+            submit(&state, &store, assign_request(&record), LEASE).await.unwrap();
+            assert_eq!(store.get_task_execution("sync").await.unwrap().state, TaskState::Pending);
+
+            // It waits parked, the only pool thread runs the next execution meanwhile
+            let record = ExecutionRecord::new("local".to_string(), task("local-task"));
+            store.add_task_execution(record.clone()).await.unwrap();
+            let local = state.waiters().register("local");
+            submit(&state, &store, assign_request(&record), LEASE).await.unwrap();
+            assert_eq!(local.wait(Some(Duration::from_secs(5))).await.unwrap().state(), TaskState::Success);
+            start_parked_executions(&state, &store, LEASE).await;
+            assert_eq!(store.get_task_execution("sync").await.unwrap().state, TaskState::Pending);
+
+            store.release_concurrency_slot("customer-1", "other").await.unwrap();
+            start_parked_executions(&state, &store, LEASE).await;
+            let completion = waiter.wait(Some(Duration::from_secs(5))).await.unwrap();
+            assert_eq!(completion.state(), TaskState::Success);
+            // The completed execution gave its slot back
+            assert!(store.acquire_concurrency_slot("customer-1", "next", 1).await.unwrap());
+        })
+        .await
+        .expect("the parked execution didn't run");
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_expired_tasks_are_discarded() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+
+        let mut registry = TaskRegistry::new();
+        registry.register_task("local-task", NoopExecutor);
+        let pool = Builder::new()
+            .num_workers(1)
+            .grpc_workers()
+            .executors(Arc::new(Mutex::new(registry)))
+            .build()
+            .unwrap();
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None).with_local_pool(pool));
+
+        // The local pool discards it instead of running it
+        let mut stale = task("local-task");
+        stale.expires_at = current_timestamp() - 1;
+        let record = ExecutionRecord::new("local".to_string(), stale);
+        store.add_task_execution(record.clone()).await.unwrap();
+        let waiter = state.waiters().register("local");
+        submit(&state, &store, assign_request(&record), LEASE).await.unwrap();
+
+        let completion = waiter.wait(Some(Duration::from_secs(5))).await.unwrap();
+        assert_eq!(completion.state(), TaskState::Expired);
+        assert_eq!(completion.error.unwrap().code, TASK_EXPIRED);
+        assert_eq!(completion.output, None);
+        assert_eq!(store.get_task_execution("local").await.unwrap().state, TaskState::Expired);
+
+        // So does the dispatcher with a queued one, which is neither retried nor dead-lettered
+        let mut stale = task("remote-task");
+        stale.expires_at = current_timestamp() - 1;
+        stale.retry_policy = Some(RetryPolicy { max_attempts: 3, ..Default::default() });
+        let record = ExecutionRecord::new("remote".to_string(), stale);
+        store.add_task_execution(record.clone()).await.unwrap();
+        let waiter = state.waiters().register("remote");
+        expire(&state, &store, &assign_request(&record), LEASE).await.unwrap();
+
+        assert_eq!(waiter.wait(Some(Duration::from_secs(5))).await.unwrap().state(), TaskState::Expired);
+        let expired = store.get_task_execution("remote").await.unwrap();
+        assert_eq!(expired.state, TaskState::Expired);
+        assert_eq!(expired.retries, 0);
+        assert!(store.get_dead_letter("remote").await.is_err());
+
+        // Expiring it again leaves the finished execution alone
+        expire(&state, &store, &assign_request(&record), LEASE).await.unwrap();
+        assert_eq!(store.get_task_execution("remote").await.unwrap().state, TaskState::Expired);
+    }
+
+    #[test]
+    fn test_ttl_resolves_expiry() {
+        let mut ttl = task("ttl-task");
+        ttl.ttl = Some(prost_types::Duration { seconds: 300, nanos: 0 });
+        ttl.resolve_expiry(1_000);
+        assert_eq!(ttl.expires_at, 1_300);
+        // Resolving it again, e.g. on recovery, keeps the time it expires at
+        ttl.resolve_expiry(2_000);
+        assert_eq!(ttl.expires_at, 1_300);
+        assert!(!ttl.is_expired(1_299));
+        assert!(ttl.is_expired(1_300));
+
+        let mut never = task("ttl-task");
+        never.resolve_expiry(1_000);
+        assert_eq!(never.expires_at, 0);
+        assert!(!never.is_expired(i64::MAX));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_workflow_runs_nodes_in_dependency_order() {
+        use crate::internal::protot::core::{Workflow, WorkflowNode, WorkflowNodeState, WorkflowState, WorkflowStatus};
+
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+
+        let mut registry = TaskRegistry::new();
+        registry.register_task("step", NoopExecutor);
+        registry.register_task("broken", FailingExecutor);
+        let pool = Builder::new()
+            .num_workers(2)
+            .grpc_workers()
+            .executors(Arc::new(Mutex::new(registry)))
+            .build()
+            .unwrap();
+        let state = Arc::new(GrpcSharedState::new(RoundRobinBalancer::new(), None).with_local_pool(pool));
+
+        let node = |name: &str, task_type: &str, depends_on: &[&str]| WorkflowNode {
+            name: name.to_string(),
+            task: Some(task(task_type)),
+            depends_on: depends_on.iter().map(|dependency| dependency.to_string()).collect(),
+            ..Default::default()
+        };
+        let workflow = Workflow {
+            workflow_id: "wf-1".to_string(),
+            nodes: vec![
+                node("extract", "step", &[]),
+                node("load", "step", &["extract"]),
+                node("check", "broken", &["extract"]),
+                node("report", "step", &["load", "check"]),
+            ],
+            ..Default::default()
+        };
+        let status = WorkflowStatus::new(workflow, current_timestamp()).unwrap();
+        let (_, started) = state.workflows().submit(&*store, status).await.unwrap();
+        assert_eq!(started.len(), 1);
+        submit_workflow_nodes(&state, &store, started, LEASE).await;
+
+        let mut status = store.get_workflow("wf-1").await.unwrap();
+        for _ in 0..100 {
+            if status.is_finished() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            status = store.get_workflow("wf-1").await.unwrap();
+        }
+        assert_eq!(status.state(), WorkflowState::Fail);
+        let states: Vec<WorkflowNodeState> = status.nodes.iter().map(|node| node.state()).collect();
+        assert_eq!(
+            states,
+            vec![WorkflowNodeState::Success, WorkflowNodeState::Success, WorkflowNodeState::Fail, WorkflowNodeState::Skipped]
+        );
+        assert_eq!(status.nodes[2].error.as_ref().unwrap().code, "BROKEN");
+        // Each started node ran as an execution tied to the workflow
+        let load = store.get_task_execution(&status.nodes[1].execution_id).await.unwrap();
+        assert_eq!(load.state, TaskState::Success);
+        assert_eq!((load.task.workflow_id.as_str(), load.task.workflow_node.as_str()), ("wf-1", "load"));
+        assert!(status.nodes[3].execution_id.is_empty());
+    }
+}
@@ -0,0 +1,304 @@
+// Copyright 2023 The ProtoT Authors
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use log::{error, info, warn};
+use tokio::{sync::watch, task::JoinHandle};
+use uuid::Uuid;
+
+use crate::{
+    core::{grpc_executor::GrpcSharedState, load_balancer::LoadBalancer},
+    data::DataStore,
+    internal::protot::scheduler::v1::{scheduler_message, Redirect, SchedulerMessage},
+};
+
+use super::dispatcher;
+
+/// Number of times the leader renews its lease within one lease duration.
+const RENEWALS_PER_LEASE: u32 = 3;
+
+/// Role of this scheduler among the replicas sharing a data store.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Role {
+    /// Dispatches executions, `token` is the fencing token of its term.
+    Leader { token: u64 },
+    /// Redirects workers to the leader, whose address is `leader` when known.
+    Standby { leader: Option<String> },
+}
+
+/// Elects one leader among the scheduler replicas through a lease in the data store.
+///
+/// The leader renews its lease every third of `ttl`. When it stops renewing (or resigns),
+/// a standby takes the lease over with a new fencing token once it expires.
+pub(crate) struct LeaderElection {
+    db: Arc<dyn DataStore>,
+    holder: String,
+    address: String,
+    ttl: Duration,
+    role: watch::Sender<Role>,
+    renewed_at: Mutex<Option<Instant>>,
+}
+
+impl LeaderElection {
+    pub(crate) fn new(db: Arc<dyn DataStore>, address: String, ttl: Duration) -> Self {
+        let (role, _) = watch::channel(Role::Standby { leader: None });
+        Self {
+            db,
+            holder: Uuid::new_v4().to_string(),
+            address,
+            ttl,
+            role,
+            renewed_at: Mutex::new(None),
+        }
+    }
+
+    pub(crate) fn role(&self) -> Role {
+        self.role.borrow().clone()
+    }
+
+    pub(crate) fn is_leader(&self) -> bool {
+        matches!(*self.role.borrow(), Role::Leader { .. })
+    }
+
+    /// Fencing token of the current term, while this scheduler leads.
+    pub(crate) fn fencing_token(&self) -> Option<u64> {
+        match *self.role.borrow() {
+            Role::Leader { token } => Some(token),
+            Role::Standby { .. } => None,
+        }
+    }
+
+    /// Address of the current leader, when known.
+    pub(crate) fn leader_address(&self) -> Option<String> {
+        match self.role() {
+            Role::Leader { .. } => Some(self.address.clone()).filter(|address| !address.is_empty()),
+            Role::Standby { leader } => leader,
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> watch::Receiver<Role> {
+        self.role.subscribe()
+    }
+
+    /// Runs one election round and publishes the resulting role.
+    pub(crate) async fn campaign(&self) -> Role {
+        let role = match self.db.acquire_leader_lease(&self.holder, &self.address, self.ttl).await {
+            Ok(lease) if lease.holder == self.holder => {
+                *self.renewed_at.lock().unwrap() = Some(Instant::now());
+                Role::Leader { token: lease.token }
+            }
+            Ok(lease) => Role::Standby { leader: Some(lease.address).filter(|address| !address.is_empty()) },
+            Err(err) => {
+                error!("leader election failed: {}", err);
+                // A leader keeps leading for as long as its last renewed lease holds
+                let renewed_at = *self.renewed_at.lock().unwrap();
+                match self.role() {
+                    Role::Leader { token } if renewed_at.is_some_and(|at| at.elapsed() < self.ttl) => Role::Leader { token },
+                    _ => Role::Standby { leader: None },
+                }
+            }
+        };
+
+        self.role.send_if_modified(|current| {
+            let changed = *current != role;
+            *current = role.clone();
+            changed
+        });
+        role
+    }
+
+    /// Gives up the lease so a standby takes over without waiting for it to expire.
+    pub(crate) async fn resign(&self) {
+        if !self.is_leader() {
+            return;
+        }
+        if let Err(err) = self.db.release_leader_lease(&self.holder).await {
+            error!("failed to release leader lease: {}", err);
+        }
+        self.role.send_replace(Role::Standby { leader: None });
+    }
+}
+
+/// Campaigns for leadership in the background, reacting to every role change.
+///
+/// A new leader rebuilds the queue from the data store, a scheduler that loses the
+/// lease redirects its workers to the new leader.
+pub(crate) fn spawn_leader_election<B: LoadBalancer>(
+    election: Arc<LeaderElection>,
+    state: Arc<GrpcSharedState<B>>,
+    lease_duration: Duration,
+) -> JoinHandle<()> {
+    let mut roles = election.subscribe();
+    let campaigner = election.clone();
+    tokio::spawn(async move {
+        loop {
+            campaigner.campaign().await;
+            tokio::time::sleep(campaigner.ttl / RENEWALS_PER_LEASE).await;
+        }
+    });
+
+    tokio::spawn(async move {
+        while roles.changed().await.is_ok() {
+            let role = roles.borrow_and_update().clone();
+            match role {
+                Role::Leader { token } => {
+                    info!("elected scheduler leader with fencing token {}", token);
+                    if let Err(err) = dispatcher::recover_executions(&state, &election.db, &election.holder, lease_duration).await {
+                        error!("failed to recover task executions after election: {}", err);
+                    }
+                    state.notify_dispatcher();
+                }
+                Role::Standby { leader } => {
+                    warn!("standing by, current leader: {}", leader.as_deref().unwrap_or("unknown"));
+                    redirect_workers(&state, leader).await;
+                }
+            }
+        }
+    })
+}
+
+/// Tells every connected worker to reconnect to the leader and drops its channel.
+pub(crate) async fn redirect_workers<B: LoadBalancer>(state: &GrpcSharedState<B>, leader: Option<String>) {
+    let mut channels = state.grpc_worker_channels.lock().await;
+    for (worker_id, (sender, cancel)) in channels.drain() {
+        info!("redirecting worker {} to the leader", worker_id);
+        let _ = sender.send(Ok(redirect_message(leader.clone()))).await;
+        let _ = cancel.send(()).await;
+    }
+}
+
+pub(crate) fn redirect_message(leader: Option<String>) -> SchedulerMessage {
+    SchedulerMessage {
+        scheduler_message_type: Some(scheduler_message::SchedulerMessageType::Redirect(Redirect {
+            leader_address: leader.unwrap_or_default(),
+        })),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::data::{DataStoreError, ExecutionFilter, ExecutionRecord, LeaderLease, Page, PageRequest, TokenBucket, WalDataStore};
+    use crate::internal::protot::{core::{ExecutionAttempt, TaskState, WorkflowState, WorkflowStatus}, scheduler::v1::{DeadLetter, TaskCompletion}};
+    use async_trait::async_trait;
+    use std::collections::HashMap;
+
+    use super::*;
+
+    /// A WAL store whose leader lease can be contested, which the WAL store itself doesn't
+    /// support as it belongs to a single scheduler. Everything else goes to the WAL store.
+    struct ContestedStore {
+        store: WalDataStore,
+        leader: Mutex<Option<LeaderLease>>,
+    }
+
+    #[async_trait]
+    impl DataStore for ContestedStore {
+        async fn add_task_execution(&self, record: ExecutionRecord) -> Result<(), DataStoreError> { self.store.add_task_execution(record).await }
+        async fn get_task_execution(&self, id: &str) -> Result<ExecutionRecord, DataStoreError> { self.store.get_task_execution(id).await }
+        async fn list_task_executions(&self, filter: &ExecutionFilter, page: &PageRequest) -> Result<Page<ExecutionRecord>, DataStoreError> {
+            self.store.list_task_executions(filter, page).await
+        }
+        async fn count_task_executions_by_state(&self, filter: &ExecutionFilter) -> Result<HashMap<TaskState, u64>, DataStoreError> {
+            self.store.count_task_executions_by_state(filter).await
+        }
+        async fn update_task_execution_state(&self, id: &str, state: TaskState, worker_id: Option<&str>) -> Result<(), DataStoreError> {
+            self.store.update_task_execution_state(id, state, worker_id).await
+        }
+        async fn complete_task_execution(&self, completion: &TaskCompletion, worker_id: Option<&str>) -> Result<(), DataStoreError> {
+            self.store.complete_task_execution(completion, worker_id).await
+        }
+        async fn retry_task_execution(&self, id: &str, attempt: &ExecutionAttempt) -> Result<u32, DataStoreError> {
+            self.store.retry_task_execution(id, attempt).await
+        }
+        async fn lease_task_execution(&self, id: &str, worker_id: &str, expires_at: i64, token: Option<u64>) -> Result<(), DataStoreError> {
+            self.store.lease_task_execution(id, worker_id, expires_at, token).await
+        }
+        async fn requeue_task_execution(&self, id: &str, token: u64) -> Result<(), DataStoreError> { self.store.requeue_task_execution(id, token).await }
+        async fn renew_task_execution_leases(&self, worker_id: &str, expires_at: i64) -> Result<u64, DataStoreError> {
+            self.store.renew_task_execution_leases(worker_id, expires_at).await
+        }
+        async fn claim_task_executions(&self, owner: &str, limit: usize) -> Result<Vec<ExecutionRecord>, DataStoreError> {
+            self.store.claim_task_executions(owner, limit).await
+        }
+        async fn purge_task_executions(&self, filter: &ExecutionFilter) -> Result<u64, DataStoreError> { self.store.purge_task_executions(filter).await }
+        async fn claim_idempotency_key(&self, key: &str, id: &str, window: Duration) -> Result<String, DataStoreError> {
+            self.store.claim_idempotency_key(key, id, window).await
+        }
+        async fn release_idempotency_key(&self, key: &str, id: &str) -> Result<(), DataStoreError> { self.store.release_idempotency_key(key, id).await }
+        async fn take_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<Option<(usize, Duration)>, DataStoreError> {
+            self.store.take_rate_limit_tokens(buckets).await
+        }
+        async fn return_rate_limit_tokens(&self, buckets: &[(String, TokenBucket)]) -> Result<(), DataStoreError> {
+            self.store.return_rate_limit_tokens(buckets).await
+        }
+        async fn acquire_concurrency_slot(&self, key: &str, id: &str, limit: u32) -> Result<bool, DataStoreError> {
+            self.store.acquire_concurrency_slot(key, id, limit).await
+        }
+        async fn release_concurrency_slot(&self, key: &str, id: &str) -> Result<(), DataStoreError> { self.store.release_concurrency_slot(key, id).await }
+        async fn add_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), DataStoreError> { self.store.add_dead_letter(dead_letter).await }
+        async fn get_dead_letter(&self, id: &str) -> Result<DeadLetter, DataStoreError> { self.store.get_dead_letter(id).await }
+        async fn list_dead_letters(&self, task_type: Option<&str>, page: &PageRequest) -> Result<Page<DeadLetter>, DataStoreError> {
+            self.store.list_dead_letters(task_type, page).await
+        }
+        async fn take_dead_letter(&self, id: &str) -> Result<DeadLetter, DataStoreError> { self.store.take_dead_letter(id).await }
+        async fn purge_dead_letters(&self, task_type: Option<&str>) -> Result<u64, DataStoreError> { self.store.purge_dead_letters(task_type).await }
+        async fn add_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError> { self.store.add_workflow(workflow).await }
+        async fn get_workflow(&self, workflow_id: &str) -> Result<WorkflowStatus, DataStoreError> { self.store.get_workflow(workflow_id).await }
+        async fn update_workflow(&self, workflow: &WorkflowStatus) -> Result<(), DataStoreError> { self.store.update_workflow(workflow).await }
+        async fn list_workflows(&self, state: WorkflowState) -> Result<Vec<WorkflowStatus>, DataStoreError> { self.store.list_workflows(state).await }
+
+        async fn acquire_leader_lease(&self, holder: &str, address: &str, _ttl: Duration) -> Result<LeaderLease, DataStoreError> {
+            let mut leader = self.leader.lock().unwrap();
+            let lease = leader.get_or_insert_with(|| LeaderLease {
+                holder: holder.to_string(),
+                address: address.to_string(),
+                token: 1,
+            });
+            Ok(lease.clone())
+        }
+
+        async fn release_leader_lease(&self, holder: &str) -> Result<(), DataStoreError> {
+            let mut leader = self.leader.lock().unwrap();
+            if leader.as_ref().is_some_and(|lease| lease.holder == holder) {
+                *leader = None;
+            }
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_standby_takes_over_after_resign() {
+        let dir = tempfile::tempdir().unwrap();
+        let store: Arc<dyn DataStore> = Arc::new(ContestedStore {
+            store: WalDataStore::open(dir.path(), 100).unwrap(),
+            leader: Mutex::new(None),
+        });
+        let ttl = Duration::from_secs(30);
+        let first = LeaderElection::new(store.clone(), "http://first:44880".to_string(), ttl);
+        let second = LeaderElection::new(store, "http://second:44880".to_string(), ttl);
+
+        assert_eq!(first.campaign().await, Role::Leader { token: 1 });
+        assert_eq!(second.campaign().await, Role::Standby { leader: Some("http://first:44880".to_string()) });
+        assert_eq!(second.leader_address().as_deref(), Some("http://first:44880"));
+
+        first.resign().await;
+        assert!(!first.is_leader());
+        assert!(matches!(second.campaign().await, Role::Leader { .. }));
+        assert_eq!(first.campaign().await, Role::Standby { leader: Some("http://second:44880".to_string()) });
+    }
+}
@@ -19,7 +19,7 @@ use hyper::{Body, Request as HyperRequest, Response as HyperResponse, Server as
 #[cfg(feature = "stats")]
 use lazy_static::lazy_static;
 #[cfg(feature = "stats")]
-use prometheus::{CounterVec, Encoder, GaugeVec, IntCounterVec, IntGaugeVec, Opts, Registry};
+use prometheus::{Encoder, GaugeVec, IntGaugeVec, Opts, Registry};
 #[cfg(feature = "stats")]
 use std::convert::Infallible;
 #[cfg(feature = "stats")]
@@ -36,7 +36,7 @@ pub enum WorkerPoolTaskType {
     Executed,
     Panic,
     Queued,
-    Dispatched,
+    Expired,
 }
 
 #[cfg(feature = "stats")]
@@ -61,10 +61,8 @@ lazy_static! {
 
 #[cfg(feature = "stats")]
 fn register_metrics() {
-    for metric in vec![
-        Box::new(WORKER_POOL_METRICS.clone()),
-        Box::new(WORKER_POOL_TASKS.clone()),
-    ] {
+    for metric in [Box::new(WORKER_POOL_METRICS.clone()),
+        Box::new(WORKER_POOL_TASKS.clone())] {
         REGISTRY
             .register(metric)
             .expect("Failed to register metric");
@@ -86,7 +84,6 @@ pub async fn start_metrics_server() -> Result<(), Box<dyn std::error::Error>> {
         let metric_families = REGISTRY.gather(); // Use custom registry
         let mut buffer = vec![];
         encoder.encode(&metric_families, &mut buffer).unwrap();
-        let output = String::from_utf8(buffer.clone()).unwrap();
 
         Ok(HyperResponse::new(Body::from(buffer)))
     }
@@ -107,8 +104,8 @@ pub fn increment_task(task_type: WorkerPoolTaskType) {
     let label = match task_type {
         WorkerPoolTaskType::Executed => "executed",
         WorkerPoolTaskType::Panic => "panic",
-        WorkerPoolTaskType::Dispatched => "dispatched",
         WorkerPoolTaskType::Queued => "queued",
+        WorkerPoolTaskType::Expired => "expired",
     };
     WORKER_POOL_TASKS.with_label_values(&[label]).inc();
 }
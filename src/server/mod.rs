@@ -13,6 +13,8 @@
 // limitations under the License.
 
 pub mod metrics;
+mod dispatcher;
+mod leader;
 
 use std::{
     pin::Pin,
@@ -20,54 +22,57 @@ use std::{
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
-    }, time::{Duration, Instant}, collections::HashMap,
+    }, time::{Duration, Instant},
 };
-use std::future::Future;
-use tokio_util::sync::CancellationToken;
 use uuid::Uuid;
 
-use crate::{internal::protot::{scheduler::v1::{Ack, WorkerChannelStatus, worker_message::{self, WorkerMessageType}}, core::NodeType}, core::{grpc_executor::GrpcSharedState, load_balancer::{LoadBalancer, RoundRobinBalancer}}, data::{DataStore, self}};
+use crate::{internal::protot::{scheduler::v1::{Ack, WorkerChannelStatus, worker_message::WorkerMessageType}, core::TaskState}, core::{grpc_executor::GrpcSharedState, load_balancer::{LoadBalancer, RoundRobinBalancer}, queues::QueueRegistry, rate_limit::RateLimiter, waiters::{parse_grpc_timeout, CompletionWaiter, CompletionWaiters}}, server::leader::LeaderElection, data::{DataStore, DataStoreError, ExecutionFilter, ExecutionRecord, PageRequest, DEFAULT_PAGE_LIMIT, self}, utils, SchedulerError};
 #[allow(unused_imports)]
 use crate::{
-    core::worker_pool::{self, WorkerPool},
+    core::{task::TaskOutcome, worker_pool::{self, WorkerPool}},
     internal::protot::{
-        core::Task,
+        core::{Task, TaskError, Workflow, WorkflowStatus},
         scheduler::v1::{
             scheduler_message,
             scheduler_service_server::{SchedulerService, SchedulerServiceServer},
             scheduler_worker_service_server::{
                 SchedulerWorkerService, SchedulerWorkerServiceServer,
             },
-            AssignTaskRequest, ExecuteRequest, ExecuteResponse, ScheduleRequest, ScheduleResponse,
-            SchedulerMessage, WorkerMessage,
+            AssignTaskRequest, DeadLetter, DeadLetterRequest, ExecuteRequest, ExecuteResponse, GetExecutionRequest, GetWorkflowRequest, ListDeadLettersRequest,
+            ListDeadLettersResponse, PurgeDeadLettersRequest, PurgeDeadLettersResponse, PurgeExecutionsRequest, PurgeExecutionsResponse,
+            QueueRequest, QueueStatus, RequeueDeadLetterRequest, ScheduleRequest, ScheduleResponse, SchedulerMessage, SubmitTask, TaskCompletion,
+            TaskSubmitted, WorkerMessage,
         },
     },
     logger,
 };
-use futures::{Stream, StreamExt, TryFutureExt};
-use log::{info, error, debug};
+use futures::{Stream, StreamExt};
+use log::{info, error, debug, warn};
 use tokio::{
     signal::unix::{signal, SignalKind},
-    sync::{mpsc::{self, Receiver, Sender}, Mutex}, select, time::{timeout, sleep},
+    sync::{mpsc::{self, Sender}, Mutex}, select, time::sleep,
 };
 use tokio_stream::wrappers::ReceiverStream; // Import the ReceiverStream type
 use tonic::{transport::Server, Request, Response, Status};
 use tonic_types::{ErrorDetails, StatusExt};
 
 type CommunicateStreamType = Pin<Box<dyn Stream<Item = Result<SchedulerMessage, Status>> + Send>>;
-type WorkerChannels = Arc<Mutex<HashMap<String, (Sender<Result<SchedulerMessage, Status>>, Sender<()>)>>>;
 
 
 // Implement the gRPC service
 // #[derive(Default)]
 pub struct SchedulerServer<B: LoadBalancer> {
     shared_state: Arc<GrpcSharedState<B>>,
-    data_layer: Arc<Mutex<dyn DataStore>>,
+    data_layer: Arc<dyn DataStore>,
+    election: Arc<LeaderElection>,
+    lease_duration: Duration,
+    /// Idempotency window of the child tasks submitted by workers.
+    idempotency_window: Duration,
 }
 
 impl<B: LoadBalancer> SchedulerServer<B> {
-    pub fn new(shared_state: Arc<GrpcSharedState<B>>, data_layer: Arc<Mutex<dyn DataStore>>) -> Self {
-        Self { shared_state , data_layer }
+    pub(crate) fn new(shared_state: Arc<GrpcSharedState<B>>, data_layer: Arc<dyn DataStore>, election: Arc<LeaderElection>, lease_duration: Duration, idempotency_window: Duration) -> Self {
+        Self { shared_state , data_layer, election, lease_duration, idempotency_window }
     }
 }
 
@@ -85,11 +90,19 @@ impl<B: LoadBalancer> SchedulerWorkerService for SchedulerServer<B> {
         let tx_binding = tx.clone();
 
         let shared_state = self.shared_state.clone();
+        let data_layer = self.data_layer.clone();
+        let election = self.election.clone();
+        let lease_duration = self.lease_duration;
+        let idempotency_window = self.idempotency_window;
         // Spawn a new task to process incoming messages and send responses
         tokio::spawn(async move {
 
             let result = Self::handle_communicate(
                 shared_state,
+                data_layer,
+                election,
+                lease_duration,
+                idempotency_window,
                 tx.clone(),
                 tx_cancel.clone(),
                 request.into_inner()
@@ -120,8 +133,13 @@ impl<B: LoadBalancer> SchedulerWorkerService for SchedulerServer<B> {
 }
 
 impl<B: LoadBalancer> SchedulerServer<B> {
+    #[allow(clippy::too_many_arguments)]
     async fn handle_communicate(
         shared_state: Arc<GrpcSharedState<B>>, // replace SharedState with the actual type
+        data_layer: Arc<dyn DataStore>,
+        election: Arc<LeaderElection>,
+        lease_duration: Duration,
+        idempotency_window: Duration,
         tx: Sender<Result<SchedulerMessage, Status>>,
         tx_cancel: Sender<()>,
         mut stream: tonic::Streaming<WorkerMessage>,
@@ -135,23 +153,66 @@ impl<B: LoadBalancer> SchedulerServer<B> {
                         Some(WorkerMessageType::Heartbeat(pong)) => {
                             let binding = registered_worker_id.clone();
                             debug!("[{}] Got heartbeat from worker: {:?}", binding.clone().unwrap(), pong);
-                            if binding.is_some() {
+                            if let Some(worker_id) = binding {
                                 let shared_heartbeat = shared_state.worker_heartbeat.clone();  // assuming shared_data contains worker_heartbeat
                                 let mut heartbeats = shared_heartbeat.lock().await;
-                                heartbeats.insert(binding.unwrap().clone(), Instant::now());
+                                heartbeats.insert(worker_id.clone(), Instant::now());
+
+                                // A live worker keeps owning the executions it is running
+                                let lease_expires_at = utils::current_timestamp() + lease_duration.as_secs() as i64;
+                                if let Err(err) = data_layer.renew_task_execution_leases(&worker_id, lease_expires_at).await {
+                                    error!("failed to renew task execution leases of worker {}: {}", worker_id, err);
+                                }
                             }
                             SchedulerMessage::default()
                         }
                         Some(WorkerMessageType::Completion(task_completion)) => {
                             info!("got task completion event: {:?}", task_completion);
+                            let state = task_completion.state();
+                            if state == TaskState::Pending || state == TaskState::Running {
+                                error!("ignoring completion of task execution {} in non final state {:?}", task_completion.execution_id, state);
+                            } else {
+                                let worker_id = registered_worker_id.as_deref();
+                                match dispatcher::complete(&shared_state, &data_layer, &task_completion, worker_id, lease_duration).await {
+                                    Ok(()) => {}
+                                    Err(DataStoreError::LeaseLost(_)) => warn!(
+                                        "ignoring completion of task execution {} from worker {}, its lease expired",
+                                        task_completion.execution_id,
+                                        worker_id.unwrap_or_default()
+                                    ),
+                                    Err(err) => error!("failed to record completion of task execution {}: {}", task_completion.execution_id, err),
+                                }
+                            }
+                            SchedulerMessage::default()
+                        }
+                        Some(WorkerMessageType::SubmitTask(submit)) if registered_worker_id.is_some() => {
+                            // Waiting for the child task must not hold the stream up
+                            tokio::spawn(submit_child_task(
+                                shared_state.clone(),
+                                data_layer.clone(),
+                                election.clone(),
+                                lease_duration,
+                                idempotency_window,
+                                tx.clone(),
+                                submit,
+                            ));
                             SchedulerMessage::default()
                         }
                         Some(WorkerMessageType::Registration(registration_request)) => {
                             info!("worker registration: {:?}", registration_request);
+                            // Only the leader dispatches, workers of a standby would starve
+                            if !election.is_leader() {
+                                info!("redirecting worker {} to the leader", registration_request.worker_id);
+                                let _ = tx.send(Ok(leader::redirect_message(election.leader_address()))).await;
+                                return Ok(());
+                            }
                             let worker_id = registration_request.worker_id.clone();
+                            // Subscriptions are known before the dispatcher can pick the worker
+                            shared_state.subscribe_worker(&worker_id, registration_request.queues.clone()).await;
                             let mut channels = shared_state.grpc_worker_channels.lock().await;
                             channels.insert(worker_id.clone(), (tx.clone(), tx_cancel.clone()));
                             registered_worker_id = Some(worker_id.clone());
+                            shared_state.notify_dispatcher();
                             SchedulerMessage {
                                 scheduler_message_type: Some(
                                     scheduler_message::SchedulerMessageType::Ack(
@@ -173,19 +234,20 @@ impl<B: LoadBalancer> SchedulerServer<B> {
                     };
 
                     // Process the worker message and create a response
-                    if response.scheduler_message_type.is_some() {
-                        if tx.send(Ok(response)).await.is_err() {
+                    if response.scheduler_message_type.is_some()
+                        && tx.send(Ok(response)).await.is_err() {
                             error!("{:?}", "errored");
                             // TODO
                             // Handle error sending response
                         }
-                    }
                 }
                 Err(status) => {
                     error!("{:?}: {:?}", status, status.metadata());
                     if let Some(worker_id) = registered_worker_id.take() {  // Use the stored worker ID
                         let mut channels = shared_state.grpc_worker_channels.lock().await;
                         channels.remove(&worker_id);
+                        drop(channels);
+                        shared_state.unsubscribe_worker(&worker_id).await;
                     }
                 }
             }
@@ -194,28 +256,24 @@ impl<B: LoadBalancer> SchedulerServer<B> {
     }
 }
 
-async fn cancel_client(tx: Sender<Result<SchedulerMessage, Status>>, rx_cancel:&mut Receiver<()>) {
-    rx_cancel.recv().await;
-    let status = Status::aborted("Aborting channel");
-    if tx.send(Err(status)).await.is_err() {
-        error!("errored while sending worker error to scheduler");
-    }
-}
-
 pub struct SharedData {
     pub(crate) worker_pool: Arc<Mutex<WorkerPool>>,
-    pub worker_heartbeat: Arc<Mutex<HashMap<String, Instant>>>,
 }
 
 // Function to start the scheduler single process node gRPC server
 // #[tonic::async_trait]
+#[allow(clippy::too_many_arguments)]
 pub async fn start_scheduler_grpc_server(
     port: i32,
     pool: worker_pool::WorkerPool,
     graceful_timeout: u64,
     heartbeat_interval: std::time::Duration,
     max_task_queue: Option<usize>,
-    data_layer: Arc<Mutex<dyn data::DataStore>>,
+    data_layer: Arc<dyn data::DataStore>,
+    queues: QueueRegistry,
+    rate_limiter: Arc<RateLimiter>,
+    advertise_address: String,
+    idempotency_window: Duration,
 ) -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(feature = "stats")]
     {
@@ -233,24 +291,31 @@ pub async fn start_scheduler_grpc_server(
 
     // Init the worker pool
     let cloned_pool = pool.clone();
-    let shared_data = Arc::new(SharedData {
-        worker_pool: Arc::new(Mutex::new(pool)), // Pass the actual worker pool here
-        worker_heartbeat: Arc::new(Mutex::new(HashMap::new())),
-    });
 
     // gRPC server setup
     let addr = format!("0.0.0.0:{}", port).as_str().parse()?;
 
     // let binding_rx = rx.clone();
     // SchedulerWorkerService - for communication of workers to scheduler
-    let grpc_state = GrpcSharedState::new(RoundRobinBalancer::new(), max_task_queue);
+    // Tasks registered in the scheduler's own registry run on its pool, the others on remote workers
+    let grpc_state = GrpcSharedState::new(RoundRobinBalancer::new(), max_task_queue)
+        .with_queues(queues)
+        .with_rate_limiter(rate_limiter)
+        .with_local_pool(cloned_pool.clone());
     let shared_grpc_state = Arc::new(grpc_state);
-    let scheduler_worker_svc = SchedulerServer::new(shared_grpc_state.clone(), data_layer.clone());
+    let lease_duration = dispatcher::lease_duration(heartbeat_interval);
+    let election = Arc::new(LeaderElection::new(data_layer.clone(), advertise_address, lease_duration));
+    let scheduler_worker_svc = SchedulerServer::new(shared_grpc_state.clone(), data_layer.clone(), election.clone(), lease_duration, idempotency_window);
+
+    // The queue left by a previous leader is rebuilt from the data store once this scheduler is elected
+    leader::spawn_leader_election(election.clone(), shared_grpc_state.clone(), lease_duration);
+    dispatcher::spawn_dispatcher(shared_grpc_state.clone(), data_layer.clone(), election.clone(), lease_duration);
+    dispatcher::spawn_lease_reaper(shared_grpc_state.clone(), data_layer.clone(), election.clone(), lease_duration);
     let svc = SchedulerWorkerServiceServer::new(scheduler_worker_svc);
 
     // SchedulerService - admin service for communicating with scheduler by clients.
     let admin_service =
-        SchedulerServiceServer::new(SchedulerAdminService::new(shared_data.clone(), Some(shared_grpc_state.clone()), data_layer));
+        SchedulerServiceServer::new(SchedulerAdminService::new(Some(shared_grpc_state.clone()), data_layer.clone(), election.clone(), lease_duration, idempotency_window));
 
     // This AtomicBool will be used to track if the interrupt was previously received
     let interrupt_received = Arc::new(AtomicBool::new(false));
@@ -258,6 +323,11 @@ pub async fn start_scheduler_grpc_server(
     tokio::spawn(async move {
         loop {
             tokio::time::sleep(heartbeat_interval).await;
+
+            // The local pool keeps the executions it runs like a remote worker would
+            if let Err(err) = dispatcher::renew_local_leases(&grpc_binding, &*data_layer, lease_duration).await {
+                error!("failed to renew local task execution leases: {}", err);
+            }
     
             let mut worker_channels = grpc_binding.grpc_worker_channels.lock().await;
     
@@ -292,6 +362,9 @@ pub async fn start_scheduler_grpc_server(
 
                 interrupt_received.store(true, Ordering::Relaxed);
                 
+                // Hand the lease over right away instead of letting it expire
+                election.resign().await;
+
                 let pool = cloned_pool.clone();
                 let grpc = shared_grpc_state.clone();
                 let wc = grpc.grpc_worker_channels.lock().await;
@@ -334,6 +407,8 @@ pub async fn start_single_process_grpc_server(
     port: i32,
     pool: worker_pool::WorkerPool,
     graceful_timeout: u64,
+    data_layer: Option<Arc<dyn data::DataStore>>,
+    idempotency_window: Duration,
 ) -> Result<(), Box<dyn std::error::Error>> {
     #[cfg(feature = "stats")]
     {
@@ -353,15 +428,15 @@ pub async fn start_single_process_grpc_server(
     let cloned_pool = pool.clone();
     let shared_data = Arc::new(SharedData {
         worker_pool: Arc::new(Mutex::new(pool)),
-        worker_heartbeat: Arc::new(Mutex::new(HashMap::new())), // Pass the actual worker pool here
     });
 
     // gRPC server setup
     let addr = format!("0.0.0.0:{}", port).as_str().parse()?;
 
     // SchedulerService - admin service for communicating with scheduler by clients
-    let admin_service =
-        SchedulerServiceServer::new(SchedulerSingleProcessAdminService::new(shared_data.clone()));
+    let admin = SchedulerSingleProcessAdminService::new(shared_data.clone(), data_layer, idempotency_window);
+    admin.recover_pending_executions().await;
+    let admin_service = SchedulerServiceServer::new(admin);
 
     // This AtomicBool will be used to track if the interrupt was previously received
     let interrupt_received = Arc::new(AtomicBool::new(false));
@@ -418,14 +493,44 @@ pub async fn start_single_process_grpc_server(
 
 
 pub struct SchedulerAdminService<B: LoadBalancer> {
-    shared_data: Arc<SharedData>,
     shared_grpc_state: Option<Arc<GrpcSharedState<B>>>,
-    data_layer: Arc<Mutex<dyn DataStore>>,
+    data_layer: Arc<dyn DataStore>,
+    election: Arc<LeaderElection>,
+    lease_duration: Duration,
+    idempotency_window: Duration,
+}
+
+/// Outcome of submitting an execution.
+enum Submission {
+    /// The execution is dispatched.
+    Submitted(ExecuteResponse),
+    /// The request duplicates an earlier submission, whose execution is returned as it stands.
+    Duplicate(Box<ExecutionRecord>),
 }
 
 impl<B: LoadBalancer> SchedulerAdminService<B> {
-    fn new(shared_data: Arc<SharedData>, shared_grpc_state: Option<Arc<GrpcSharedState<B>>>, data_layer: Arc<Mutex<dyn DataStore>> ) -> Self {
-        Self { shared_data , shared_grpc_state, data_layer }
+    fn new(shared_grpc_state: Option<Arc<GrpcSharedState<B>>>, data_layer: Arc<dyn DataStore>, election: Arc<LeaderElection>, lease_duration: Duration, idempotency_window: Duration) -> Self {
+        Self { shared_grpc_state, data_layer, election, lease_duration, idempotency_window }
+    }
+
+    /// Persists the execution and submits it, unless the request duplicates an earlier one.
+    async fn submit_execution(&self, execution_id: String, req: ExecuteRequest) -> Result<Submission, Status> {
+        submit_execution(
+            self.shared_grpc_state.as_ref(),
+            &self.data_layer,
+            &self.election,
+            self.lease_duration,
+            self.idempotency_window,
+            execution_id,
+            req,
+        )
+        .await
+    }
+
+    fn grpc_state(&self) -> Result<&Arc<GrpcSharedState<B>>, Status> {
+        self.shared_grpc_state
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("queues are only managed by scheduler nodes"))
     }
 }
 
@@ -436,55 +541,16 @@ impl<B: LoadBalancer> SchedulerService for SchedulerAdminService<B> {
         &self,
         request: Request<ExecuteRequest>,
     ) -> Result<Response<ExecuteResponse>, Status> {
-        let shared_data = self.shared_data.as_ref().clone();
         let req = request.into_inner();
-        let task_name = req.task.as_ref().unwrap().id.clone();
-        println!("task->{}", task_name);
-        let db = self.data_layer.lock().await;
-        let cloned_task = req.clone();
-        db.add_task_execution(cloned_task).await;
-        // let db = self.data_layer.lock().await;
-        // db.add_task_execution(req).await;
-        match &self.shared_grpc_state {
-            Some(sd) => {
-                info!("dispatching task to workers");
-                let sm = SchedulerMessage {
-                    scheduler_message_type: Some(
-                        scheduler_message::SchedulerMessageType::AssignTask(
-                            AssignTaskRequest { task: req.task, execution_id: Uuid::new_v4().to_string()  }
-                        )
-                    )
-                };
-                sd.distribute_task(sm).await
-            }
-            None => {
-                let mut err_details = ErrorDetails::new();
-                err_details
-                    .add_precondition_failure_violation(
-                        "EXECUTOR",
-                        "WorkerPool",
-                        "failed to execute task on gRPC worker pool",
-                    )
-                    .add_help_link("documentation", "https://protot.io/docs/help")
-                    .set_localized_message("en-US", "error executing task");
-
-                // Generate error status
-                let status = Status::with_error_details(
-                    tonic::Code::FailedPrecondition,
-                    "request contains invalid arguments",
-                    err_details,
-                );
-
-                Err(status)
-            }
+        match self.submit_execution(execution_id(&req), req).await? {
+            Submission::Submitted(response) => Ok(Response::new(response)),
+            Submission::Duplicate(original) => Ok(Response::new(original.to_execute_response())),
         }
-
-        
     }
 
     async fn schedule(
         &self,
-        request: Request<ScheduleRequest>,
+        _request: Request<ScheduleRequest>,
     ) -> Result<Response<ScheduleResponse>, Status> {
         let mut err_details = ErrorDetails::new();
         err_details
@@ -503,77 +569,339 @@ impl<B: LoadBalancer> SchedulerService for SchedulerAdminService<B> {
         );
         Err(status)
     }
+
+    async fn purge_executions(
+        &self,
+        request: Request<PurgeExecutionsRequest>,
+    ) -> Result<Response<PurgeExecutionsResponse>, Status> {
+        purge_executions(&*self.data_layer, request.into_inner()).await
+    }
+
+    async fn get_execution(
+        &self,
+        request: Request<GetExecutionRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        get_execution(&*self.data_layer, request.into_inner()).await
+    }
+
+    async fn execute_and_wait(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        let deadline = request_deadline(&request);
+        let state = self.shared_grpc_state.clone()
+            .ok_or_else(|| executor_error("failed to execute task on gRPC worker pool".to_string()))?;
+        let req = request.into_inner();
+        let execution_id = execution_id(&req);
+        // Registered first so a completion arriving right after the submission isn't missed
+        let waiter = state.waiters().register(&execution_id);
+        match self.submit_execution(execution_id.clone(), req).await? {
+            Submission::Submitted(_) => wait_for_completion(waiter, deadline, &execution_id).await,
+            Submission::Duplicate(original) => {
+                drop(waiter);
+                wait_for_original(state.waiters(), &*self.data_layer, *original, deadline).await
+            }
+        }
+    }
+
+    async fn pause_queue(
+        &self,
+        request: Request<QueueRequest>,
+    ) -> Result<Response<QueueStatus>, Status> {
+        let queues = self.grpc_state()?.queues();
+        let named = queues.pause(queue_name(&request.into_inner())?)?;
+        info!("paused queue {}", named.name());
+        Ok(Response::new(queues.status(named).await?))
+    }
+
+    async fn resume_queue(
+        &self,
+        request: Request<QueueRequest>,
+    ) -> Result<Response<QueueStatus>, Status> {
+        let state = self.grpc_state()?;
+        let named = state.queues().resume(queue_name(&request.into_inner())?)?;
+        info!("resumed queue {}", named.name());
+        state.notify_dispatcher();
+        Ok(Response::new(state.queues().status(named).await?))
+    }
+
+    async fn drain_queue(
+        &self,
+        request: Request<QueueRequest>,
+    ) -> Result<Response<QueueStatus>, Status> {
+        let queues = self.grpc_state()?.queues();
+        let named = queues.drain(queue_name(&request.into_inner())?)?;
+        info!("draining queue {}", named.name());
+        Ok(Response::new(queues.status(named).await?))
+    }
+
+    async fn list_dead_letters(
+        &self,
+        request: Request<ListDeadLettersRequest>,
+    ) -> Result<Response<ListDeadLettersResponse>, Status> {
+        list_dead_letters(&*self.data_layer, request.into_inner()).await
+    }
+
+    async fn get_dead_letter(
+        &self,
+        request: Request<DeadLetterRequest>,
+    ) -> Result<Response<DeadLetter>, Status> {
+        let req = request.into_inner();
+        if req.execution_id.is_empty() {
+            return Err(Status::invalid_argument("execution id must be set"));
+        }
+        Ok(Response::new(self.data_layer.get_dead_letter(&req.execution_id).await?))
+    }
+
+    async fn requeue_dead_letter(
+        &self,
+        request: Request<RequeueDeadLetterRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        let req = request.into_inner();
+        if req.execution_id.is_empty() {
+            return Err(Status::invalid_argument("execution id must be set"));
+        }
+        // Taken out first, so concurrent requeues of the same dead letter submit it once
+        let dead_letter = self.data_layer.take_dead_letter(&req.execution_id).await?;
+        let mut task = dead_letter.task.clone().unwrap_or_default();
+        if let Some(payload) = req.payload {
+            task.payload = Some(payload);
+        }
+        // A requeued task gets its whole ttl again
+        if task.ttl.is_some() {
+            task.expires_at = 0;
+        }
+
+        let execution_id = Uuid::new_v4().to_string();
+        let request = ExecuteRequest { task: Some(task), execution_id: execution_id.clone(), ..Default::default() };
+        match self.submit_execution(execution_id.clone(), request).await {
+            Ok(submission) => {
+                info!("requeued dead letter {} as task execution {}", dead_letter.execution_id, execution_id);
+                match submission {
+                    Submission::Submitted(response) => Ok(Response::new(response)),
+                    Submission::Duplicate(original) => Ok(Response::new(original.to_execute_response())),
+                }
+            }
+            Err(status) => {
+                // Kept so the requeue can be tried again
+                if let Err(err) = self.data_layer.add_dead_letter(&dead_letter).await {
+                    error!("failed to restore dead letter {}: {}", dead_letter.execution_id, err);
+                }
+                Err(status)
+            }
+        }
+    }
+
+    async fn purge_dead_letters(
+        &self,
+        request: Request<PurgeDeadLettersRequest>,
+    ) -> Result<Response<PurgeDeadLettersResponse>, Status> {
+        purge_dead_letters(&*self.data_layer, request.into_inner()).await
+    }
+
+    async fn submit_workflow(
+        &self,
+        request: Request<Workflow>,
+    ) -> Result<Response<WorkflowStatus>, Status> {
+        let mut workflow = request.into_inner();
+        let sd = self.shared_grpc_state
+            .as_ref()
+            .ok_or_else(|| Status::failed_precondition("workflows are only run by scheduler nodes"))?;
+        if !self.election.is_leader() {
+            return Err(Status::unavailable(format!(
+                "scheduler is standing by, current leader: {}",
+                self.election.leader_address().unwrap_or_else(|| "unknown".to_string())
+            )));
+        }
+        if workflow.workflow_id.is_empty() {
+            workflow.workflow_id = Uuid::new_v4().to_string();
+        }
+        // Like single tasks, the tasks of the nodes and their compensations take the defaults of their queue
+        for task in workflow.nodes.iter_mut().flat_map(|node| node.task.iter_mut().chain(node.compensation.iter_mut())) {
+            sd.queues().apply_defaults(task)?;
+        }
+
+        let status = WorkflowStatus::new(workflow, utils::current_timestamp())?;
+        let (status, started) = sd.workflows().submit(&*self.data_layer, status).await.map_err(|err| match err {
+            DataStoreError::Duplicate(id) => Status::already_exists(format!("Workflow already exists: {}", id)),
+            err => err.into(),
+        })?;
+        dispatcher::submit_workflow_nodes(sd, &self.data_layer, started, self.lease_duration).await;
+        Ok(Response::new(status))
+    }
+
+    async fn get_workflow(
+        &self,
+        request: Request<GetWorkflowRequest>,
+    ) -> Result<Response<WorkflowStatus>, Status> {
+        let req = request.into_inner();
+        if req.workflow_id.is_empty() {
+            return Err(Status::invalid_argument("workflow id must be set"));
+        }
+        match self.data_layer.get_workflow(&req.workflow_id).await {
+            Ok(status) => Ok(Response::new(status)),
+            Err(DataStoreError::NotFound(id)) => Err(Status::not_found(format!("Workflow not found: {}", id))),
+            Err(err) => Err(err.into()),
+        }
+    }
 }
 
 
 pub struct SchedulerSingleProcessAdminService {
     shared_data: Arc<SharedData>,
+    data_layer: Option<Arc<dyn DataStore>>,
+    waiters: Arc<CompletionWaiters>,
+    idempotency_window: Duration,
 }
 
 impl SchedulerSingleProcessAdminService {
-    fn new(shared_data: Arc<SharedData>) -> Self {
-        Self { shared_data  }
+    fn new(shared_data: Arc<SharedData>, data_layer: Option<Arc<dyn DataStore>>, idempotency_window: Duration) -> Self {
+        Self { shared_data, data_layer, waiters: Arc::new(CompletionWaiters::new()), idempotency_window }
     }
-}
 
-#[tonic::async_trait]
-impl SchedulerService for SchedulerSingleProcessAdminService {
-    async fn execute(
-        &self,
-        request: Request<ExecuteRequest>,
-    ) -> Result<Response<ExecuteResponse>, Status> {
-        let shared_data = self.shared_data.as_ref().clone();
-        let req = request.into_inner().clone();
-        let task_name = req.task.as_ref().unwrap().id.clone();
-        println!("task->{}", task_name);
+    /// Dispatches the execution unless the request duplicates an earlier one.
+    async fn submit_execution(&self, mut req: ExecuteRequest) -> Result<Submission, Status> {
+        match req.task.as_mut() {
+            // Stored with the time it expires at, so a recovered task doesn't get its ttl again
+            Some(task) => task.resolve_expiry(utils::current_timestamp()),
+            None => return Err(Status::invalid_argument("task execution must include valid data")),
+        }
+        if !req.idempotency_key.is_empty() {
+            let db = self.data_layer.as_ref()
+                .ok_or_else(|| Status::failed_precondition("idempotency keys require a configured data store"))?;
+            if let Some(original) = claim_idempotency_key(&**db, &req, &req.execution_id, self.idempotency_window).await? {
+                return Ok(Submission::Duplicate(Box::new(original)));
+            }
+        }
+
+        let task_id = req.task.as_ref().map(|t| t.id.clone()).unwrap_or_default();
+        let execution_id = req.execution_id.clone();
+        debug!("submitting task {} as execution {}", task_id, execution_id);
+        match self.dispatch(req.clone(), true).await {
+            Ok(()) => Ok(Submission::Submitted(ExecuteResponse {
+                task_id,
+                execution_id,
+                state: TaskState::Pending.into(),
+                ..Default::default()
+            })),
+            Err(err) => {
+                // Only storing the execution fails with a data store error, the key can be retried
+                if let (SchedulerError::DataStoreError(_), Some(db)) = (&err, &self.data_layer) {
+                    release_idempotency_key(&**db, &req, &execution_id).await;
+                }
+                Err(dispatch_error(err))
+            }
+        }
+    }
+
+    /// Queues the execution on the local worker pool, when a data store is configured
+    /// the execution state is tracked there so it can be recovered after a restart.
+    async fn dispatch(&self, req: ExecuteRequest, persist: bool) -> Result<(), SchedulerError> {
+        let (task_id, task_name) = match req.task.as_ref() {
+            Some(task) => (task.id.clone(), task.executor_name().to_string()),
+            None => Err(SchedulerError::TaskExecutionError(
+                "task execution must include valid data".to_string(),
+            ))?,
+        };
+        let execution_id = req.execution_id.clone();
+
+        if let (true, Some(db)) = (persist, &self.data_layer) {
+            db.add_task_execution(ExecutionRecord::from_request(req.clone())?).await?;
+        }
 
         // Clone the shared data for the closure
-        let cloned_shared = shared_data.worker_pool.as_ref().lock().await;
+        let cloned_shared = self.shared_data.worker_pool.as_ref().lock().await;
 
-        // Capture the 'o' value from the lock before the async block
+        // Capture the executors from the lock before the async block
         let o_clone = { cloned_shared.executors.clone() };
+        let data_layer = self.data_layer.clone();
+        let waiters = self.waiters.clone();
+        let handle = tokio::runtime::Handle::current();
 
         // Execute the logic using the cloned shared data and the cloned request
-        match cloned_shared.execute(
+        cloned_shared.execute(
             move |args| {
-                let logic = o_clone.lock().unwrap();
                 log::debug!("executing task: {}", task_name,);
-                logic.get_executor(&task_name).unwrap().execute(args);
+                let outcome = o_clone.lock().unwrap().execute(&task_name, args);
+                if let TaskOutcome::Failed(err) = &outcome {
+                    error!("task execution {} failed: {:?}", execution_id, err);
+                }
+
+                // Worker threads live outside of the runtime, so we block on the data store update
+                let completion = TaskCompletion::from_outcome(task_id, execution_id, outcome);
+                if let Some(db) = data_layer {
+                    handle.block_on(async {
+                        if let Err(err) = db.complete_task_execution(&completion, None).await {
+                            error!("failed to update task execution {}: {}", completion.execution_id, err);
+                        }
+                    });
+                }
+                waiters.notify(&completion);
             },
             req,
-        ) {
-            Ok(res) => Ok(Response::new(ExecuteResponse {
-                ..Default::default()
-            })),
-            Err(err) => {
-                let mut err_details = ErrorDetails::new();
-                err_details
-                    .add_precondition_failure_violation(
-                        "EXECUTOR",
-                        "WorkerPool",
-                        format!("failed to execute task on worker pool: {:?}", err),
-                    )
-                    .add_help_link("documentation", "https://protot.io/docs/help")
-                    .set_localized_message("en-US", "error executing task");
+        )
+    }
 
-                // Generate error status
-                let status = Status::with_error_details(
-                    tonic::Code::FailedPrecondition,
-                    "request contains invalid arguments",
-                    err_details,
-                );
+    /// Re-queues the executions that were still pending when the process stopped, along with
+    /// the running ones whose lease expired as nothing runs them anymore.
+    async fn recover_pending_executions(&self) {
+        let db = match &self.data_layer {
+            Some(db) => db.clone(),
+            None => return,
+        };
 
-                Err(status)
+        let now = utils::current_timestamp();
+        let running = db
+            .list_all_task_executions(&ExecutionFilter::default().with_state(TaskState::Running))
+            .await;
+        match running {
+            Ok(executions) => {
+                for record in executions.iter().filter(|record| record.lease_expired(now)) {
+                    warn!("lease of task execution {} expired, queueing it again", record.execution_id);
+                    if let Err(err) = db.update_task_execution_state(&record.execution_id, TaskState::Pending, None).await {
+                        error!("failed to requeue task execution {}: {}", record.execution_id, err);
+                    }
+                }
             }
+            Err(err) => error!("unable to recover running task executions: {}", err),
+        }
+
+        let pending = db
+            .list_all_task_executions(&ExecutionFilter::default().with_state(TaskState::Pending))
+            .await;
+        match pending {
+            Ok(executions) => {
+                info!("recovering {} pending task executions", executions.len());
+                for record in executions {
+                    let execution_id = record.execution_id.clone();
+                    if let Err(err) = self.dispatch(record.to_execute_request(), false).await {
+                        error!("failed to recover task execution {}: {}", execution_id, err);
+                    }
+                }
+            }
+            Err(err) => error!("unable to recover pending task executions: {}", err),
+        }
+    }
+}
+
+#[tonic::async_trait]
+impl SchedulerService for SchedulerSingleProcessAdminService {
+    async fn execute(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        let mut req = request.into_inner();
+        req.execution_id = execution_id(&req);
+        match self.submit_execution(req).await? {
+            Submission::Submitted(response) => Ok(Response::new(response)),
+            Submission::Duplicate(original) => Ok(Response::new(original.to_execute_response())),
         }
-        
     }
 
     async fn schedule(
         &self,
-        request: Request<ScheduleRequest>,
+        _request: Request<ScheduleRequest>,
     ) -> Result<Response<ScheduleResponse>, Status> {
-        let shared_data = self.shared_data.clone();
         let mut err_details = ErrorDetails::new();
         err_details
             .add_precondition_failure_violation(
@@ -591,4 +919,479 @@ impl SchedulerService for SchedulerSingleProcessAdminService {
         );
         Err(status)
     }
-}
\ No newline at end of file
+
+    async fn purge_executions(
+        &self,
+        request: Request<PurgeExecutionsRequest>,
+    ) -> Result<Response<PurgeExecutionsResponse>, Status> {
+        match &self.data_layer {
+            Some(db) => purge_executions(&**db, request.into_inner()).await,
+            None => Err(Status::failed_precondition("purging executions requires a configured data store")),
+        }
+    }
+
+    async fn get_execution(
+        &self,
+        request: Request<GetExecutionRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        match &self.data_layer {
+            Some(db) => get_execution(&**db, request.into_inner()).await,
+            None => Err(Status::failed_precondition("fetching executions requires a configured data store")),
+        }
+    }
+
+    async fn execute_and_wait(
+        &self,
+        request: Request<ExecuteRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        let deadline = request_deadline(&request);
+        let mut req = request.into_inner();
+        req.execution_id = execution_id(&req);
+        let execution_id = req.execution_id.clone();
+
+        let waiter = self.waiters.register(&execution_id);
+        match self.submit_execution(req).await? {
+            Submission::Submitted(_) => wait_for_completion(waiter, deadline, &execution_id).await,
+            Submission::Duplicate(original) => {
+                drop(waiter);
+                match &self.data_layer {
+                    Some(db) => wait_for_original(&self.waiters, &**db, *original, deadline).await,
+                    None => Ok(Response::new(original.to_execute_response())),
+                }
+            }
+        }
+    }
+
+    async fn pause_queue(
+        &self,
+        _request: Request<QueueRequest>,
+    ) -> Result<Response<QueueStatus>, Status> {
+        Err(Status::failed_precondition("queues are only managed by scheduler nodes"))
+    }
+
+    async fn resume_queue(
+        &self,
+        _request: Request<QueueRequest>,
+    ) -> Result<Response<QueueStatus>, Status> {
+        Err(Status::failed_precondition("queues are only managed by scheduler nodes"))
+    }
+
+    async fn drain_queue(
+        &self,
+        _request: Request<QueueRequest>,
+    ) -> Result<Response<QueueStatus>, Status> {
+        Err(Status::failed_precondition("queues are only managed by scheduler nodes"))
+    }
+
+    async fn list_dead_letters(
+        &self,
+        _request: Request<ListDeadLettersRequest>,
+    ) -> Result<Response<ListDeadLettersResponse>, Status> {
+        Err(Status::failed_precondition("dead letters are only kept by scheduler nodes"))
+    }
+
+    async fn get_dead_letter(
+        &self,
+        _request: Request<DeadLetterRequest>,
+    ) -> Result<Response<DeadLetter>, Status> {
+        Err(Status::failed_precondition("dead letters are only kept by scheduler nodes"))
+    }
+
+    async fn requeue_dead_letter(
+        &self,
+        _request: Request<RequeueDeadLetterRequest>,
+    ) -> Result<Response<ExecuteResponse>, Status> {
+        Err(Status::failed_precondition("dead letters are only kept by scheduler nodes"))
+    }
+
+    async fn purge_dead_letters(
+        &self,
+        _request: Request<PurgeDeadLettersRequest>,
+    ) -> Result<Response<PurgeDeadLettersResponse>, Status> {
+        Err(Status::failed_precondition("dead letters are only kept by scheduler nodes"))
+    }
+
+    async fn submit_workflow(
+        &self,
+        _request: Request<Workflow>,
+    ) -> Result<Response<WorkflowStatus>, Status> {
+        Err(Status::failed_precondition("workflows are only run by scheduler nodes"))
+    }
+
+    async fn get_workflow(
+        &self,
+        _request: Request<GetWorkflowRequest>,
+    ) -> Result<Response<WorkflowStatus>, Status> {
+        Err(Status::failed_precondition("workflows are only run by scheduler nodes"))
+    }
+}
+
+/// Persists the execution and submits it, unless the request duplicates an earlier one.
+async fn submit_execution<B: LoadBalancer>(
+    state: Option<&Arc<GrpcSharedState<B>>>,
+    db: &Arc<dyn DataStore>,
+    election: &LeaderElection,
+    lease_duration: Duration,
+    idempotency_window: Duration,
+    execution_id: String,
+    req: ExecuteRequest,
+) -> Result<Submission, Status> {
+    let mut task = req.task.clone()
+        .ok_or_else(|| Status::invalid_argument("task execution must include valid data"))?;
+    if !election.is_leader() {
+        return Err(Status::unavailable(format!(
+            "scheduler is standing by, current leader: {}",
+            election.leader_address().unwrap_or_else(|| "unknown".to_string())
+        )));
+    }
+    // The task names a configured queue and takes its defaults for what it leaves unset
+    if let Some(sd) = state {
+        sd.queues().apply_defaults(&mut task)?;
+    }
+    task.resolve_expiry(utils::current_timestamp());
+    if let Some(original) = claim_idempotency_key(&**db, &req, &execution_id, idempotency_window).await? {
+        return Ok(Submission::Duplicate(Box::new(original)));
+    }
+    if let Some(sd) = state {
+        if let Err(status) = sd.queues().admit(&task).await {
+            release_idempotency_key(&**db, &req, &execution_id).await;
+            return Err(status);
+        }
+    }
+    debug!("queueing task {} ({}) as execution {}", task.id, task.executor_name(), execution_id);
+    // Executions are queued from the data store after a restart, so they must be persisted first
+    if let Err(err) = db.add_task_execution(ExecutionRecord::new(execution_id.clone(), task.clone())).await {
+        release_idempotency_key(&**db, &req, &execution_id).await;
+        return Err(err.into());
+    }
+    match state {
+        Some(sd) => {
+            let task_id = task.id.clone();
+            let request = AssignTaskRequest { task: Some(task), execution_id: execution_id.clone() };
+            dispatcher::submit(sd, db, request, lease_duration).await?;
+            Ok(Submission::Submitted(ExecuteResponse {
+                task_id,
+                execution_id,
+                state: TaskState::Pending.into(),
+                ..Default::default()
+            }))
+        }
+        None => Err(executor_error("failed to execute task on gRPC worker pool".to_string())),
+    }
+}
+
+/// Submits a child task on behalf of a running execution and replies on the worker stream,
+/// then sends the completion of the child once it finishes when the worker asked for it.
+async fn submit_child_task<B: LoadBalancer>(
+    state: Arc<GrpcSharedState<B>>,
+    db: Arc<dyn DataStore>,
+    election: Arc<LeaderElection>,
+    lease_duration: Duration,
+    idempotency_window: Duration,
+    tx: Sender<Result<SchedulerMessage, Status>>,
+    submit: SubmitTask,
+) {
+    let mut req = submit.request.unwrap_or_default();
+    if let Some(task) = req.task.as_mut() {
+        task.parent_execution_id = submit.parent_execution_id.clone();
+    }
+    let execution_id = execution_id(&req);
+    // Registered first so a child finishing right after its submission isn't missed
+    let waiter = submit.notify_completion.then(|| state.waiters().register(&execution_id));
+    let submission = submit_execution(Some(&state), &db, &election, lease_duration, idempotency_window, execution_id, req).await;
+    let (reply, original) = match submission {
+        Ok(Submission::Submitted(response)) => (child_task_submitted(&submit.request_id, response.execution_id, Status::ok("")), None),
+        Ok(Submission::Duplicate(original)) => {
+            (child_task_submitted(&submit.request_id, original.execution_id.clone(), Status::ok("")), Some(original))
+        }
+        Err(status) => {
+            info!("rejected child task of task execution {}: {}", submit.parent_execution_id, status.message());
+            (child_task_submitted(&submit.request_id, String::new(), status), None)
+        }
+    };
+    let submitted = reply.code == tonic::Code::Ok as i32;
+    let message = scheduler_message::SchedulerMessageType::TaskSubmitted(reply);
+    if tx.send(Ok(SchedulerMessage { scheduler_message_type: Some(message) })).await.is_err() || !submitted {
+        return;
+    }
+    let Some(waiter) = waiter else { return };
+
+    let waiter = match original {
+        None => waiter,
+        Some(original) => {
+            drop(waiter);
+            let waiter = state.waiters().register(&original.execution_id);
+            // It may have finished before the waiter was registered
+            match db.get_task_execution(&original.execution_id).await {
+                Ok(record) if record.is_finished() => {
+                    let message = scheduler_message::SchedulerMessageType::ChildCompletion(record.to_completion());
+                    let _ = tx.send(Ok(SchedulerMessage { scheduler_message_type: Some(message) })).await;
+                    return;
+                }
+                Ok(_) => waiter,
+                Err(err) => {
+                    error!("failed to get child task execution {}: {}", original.execution_id, err);
+                    // The parent would wait forever for a completion it can't be sent
+                    let error = TaskError::new("CHILD_TASK_UNAVAILABLE", err.to_string());
+                    let completion = TaskCompletion::from_result(original.task.id.clone(), original.execution_id.clone(), Err(error));
+                    let message = scheduler_message::SchedulerMessageType::ChildCompletion(completion);
+                    let _ = tx.send(Ok(SchedulerMessage { scheduler_message_type: Some(message) })).await;
+                    return;
+                }
+            }
+        }
+    };
+    select! {
+        Some(completion) = waiter.wait(None) => {
+            let message = scheduler_message::SchedulerMessageType::ChildCompletion(completion);
+            let _ = tx.send(Ok(SchedulerMessage { scheduler_message_type: Some(message) })).await;
+        }
+        // The worker went away, its parent execution is no longer waiting
+        _ = tx.closed() => {}
+    }
+}
+
+/// Reply to the child task submission `request_id`, `status` tells whether it was submitted.
+fn child_task_submitted(request_id: &str, execution_id: String, status: Status) -> TaskSubmitted {
+    TaskSubmitted {
+        request_id: request_id.to_string(),
+        execution_id,
+        code: status.code() as i32,
+        message: status.message().to_string(),
+    }
+}
+
+/// The execution id set by the caller, a new one when it didn't set any.
+///
+/// A reused execution id is rejected by the data store.
+fn execution_id(req: &ExecuteRequest) -> String {
+    if req.execution_id.is_empty() {
+        Uuid::new_v4().to_string()
+    } else {
+        req.execution_id.clone()
+    }
+}
+
+/// The queue named by the request, which must name one.
+fn queue_name(req: &QueueRequest) -> Result<&str, Status> {
+    if req.queue.is_empty() {
+        return Err(Status::invalid_argument("queue must be set"));
+    }
+    Ok(&req.queue)
+}
+
+/// Precondition failure returned when a task can't be handed to the worker pool.
+fn executor_error(violation: String) -> Status {
+    let mut err_details = ErrorDetails::new();
+    err_details
+        .add_precondition_failure_violation("EXECUTOR", "WorkerPool", violation)
+        .add_help_link("documentation", "https://protot.io/docs/help")
+        .set_localized_message("en-US", "error executing task");
+
+    // Generate error status
+    Status::with_error_details(
+        tonic::Code::FailedPrecondition,
+        "request contains invalid arguments",
+        err_details,
+    )
+}
+
+/// Claims the idempotency key of the request for `execution_id`, returns the original
+/// execution when the request duplicates an earlier submission.
+async fn claim_idempotency_key(
+    db: &dyn DataStore,
+    req: &ExecuteRequest,
+    execution_id: &str,
+    window: Duration,
+) -> Result<Option<ExecutionRecord>, Status> {
+    if req.idempotency_key.is_empty() {
+        return Ok(None);
+    }
+    let holder = db.claim_idempotency_key(&req.idempotency_key, execution_id, window).await?;
+    if holder == execution_id {
+        return Ok(None);
+    }
+    info!("idempotency key {} was already submitted as task execution {}", req.idempotency_key, holder);
+    Ok(Some(db.get_task_execution(&holder).await?))
+}
+
+/// Frees the idempotency key of a request whose execution could not be stored, so it can be retried.
+async fn release_idempotency_key(db: &dyn DataStore, req: &ExecuteRequest, execution_id: &str) {
+    if req.idempotency_key.is_empty() {
+        return;
+    }
+    if let Err(err) = db.release_idempotency_key(&req.idempotency_key, execution_id).await {
+        error!("failed to release idempotency key {}: {}", req.idempotency_key, err);
+    }
+}
+
+/// Waits for the original execution of a duplicate `ExecuteAndWait` request.
+async fn wait_for_original(
+    waiters: &CompletionWaiters,
+    db: &dyn DataStore,
+    original: ExecutionRecord,
+    deadline: Option<Duration>,
+) -> Result<Response<ExecuteResponse>, Status> {
+    if original.is_finished() {
+        return Ok(Response::new(original.to_execute_response()));
+    }
+    let waiter = waiters.register(&original.execution_id);
+    // It may have finished before the waiter was registered
+    let record = db.get_task_execution(&original.execution_id).await?;
+    if record.is_finished() {
+        return Ok(Response::new(record.to_execute_response()));
+    }
+    wait_for_completion(waiter, deadline, &record.execution_id).await
+}
+
+/// Keeps the status of data store failures (e.g. a reused execution id), the pool ones
+/// are precondition failures.
+fn dispatch_error(err: SchedulerError) -> Status {
+    match err {
+        SchedulerError::DataStoreError(err) => err.into(),
+        err => executor_error(format!("failed to execute task on worker pool: {:?}", err)),
+    }
+}
+
+/// Deadline the client set on the call, if any.
+fn request_deadline<T>(request: &Request<T>) -> Option<Duration> {
+    request
+        .metadata()
+        .get("grpc-timeout")
+        .and_then(|value| value.to_str().ok())
+        .and_then(parse_grpc_timeout)
+}
+
+/// Waits for the execution to finish, the execution keeps running when the deadline
+/// expires first and its result can still be fetched with `GetExecution`.
+async fn wait_for_completion(
+    waiter: CompletionWaiter<'_>,
+    deadline: Option<Duration>,
+    execution_id: &str,
+) -> Result<Response<ExecuteResponse>, Status> {
+    match waiter.wait(deadline).await {
+        Some(completion) => Ok(Response::new(completion.into())),
+        None => Err(Status::deadline_exceeded(format!(
+            "task execution {} did not finish before the deadline",
+            execution_id
+        ))),
+    }
+}
+
+/// Deletes the executions matching the request filter, an empty filter is rejected
+/// so a malformed request can't wipe the whole data store.
+async fn purge_executions(db: &dyn DataStore, req: PurgeExecutionsRequest) -> Result<Response<PurgeExecutionsResponse>, Status> {
+    let filter = ExecutionFilter::try_from(&req.filter.unwrap_or_default())?;
+    if filter.is_empty() {
+        return Err(Status::invalid_argument("purge filter must set at least one field"));
+    }
+
+    let purged = db.purge_task_executions(&filter).await?;
+    info!("purged {} task executions", purged);
+    Ok(Response::new(PurgeExecutionsResponse { purged }))
+}
+
+/// Returns the execution state, along with the task output or error once it finished.
+async fn get_execution(db: &dyn DataStore, req: GetExecutionRequest) -> Result<Response<ExecuteResponse>, Status> {
+    if req.execution_id.is_empty() {
+        return Err(Status::invalid_argument("execution id must be set"));
+    }
+    let record = db.get_task_execution(&req.execution_id).await?;
+    Ok(Response::new(record.to_execute_response()))
+}
+
+/// Returns a page of dead letters, optionally of a single task type.
+async fn list_dead_letters(db: &dyn DataStore, req: ListDeadLettersRequest) -> Result<Response<ListDeadLettersResponse>, Status> {
+    let page = PageRequest {
+        cursor: Some(req.cursor).filter(|cursor| !cursor.is_empty()),
+        limit: if req.limit == 0 { DEFAULT_PAGE_LIMIT } else { req.limit as usize },
+    };
+    let task_type = Some(req.task_type.as_str()).filter(|task_type| !task_type.is_empty());
+
+    let page = db.list_dead_letters(task_type, &page).await?;
+    Ok(Response::new(ListDeadLettersResponse {
+        dead_letters: page.items,
+        next_cursor: page.next_cursor.unwrap_or_default(),
+    }))
+}
+
+/// Deletes the dead letters of the requested executions, or of a task type when none are
+/// requested. Executions without a dead letter are skipped. Purging all of them has to be
+/// asked for with `all`, an empty request is rejected.
+async fn purge_dead_letters(db: &dyn DataStore, req: PurgeDeadLettersRequest) -> Result<Response<PurgeDeadLettersResponse>, Status> {
+    if req.execution_ids.is_empty() && req.task_type.is_empty() && !req.all {
+        return Err(Status::invalid_argument("execution ids, a task type or all must be set"));
+    }
+    let purged = if req.execution_ids.is_empty() {
+        let task_type = Some(req.task_type.as_str()).filter(|task_type| !task_type.is_empty());
+        db.purge_dead_letters(task_type).await?
+    } else {
+        let mut purged = 0;
+        for execution_id in &req.execution_ids {
+            match db.take_dead_letter(execution_id).await {
+                Ok(_) => purged += 1,
+                Err(DataStoreError::NotFound(_)) => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        purged
+    };
+    info!("purged {} dead letters", purged);
+    Ok(Response::new(PurgeDeadLettersResponse { purged }))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex as StdMutex;
+
+    use prost_types::Any;
+
+    use crate::{
+        core::worker_pool::{Builder, TaskExecutor, TaskRegistry},
+        data::WalDataStore,
+        internal::protot::core::{Task, TaskError},
+    };
+
+    use super::*;
+
+    struct NoopExecutor;
+
+    impl TaskExecutor for NoopExecutor {
+        fn execute(&self, _args: ExecuteRequest) -> Result<Any, TaskError> {
+            Ok(Any::default())
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_restart_recovers_pending_and_expired_executions() {
+        let dir = tempfile::tempdir().unwrap();
+        let now = utils::current_timestamp();
+        let task = Task { id: "task-1".to_string(), task_type: "noop".to_string(), ..Default::default() };
+        {
+            let store = WalDataStore::open(dir.path(), 100).unwrap();
+            for execution_id in ["queued", "leased", "expired"] {
+                store.add_task_execution(ExecutionRecord::new(execution_id.to_string(), task.clone())).await.unwrap();
+            }
+            store.lease_task_execution("leased", "worker-1", now + 60, None).await.unwrap();
+            store.lease_task_execution("expired", "worker-1", now - 1, None).await.unwrap();
+        }
+
+        // The process comes back up on the same log
+        let store: Arc<dyn DataStore> = Arc::new(WalDataStore::open(dir.path(), 100).unwrap());
+        let mut registry = TaskRegistry::new();
+        registry.register_task("noop", NoopExecutor);
+        let pool = Builder::new().num_workers(1).executors(Arc::new(StdMutex::new(registry))).build().unwrap();
+        let shared_data = Arc::new(SharedData { worker_pool: Arc::new(Mutex::new(pool)) });
+        let admin = SchedulerSingleProcessAdminService::new(shared_data, Some(store.clone()), Duration::from_secs(60));
+        let waiters = ["queued", "expired"].map(|execution_id| admin.waiters.register(execution_id));
+        admin.recover_pending_executions().await;
+
+        for waiter in waiters {
+            assert_eq!(waiter.wait(Some(Duration::from_secs(5))).await.unwrap().state(), TaskState::Success);
+        }
+        assert_eq!(store.get_task_execution("queued").await.unwrap().state, TaskState::Success);
+        assert_eq!(store.get_task_execution("expired").await.unwrap().state, TaskState::Success);
+        // Its worker may still be running the one under a live lease
+        assert_eq!(store.get_task_execution("leased").await.unwrap().state, TaskState::Running);
+    }
+}
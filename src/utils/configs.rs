@@ -17,7 +17,7 @@ use serde::{Deserialize, Serialize};
 use serde_json;
 use serde_yaml;
 use std::fs;
-use crate::internal::protot::core::{self, Config, DataStore};
+use crate::internal::protot::core::{self, Config, DataStore, QueueConfig, RateLimit, Retention, RetryPolicy, TaskRateLimit, WorkerConfig};
 
 use super::error::SchedulerError; // Import Serialize and Deserialize traits
 
@@ -37,6 +37,21 @@ pub enum NodeType {
 pub enum DataStoreType {
     #[serde(rename = "REDIS")]
     Redis,
+    #[serde(rename = "SQLITE")]
+    Sqlite,
+    #[serde(rename = "POSTGRES")]
+    Postgres,
+    #[serde(rename = "FILE")]
+    File,
+}
+
+#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[allow(non_camel_case_types)]
+pub enum QueueType {
+    #[serde(rename = "MEMORY")]
+    Memory,
+    #[serde(rename = "REDIS_STREAMS")]
+    RedisStreams,
 }
 
 #[derive(Debug, Serialize, Deserialize, PartialEq)]
@@ -58,6 +73,103 @@ pub struct DataStoreWrapper {
     r#type: DataStoreType,
     #[serde(rename = "host")]
     host: String,
+    #[serde(rename = "retention", default)]
+    retention: Option<RetentionWrapper>,
+    #[serde(rename = "queue", default)]
+    queue: Option<QueueType>,
+    #[serde(rename = "idempotency_window", default)]
+    idempotency_window: Option<WrapperDuration>,
+}
+
+#[derive(Debug, Serialize, Deserialize)] // Use the derive macros for serialization and deserialization
+pub struct RetentionWrapper {
+    #[serde(rename = "success", default)]
+    success: Option<WrapperDuration>,
+    #[serde(rename = "fail", default)]
+    fail: Option<WrapperDuration>,
+    #[serde(rename = "sweep_interval", default)]
+    sweep_interval: Option<WrapperDuration>,
+    #[serde(rename = "expired", default)]
+    expired: Option<WrapperDuration>,
+}
+
+impl From<WrapperDuration> for Duration {
+    fn from(duration: WrapperDuration) -> Self {
+        Duration {
+            seconds: duration.seconds,
+            nanos: duration.nanos,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)] // Use the derive macros for serialization and deserialization
+pub struct WorkerWrapper {
+    #[serde(rename = "schedulers", default)]
+    schedulers: Vec<String>,
+    #[serde(rename = "worker_id", default)]
+    worker_id: Option<String>,
+    #[serde(rename = "concurrency", default)]
+    concurrency: Option<u32>,
+    #[serde(rename = "magic_cookie", default)]
+    magic_cookie: Option<String>,
+    #[serde(rename = "queues", default)]
+    queues: Vec<String>,
+}
+
+#[derive(Debug, Serialize, Deserialize)] // Use the derive macros for serialization and deserialization
+pub struct QueueWrapper {
+    #[serde(rename = "name")]
+    name: String,
+    #[serde(rename = "max_concurrency", default)]
+    max_concurrency: Option<u32>,
+    #[serde(rename = "max_depth", default)]
+    max_depth: Option<u32>,
+    #[serde(rename = "default_priority", default)]
+    default_priority: Option<i32>,
+    #[serde(rename = "retry_policy", default)]
+    retry_policy: Option<RetryPolicyWrapper>,
+    #[serde(rename = "rate_limit", default)]
+    rate_limit: Option<RateLimitWrapper>,
+}
+
+#[derive(Debug, Serialize, Deserialize)] // Use the derive macros for serialization and deserialization
+pub struct RetryPolicyWrapper {
+    #[serde(rename = "max_attempts")]
+    max_attempts: u32,
+    #[serde(rename = "initial_backoff", default)]
+    initial_backoff: Option<WrapperDuration>,
+    #[serde(rename = "max_backoff", default)]
+    max_backoff: Option<WrapperDuration>,
+    #[serde(rename = "backoff_multiplier", default)]
+    backoff_multiplier: Option<f64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)] // Use the derive macros for serialization and deserialization
+pub struct RateLimitWrapper {
+    #[serde(rename = "limit")]
+    limit: u32,
+    #[serde(rename = "period")]
+    period: WrapperDuration,
+    #[serde(rename = "burst", default)]
+    burst: Option<u32>,
+}
+
+impl From<RateLimitWrapper> for RateLimit {
+    fn from(rate_limit: RateLimitWrapper) -> Self {
+        RateLimit {
+            limit: rate_limit.limit,
+            period: Some(rate_limit.period.into()),
+            burst: rate_limit.burst.unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)] // Use the derive macros for serialization and deserialization
+pub struct TaskRateLimitWrapper {
+    #[serde(rename = "task_type")]
+    task_type: String,
+    #[serde(rename = "rate_limit")]
+    rate_limit: RateLimitWrapper,
 }
 
 #[derive(Debug, Serialize, Deserialize)] // Use the derive macros for serialization and deserialization
@@ -76,6 +188,14 @@ pub struct SerdeConfig {
     heartbeat_interval: Option<WrapperDuration>,
     #[serde(rename = "data_store")]
     data_store: DataStoreWrapper,
+    #[serde(rename = "advertise_address", default)]
+    advertise_address: Option<String>,
+    #[serde(rename = "worker", default)]
+    worker: Option<WorkerWrapper>,
+    #[serde(rename = "queues", default)]
+    queues: Vec<QueueWrapper>,
+    #[serde(rename = "rate_limits", default)]
+    rate_limits: Vec<TaskRateLimitWrapper>,
 }
 
 #[allow(unused)]
@@ -151,11 +271,13 @@ pub fn config_load(path: String) -> Result<Config, SchedulerError> {
         ));
     };
 
-    let heartbeat_interval = config.heartbeat_interval.map(|custom_duration| {
-        prost_types::Duration {
-            seconds: custom_duration.seconds,
-            nanos: custom_duration.nanos,
-        }
+    let heartbeat_interval = config.heartbeat_interval.map(Duration::from);
+
+    let retention = config.data_store.retention.map(|retention| Retention {
+        success: retention.success.map(Duration::from),
+        fail: retention.fail.map(Duration::from),
+        sweep_interval: retention.sweep_interval.map(Duration::from),
+        expired: retention.expired.map(Duration::from),
     });
 
     let cfg = Config {
@@ -170,13 +292,47 @@ pub fn config_load(path: String) -> Result<Config, SchedulerError> {
         load_balancer: match config.load_balancer {
             LoadBalancer::RoundRobin => core::LoadBalancer::RoundRobin.into(),
         },
-        heartbeat_interval: heartbeat_interval,
+        heartbeat_interval,
         data_store: Some(DataStore {
             r#type: match  config.data_store.r#type {
                 DataStoreType::Redis => core::DataStoreType::Redis.into(),
+                DataStoreType::Sqlite => core::DataStoreType::Sqlite.into(),
+                DataStoreType::Postgres => core::DataStoreType::Postgres.into(),
+                DataStoreType::File => core::DataStoreType::File.into(),
+            },
+            host:  config.data_store.host,
+            retention,
+            queue: match config.data_store.queue {
+                Some(QueueType::RedisStreams) => core::QueueType::RedisStreams.into(),
+                _ => core::QueueType::Memory.into(),
             },
-            host:  config.data_store.host
-        })
+            idempotency_window: config.data_store.idempotency_window.map(Duration::from),
+        }),
+        advertise_address: config.advertise_address.unwrap_or_default(),
+        worker: config.worker.map(|worker| WorkerConfig {
+            schedulers: worker.schedulers,
+            worker_id: worker.worker_id.unwrap_or_default(),
+            concurrency: worker.concurrency.unwrap_or_default(),
+            magic_cookie: worker.magic_cookie.unwrap_or_default(),
+            queues: worker.queues,
+        }),
+        queues: config.queues.into_iter().map(|queue| QueueConfig {
+            name: queue.name,
+            max_concurrency: queue.max_concurrency.unwrap_or_default(),
+            max_depth: queue.max_depth.unwrap_or_default(),
+            default_priority: queue.default_priority.unwrap_or_default(),
+            retry_policy: queue.retry_policy.map(|policy| RetryPolicy {
+                max_attempts: policy.max_attempts,
+                initial_backoff: policy.initial_backoff.map(Duration::from),
+                max_backoff: policy.max_backoff.map(Duration::from),
+                backoff_multiplier: policy.backoff_multiplier.unwrap_or_default(),
+            }),
+            rate_limit: queue.rate_limit.map(RateLimit::from),
+        }).collect(),
+        rate_limits: config.rate_limits.into_iter().map(|limit| TaskRateLimit {
+            task_type: limit.task_type,
+            rate_limit: Some(limit.rate_limit.into()),
+        }).collect(),
     };
 
     Ok(cfg)
@@ -12,6 +12,8 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+use crate::data::DataStoreError;
+
 /// Enumerates all possible errors that can occur within the Scheduler.
 ///
 /// This error type contains variations for each type of error that can
@@ -56,6 +58,11 @@ pub enum SchedulerError {
     SchedulerServiceError(String),
     LoggerSetupError(String),
     DataLayerError(String),
+
+    /// Represents an error returned by a `DataStore` operation.
+    ///
+    /// The contained `DataStoreError` keeps the failure kind (e.g. not found, duplicate).
+    DataStoreError(DataStoreError),
 }
 
 /// Implementation of the `std::fmt::Display` trait for `SchedulerError`.
@@ -78,10 +85,19 @@ impl std::fmt::Display for SchedulerError {
             SchedulerError::DataLayerError(msg) => {
                 write!(f, "Scheduler data layer error: {}", msg)
             }
+            SchedulerError::DataStoreError(err) => {
+                write!(f, "Scheduler data store error: {}", err)
+            }
         }
     }
 }
 
+impl From<DataStoreError> for SchedulerError {
+    fn from(err: DataStoreError) -> Self {
+        SchedulerError::DataStoreError(err)
+    }
+}
+
 /// Implementation of the `std::error::Error` trait for `SchedulerError`.
 ///
 /// By implementing this trait, `SchedulerError` can be used with interfaces that
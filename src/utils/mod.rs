@@ -12,20 +12,16 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use core::fmt;
-
-use crate::internal::protot::core::Config;
-
 pub mod configs;
 pub mod error;
 pub mod logger;
 pub mod shared;
 
-const NAME: &'static str = env!("CARGO_PKG_NAME");
-const VERSION: &'static str = env!("CARGO_PKG_VERSION");
-const AUTHORS: &'static str = env!("CARGO_PKG_AUTHORS");
-const DESCRIPTION: &'static str = env!("CARGO_PKG_DESCRIPTION");
-const REPOSITORY: &'static str = env!("CARGO_PKG_REPOSITORY");
+const NAME: &str = env!("CARGO_PKG_NAME");
+const VERSION: &str = env!("CARGO_PKG_VERSION");
+const AUTHORS: &str = env!("CARGO_PKG_AUTHORS");
+const DESCRIPTION: &str = env!("CARGO_PKG_DESCRIPTION");
+const REPOSITORY: &str = env!("CARGO_PKG_REPOSITORY");
 
 /// Fetches the current timestamp.
 pub fn current_timestamp() -> i64 {
@@ -38,15 +34,14 @@ pub fn current_timestamp() -> i64 {
 }
 
 pub fn get_ascii_logo() -> String {
-    let logo = "
+    "
                              
     _____         _       _____ 
    |  _  |___ ___| |_ ___|_   _|
    |   __|  _| . |  _| . | | |  
    |__|  |_| |___|_| |___| |_|  
                                 
-   ".to_string();
-   logo
+   ".to_string()
 }
 
 pub fn get_protot_metadata() -> String {